@@ -15,13 +15,72 @@ use std::path::PathBuf;
 
 use vergen::EmitBuilder;
 
+#[allow(dead_code)]
+#[path = "src/layout.rs"]
+mod layout;
+
+use layout::Region;
+
 fn main() {
+    // The linker scripts of the application and the bootloader must agree with the memory map the firmware uses.
+    check_memory_x(
+        "memory.x",
+        &[
+            ("MBR", layout::MBR),
+            ("SOFTDEVICE", layout::SOFTDEVICE),
+            ("FLASH", layout::APP),
+            ("BONDS", layout::BONDS),
+            ("BOOTLOADER", layout::BOOTLOADER),
+            ("MBR_PARAMS", layout::MBR_PARAMS),
+            ("BOOTLOADER_STATE", layout::BOOTLOADER_STATE),
+            ("DFU", layout::DFU),
+            ("RAM", layout::APP_RAM),
+        ],
+    );
+    check_memory_x(
+        "../boot/memory.x",
+        &[
+            ("MBR", layout::MBR),
+            ("SOFTDEVICE", layout::SOFTDEVICE),
+            ("ACTIVE", layout::APP),
+            ("BONDS", layout::BONDS),
+            ("FLASH", layout::BOOTLOADER),
+            ("MBR_PARAMS", layout::MBR_PARAMS),
+            ("BOOTLOADER_STATE", layout::BOOTLOADER_STATE),
+            ("DFU", layout::DFU),
+            ("STAGING_HEADER", layout::STAGING_HEADER),
+            ("STAGING", layout::STAGING),
+        ],
+    );
+    check_memory_x(
+        "memory-no-softdevice.x",
+        &[
+            ("FLASH", layout::APP_WITHOUT_SOFTDEVICE),
+            ("BONDS", layout::BONDS),
+            ("BOOTLOADER", layout::BOOTLOADER),
+            ("MBR_PARAMS", layout::MBR_PARAMS),
+            ("BOOTLOADER_STATE", layout::BOOTLOADER_STATE),
+            ("DFU", layout::DFU),
+            ("RAM", layout::RAM),
+        ],
+    );
+    println!("cargo:rerun-if-changed=src/layout.rs");
+    println!("cargo:rerun-if-changed=../boot/memory.x");
+    println!("cargo:rerun-if-changed=memory-no-softdevice.x");
+
+    // Without the SoftDevice the firmware takes its flash and RAM.
+    let memory_x = if env::var_os("CARGO_FEATURE_NO_SOFTDEVICE").is_some() {
+        "memory-no-softdevice.x"
+    } else {
+        "memory.x"
+    };
+
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(&std::fs::read(memory_x).unwrap())
         .unwrap();
 
     println!("cargo:rustc-link-search={}", out.display());
@@ -35,6 +94,78 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+    println!("cargo:rustc-link-arg-tests=--nmagic");
+    println!("cargo:rustc-link-arg-tests=-Tlink.x");
+    println!("cargo:rustc-link-arg-tests=-Tdefmt.x");
+    println!("cargo:rustc-link-arg-tests=-Tembedded-test.x");
+
+    // Public key firmware updates must be signed with, see "Updating firmware" in the README.
+    println!("cargo:rerun-if-env-changed=WATCHFUL_DFU_KEY");
+    if let Ok(key) = env::var("WATCHFUL_DFU_KEY") {
+        assert!(
+            key.len() == 128 && key.chars().all(|c| c.is_ascii_hexdigit()),
+            "WATCHFUL_DFU_KEY must be the 64 byte P-256 public key X and Y in hex"
+        );
+        println!("cargo:rustc-env=WATCHFUL_DFU_KEY={}", key);
+    } else {
+        // Without a key any update is accepted, which only development builds may do.
+        let dfu =
+            env::var_os("CARGO_FEATURE_NO_SOFTDEVICE").is_none() || env::var_os("CARGO_FEATURE_SERIAL_DFU").is_some();
+        assert!(
+            !dfu || env::var_os("CARGO_FEATURE_UNSIGNED_DFU").is_some(),
+            "WATCHFUL_DFU_KEY must be set, or the unsigned-dfu feature enabled to accept unsigned updates"
+        );
+    }
 
+    // Shown on the About screen and logged at boot, next to the commit and build time from vergen.
+    println!("cargo:rustc-env=WATCHFUL_PROFILE={}", env::var("PROFILE").unwrap());
     EmitBuilder::builder().all_build().all_git().emit().unwrap();
 }
+
+fn check_memory_x(path: &str, expected: &[(&str, Region)]) {
+    let script = std::fs::read_to_string(path).unwrap();
+    for (name, region) in expected {
+        let found = memory_region(&script, name).unwrap_or_else(|| panic!("{}: no {} region", path, name));
+        assert!(
+            found == *region,
+            "{}: {} is at {:#x} with length {:#x}, src/layout.rs has {:#x} with length {:#x}",
+            path,
+            name,
+            found.start,
+            found.size,
+            region.start,
+            region.size
+        );
+    }
+}
+
+/// Parse `NAME (attributes) : ORIGIN = start, LENGTH = size` from the MEMORY command of a linker script.
+fn memory_region(script: &str, name: &str) -> Option<Region> {
+    script.lines().find_map(|line| {
+        let rest = line.trim().strip_prefix(name)?.trim_start();
+        if !rest.starts_with(':') && !rest.starts_with('(') {
+            return None;
+        }
+        let (_, rest) = rest.split_once(':')?;
+        let (origin, length) = rest.split_once(',')?;
+        let origin = origin.trim().strip_prefix("ORIGIN")?.trim_start().strip_prefix('=')?;
+        let length = length.trim().strip_prefix("LENGTH")?.trim_start().strip_prefix('=')?;
+        Some(Region::new(number(origin)?, number(length)?))
+    })
+}
+
+fn number(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let (digits, scale) = match text.strip_suffix('K') {
+        Some(digits) => (digits, 1024),
+        None => match text.strip_suffix('M') {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (text, 1),
+        },
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    Some(value * scale)
+}
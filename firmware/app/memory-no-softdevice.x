@@ -0,0 +1,23 @@
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* For the no-softdevice feature, in place of memory.x. Must match src/layout.rs, build.rs checks it */
+  FLASH                             : ORIGIN = 0x00000000, LENGTH = 472K
+  BONDS                             : ORIGIN = 0x00076000, LENGTH = 4K
+  BOOTLOADER                        : ORIGIN = 0x00077000, LENGTH = 28K
+  MBR_PARAMS                        : ORIGIN = 0x0007E000, LENGTH = 4K
+  BOOTLOADER_STATE                  : ORIGIN = 0x0007F000, LENGTH = 4K
+
+  DFU                               : ORIGIN = 0x00000000, LENGTH = 328K
+
+  RAM                               : ORIGIN = 0x20000000, LENGTH = 64K
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
+
+__bonds_start = ORIGIN(BONDS);
+__bonds_end = ORIGIN(BONDS) + LENGTH(BONDS);
+
+__bootloader_dfu_start = ORIGIN(DFU);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
@@ -1,19 +1,25 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* Must match src/layout.rs, build.rs checks it */
   MBR                               : ORIGIN = 0x00000000, LENGTH = 4K
   SOFTDEVICE                        : ORIGIN = 0x00001000, LENGTH = 148K
-  FLASH                             : ORIGIN = 0x00026000, LENGTH = 324K
-  BOOTLOADER                        : ORIGIN = 0x00077000, LENGTH = 32K
+  FLASH                             : ORIGIN = 0x00026000, LENGTH = 320K
+  BONDS                             : ORIGIN = 0x00076000, LENGTH = 4K
+  BOOTLOADER                        : ORIGIN = 0x00077000, LENGTH = 28K
+  MBR_PARAMS                        : ORIGIN = 0x0007E000, LENGTH = 4K
   BOOTLOADER_STATE                  : ORIGIN = 0x0007F000, LENGTH = 4K
 
   DFU                               : ORIGIN = 0x00000000, LENGTH = 328K
 
-  RAM                               : ORIGIN = 0x2000BAF0, LENGTH = 17680
+  RAM                               : ORIGIN = 0x2000C2F0, LENGTH = 15632
 }
 
 __bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
 __bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
 
+__bonds_start = ORIGIN(BONDS);
+__bonds_end = ORIGIN(BONDS) + LENGTH(BONDS);
+
 __bootloader_dfu_start = ORIGIN(DFU);
 __bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
@@ -0,0 +1,677 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, String, Vec};
+
+use crate::clock::Clock;
+use crate::device::SharedMotor;
+use crate::events::{self, SensorEvent};
+use crate::kv::{keys, SharedKv};
+use crate::profile::{profiled, Task};
+use crate::ringlog::{self, RingLog};
+use crate::settings::SettingsCache;
+use crate::{health, layout, LogPartition};
+
+/// Start of the activity log region on the external flash.
+pub const LOG_OFFSET: u32 = layout::ACTIVITY_LOG.start;
+/// Size of the activity log region.
+pub const LOG_SIZE: u32 = layout::ACTIVITY_LOG.size;
+
+/// The resting heart rate of a day is the average of its lowest samples taken while still, which is robust to a
+/// single bad measurement. Days with fewer samples have no estimate.
+const RESTING_SAMPLES: usize = 5;
+const RESTING_MIN_SAMPLES: usize = 3;
+/// Days in the resting heart rate trend.
+pub const TREND_DAYS: usize = 7;
+/// Vibration pulses when the step goal is reached.
+const GOAL_PULSES: u8 = 2;
+const GOAL_PULSE: Duration = Duration::from_millis(250);
+/// Day number, steps of the day and whether the goal was reached, as stored in the key-value store.
+const STEPS_SIZE: usize = 9;
+/// Day number of the steps restored before the clock was synchronized.
+const NO_DAY: u32 = u32::MAX;
+/// Time of the last steps before any were counted.
+const NEVER: u32 = u32::MAX;
+/// Cadence in steps per minute at which the stride length of the settings applies.
+const WALKING_CADENCE: u32 = 110;
+/// Bounds in percent of the stride length scaled by cadence.
+const STRIDE_SCALE: (u32, u32) = (80, 150);
+/// Updates over which cadence and pace are measured.
+const PACE_WINDOW: usize = 15;
+/// Distance in cm below which no pace is shown, as the wearer is standing still.
+const PACE_MIN_DISTANCE: u32 = 500;
+/// Workouts shown in the history.
+pub const HISTORY_SIZE: usize = 6;
+
+/// Uptime in seconds when steps were last counted.
+static LAST_STEP: AtomicU32 = AtomicU32::new(NEVER);
+static HOURLY_STEPS: AtomicU32 = AtomicU32::new(0);
+static DAILY_STEPS: AtomicU32 = AtomicU32::new(0);
+/// The lowest heart rates measured while still today.
+static RESTING: BMutex<CriticalSectionRawMutex, RefCell<Vec<u8, RESTING_SAMPLES>>> =
+    BMutex::new(RefCell::new(Vec::new()));
+/// Whether today's step goal has been celebrated.
+static GOAL_REACHED: AtomicBool = AtomicBool::new(false);
+static RESTORED_DAY: AtomicU32 = AtomicU32::new(NO_DAY);
+
+/// Register steps detected by the step counter.
+pub fn add_steps(steps: u32) {
+    HOURLY_STEPS.fetch_add(steps, Ordering::Relaxed);
+    DAILY_STEPS.fetch_add(steps, Ordering::Relaxed);
+    if steps > 0 {
+        LAST_STEP.store(Instant::now().as_secs() as u32, Ordering::Relaxed);
+    }
+}
+
+/// Whether steps were counted within `window`, as while walking.
+pub fn walking(window: Duration) -> bool {
+    match LAST_STEP.load(Ordering::Relaxed) {
+        NEVER => false,
+        last => (Instant::now().as_secs() as u32).saturating_sub(last) as u64 <= window.as_secs(),
+    }
+}
+
+/// Steps counted since midnight.
+pub fn steps_today() -> u32 {
+    DAILY_STEPS.load(Ordering::Relaxed)
+}
+
+/// Steps not yet logged, for the current hour and the current day.
+pub fn step_counts() -> (u32, u32) {
+    (
+        HOURLY_STEPS.load(Ordering::Relaxed),
+        DAILY_STEPS.load(Ordering::Relaxed),
+    )
+}
+
+/// Register a background heart rate sample taken while the wearer was still.
+pub fn add_resting_heart_rate(bpm: u8) {
+    RESTING.lock(|samples| {
+        let mut samples = samples.borrow_mut();
+        if samples.push(bpm).is_err() {
+            if let Some(highest) = samples.iter_mut().max() {
+                *highest = (*highest).min(bpm);
+            }
+        }
+    })
+}
+
+fn resting_estimate(samples: &[u8]) -> Option<u8> {
+    (samples.len() >= RESTING_MIN_SAMPLES)
+        .then(|| (samples.iter().map(|bpm| *bpm as u32).sum::<u32>() / samples.len() as u32) as u8)
+}
+
+/// The resting heart rate estimate of the day so far, if enough samples were taken.
+pub fn resting_heart_rate() -> Option<u8> {
+    RESTING.lock(|samples| resting_estimate(&samples.borrow()))
+}
+
+/// The resting heart rate estimate of the day so far, starting over for the next day.
+fn take_resting_heart_rate() -> Option<u8> {
+    RESTING.lock(|samples| resting_estimate(&core::mem::take(&mut *samples.borrow_mut())))
+}
+
+/// Continue counting from steps saved before a power off.
+pub fn restore_steps(hourly: u32, daily: u32) {
+    HOURLY_STEPS.store(hourly, Ordering::Relaxed);
+    DAILY_STEPS.store(daily, Ordering::Relaxed);
+}
+
+fn day_number(time: time::PrimitiveDateTime) -> u32 {
+    time.date().to_julian_day() as u32
+}
+
+/// Continue counting from the steps of today stored by an earlier boot.
+///
+/// Steps stored on another day are dropped. Before the clock is synchronized the day is unknown, so the steps are
+/// restored and dropped later if they turn out to be from another day.
+pub async fn load_steps(kv: &SharedKv<'_>, clock: &Clock) {
+    let mut buf = [0; STEPS_SIZE];
+    match kv.lock().await.get(keys::STEPS, &mut buf) {
+        Ok(Some(STEPS_SIZE)) => {}
+        Ok(_) => return,
+        Err(e) => {
+            warn!("Error loading steps: {:?}", e);
+            return;
+        }
+    }
+    let day = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let steps = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    let now = clock.get();
+    if now.year() < 2000 {
+        RESTORED_DAY.store(day, Ordering::Relaxed);
+    } else if day != day_number(now) {
+        return;
+    }
+    // Counts retained through System OFF are more recent than the stored ones.
+    DAILY_STEPS.fetch_max(steps, Ordering::Relaxed);
+    GOAL_REACHED.fetch_or(buf[8] != 0, Ordering::Relaxed);
+    info!("Restored {} steps", steps);
+}
+
+async fn store_steps(kv: &SharedKv<'_>, now: time::PrimitiveDateTime) {
+    let mut buf = [0; STEPS_SIZE];
+    buf[0..4].copy_from_slice(&day_number(now).to_le_bytes());
+    buf[4..8].copy_from_slice(&steps_today().to_le_bytes());
+    buf[8] = GOAL_REACHED.load(Ordering::Relaxed) as u8;
+    if let Err(e) = kv.lock().await.set(keys::STEPS, &buf) {
+        warn!("Error storing steps: {:?}", e);
+    }
+}
+
+/// Celebrate the step goal the first time it is reached in a day, returning whether it was.
+async fn celebrate_goal(settings: &SettingsCache, motor: &SharedMotor) -> bool {
+    let goal = settings.get().step_goal as u32;
+    let steps = steps_today();
+    if goal == 0 || steps < goal || GOAL_REACHED.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    info!("Step goal of {} reached", goal);
+    events::publish(SensorEvent::StepGoalReached(steps));
+    motor.lock().await.pulses(GOAL_PULSES, GOAL_PULSE).await;
+    true
+}
+
+/// Distance and pace of a workout, estimated from the steps counted since it started.
+///
+/// The stride length of the settings is for walking. Strides lengthen with faster steps, so it is scaled with the
+/// cadence of the last updates, between 80% and 150%.
+pub struct WorkoutDistance {
+    /// Steps of the day at the last update.
+    last_steps: u32,
+    steps: u32,
+    distance_cm: u32,
+    /// Seconds, steps and distance in cm of the last updates.
+    window: Deque<(u32, u32, u32), PACE_WINDOW>,
+}
+
+impl WorkoutDistance {
+    pub fn new() -> Self {
+        Self {
+            last_steps: steps_today(),
+            steps: 0,
+            distance_cm: 0,
+            window: Deque::new(),
+        }
+    }
+
+    /// Add the steps counted in the last `seconds`, with a walking stride of `stride_cm`.
+    pub fn update(&mut self, seconds: u32, stride_cm: u8) {
+        let now = steps_today();
+        // The daily count starts over at midnight.
+        let steps = now.checked_sub(self.last_steps).unwrap_or(now);
+        self.last_steps = now;
+        self.steps += steps;
+
+        let (window_seconds, window_steps, _) = self.window_totals();
+        let cadence = (window_steps + steps) * 60 / (window_seconds + seconds).max(1);
+        let scale = (cadence * 100 / WALKING_CADENCE).clamp(STRIDE_SCALE.0, STRIDE_SCALE.1);
+        let distance = steps * stride_cm as u32 * scale / 100;
+        self.distance_cm += distance;
+        if self.window.is_full() {
+            self.window.pop_front();
+        }
+        let _ = self.window.push_back((seconds, steps, distance));
+    }
+
+    fn window_totals(&self) -> (u32, u32, u32) {
+        self.window
+            .iter()
+            .fold((0, 0, 0), |(s, n, d), (seconds, steps, distance)| {
+                (s + seconds, n + steps, d + distance)
+            })
+    }
+
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Distance covered in meters.
+    pub fn distance(&self) -> u32 {
+        self.distance_cm / 100
+    }
+
+    /// Current pace in seconds per kilometer, None while standing still.
+    pub fn pace(&self) -> Option<u32> {
+        let (seconds, _, distance) = self.window_totals();
+        (distance >= PACE_MIN_DISTANCE).then(|| seconds * 100_000 / distance)
+    }
+}
+
+/// The kinds of workout that can be started from the Workout app.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum WorkoutKind {
+    Walk = 1,
+    Run = 2,
+}
+
+impl WorkoutKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Walk),
+            2 => Some(Self::Run),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Walk => "Walk",
+            Self::Run => "Run",
+        }
+    }
+
+    /// Estimate of the energy spent over `seconds` by a wearer of `weight_kg`, from the typical metabolic
+    /// equivalent of the activity: 3.5 for brisk walking and 9.8 for running.
+    pub fn kcal(self, seconds: u32, weight_kg: u8) -> u16 {
+        let met_tenths = match self {
+            Self::Walk => 35,
+            Self::Run => 98,
+        };
+        (met_tenths * weight_kg as u32 * seconds / 36_000).min(u16::MAX as u32) as u16
+    }
+}
+
+/// Summary of a completed workout.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct WorkoutSummary {
+    pub kind: WorkoutKind,
+    /// Start of the workout, seconds since the unix epoch in watch local time.
+    pub timestamp: u32,
+    /// Duration in seconds.
+    pub duration: u32,
+    pub steps: u32,
+    /// Distance in meters.
+    pub distance: u32,
+    /// Average and maximum heart rate in bpm, 0 if none was measured.
+    pub avg_bpm: u8,
+    pub max_bpm: u8,
+    pub kcal: u16,
+}
+
+/// A record in the activity log. Timestamps are seconds since the unix epoch in watch local time.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum ActivityRecord {
+    /// Steps counted during the hour starting at `timestamp`.
+    Hourly { timestamp: u32, steps: u32 },
+    /// Summary of the day starting at `timestamp`.
+    Daily {
+        timestamp: u32,
+        steps: u32,
+        active_hours: u8,
+    },
+    /// Resting heart rate estimate of the day starting at `timestamp`.
+    RestingHeartRate { timestamp: u32, bpm: u8 },
+    /// Summary of a completed workout.
+    Workout(WorkoutSummary),
+}
+
+impl ActivityRecord {
+    const HOURLY: u8 = 1;
+    const DAILY: u8 = 2;
+    const RESTING_HEART_RATE: u8 = 3;
+    const WORKOUT: u8 = 4;
+
+    pub const MAX_SIZE: usize = 22;
+
+    pub fn timestamp(&self) -> u32 {
+        match self {
+            Self::Hourly { timestamp, .. } => *timestamp,
+            Self::Daily { timestamp, .. } => *timestamp,
+            Self::RestingHeartRate { timestamp, .. } => *timestamp,
+            Self::Workout(workout) => workout.timestamp,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8; Self::MAX_SIZE]) -> usize {
+        match self {
+            Self::Hourly { timestamp, steps } => {
+                buf[0] = Self::HOURLY;
+                buf[1..5].copy_from_slice(&timestamp.to_le_bytes());
+                buf[5..9].copy_from_slice(&steps.to_le_bytes());
+                9
+            }
+            Self::Daily {
+                timestamp,
+                steps,
+                active_hours,
+            } => {
+                buf[0] = Self::DAILY;
+                buf[1..5].copy_from_slice(&timestamp.to_le_bytes());
+                buf[5..9].copy_from_slice(&steps.to_le_bytes());
+                buf[9] = *active_hours;
+                10
+            }
+            Self::RestingHeartRate { timestamp, bpm } => {
+                buf[0] = Self::RESTING_HEART_RATE;
+                buf[1..5].copy_from_slice(&timestamp.to_le_bytes());
+                buf[5] = *bpm;
+                6
+            }
+            Self::Workout(workout) => {
+                buf[0] = Self::WORKOUT;
+                buf[1..5].copy_from_slice(&workout.timestamp.to_le_bytes());
+                buf[5..9].copy_from_slice(&workout.duration.to_le_bytes());
+                buf[9..13].copy_from_slice(&workout.steps.to_le_bytes());
+                buf[13..17].copy_from_slice(&workout.distance.to_le_bytes());
+                buf[17] = workout.kind as u8;
+                buf[18] = workout.avg_bpm;
+                buf[19] = workout.max_bpm;
+                buf[20..22].copy_from_slice(&workout.kcal.to_le_bytes());
+                22
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let u32_at = |at: usize| {
+            data.get(at..at + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        match data.first()? {
+            &Self::HOURLY => Some(Self::Hourly {
+                timestamp: u32_at(1)?,
+                steps: u32_at(5)?,
+            }),
+            &Self::DAILY => Some(Self::Daily {
+                timestamp: u32_at(1)?,
+                steps: u32_at(5)?,
+                active_hours: *data.get(9)?,
+            }),
+            &Self::RESTING_HEART_RATE => Some(Self::RestingHeartRate {
+                timestamp: u32_at(1)?,
+                bpm: *data.get(5)?,
+            }),
+            &Self::WORKOUT => Some(Self::Workout(WorkoutSummary {
+                timestamp: u32_at(1)?,
+                duration: u32_at(5)?,
+                steps: u32_at(9)?,
+                distance: u32_at(13)?,
+                kind: WorkoutKind::from_u8(*data.get(17)?)?,
+                avg_bpm: *data.get(18)?,
+                max_bpm: *data.get(19)?,
+                kcal: data.get(20..22).map(|b| u16::from_le_bytes([b[0], b[1]]))?,
+            })),
+            _ => None,
+        }
+    }
+}
+
+const _: () = assert!(ActivityRecord::MAX_SIZE <= ringlog::MAX_RECORD_SIZE);
+
+/// History of step counts persisted in flash.
+pub struct ActivityLog<'a> {
+    log: Mutex<CriticalSectionRawMutex, RingLog<LogPartition<'a>>>,
+}
+
+impl<'a> ActivityLog<'a> {
+    pub fn new(log: RingLog<LogPartition<'a>>) -> Self {
+        Self { log: Mutex::new(log) }
+    }
+
+    pub async fn append(&self, record: ActivityRecord) -> Result<u32, ringlog::Error> {
+        let mut buf = [0; ActivityRecord::MAX_SIZE];
+        let len = record.encode(&mut buf);
+        self.log.lock().await.append(&buf[..len])
+    }
+
+    /// Visit all records from oldest to newest together with their record id.
+    pub async fn for_each<F: FnMut(u32, ActivityRecord)>(&self, mut f: F) -> Result<(), ringlog::Error> {
+        self.log.lock().await.for_each(|id, data| {
+            if let Some(record) = ActivityRecord::decode(data) {
+                f(id, record);
+            }
+        })
+    }
+
+    /// Visit encoded records with an id of at least `start_id` until `f` returns false, used for data export.
+    #[cfg(not(feature = "no-softdevice"))]
+    pub async fn read_from<F: FnMut(u32, &[u8]) -> bool>(&self, start_id: u32, f: F) -> Result<(), ringlog::Error> {
+        self.log.lock().await.read_from(start_id, f)
+    }
+
+    /// Bytes used by the log and its capacity.
+    pub async fn usage(&self) -> Result<(u32, u32), ringlog::Error> {
+        self.log.lock().await.usage()
+    }
+
+    pub async fn erase_ahead(&self) -> Result<bool, ringlog::Error> {
+        self.log.lock().await.erase_ahead()
+    }
+
+    /// Steps per hour for the day starting at `day_start`, used by the step history chart. The current hour is not
+    /// logged yet, see `step_counts`.
+    pub async fn hourly_steps(&self, day_start: u32) -> Result<[u32; 24], ringlog::Error> {
+        let mut hours = [0; 24];
+        self.for_each(|_, record| {
+            if let ActivityRecord::Hourly { timestamp, steps } = record {
+                if timestamp >= day_start && timestamp < day_start + 24 * 3600 {
+                    hours[((timestamp - day_start) / 3600) as usize] = steps;
+                }
+            }
+        })
+        .await?;
+        Ok(hours)
+    }
+
+    /// Resting heart rate of the `TREND_DAYS` days from `since`, 0 for days without an estimate.
+    pub async fn resting_heart_rates(&self, since: u32) -> Result<[u16; TREND_DAYS], ringlog::Error> {
+        let mut days = [0; TREND_DAYS];
+        self.for_each(|_, record| {
+            if let ActivityRecord::RestingHeartRate { timestamp, bpm } = record {
+                if let Some(day) = timestamp
+                    .checked_sub(since)
+                    .and_then(|t| days.get_mut((t / 86400) as usize))
+                {
+                    *day = bpm as u16;
+                }
+            }
+        })
+        .await?;
+        Ok(days)
+    }
+
+    /// The last `HISTORY_SIZE` workouts, newest first.
+    pub async fn workouts(&self) -> Result<Vec<WorkoutSummary, HISTORY_SIZE>, ringlog::Error> {
+        let mut recent: Deque<WorkoutSummary, HISTORY_SIZE> = Deque::new();
+        self.for_each(|_, record| {
+            if let ActivityRecord::Workout(workout) = record {
+                if recent.is_full() {
+                    recent.pop_front();
+                }
+                let _ = recent.push_back(workout);
+            }
+        })
+        .await?;
+        Ok(recent.iter().rev().copied().collect())
+    }
+}
+
+/// Month, day, hour and minute of a timestamp.
+fn date_time(timestamp: u32) -> (u8, u8, u32, u32) {
+    let date = time::OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .map(|t| t.date())
+        .unwrap_or(time::Date::MIN);
+    (
+        date.month() as u8,
+        date.day(),
+        timestamp / 3600 % 24,
+        timestamp / 60 % 60,
+    )
+}
+
+/// The workout history, one line per workout with `selected` marked. Returns the number of workouts listed.
+pub async fn report_workouts<const N: usize>(
+    log: Option<&ActivityLog<'_>>,
+    selected: usize,
+    text: &mut String<N>,
+) -> usize {
+    let Some(log) = log else {
+        let _ = write!(text, "Activity log unavailable");
+        return 0;
+    };
+    let workouts = match log.workouts().await {
+        Ok(workouts) => workouts,
+        Err(e) => {
+            let _ = write!(text, "Error reading activity log: {:?}", e);
+            return 0;
+        }
+    };
+    if workouts.is_empty() {
+        let _ = write!(text, "No workouts yet");
+    }
+    for (i, workout) in workouts.iter().enumerate() {
+        let (month, day, _, _) = date_time(workout.timestamp);
+        let _ = writeln!(
+            text,
+            "{} {:02}-{:02} {} {}.{:02} km",
+            if i == selected { '>' } else { ' ' },
+            month,
+            day,
+            workout.kind.name(),
+            workout.distance / 1000,
+            workout.distance % 1000 / 10
+        );
+    }
+    workouts.len()
+}
+
+/// Details of the workout at `index` in the history, newest first.
+pub async fn report_workout<const N: usize>(log: Option<&ActivityLog<'_>>, index: usize, text: &mut String<N>) {
+    let Some(log) = log else {
+        let _ = write!(text, "Activity log unavailable");
+        return;
+    };
+    let workout = match log.workouts().await {
+        Ok(workouts) => match workouts.get(index) {
+            Some(workout) => *workout,
+            None => return,
+        },
+        Err(e) => {
+            let _ = write!(text, "Error reading activity log: {:?}", e);
+            return;
+        }
+    };
+    let (month, day, hour, minute) = date_time(workout.timestamp);
+    let _ = writeln!(
+        text,
+        "{} {:02}-{:02} {:02}:{:02}",
+        workout.kind.name(),
+        month,
+        day,
+        hour,
+        minute
+    );
+    let _ = writeln!(
+        text,
+        "Duration: {}m {:02}s",
+        workout.duration / 60,
+        workout.duration % 60
+    );
+    let _ = writeln!(
+        text,
+        "Distance: {}.{:02} km",
+        workout.distance / 1000,
+        workout.distance % 1000 / 10
+    );
+    let _ = writeln!(text, "Steps: {}", workout.steps);
+    if workout.max_bpm > 0 {
+        let _ = writeln!(text, "HR: {} avg, {} max", workout.avg_bpm, workout.max_bpm);
+    }
+    let _ = writeln!(text, "Energy: {} kcal", workout.kcal);
+}
+
+/// Seconds since the unix epoch for a watch local time.
+pub fn timestamp(time: time::PrimitiveDateTime) -> u32 {
+    time.assume_utc().unix_timestamp() as u32
+}
+
+#[embassy_executor::task]
+pub async fn activity_task(
+    log: &'static ActivityLog<'static>,
+    clock: &'static Clock,
+    kv: Option<&'static SharedKv<'static>>,
+    settings: &'static SettingsCache,
+    motor: &'static SharedMotor,
+) {
+    profiled(Task::Activity, async move {
+        let mut last = clock.get();
+        let mut active_hours = 0;
+        loop {
+            health::heartbeat(Task::Activity, Duration::from_secs(3 * 60));
+            Timer::after(Duration::from_secs(60)).await;
+            let now = clock.get();
+            if last.year() < 2000 && now.year() >= 2000 {
+                let day = RESTORED_DAY.swap(NO_DAY, Ordering::Relaxed);
+                if day != NO_DAY && day != day_number(now) {
+                    info!("Dropping steps restored from another day");
+                    restore_steps(0, 0);
+                    GOAL_REACHED.store(false, Ordering::Relaxed);
+                }
+            }
+            let celebrated = celebrate_goal(settings, motor).await;
+            // Steps are stored once an hour so that a reset loses little, and when the goal is reached so that it
+            // is not celebrated twice.
+            let kv = kv.filter(|_| now.year() >= 2000);
+            if now.hour() == last.hour() && now.date() == last.date() {
+                if let (true, Some(kv)) = (celebrated, kv) {
+                    store_steps(kv, now).await;
+                }
+                continue;
+            }
+
+            // Don't log anything until the clock has been synchronized.
+            if last.year() >= 2000 {
+                let steps = HOURLY_STEPS.swap(0, Ordering::Relaxed);
+                if steps > 0 {
+                    active_hours += 1;
+                }
+                let hour = last.date().with_hms(last.hour(), 0, 0).unwrap_or(last);
+                let record = ActivityRecord::Hourly {
+                    timestamp: timestamp(hour),
+                    steps,
+                };
+                if let Err(e) = log.append(record).await {
+                    warn!("Error logging hourly steps: {:?}", e);
+                }
+
+                if now.date() != last.date() {
+                    let steps = DAILY_STEPS.swap(0, Ordering::Relaxed);
+                    let record = ActivityRecord::Daily {
+                        timestamp: timestamp(last.date().midnight()),
+                        steps,
+                        active_hours,
+                    };
+                    info!("Daily summary: {:?}", record);
+                    if let Err(e) = log.append(record).await {
+                        warn!("Error logging daily summary: {:?}", e);
+                    }
+                    if let Some(bpm) = take_resting_heart_rate() {
+                        let record = ActivityRecord::RestingHeartRate {
+                            timestamp: timestamp(last.date().midnight()),
+                            bpm,
+                        };
+                        info!("Resting heart rate: {:?}", record);
+                        if let Err(e) = log.append(record).await {
+                            warn!("Error logging resting heart rate: {:?}", e);
+                        }
+                    }
+                    active_hours = 0;
+                    GOAL_REACHED.store(false, Ordering::Relaxed);
+                }
+            }
+            if let Some(kv) = kv {
+                store_steps(kv, now).await;
+            }
+            last = now;
+        }
+    })
+    .await
+}
@@ -0,0 +1,330 @@
+use core::cell::RefCell;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, String, Vec};
+
+use crate::device::SharedBattery;
+use crate::health;
+use crate::kv::{keys, SharedKv};
+use crate::power::PowerManager;
+use crate::profile::{profiled, Task};
+
+/// Interval between battery level samples, matching the resolution of the daily graph.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(600);
+/// Samples kept, a day's worth.
+pub const HISTORY: usize = 144;
+/// Weight of a new measurement in the moving average of each rate, in percent.
+const RATE_WEIGHT: i32 = 25;
+/// Samples per period of the battery health history, 30 days.
+const PERIOD_SAMPLES: u16 = 30 * HISTORY as u16;
+/// Periods of battery health history kept, a year.
+const PERIODS: usize = 12;
+/// Samples between writes of the battery health to flash, an hour.
+const PERSIST_SAMPLES: u16 = 6;
+
+/// What the watch was doing while the battery level changed.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum PowerState {
+    Charging,
+    /// Display on.
+    Active,
+    Idle,
+}
+
+struct Sample {
+    level: u8,
+    state: PowerState,
+}
+
+/// Wear of the battery over its lifetime, persisted across reboots.
+struct Health {
+    /// Battery level lost while discharging since the watch was first used, in percent. 100 is one full cycle.
+    discharged: u32,
+    /// Samples taken in the current period.
+    period_samples: u16,
+    /// Idle discharge rate at the end of each period, oldest first, in hundredths of a percent per hour. As the
+    /// cell ages and loses capacity, the same load drains it faster.
+    idle_rates: Deque<i16, PERIODS>,
+}
+
+impl Health {
+    fn encode(&self) -> Vec<u8, { 6 + 2 * PERIODS }> {
+        let mut data = Vec::new();
+        let _ = data.extend_from_slice(&self.discharged.to_le_bytes());
+        let _ = data.extend_from_slice(&self.period_samples.to_le_bytes());
+        for rate in self.idle_rates.iter() {
+            let _ = data.extend_from_slice(&rate.to_le_bytes());
+        }
+        data
+    }
+
+    fn decode(&mut self, data: &[u8]) {
+        if data.len() < 6 {
+            return;
+        }
+        self.discharged = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        self.period_samples = u16::from_le_bytes([data[4], data[5]]);
+        self.idle_rates.clear();
+        for rate in data[6..].chunks_exact(2).take(PERIODS) {
+            let _ = self.idle_rates.push_back(i16::from_le_bytes([rate[0], rate[1]]));
+        }
+    }
+}
+
+struct Stats {
+    history: Deque<Sample, HISTORY>,
+    /// Moving average of the change in battery level per state, in hundredths of a percent per hour.
+    rates: [Option<i32>; 3],
+    active_time: Duration,
+    idle_time: Duration,
+    since: Instant,
+    health: Health,
+}
+
+/// Battery level history and charge and discharge rates for each power state, kept since boot.
+pub struct BatteryStats {
+    stats: BMutex<CriticalSectionRawMutex, RefCell<Stats>>,
+    active: AtomicBool,
+}
+
+impl BatteryStats {
+    pub fn new() -> Self {
+        Self {
+            stats: BMutex::new(RefCell::new(Stats {
+                history: Deque::new(),
+                rates: [None; 3],
+                active_time: Duration::from_ticks(0),
+                idle_time: Duration::from_ticks(0),
+                since: Instant::now(),
+                health: Health {
+                    discharged: 0,
+                    period_samples: 0,
+                    idle_rates: Deque::new(),
+                },
+            })),
+            active: AtomicBool::new(true),
+        }
+    }
+
+    /// Report whether the display is on, to attribute battery drain to the right state.
+    pub fn set_active(&self, active: bool) {
+        if self.active.swap(active, Ordering::Relaxed) != active {
+            self.stats.lock(|stats| {
+                let mut stats = stats.borrow_mut();
+                let elapsed = stats.since.elapsed();
+                if active {
+                    stats.idle_time += elapsed;
+                } else {
+                    stats.active_time += elapsed;
+                }
+                stats.since = Instant::now();
+            });
+        }
+    }
+
+    /// Load the battery health stored by earlier boots.
+    pub async fn load(&self, kv: &SharedKv<'_>) {
+        let mut buf = [0; 6 + 2 * PERIODS];
+        match kv.lock().await.get(keys::BATTERY_HEALTH, &mut buf) {
+            Ok(Some(len)) => self.stats.lock(|stats| stats.borrow_mut().health.decode(&buf[..len])),
+            Ok(None) => {}
+            Err(e) => warn!("Error loading battery health: {:?}", e),
+        }
+    }
+
+    async fn persist(&self, kv: &SharedKv<'_>) {
+        let data = self.stats.lock(|stats| stats.borrow().health.encode());
+        if let Err(e) = kv.lock().await.set(keys::BATTERY_HEALTH, &data) {
+            warn!("Error storing battery health: {:?}", e);
+        }
+    }
+
+    /// Record a battery sample, returning whether the battery health should be written to flash.
+    fn record(&self, level: u8, charging: bool) -> bool {
+        let active = self.active.load(Ordering::Relaxed);
+        self.stats.lock(|stats| {
+            let mut stats = stats.borrow_mut();
+            let elapsed = stats.since.elapsed();
+            if active {
+                stats.active_time += elapsed;
+            } else {
+                stats.idle_time += elapsed;
+            }
+            // The state the watch spent most of the interval in.
+            let state = if charging {
+                PowerState::Charging
+            } else if stats.active_time > stats.idle_time {
+                PowerState::Active
+            } else {
+                PowerState::Idle
+            };
+
+            // Skip intervals where the charger was connected or removed, as their rate is meaningless.
+            if let Some(previous) = stats.history.back() {
+                if (previous.state == PowerState::Charging) == charging {
+                    let dropped = previous.level.saturating_sub(level);
+                    let change = (level as i32 - previous.level as i32) * 100 * 3600 / SAMPLE_INTERVAL.as_secs() as i32;
+                    let rate = &mut stats.rates[state as usize];
+                    *rate = Some(match *rate {
+                        Some(rate) => (rate * (100 - RATE_WEIGHT) + change * RATE_WEIGHT) / 100,
+                        None => change,
+                    });
+                    if !charging {
+                        stats.health.discharged += dropped as u32;
+                    }
+                }
+            }
+
+            stats.health.period_samples += 1;
+            if stats.health.period_samples >= PERIOD_SAMPLES {
+                stats.health.period_samples = 0;
+                if let Some(rate) = stats.rates[PowerState::Idle as usize] {
+                    if stats.health.idle_rates.is_full() {
+                        stats.health.idle_rates.pop_front();
+                    }
+                    let _ = stats.health.idle_rates.push_back(rate as i16);
+                }
+            }
+
+            if stats.history.is_full() {
+                stats.history.pop_front();
+            }
+            let _ = stats.history.push_back(Sample { level, state });
+            stats.active_time = Duration::from_ticks(0);
+            stats.idle_time = Duration::from_ticks(0);
+            stats.since = Instant::now();
+            stats.health.period_samples % PERSIST_SAMPLES == 0
+        })
+    }
+
+    /// Battery level over the last day, oldest first, with 0 for the time before any samples were taken.
+    pub fn history(&self) -> Vec<u16, HISTORY> {
+        self.stats.lock(|stats| {
+            let stats = stats.borrow();
+            let mut history: Vec<u16, HISTORY> = Vec::new();
+            let _ = history.resize(HISTORY - stats.history.len(), 0);
+            for sample in stats.history.iter() {
+                let _ = history.push(sample.level as u16);
+            }
+            history
+        })
+    }
+
+    /// Battery level lost while discharging over the last `period`, up to a day, in percent. Charging in between
+    /// does not make up for it.
+    pub fn used(&self, period: Duration) -> u32 {
+        let samples = (period.as_secs() / SAMPLE_INTERVAL.as_secs()) as usize;
+        self.stats.lock(|stats| {
+            let stats = stats.borrow();
+            let skip = stats.history.len().saturating_sub(samples + 1);
+            let mut used = 0;
+            let mut previous: Option<u8> = None;
+            for sample in stats.history.iter().skip(skip) {
+                if let Some(previous) = previous {
+                    used += previous.saturating_sub(sample.level) as u32;
+                }
+                previous = Some(sample.level);
+            }
+            used
+        })
+    }
+
+    /// Change of the battery level in `state`, in hundredths of a percent per hour.
+    pub fn rate(&self, state: PowerState) -> Option<i32> {
+        self.stats.lock(|stats| stats.borrow().rates[state as usize])
+    }
+
+    /// Estimated hours until the battery is empty, based on the discharge rates and how much of the last day the
+    /// display was on.
+    pub fn remaining_hours(&self, level: u32) -> Option<u32> {
+        self.stats.lock(|stats| {
+            let stats = stats.borrow();
+            let idle_rate = stats.rates[PowerState::Idle as usize]?;
+            let active_rate = stats.rates[PowerState::Active as usize].unwrap_or(idle_rate);
+            let active = stats.history.iter().filter(|s| s.state == PowerState::Active).count() as i32;
+            let idle = stats.history.iter().filter(|s| s.state == PowerState::Idle).count() as i32;
+            if active + idle == 0 {
+                return None;
+            }
+            let rate = (active_rate * active + idle_rate * idle) / (active + idle);
+            if rate >= 0 {
+                return None;
+            }
+            Some(level * 100 / (-rate) as u32)
+        })
+    }
+
+    /// Equivalent full charge cycles and the idle drain rate over the last months, for the diagnostics screen.
+    pub fn report_health<const N: usize>(&self, text: &mut String<N>) {
+        const SHOWN: usize = 6;
+        self.stats.lock(|stats| {
+            let stats = stats.borrow();
+            let health = &stats.health;
+            let _ = writeln!(
+                text,
+                "Charge cycles: {}.{}",
+                health.discharged / 100,
+                health.discharged % 100 / 10
+            );
+            let _ = writeln!(text, "Idle drain (0.01%/h):");
+            if let Some(rate) = stats.rates[PowerState::Idle as usize] {
+                let _ = writeln!(text, " now {}", -rate);
+            }
+            for (months, rate) in health.idle_rates.iter().rev().enumerate().take(SHOWN) {
+                let _ = writeln!(text, " {} months ago {}", months + 1, -rate);
+            }
+            // Growth of the idle drain since the oldest period, as an indication of capacity lost.
+            if let (Some(first), Some(last)) = (health.idle_rates.front(), health.idle_rates.back()) {
+                if *first < 0 && health.idle_rates.len() > 1 {
+                    let _ = writeln!(
+                        text,
+                        "Drain change: {:+}%",
+                        (*last as i32 - *first as i32) * 100 / *first as i32
+                    );
+                }
+            }
+        })
+    }
+}
+
+/// Periodically sample the battery level, also reporting it to the power budget, and persist the battery health.
+#[embassy_executor::task]
+pub async fn battery_stats_task(
+    stats: &'static BatteryStats,
+    battery: &'static SharedBattery,
+    power: &'static PowerManager,
+    kv: Option<&'static SharedKv<'static>>,
+) {
+    profiled(Task::BatteryStats, async move {
+        let measure = move || async move {
+            let mut battery = battery.lock().await;
+            (battery.measure().await, battery.is_charging())
+        };
+        let (level, charging) = measure().await;
+        power.update_battery(level as u8, charging);
+        loop {
+            health::heartbeat(Task::BatteryStats, SAMPLE_INTERVAL * 2);
+            Timer::after(SAMPLE_INTERVAL).await;
+            let (level, charging) = measure().await;
+            power.update_battery(level as u8, charging);
+            if stats.record(level as u8, charging) {
+                if let Some(kv) = kv {
+                    stats.persist(kv).await;
+                }
+            }
+            info!(
+                "Battery {}%, rates (0.01%/h): charging {:?} active {:?} idle {:?}",
+                level,
+                stats.rate(PowerState::Charging),
+                stats.rate(PowerState::Active),
+                stats.rate(PowerState::Idle)
+            );
+        }
+    })
+    .await
+}
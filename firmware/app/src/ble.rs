@@ -1,124 +1,160 @@
 use defmt::{info, warn};
-use embedded_storage::nor_flash::NorFlash;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, select4, Either3, Either4};
+use embassy_time::{Duration, Instant, Timer};
 use heapless::Vec;
-use nrf_dfu_target::prelude::*;
 use nrf_softdevice::ble::gatt_server::NotifyValueError;
-use nrf_softdevice::ble::{gatt_client, Connection};
+use nrf_softdevice::ble::peripheral::FilterPolicy;
+use nrf_softdevice::ble::{gatt_client, gatt_server, peripheral, Connection};
+use nrf_softdevice::{raw, RawError, Softdevice};
 
-pub const MTU: usize = 120;
-// Aligned to 4 bytes + 3 bytes for header
+use crate::bonds::Bonder;
+use crate::crash::CrashLog;
+use crate::device::{SharedBattery, SharedMotor};
+use crate::error::{self, Error};
+use crate::events::{self, BleCommand};
+use crate::fs::FileSystem;
+use crate::power::{PowerManager, Subsystem};
+use crate::profile::{profiled, Task};
+use crate::wakelock::{self, WakeLockKind};
+use crate::{DfuConfig, Logs};
+
+#[cfg(feature = "ancs")]
+mod ancs;
+#[cfg(feature = "ans")]
+mod ans;
+#[cfg(feature = "bas")]
+mod bas;
+#[cfg(feature = "gadgetbridge")]
+mod cts;
+mod dfu;
+mod dis;
+#[cfg(feature = "export")]
+mod export;
+#[cfg(feature = "fs")]
+mod filetransfer;
+#[cfg(feature = "hrs")]
+mod hrs;
+#[cfg(feature = "find-phone")]
+mod ias;
+#[cfg(feature = "music")]
+mod music;
+#[cfg(feature = "navigation")]
+mod navigation;
+#[cfg(feature = "nus")]
+mod uart;
+#[cfg(feature = "weather")]
+mod weather;
+
+#[cfg(feature = "ans")]
+use self::ans::{AlertNotificationService, AlertNotificationServiceEvent};
+#[cfg(feature = "bas")]
+use self::bas::{BatteryService, BatteryServiceEvent};
+#[cfg(feature = "gadgetbridge")]
+use self::cts::{CurrentTimeService, CurrentTimeServiceEvent};
+pub use self::dfu::{dfu_progress_task, load_progress as load_dfu_progress};
+use self::dfu::{DfuConnection, NrfDfuService, NrfDfuServiceEvent};
+use self::dis::DeviceInformationService;
+#[cfg(feature = "export")]
+use self::export::{ExportService, ExportServiceEvent};
+#[cfg(feature = "fs")]
+use self::filetransfer::{FileSystemService, FileSystemServiceEvent};
+#[cfg(feature = "hrs")]
+use self::hrs::{HeartRateService, HeartRateServiceEvent};
+#[cfg(feature = "find-phone")]
+use self::ias::{ImmediateAlertService, ImmediateAlertServiceEvent};
+#[cfg(feature = "music")]
+use self::music::{MusicService, MusicServiceEvent};
+#[cfg(feature = "navigation")]
+use self::navigation::{NavigationService, NavigationServiceEvent};
+#[cfg(feature = "nus")]
+use self::uart::{NrfUartService, NrfUartServiceEvent};
+#[cfg(feature = "weather")]
+use self::weather::{SimpleWeatherService, SimpleWeatherServiceEvent};
+
+/// Largest value of a notification or write, the ATT MTU less 3 bytes for the opcode and handle.
+pub const MTU: usize = 244;
+/// ATT MTU configured for the SoftDevice and offered to phones. With the 4 byte L2CAP header a full packet is 251
+/// bytes, the most a link layer packet carries with data length extension.
 pub const ATT_MTU: usize = MTU + 3;
 
-type Target = DfuTarget<256>;
+/// How long to advertise at the fast interval after booting, disconnecting or waking the watch.
+pub const FAST_ADVERTISING_TIME: Duration = Duration::from_secs(5 * 60);
+/// Advertising interval in units of 0.625 ms while fast, 250 ms.
+pub const FAST_ADV_INTERVAL: u32 = 400;
+/// Advertising interval in units of 0.625 ms once the fast period is over, 5 s.
+pub const SLOW_ADV_INTERVAL: u32 = 8000;
 
-#[nrf_softdevice::gatt_service(uuid = "6E400001-B5A3-F393-E0A9-E50E24DCCA9E")]
-pub struct NrfUartService {
-    #[characteristic(uuid = "6E400002-B5A3-F393-E0A9-E50E24DCCA9E", write)]
-    rx: Vec<u8, ATT_MTU>,
+/// Connection interval requested while a BLE wake lock is held, 7.5 to 15 ms.
+const FAST_CONN_PARAMS: raw::ble_gap_conn_params_t = raw::ble_gap_conn_params_t {
+    min_conn_interval: 6,
+    max_conn_interval: 12,
+    slave_latency: 0,
+    conn_sup_timeout: 400,
+};
 
-    #[characteristic(uuid = "6E400003-B5A3-F393-E0A9-E50E24DCCA9E", notify)]
-    tx: Vec<u8, ATT_MTU>,
-}
+/// Connection interval requested once the last BLE wake lock is released, 30 to 50 ms.
+const NORMAL_CONN_PARAMS: raw::ble_gap_conn_params_t = raw::ble_gap_conn_params_t {
+    min_conn_interval: 24,
+    max_conn_interval: 40,
+    slave_latency: 0,
+    conn_sup_timeout: 400,
+};
 
-impl NrfUartService {
-    fn handle(&self, _connection: &mut ConnectionHandle, event: NrfUartServiceEvent) {
-        match event {
-            NrfUartServiceEvent::TxCccdWrite { notifications } => {
-                info!("Enable logging: {}", notifications);
-            }
-            _ => {}
-        }
-    }
+/// Bytes a notification can carry on a connection: the MTU it negotiated less the opcode and handle, at most `MTU`.
+/// Phones that never exchange the MTU only take 20.
+pub fn payload_size(conn: &Connection) -> usize {
+    (conn.att_mtu() as usize).saturating_sub(3).clamp(1, MTU)
 }
 
-#[nrf_softdevice::gatt_service(uuid = "FE59")]
-pub struct NrfDfuService {
-    #[characteristic(uuid = "8EC90001-F315-4F60-9FB8-838830DAEA50", write, notify)]
-    control: Vec<u8, ATT_MTU>,
-
-    /// The maximum size of each packet is derived from the Att MTU size of the connection.
-    /// The maximum Att MTU size of the DFU Service is 256 bytes (saved in NRF_SDH_BLE_GATT_MAX_MTU_SIZE),
-    /// making the maximum size of the DFU Packet characteristic 253 bytes. (3 bytes are used for opcode and handle ID upon writing.)
-    #[characteristic(uuid = "8EC90002-F315-4F60-9FB8-838830DAEA50", write_without_response, notify)]
-    packet: Vec<u8, ATT_MTU>,
+/// Copy data into a characteristic value, failing instead of panicking if it does not fit.
+fn value(data: &[u8]) -> Result<Vec<u8, ATT_MTU>, NotifyValueError> {
+    Vec::from_slice(data).map_err(|_| NotifyValueError::Raw(RawError::DataSize))
 }
 
+/// State of a connection, handed to the service handlers.
 pub struct ConnectionHandle {
     pub connection: Connection,
-    pub notify_control: bool,
-    pub notify_packet: bool,
 }
 
-impl NrfDfuService {
-    fn process<DFU: NorFlash, F: FnOnce(&ConnectionHandle, &[u8]) -> Result<(), NotifyValueError>>(
-        &self,
-        target: &mut Target,
-        dfu: &mut DFU,
-        conn: &mut ConnectionHandle,
-        request: DfuRequest<'_>,
-        notify: F,
-    ) -> DfuStatus {
-        let (response, status) = target.process(request, dfu);
-        let mut buf: [u8; 32] = [0; 32];
-        match response.encode(&mut buf[..]) {
-            Ok(len) => match notify(&conn, &buf[..len]) {
-                Ok(_) => {}
-                Err(e) => {
-                    warn!("Error sending notification: {:?}", e);
-                }
-            },
-            Err(e) => {
-                warn!("Error encoding DFU response: {:?}", e);
-            }
-        }
-        status
-    }
-
-    fn handle<DFU: NorFlash>(
-        &self,
-        target: &mut Target,
-        dfu: &mut DFU,
-        connection: &mut ConnectionHandle,
-        event: NrfDfuServiceEvent,
-    ) -> Option<DfuStatus> {
-        match event {
-            NrfDfuServiceEvent::ControlWrite(data) => {
-                if let Ok((request, _)) = DfuRequest::decode(&data) {
-                    return Some(self.process(target, dfu, connection, request, |conn, response| {
-                        if conn.notify_control {
-                            self.control_notify(&conn.connection, &Vec::from_slice(response).unwrap())?;
-                        }
-                        Ok(())
-                    }));
-                }
-            }
-            NrfDfuServiceEvent::ControlCccdWrite { notifications } => {
-                connection.notify_control = notifications;
-            }
-            NrfDfuServiceEvent::PacketWrite(data) => {
-                let request = DfuRequest::Write { data: &data[..] };
-                return Some(self.process(target, dfu, connection, request, |conn, response| {
-                    if conn.notify_control {
-                        self.control_notify(&conn.connection, &Vec::from_slice(response).unwrap())?;
-                    }
-                    // if conn.notify_packet {
-                    //     self.packet_notify(&conn.connection, &Vec::from_slice(response).unwrap())?;
-                    // }
-                    Ok(())
-                }));
-            }
-            NrfDfuServiceEvent::PacketCccdWrite { notifications } => {
-                connection.notify_packet = notifications;
-            }
-        }
-        None
-    }
+#[cfg(any(feature = "fs", feature = "export"))]
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
 }
 
+/// The GATT services, each but DFU and device information enabled with a feature of the same name, see Cargo.toml.
+/// The current time service comes with `gadgetbridge`.
+///
+/// Each service lives in its own module under `ble/`, with a `handle` method for its events. Adding one takes a
+/// field here, an arm in `handle`, and an `init` or `run_*` method if it sets values up front or works in the
+/// background of a connection.
 #[nrf_softdevice::gatt_server]
 pub struct PineTimeServer {
     dfu: NrfDfuService,
+    dis: DeviceInformationService,
+    #[cfg(feature = "nus")]
     uart: NrfUartService,
+    #[cfg(feature = "fs")]
+    fs: FileSystemService,
+    #[cfg(feature = "export")]
+    export: ExportService,
+    #[cfg(feature = "bas")]
+    bas: BatteryService,
+    #[cfg(feature = "hrs")]
+    hrs: HeartRateService,
+    #[cfg(feature = "ans")]
+    ans: AlertNotificationService,
+    #[cfg(feature = "find-phone")]
+    ias: ImmediateAlertService,
+    #[cfg(feature = "navigation")]
+    navigation: NavigationService,
+    #[cfg(feature = "weather")]
+    weather: SimpleWeatherService,
+    #[cfg(feature = "music")]
+    music: MusicService,
+    #[cfg(feature = "gadgetbridge")]
+    cts: CurrentTimeService,
 }
 
 #[nrf_softdevice::gatt_client(uuid = "1805")]
@@ -127,6 +163,35 @@ struct CurrentTimeServiceClient {
     current_time: Vec<u8, 10>,
 }
 
+/// Request a short connection interval while a BLE wake lock is held, and a power saving one once released.
+pub async fn apply_wake_locks(conn: &Connection) {
+    let mut fast = false;
+    loop {
+        if wakelock::is_held(WakeLockKind::BleFast) != fast {
+            fast = !fast;
+            let params = if fast { FAST_CONN_PARAMS } else { NORMAL_CONN_PARAMS };
+            match conn.set_conn_params(params) {
+                Ok(_) => info!("Requested {} connection interval", if fast { "fast" } else { "normal" }),
+                Err(e) => warn!("Error setting connection parameters: {:?}", e),
+            }
+        }
+        wakelock::wait_change(WakeLockKind::BleFast).await;
+    }
+}
+
+/// Ask for the largest ATT MTU and link layer packets, which many phones only take when offered, so that DFU and
+/// notifications move several times as much per connection event. Phones that ask first are answered by the
+/// SoftDevice.
+async fn raise_mtu(conn: &mut Connection) {
+    if let Err(e) = conn.data_length_update(None) {
+        warn!("Error requesting data length extension: {:?}", e);
+    }
+    if let Err(e) = gatt_client::att_mtu_exchange(conn, ATT_MTU as u16).await {
+        warn!("Error exchanging the MTU: {:?}", e);
+    }
+    info!("ATT MTU {}", conn.att_mtu());
+}
+
 pub async fn sync_time(conn: &Connection, clock: &crate::clock::Clock) {
     if let Ok(time_client) = gatt_client::discover::<CurrentTimeServiceClient>(&conn).await {
         info!("Found time server on peer, synchronizing time");
@@ -145,44 +210,376 @@ pub async fn sync_time(conn: &Connection, clock: &crate::clock::Clock) {
 impl CurrentTimeServiceClient {
     pub async fn get_time(&self) -> Result<time::PrimitiveDateTime, gatt_client::ReadError> {
         let data = self.current_time_read().await?;
-        if data.len() == 10 {
-            let year = u16::from_le_bytes([data[0], data[1]]);
-            let month = data[2];
-            let day = data[3];
-            let hour = data[4];
-            let minute = data[5];
-            let second = data[6];
-            let _weekday = data[7];
-            let secs_frac = data[8];
-
-            if let Ok(month) = month.try_into() {
-                let date = time::Date::from_calendar_date(year as i32, month, day);
-                let micros = secs_frac as u32 * 1000000 / 256;
-                let time = time::Time::from_hms_micro(hour, minute, second, micros);
-                if let (Ok(time), Ok(date)) = (time, date) {
-                    let dt = time::PrimitiveDateTime::new(date, time);
-                    return Ok(dt);
-                }
-            }
-        }
-        Err(gatt_client::ReadError::Truncated)
+        current_time(&data).ok_or(gatt_client::ReadError::Truncated)
+    }
+}
+
+/// Decode a Current Time characteristic, the same whether read from the phone or written by it.
+fn current_time(data: &[u8]) -> Option<time::PrimitiveDateTime> {
+    if data.len() != 10 {
+        return None;
     }
+    let year = u16::from_le_bytes([data[0], data[1]]);
+    let month = data[2];
+    let day = data[3];
+    let hour = data[4];
+    let minute = data[5];
+    let second = data[6];
+    let _weekday = data[7];
+    let secs_frac = data[8];
+
+    let date = time::Date::from_calendar_date(year as i32, month.try_into().ok()?, day).ok()?;
+    let micros = secs_frac as u32 * 1000000 / 256;
+    let time = time::Time::from_hms_micro(hour, minute, second, micros).ok()?;
+    Some(time::PrimitiveDateTime::new(date, time))
 }
 
 impl PineTimeServer {
-    pub fn handle<DFU: NorFlash>(
-        &self,
-        target: &mut Target,
-        dfu: &mut DFU,
-        conn: &mut ConnectionHandle,
-        event: PineTimeServerEvent,
-    ) -> Option<DfuStatus> {
+    pub fn handle(&self, conn: &mut ConnectionHandle, event: PineTimeServerEvent) {
         match event {
-            PineTimeServerEvent::Dfu(event) => self.dfu.handle(target, dfu, conn, event),
-            PineTimeServerEvent::Uart(event) => {
-                self.uart.handle(conn, event);
-                None
-            }
+            PineTimeServerEvent::Dfu(event) => self.dfu.handle(event),
+            // All device information is read only.
+            PineTimeServerEvent::Dis(event) => match event {},
+            #[cfg(feature = "nus")]
+            PineTimeServerEvent::Uart(event) => self.uart.handle(conn, event),
+            #[cfg(feature = "fs")]
+            PineTimeServerEvent::Fs(event) => self.fs.handle(event),
+            #[cfg(feature = "export")]
+            PineTimeServerEvent::Export(event) => self.export.handle(event),
+            #[cfg(feature = "bas")]
+            PineTimeServerEvent::Bas(event) => self.bas.handle(event),
+            #[cfg(feature = "hrs")]
+            PineTimeServerEvent::Hrs(event) => self.hrs.handle(event),
+            #[cfg(feature = "ans")]
+            PineTimeServerEvent::Ans(event) => self.ans.handle(event),
+            #[cfg(feature = "find-phone")]
+            PineTimeServerEvent::Ias(event) => self.ias.handle(event),
+            #[cfg(feature = "navigation")]
+            PineTimeServerEvent::Navigation(event) => self.navigation.handle(event),
+            #[cfg(feature = "weather")]
+            PineTimeServerEvent::Weather(event) => self.weather.handle(event),
+            #[cfg(feature = "music")]
+            PineTimeServerEvent::Music(event) => self.music.handle(event),
+            #[cfg(feature = "gadgetbridge")]
+            PineTimeServerEvent::Cts(event) => self.cts.handle(event),
         }
     }
+
+    pub fn init(&self) {
+        self.dis.init();
+        #[cfg(feature = "fs")]
+        self.fs.init();
+        #[cfg(feature = "hrs")]
+        self.hrs.init();
+        #[cfg(feature = "ans")]
+        self.ans.init();
+    }
+
+    pub async fn run_dfu(&self, conn: &Connection, dfu: &mut DfuConnection) {
+        self.dfu.run(conn, dfu).await
+    }
+
+    // The services left out of the build never finish, so the connection is run the same way with any of them.
+
+    pub async fn run_fs(&self, _conn: &Connection, _fs: &FileSystem<'_>) {
+        #[cfg(feature = "fs")]
+        self.fs.run(_conn, _fs).await;
+        core::future::pending().await
+    }
+
+    pub async fn run_uart(
+        &self,
+        _conn: &Connection,
+        _crash_log: Option<&CrashLog<'_>>,
+        _battery: &SharedBattery,
+        _power: &PowerManager,
+    ) {
+        #[cfg(feature = "nus")]
+        self.uart.run(_conn, _crash_log, _battery, _power).await;
+        core::future::pending().await
+    }
+
+    pub async fn run_export(&self, _conn: &Connection, _logs: Logs) {
+        #[cfg(feature = "export")]
+        self.export.run(_conn, _logs).await;
+        core::future::pending().await
+    }
+
+    pub async fn run_bas(&self, _conn: &Connection, _battery: &SharedBattery) {
+        #[cfg(feature = "bas")]
+        self.bas.run(_conn, _battery).await;
+        core::future::pending().await
+    }
+
+    pub async fn run_hrs(&self, _conn: &Connection) {
+        #[cfg(feature = "hrs")]
+        self.hrs.run(_conn).await;
+        core::future::pending().await
+    }
+
+    pub async fn run_ans(&self, _motor: &SharedMotor) {
+        #[cfg(feature = "ans")]
+        self.ans.run(_motor).await;
+        core::future::pending().await
+    }
+
+    pub async fn run_music(&self, _conn: &Connection) {
+        #[cfg(feature = "music")]
+        self.music.run(_conn).await;
+        core::future::pending().await
+    }
+}
+
+/// Receive the notifications of an iPhone through its ANCS, the watch being the client.
+async fn run_ancs(_conn: &Connection, _motor: &SharedMotor) {
+    #[cfg(feature = "ancs")]
+    ancs::run(_conn, _motor).await;
+    core::future::pending().await
+}
+
+/// Ring the phone through its Immediate Alert Service when Find Phone asks, the watch being the client.
+async fn run_ias(_conn: &Connection) {
+    #[cfg(feature = "find-phone")]
+    ias::run(_conn).await;
+    core::future::pending().await
+}
+
+/// Pass a Find Phone request on to the connection, or answer it at once without one.
+fn find_phone(ring: bool, _connected: bool) {
+    #[cfg(feature = "find-phone")]
+    if _connected {
+        ias::ring_phone(ring);
+        return;
+    }
+    if ring {
+        events::publish(events::SensorEvent::PhoneNotFound);
+    }
+}
+
+/// Serve a connection until it ends. Returns whether it was ended for a new phone to pair.
+async fn gatt_server_task(
+    conn: Connection,
+    server: &'static PineTimeServer,
+    dfu_config: DfuConfig<'static>,
+    fs: &'static FileSystem<'static>,
+    logs: Logs,
+    battery: &'static SharedBattery,
+    motor: &'static SharedMotor,
+    power: &'static PowerManager,
+) -> bool {
+    // File transfers, exports and firmware updates access the flash in bursts for the whole connection.
+    let _flash = power.acquire(Subsystem::ExternalFlash).await;
+
+    info!("Running GATT server");
+    let spawner = Spawner::for_current_executor().await;
+    let mut conn_handle = ConnectionHandle {
+        connection: conn.clone(),
+    };
+    let mut dfu = DfuConnection::new(dfu_config, fs, power, spawner);
+
+    let ended = select4(
+        gatt_server::run(&conn, server, |e| server.handle(&mut conn_handle, e)),
+        select4(
+            server.run_fs(&conn, fs),
+            server.run_uart(&conn, logs.crash, battery, power),
+            server.run_export(&conn, logs),
+            select4(
+                server.run_bas(&conn, battery),
+                server.run_hrs(&conn),
+                server.run_ans(motor),
+                select3(run_ancs(&conn, motor), run_ias(&conn), server.run_music(&conn)),
+            ),
+        ),
+        select(server.run_dfu(&conn, &mut dfu), apply_wake_locks(&conn)),
+        async {
+            // Disconnect, Suspend and PairingMode end the connection, the others only apply while advertising.
+            let pairing = loop {
+                match events::next_ble_command().await {
+                    BleCommand::Disconnect | BleCommand::Suspend => break false,
+                    BleCommand::PairingMode => break true,
+                    BleCommand::FindPhone(ring) => find_phone(ring, true),
+                    BleCommand::AdvertiseFast | BleCommand::Resume => {}
+                }
+            };
+            if let Err(e) = conn.disconnect() {
+                warn!("Error disconnecting: {:?}", e);
+            }
+            pairing
+        },
+    )
+    .await;
+    info!("Disconnected");
+    dfu.disconnected();
+    // Without the phone, the watch no longer follows the route nor what it plays.
+    #[cfg(feature = "navigation")]
+    crate::navigation::clear();
+    #[cfg(feature = "music")]
+    crate::music::clear();
+    matches!(ended, Either4::Fourth(true))
+}
+
+#[embassy_executor::task]
+pub async fn advertiser_task(
+    _spawner: Spawner,
+    sd: &'static Softdevice,
+    server: &'static PineTimeServer,
+    dfu_config: DfuConfig<'static>,
+    fs: &'static FileSystem<'static>,
+    logs: Logs,
+    battery: &'static SharedBattery,
+    motor: &'static SharedMotor,
+    power: &'static PowerManager,
+    bonder: &'static Bonder,
+    name: &'static str,
+) {
+    profiled(Task::Ble, async move {
+        const HEADER_SIZE: usize = 9;
+        // A name that does not fit in the advertisement is shortened.
+        let name = &name.as_bytes()[..name.len().min(31 - HEADER_SIZE)];
+        let mut adv_data: Vec<u8, 31> = Vec::new();
+        #[rustfmt::skip]
+        let _ = adv_data.extend_from_slice(&[
+            0x02, 0x01, raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
+            0x03, 0x03, 0xFE, 0x59,
+            (1 + name.len() as u8), 0x09]);
+        let _ = adv_data.extend_from_slice(name);
+
+        let mut scan_data: Vec<u8, 31> = Vec::new();
+        // Fitness apps look for the heart rate service in the advertisement.
+        let services: &[u8] = if cfg!(feature = "hrs") {
+            &[0x0A, 0x18, 0x0D, 0x18]
+        } else {
+            &[0x0A, 0x18]
+        };
+        let _ = scan_data.extend_from_slice(&[1 + services.len() as u8, 0x03]);
+        let _ = scan_data.extend_from_slice(services);
+        // iPhones only offer the ANCS to devices that solicit it.
+        #[cfg(feature = "ancs")]
+        {
+            let _ = scan_data.extend_from_slice(&[1 + ancs::ANCS_UUID.len() as u8, 0x15]);
+            let _ = scan_data.extend_from_slice(&ancs::ANCS_UUID);
+        }
+
+        let mut fast_until = Instant::now() + FAST_ADVERTISING_TIME;
+        // Once a phone is bonded, only bonded phones may connect, except in pairing mode. It ends with the fast
+        // advertising period, or at the first connection.
+        let mut pairing_until = Instant::MIN;
+        loop {
+            if power.in_reserve() {
+                info!("Not advertising in power reserve");
+                while power.in_reserve() {
+                    events::next_ble_command().await;
+                }
+                continue;
+            }
+            let fast = Instant::now() < fast_until;
+            let open = bonder.len() == 0
+                || Instant::now() < pairing_until
+                || match bonder.apply_whitelist() {
+                    Ok(()) => false,
+                    Err(e) => {
+                        warn!("Error setting the whitelist, advertising to any phone: {}", e);
+                        true
+                    }
+                };
+            let config = peripheral::Config {
+                interval: if fast { FAST_ADV_INTERVAL } else { SLOW_ADV_INTERVAL },
+                filter_policy: if open { FilterPolicy::Any } else { FilterPolicy::Both },
+                ..Default::default()
+            };
+            let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
+                adv_data: &adv_data[..],
+                scan_data: &scan_data[..],
+            };
+            info!(
+                "Advertising ({}, {})",
+                if fast { "fast" } else { "slow" },
+                if open { "open" } else { "bonded only" }
+            );
+            let mut conn = match select3(
+                peripheral::advertise_pairable(sd, adv, &config, bonder),
+                Timer::at(if fast { fast_until } else { Instant::MAX }),
+                events::next_ble_command(),
+            )
+            .await
+            {
+                Either3::First(Ok(conn)) => conn,
+                Either3::First(Err(e)) => {
+                    error::report(Error::Ble, e);
+                    Timer::after(Duration::from_secs(1)).await;
+                    continue;
+                }
+                Either3::Third(BleCommand::AdvertiseFast) => {
+                    fast_until = Instant::now() + FAST_ADVERTISING_TIME;
+                    continue;
+                }
+                Either3::Third(BleCommand::FindPhone(ring)) => {
+                    find_phone(ring, false);
+                    continue;
+                }
+                Either3::Third(BleCommand::PairingMode) => {
+                    info!("Pairing mode");
+                    fast_until = Instant::now() + FAST_ADVERTISING_TIME;
+                    pairing_until = fast_until;
+                    continue;
+                }
+                // Nothing to disconnect, and a suspend is picked up above.
+                Either3::Second(_) | Either3::Third(_) => continue,
+            };
+
+            info!("Connection established");
+            raise_mtu(&mut conn).await;
+            Timer::after(Duration::from_secs(1)).await;
+            info!("Syncing time");
+            sync_time(&conn, &crate::CLOCK).await;
+
+            let pairing = gatt_server_task(conn, server, dfu_config.clone(), fs, logs, battery, motor, power).await;
+            fast_until = Instant::now() + FAST_ADVERTISING_TIME;
+            pairing_until = if pairing { fast_until } else { Instant::MIN };
+        }
+    })
+    .await
+}
+
+pub fn enable_softdevice(name: &'static str) -> &'static mut Softdevice {
+    let config = nrf_softdevice::Config {
+        clock: Some(raw::nrf_clock_lf_cfg_t {
+            source: raw::NRF_CLOCK_LF_SRC_RC as u8,
+            rc_ctiv: 4,
+            rc_temp_ctiv: 2,
+            accuracy: 7,
+        }),
+        conn_gap: Some(raw::ble_gap_conn_cfg_t {
+            conn_count: 2,
+            event_length: 24,
+        }),
+        conn_gatt: Some(raw::ble_gatt_conn_cfg_t {
+            att_mtu: ATT_MTU as u16,
+        }),
+        gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t { attr_tab_size: 32768 }),
+        gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
+            adv_set_count: 1,
+            periph_role_count: 3,
+            central_role_count: 1,
+            central_sec_count: 1,
+            _bitfield_1: Default::default(),
+        }),
+        gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
+            p_value: name.as_ptr() as *const u8 as _,
+            current_len: name.len() as u16,
+            max_len: name.len() as u16,
+            write_perm: unsafe { core::mem::zeroed() },
+            _bitfield_1: raw::ble_gap_cfg_device_name_t::new_bitfield_1(raw::BLE_GATTS_VLOC_STACK as u8),
+        }),
+        ..Default::default()
+    };
+    Softdevice::enable(&config)
+}
+
+#[embassy_executor::task]
+pub async fn softdevice_task(sd: &'static Softdevice) {
+    profiled(Task::Softdevice, async move {
+        sd.run().await;
+    })
+    .await
 }
@@ -0,0 +1,212 @@
+use defmt::{info, warn};
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{with_timeout, Duration, Timer};
+use heapless::Vec;
+use nrf_softdevice::ble::{gatt_client, Connection, SecurityMode};
+
+use super::ATT_MTU;
+use crate::device::SharedMotor;
+use crate::notifications::{self, Category, Notification, BODY_SIZE, TITLE_SIZE};
+
+/// The ANCS UUID, least significant byte first, which the watch solicits in its scan response so that iPhones offer
+/// it their notifications.
+pub const ANCS_UUID: [u8; 16] = [
+    0xD0, 0x00, 0x2D, 0x12, 0x1E, 0x4B, 0x0F, 0xA4, 0x99, 0x4E, 0xCE, 0xB5, 0x31, 0xF4, 0x05, 0x79,
+];
+
+/// Time the iPhone has to encrypt the link, pairing first if it is not bonded yet, as the ANCS is only open to
+/// bonded devices.
+const ENCRYPT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time the iPhone has to send the attributes of a notification.
+const ATTRIBUTES_TIMEOUT: Duration = Duration::from_secs(5);
+const ALERT_VIBRATION: Duration = Duration::from_millis(100);
+
+const EVENT_ADDED: u8 = 0;
+const FLAG_SILENT: u8 = 1 << 0;
+const FLAG_PRE_EXISTING: u8 = 1 << 2;
+
+const GET_NOTIFICATION_ATTRIBUTES: u8 = 0;
+const ATTRIBUTE_TITLE: u8 = 1;
+const ATTRIBUTE_MESSAGE: u8 = 3;
+/// Size of the attributes response: the command, the notification UID, then the title and the message, each with
+/// their id and length.
+const RESPONSE_SIZE: usize = 5 + 3 + TITLE_SIZE + 3 + BODY_SIZE;
+
+/// Apple Notification Center Service of an iPhone, which the watch subscribes to.
+#[nrf_softdevice::gatt_client(uuid = "7905F431-B5CE-4E99-A40F-4B1E122D00D0")]
+pub struct AncsClient {
+    /// Event, flags, category, category count and UID of each notification added, modified or removed.
+    #[characteristic(uuid = "9FBF120D-6301-42D9-8C58-25E699A21DBD", notify)]
+    notification_source: Vec<u8, 8>,
+
+    #[characteristic(uuid = "69D1D8F3-45E1-49A8-9821-9BBDFDAAD9D9", write)]
+    control_point: Vec<u8, 16>,
+
+    /// Responses to the control point, split over as many notifications as they take.
+    #[characteristic(uuid = "22EAC6E9-24D6-4BB5-BE44-B36ACE7C7BFB", notify)]
+    data_source: Vec<u8, ATT_MTU>,
+}
+
+/// Notifications of the client, handled outside of the GATT callback as attributes are requested with writes.
+static NOTIFICATION_SOURCE: Channel<CriticalSectionRawMutex, Vec<u8, 8>, 4> = Channel::new();
+/// Each notification is taken as it arrives while a response is assembled, two cover those that come in before.
+static DATA_SOURCE: Channel<CriticalSectionRawMutex, Vec<u8, ATT_MTU>, 2> = Channel::new();
+
+/// Receive the notifications of an iPhone until the connection is dropped. Returns at once if the phone has no ANCS,
+/// as other phones do not.
+pub async fn run(conn: &Connection, motor: &SharedMotor) {
+    let Ok(client) = gatt_client::discover::<AncsClient>(conn).await else {
+        return;
+    };
+    info!("Found ANCS on peer");
+    if !encrypt(conn).await {
+        warn!("Link not encrypted, no ANCS notifications");
+        return;
+    }
+    // The data source first, so that no response is missed.
+    let subscribed = match client.data_source_cccd_write(true).await {
+        Ok(()) => client.notification_source_cccd_write(true).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = subscribed {
+        warn!("Error subscribing to ANCS: {:?}", e);
+        return;
+    }
+    // Left over from an earlier connection.
+    while NOTIFICATION_SOURCE.try_receive().is_ok() {}
+    while DATA_SOURCE.try_receive().is_ok() {}
+
+    let events = gatt_client::run(conn, &client, |event| match event {
+        AncsClientEvent::NotificationSourceNotification(data) => {
+            if NOTIFICATION_SOURCE.try_send(data).is_err() {
+                warn!("ANCS queue full, dropping notification");
+            }
+        }
+        AncsClientEvent::DataSourceNotification(data) => {
+            if DATA_SOURCE.try_send(data).is_err() {
+                warn!("ANCS queue full, dropping attributes");
+            }
+        }
+    });
+    select(events, receive(&client, motor)).await;
+}
+
+fn encrypted(conn: &Connection) -> bool {
+    !matches!(conn.security_mode(), SecurityMode::NoAccess | SecurityMode::Open)
+}
+
+/// Ask the iPhone to encrypt the link, and wait until it did.
+async fn encrypt(conn: &Connection) -> bool {
+    if encrypted(conn) {
+        return true;
+    }
+    if let Err(e) = conn.request_security() {
+        warn!("Error requesting security: {:?}", e);
+        return false;
+    }
+    let encrypted = async {
+        while !encrypted(conn) {
+            Timer::after(Duration::from_millis(100)).await;
+        }
+    };
+    with_timeout(ENCRYPT_TIMEOUT, encrypted).await.is_ok()
+}
+
+/// Fetch the title and message of each new notification, store it for the UI and vibrate.
+async fn receive(client: &AncsClient, motor: &SharedMotor) {
+    loop {
+        let source = NOTIFICATION_SOURCE.receive().await;
+        let &[event, flags, category, _count, a, b, c, d] = &source[..] else {
+            continue;
+        };
+        // Notifications already on the phone when the watch connected are left there.
+        if event != EVENT_ADDED || flags & FLAG_PRE_EXISTING != 0 {
+            continue;
+        }
+        let uid = [a, b, c, d];
+        let Some(notification) = attributes(client, uid, ancs_category(category)).await else {
+            continue;
+        };
+        info!("Notification: {:?}", notification.category);
+        notifications::push(notification, &crate::CLOCK);
+        if flags & FLAG_SILENT == 0 {
+            motor.lock().await.vibrate(ALERT_VIBRATION).await;
+        }
+    }
+}
+
+/// Request the title and message of notification `uid`, and assemble them from the data source.
+async fn attributes(client: &AncsClient, uid: [u8; 4], category: Category) -> Option<Notification> {
+    // Responses to earlier requests that timed out.
+    while DATA_SOURCE.try_receive().is_ok() {}
+    let mut request: Vec<u8, 16> = Vec::new();
+    let _ = request.push(GET_NOTIFICATION_ATTRIBUTES);
+    let _ = request.extend_from_slice(&uid);
+    let _ = request.push(ATTRIBUTE_TITLE);
+    let _ = request.extend_from_slice(&(TITLE_SIZE as u16).to_le_bytes());
+    let _ = request.push(ATTRIBUTE_MESSAGE);
+    let _ = request.extend_from_slice(&(BODY_SIZE as u16).to_le_bytes());
+    if let Err(e) = client.control_point_write(&request).await {
+        warn!("Error requesting notification attributes: {:?}", e);
+        return None;
+    }
+
+    let mut response: Vec<u8, RESPONSE_SIZE> = Vec::new();
+    let assembled = async {
+        loop {
+            let data = DATA_SOURCE.receive().await;
+            if response.extend_from_slice(&data).is_err() {
+                return None;
+            }
+            if let Some((title, message)) = parse(&response, uid) {
+                return Some(Notification::new(category, title, message));
+            }
+        }
+    };
+    match with_timeout(ATTRIBUTES_TIMEOUT, assembled).await {
+        Ok(Some(notification)) => Some(notification),
+        _ => {
+            warn!("Incomplete notification attributes");
+            None
+        }
+    }
+}
+
+/// The title and message of a Get Notification Attributes response for `uid`, `None` until it is whole.
+fn parse(data: &[u8], uid: [u8; 4]) -> Option<(&str, &str)> {
+    let (header, mut rest) = (data.get(..5)?, data.get(5..)?);
+    if header[0] != GET_NOTIFICATION_ATTRIBUTES || header[1..] != uid {
+        return None;
+    }
+    let (mut title, mut message) = ("", "");
+    for _ in 0..2 {
+        let &[id, low, high] = rest.get(..3)? else {
+            return None;
+        };
+        let len = u16::from_le_bytes([low, high]) as usize;
+        let value = notifications::utf8_prefix(rest.get(3..3 + len)?);
+        match id {
+            ATTRIBUTE_TITLE => title = value,
+            ATTRIBUTE_MESSAGE => message = value,
+            _ => {}
+        }
+        rest = &rest[3 + len..];
+    }
+    Some((title, message))
+}
+
+/// The category of an ANCS category id.
+fn ancs_category(id: u8) -> Category {
+    match id {
+        1 => Category::Call,
+        2 => Category::MissedCall,
+        3 => Category::VoiceMail,
+        4 => Category::InstantMessage,
+        5 => Category::Schedule,
+        6 => Category::Email,
+        7 => Category::News,
+        _ => Category::Other,
+    }
+}
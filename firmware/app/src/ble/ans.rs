@@ -0,0 +1,76 @@
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+use heapless::Vec;
+
+use super::ATT_MTU;
+use crate::device::SharedMotor;
+use crate::notifications::{self, Category, Notification};
+
+/// Categories the watch shows, all those of the service: simple alert to instant message.
+const SUPPORTED_CATEGORIES: [u8; 2] = [0xFF, 0x03];
+/// Category Gadgetbridge sends notifications with, followed by an icon byte before the text.
+const CUSTOM_CATEGORY: u8 = 0xFA;
+const ALERT_VIBRATION: Duration = Duration::from_millis(100);
+
+/// Alerts written by the phone, handled outside of the GATT callback as the motor is shared.
+static ALERTS: Channel<CriticalSectionRawMutex, Vec<u8, ATT_MTU>, 2> = Channel::new();
+
+/// Alert Notification Service as InfiniTime has it, which Gadgetbridge pushes phone notifications to. Unlike in the
+/// standard, the phone writes the alerts to the watch.
+#[nrf_softdevice::gatt_service(uuid = "1811")]
+pub struct AlertNotificationService {
+    #[characteristic(uuid = "2A47", read)]
+    supported_new_alert_category: [u8; 2],
+
+    /// The category id, the number of new alerts, an icon byte in `CUSTOM_CATEGORY`, then the text: the title and the
+    /// body separated by a NUL, in UTF-8. Only written by a phone paired with the passkey.
+    #[characteristic(uuid = "2A46", write, notify, security = "mitm")]
+    new_alert: Vec<u8, ATT_MTU>,
+}
+
+impl AlertNotificationService {
+    pub(super) fn init(&self) {
+        if let Err(e) = self.supported_new_alert_category_set(&SUPPORTED_CATEGORIES) {
+            warn!("Error setting the alert categories: {:?}", e);
+        }
+    }
+
+    pub(super) fn handle(&self, event: AlertNotificationServiceEvent) {
+        match event {
+            AlertNotificationServiceEvent::NewAlertWrite(data) => {
+                if ALERTS.try_send(data).is_err() {
+                    warn!("Alert queue full, dropping alert");
+                }
+            }
+            AlertNotificationServiceEvent::NewAlertCccdWrite { .. } => {}
+        }
+    }
+
+    /// Store the alerts of a connection for the UI and vibrate, until the connection is dropped.
+    pub(super) async fn run(&self, motor: &SharedMotor) {
+        loop {
+            let alert = ALERTS.receive().await;
+            let Some(notification) = decode(&alert) else {
+                warn!("Malformed alert");
+                continue;
+            };
+            info!("Notification: {:?}", notification.category);
+            notifications::push(notification, &crate::CLOCK);
+            motor.lock().await.vibrate(ALERT_VIBRATION).await;
+        }
+    }
+}
+
+/// The notification of a New Alert value, `None` if it carries no text.
+fn decode(data: &[u8]) -> Option<Notification> {
+    let (&category, rest) = data.split_first()?;
+    let header = if category == CUSTOM_CATEGORY { 2 } else { 1 };
+    let text = notifications::utf8_prefix(rest.get(header..)?).trim_end_matches('\0');
+    if text.is_empty() {
+        return None;
+    }
+    let (title, body) = text.split_once('\0').unwrap_or(("", text));
+    Some(Notification::new(Category::from_id(category), title, body))
+}
@@ -0,0 +1,62 @@
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use nrf_softdevice::ble::Connection;
+
+use crate::device::SharedBattery;
+
+/// Interval between battery measurements while a phone is connected. The battery statistics sample far less often,
+/// too seldom for a level the phone shows next to its own.
+const MEASURE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether the phone enabled battery level notifications, passed from the GATT callback to `BatteryService::run`.
+static NOTIFICATIONS: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Standard Battery Service, which phones and Gadgetbridge show the battery of the watch from without a companion
+/// protocol.
+#[nrf_softdevice::gatt_service(uuid = "180F")]
+pub struct BatteryService {
+    /// Battery level in percent.
+    #[characteristic(uuid = "2A19", read, notify)]
+    battery_level: u8,
+}
+
+impl BatteryService {
+    pub(super) fn handle(&self, event: BatteryServiceEvent) {
+        match event {
+            BatteryServiceEvent::BatteryLevelCccdWrite { notifications } => {
+                info!("Enable battery notifications: {}", notifications);
+                NOTIFICATIONS.signal(notifications);
+            }
+        }
+    }
+
+    /// Measure the battery for a connection until it is dropped, notifying the level when it changes.
+    pub(super) async fn run(&self, conn: &Connection, battery: &SharedBattery) {
+        NOTIFICATIONS.reset();
+        let mut notify = false;
+        let mut notified = None;
+        loop {
+            let level = battery.lock().await.measure().await.min(100) as u8;
+            if let Err(e) = self.battery_level_set(&level) {
+                warn!("Error setting the battery level: {:?}", e);
+            }
+            if notify && notified != Some(level) {
+                match self.battery_level_notify(conn, &level) {
+                    Ok(()) => notified = Some(level),
+                    Err(e) => warn!("Error sending the battery level: {:?}", e),
+                }
+            }
+            match select(Timer::after(MEASURE_INTERVAL), NOTIFICATIONS.wait()).await {
+                Either::First(_) => {}
+                // The current level is sent as soon as notifications are enabled.
+                Either::Second(enabled) => {
+                    notify = enabled;
+                    notified = None;
+                }
+            }
+        }
+    }
+}
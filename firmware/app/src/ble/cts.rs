@@ -0,0 +1,24 @@
+use defmt::{info, warn};
+use heapless::Vec;
+
+/// Current Time Service of the watch, which Gadgetbridge writes the time to on connecting, as it does with InfiniTime.
+/// Phones that have their own are read at connection time instead, see `sync_time`.
+#[nrf_softdevice::gatt_service(uuid = "1805")]
+pub struct CurrentTimeService {
+    #[characteristic(uuid = "2A2B", write)]
+    current_time: Vec<u8, 10>,
+}
+
+impl CurrentTimeService {
+    pub(super) fn handle(&self, event: CurrentTimeServiceEvent) {
+        match event {
+            CurrentTimeServiceEvent::CurrentTimeWrite(data) => match super::current_time(&data) {
+                Some(time) => {
+                    info!("Time set by the phone");
+                    crate::CLOCK.set(time);
+                }
+                None => warn!("Malformed current time"),
+            },
+        }
+    }
+}
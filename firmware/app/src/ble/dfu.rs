@@ -0,0 +1,470 @@
+use core::cell::{Cell, RefCell};
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embedded_storage::nor_flash::ReadNorFlash;
+use heapless::Vec;
+use nrf_dfu::initpacket::MAX_SIZE;
+use nrf_dfu::{DfuEvent, DfuPhase, DfuProgress, DfuRequest, DfuSession, DfuStatus, DfuTarget, DfuTransport};
+use nrf_softdevice::ble::gatt_server::NotifyValueError;
+use nrf_softdevice::ble::{Connection, SecurityMode};
+
+use super::{payload_size, value, ATT_MTU};
+use crate::error::{self, Error};
+use crate::events::{self, SensorEvent, UpdateProgress};
+use crate::factory::FactoryReset;
+use crate::firmware::UpdateOwner;
+use crate::fs::FileSystem;
+use crate::kv::{keys, SharedKv, MAX_VALUE_SIZE};
+use crate::power::{Feature, PowerManager};
+use crate::profile::{profiled, Task};
+use crate::wakelock::{WakeLock, WakeLockKind};
+use crate::{firmware, health, maintenance, DfuConfig, DfuPartition, LogPartition};
+
+/// Time an update under way may go without a request before the supervisor resets the watch, see `health`. Phones
+/// send the image without pausing, so a longer gap means the GATT server or the flash is stuck, and the update is
+/// better resumed after a reset than left holding the wake locks.
+const DFU_STALL: Duration = Duration::from_secs(60);
+
+/// Progress of the last update, taken up by the next connection that sends the same init packet.
+static SAVED: BMutex<CriticalSectionRawMutex, Cell<Option<DfuProgress>>> = BMutex::new(Cell::new(None));
+/// Progress to store, `None` once the update is done.
+static PROGRESS: Signal<CriticalSectionRawMutex, Option<DfuProgress>> = Signal::new();
+/// Command object of the last update, which the next connection can select instead of sending it again.
+static SAVED_COMMAND: BMutex<CriticalSectionRawMutex, RefCell<Vec<u8, MAX_SIZE>>> =
+    BMutex::new(RefCell::new(Vec::new()));
+/// Command object to store, empty once the update is done.
+static COMMAND: Signal<CriticalSectionRawMutex, Vec<u8, MAX_SIZE>> = Signal::new();
+/// Keys the command object is stored under, `MAX_VALUE_SIZE` bytes each.
+const COMMAND_KEYS: core::ops::Range<u16> = keys::DFU_COMMAND..keys::DFU_COMMAND + (MAX_SIZE / MAX_VALUE_SIZE) as u16;
+
+/// Writes to the service, handled by `NrfDfuService::run` outside of the GATT callback, which owns the flash for the
+/// connection. Room is left for the packets a phone sends while an object is flushed, a packet dropped when it is
+/// full fails the CRC check and the phone sends the object again. Four packets of the full MTU hold as much as eight
+/// did at the former 120 bytes, each event takes `ATT_MTU` bytes of RAM.
+static DFU_EVENTS: Channel<CriticalSectionRawMutex, NrfDfuServiceEvent, 4> = Channel::new();
+
+/// Size of the status characteristic: the `DfuPhase`, the percent done, then the bytes received and the size of the
+/// image, little-endian.
+const STATUS_SIZE: usize = 10;
+
+#[nrf_softdevice::gatt_service(uuid = "FE59")]
+pub struct NrfDfuService {
+    /// Written only over a link encrypted after pairing with the passkey, so that no stranger can flash the watch.
+    #[characteristic(uuid = "8EC90001-F315-4F60-9FB8-838830DAEA50", write, notify, security = "mitm")]
+    control: Vec<u8, ATT_MTU>,
+
+    /// Packets carry up to the ATT MTU negotiated by the connection less 3 bytes for the opcode and handle, at most
+    /// `ATT_MTU` as configured for the SoftDevice.
+    #[characteristic(uuid = "8EC90002-F315-4F60-9FB8-838830DAEA50", write_without_response, notify)]
+    packet: Vec<u8, ATT_MTU>,
+
+    /// Progress of the update, see `STATUS_SIZE`, notified after each data object and when the phase changes, so that
+    /// companion apps can show it without following the protocol. Not part of Nordic's service.
+    #[characteristic(uuid = "8c2a0010-7c3e-4f3a-9a7e-5761746368fe", read, notify)]
+    status: [u8; STATUS_SIZE],
+}
+
+/// Firmware update state of a connection.
+pub struct DfuConnection {
+    session: DfuSession,
+    target: DfuTarget,
+    partition: DfuPartition<'static>,
+    /// Where SoftDevice and bootloader updates go instead of `partition`.
+    staging: LogPartition<'static>,
+    /// Where resource packs sent as assets go instead of `partition`.
+    assets: LogPartition<'static>,
+    config: DfuConfig<'static>,
+    /// Where received resource packs are installed.
+    fs: &'static FileSystem<'static>,
+    power: &'static PowerManager,
+    spawner: Spawner,
+    /// Held from the first request that writes an update, see `writes_update`, until the update is done or aborted,
+    /// or the connection ends.
+    locks: Option<[WakeLock; 2]>,
+    /// Owner of the update for this connection, released along with the wake locks.
+    owner: Option<UpdateOwner>,
+    /// Progress last saved by this connection.
+    progress: Option<DfuProgress>,
+    /// Progress last shown on screen.
+    shown: Option<UpdateProgress>,
+    /// Status last notified, and whether the phone enabled status notifications.
+    status: [u8; STATUS_SIZE],
+    notify_status: bool,
+}
+
+impl DfuConnection {
+    pub fn new(
+        config: DfuConfig<'static>,
+        fs: &'static FileSystem<'static>,
+        power: &'static PowerManager,
+        spawner: Spawner,
+    ) -> Self {
+        let partition = config.dfu();
+        let mut target = firmware::dfu_target(partition.capacity() as u32);
+        if let Some(progress) = SAVED.lock(|saved| saved.get()) {
+            SAVED_COMMAND.lock(|command| target.restore_command(&command.borrow()));
+            target.resume(progress);
+        }
+        Self {
+            session: DfuSession::default(),
+            target,
+            partition,
+            staging: config.staging(),
+            assets: config.assets(),
+            config,
+            fs,
+            power,
+            spawner,
+            locks: None,
+            owner: None,
+            progress: None,
+            shown: None,
+            status: [0; STATUS_SIZE],
+            notify_status: false,
+        }
+    }
+
+    /// End the update of a connection that was lost. The progress up to the last executed object is already saved
+    /// for the next connection to resume from, the rest is dropped along with the wake locks.
+    pub fn disconnected(mut self) {
+        self.end();
+    }
+
+    /// Release the wake locks once an update is done, aborted or lost, so that the connection goes back to the power
+    /// saving interval.
+    fn end(&mut self) {
+        if let Some(owner) = self.owner.take() {
+            firmware::release_update(owner);
+        }
+        if self.locks.take().is_none() {
+            return;
+        }
+        health::stop(Task::Ble);
+        if let Some(shown) = self.shown.take().filter(|shown| shown.received < shown.total) {
+            info!(
+                "Firmware update interrupted at {} of {} bytes",
+                shown.received, shown.total
+            );
+            events::publish(SensorEvent::FirmwareUpdateStopped);
+        }
+    }
+
+    /// Value of the status characteristic.
+    fn status(&self) -> [u8; STATUS_SIZE] {
+        let phase = self.target.phase();
+        let (received, total) = match self.shown {
+            Some(shown) if phase != DfuPhase::Idle => (shown.received, shown.total),
+            _ => (0, 0),
+        };
+        let percent = if total > 0 {
+            (received as u64 * 100 / total as u64) as u8
+        } else {
+            0
+        };
+        let mut value = [0; STATUS_SIZE];
+        value[0] = phase as u8;
+        value[1] = percent;
+        value[2..6].copy_from_slice(&received.to_le_bytes());
+        value[6..10].copy_from_slice(&total.to_le_bytes());
+        value
+    }
+
+    /// Save the progress after each executed data object, and clear it once the update is done or aborted. The UI is
+    /// told along with it, so the update is shown on screen.
+    fn save_progress(&mut self, status: Option<DfuStatus>) {
+        let shown = match (status, self.shown, self.target.transferred()) {
+            (Some(status), Some(shown), _) if status.is_done() => Some(UpdateProgress {
+                received: shown.total,
+                total: shown.total,
+            }),
+            (_, _, Some((received, total))) => Some(UpdateProgress { received, total }),
+            _ => None,
+        };
+        if let Some(shown) = shown.filter(|shown| Some(*shown) != self.shown) {
+            self.shown = Some(shown);
+            events::publish(SensorEvent::FirmwareUpdate(shown));
+        }
+
+        let progress = match status {
+            Some(status) if status.is_done() || status == DfuStatus::Aborted => None,
+            _ => match self.target.progress() {
+                Some(progress) if Some(progress) != self.progress => Some(progress),
+                _ => return,
+            },
+        };
+        // The command object is stored along with the first progress of an update.
+        let command_crc = |progress: Option<DfuProgress>| progress.map(|progress| progress.command_crc);
+        if command_crc(progress) != command_crc(self.progress) {
+            let command = Vec::from_slice(self.target.command().unwrap_or_default()).unwrap_or_default();
+            SAVED_COMMAND.lock(|saved| saved.replace(command.clone()));
+            COMMAND.signal(command);
+        }
+        self.progress = progress;
+        SAVED.lock(|saved| saved.set(progress));
+        PROGRESS.signal(progress);
+    }
+}
+
+/// Sends the DFU session's notifications on the control point characteristic.
+struct ControlNotifier<'a> {
+    service: &'a NrfDfuService,
+    connection: &'a Connection,
+}
+
+impl DfuTransport for ControlNotifier<'_> {
+    type Error = NotifyValueError;
+
+    /// A response longer than a notification, such as to HwVersion before the phone raised the MTU, is split over as
+    /// many as it takes.
+    fn notify(&self, data: &[u8]) -> Result<(), NotifyValueError> {
+        for chunk in data.chunks(payload_size(self.connection)) {
+            self.service.control_notify(self.connection, &value(chunk)?)?;
+        }
+        Ok(())
+    }
+
+    /// The phone may exchange the MTU at any time, it only asks for it before sending packets.
+    fn mtu(&self) -> u16 {
+        self.connection.att_mtu()
+    }
+}
+
+impl NrfDfuService {
+    pub(super) fn handle(&self, event: NrfDfuServiceEvent) {
+        if DFU_EVENTS.try_send(event).is_err() {
+            warn!("DFU queue full, dropping write");
+        }
+    }
+
+    /// Process the writes of a connection until it is dropped.
+    pub(super) async fn run(&self, conn: &Connection, dfu: &mut DfuConnection) {
+        // Writes left over from a previous connection belong to its update.
+        while DFU_EVENTS.try_receive().is_ok() {}
+        loop {
+            let event = DFU_EVENTS.receive().await;
+            self.process(conn, dfu, event);
+        }
+    }
+
+    fn process(&self, conn: &Connection, dfu: &mut DfuConnection, event: NrfDfuServiceEvent) {
+        if let NrfDfuServiceEvent::StatusCccdWrite { notifications } = event {
+            dfu.notify_status = notifications;
+            self.send_status(conn, dfu, true);
+            return;
+        }
+        let notifier = ControlNotifier {
+            service: self,
+            connection: conn,
+        };
+        let event = match &event {
+            NrfDfuServiceEvent::ControlWrite(data) => DfuEvent::ControlWrite(data),
+            NrfDfuServiceEvent::ControlCccdWrite { notifications } => DfuEvent::ControlNotifications(*notifications),
+            NrfDfuServiceEvent::PacketWrite(data) => DfuEvent::PacketWrite(data),
+            NrfDfuServiceEvent::PacketCccdWrite { .. } => DfuEvent::PacketNotifications,
+            NrfDfuServiceEvent::StatusCccdWrite { .. } => return,
+        };
+        // The handle is gone once the connection is, along with the phone to answer.
+        let Some(handle) = conn.handle() else {
+            return;
+        };
+        let owner = UpdateOwner::Ble(handle);
+        if !firmware::claim_update(owner, &event) {
+            if let DfuEvent::ControlWrite(_) = event {
+                warn!("Firmware update refused, another one is under way");
+            }
+            dfu.session.refuse(&notifier, event);
+            return;
+        }
+        dfu.owner = Some(owner);
+
+        // An update that has started is allowed to finish even if the battery drops below the threshold.
+        let allowed = dfu.locks.is_some() || dfu.power.allows(Feature::FirmwareUpdate);
+        if !allowed {
+            if let DfuEvent::ControlWrite(_) = event {
+                warn!("Firmware update refused, battery too low");
+            }
+            dfu.session.refuse(&notifier, event);
+            return;
+        }
+        // Queries and CCCD writes leave the watch as it is, a phone may send them and then wait on its user for long.
+        if writes_update(&event) {
+            dfu.locks.get_or_insert_with(|| {
+                info!("Firmware update started");
+                [
+                    WakeLock::acquire(WakeLockKind::Cpu),
+                    WakeLock::acquire(WakeLockKind::BleFast),
+                ]
+            });
+        }
+        if dfu.locks.is_some() {
+            health::heartbeat(Task::Ble, DFU_STALL);
+        }
+        // Anyone in range can write the control point, only a paired phone may erase the watch.
+        let encrypted = !matches!(conn.security_mode(), SecurityMode::NoAccess | SecurityMode::Open);
+        dfu.target.set_allow_factory_reset(encrypted);
+        let status = if dfu.target.staged() {
+            dfu.session.handle(&mut dfu.target, &mut dfu.staging, &notifier, event)
+        } else if dfu.target.assets() {
+            dfu.session.handle(&mut dfu.target, &mut dfu.assets, &notifier, event)
+        } else {
+            dfu.session
+                .handle(&mut dfu.target, &mut dfu.partition, &notifier, event)
+        };
+        dfu.save_progress(status);
+        self.send_status(conn, dfu, false);
+        if status.is_some_and(|status| status.is_done() || status == DfuStatus::Aborted) {
+            dfu.end();
+        }
+        match status {
+            Some(DfuStatus::DoneReset) => {
+                error::recover(dfu.spawner.spawn(finish_dfu(dfu.config.clone())), Error::Spawn);
+            }
+            Some(DfuStatus::DoneStaged { softdevice, bootloader }) => {
+                let task = install_staged(dfu.config.clone(), softdevice, bootloader);
+                error::recover(dfu.spawner.spawn(task), Error::Spawn);
+            }
+            Some(DfuStatus::DoneAssets { size }) => {
+                let task = install_assets(dfu.config.clone(), dfu.fs, size);
+                error::recover(dfu.spawner.spawn(task), Error::Spawn);
+            }
+            Some(DfuStatus::FactoryReset) => {
+                let reset = FactoryReset::new(dfu.fs, dfu.config.internal(), dfu.config.clone());
+                error::recover(dfu.spawner.spawn(factory_reset(reset)), Error::Spawn);
+            }
+            _ => {}
+        }
+    }
+
+    /// Update the status characteristic, and notify it if it changed or `force` is set.
+    fn send_status(&self, connection: &Connection, dfu: &mut DfuConnection, force: bool) {
+        let status = dfu.status();
+        if status == dfu.status && !force {
+            return;
+        }
+        dfu.status = status;
+        if let Err(e) = self.status_set(&status) {
+            warn!("Error setting the DFU status: {:?}", e);
+        }
+        if dfu.notify_status {
+            if let Err(e) = self.status_notify(connection, &status) {
+                warn!("Error sending the DFU status: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Whether `event` writes an update: creates an object, sends its data or executes it. Data and Execute also resume
+/// an update interrupted by a disconnect or a reset, which the phone does not create again.
+fn writes_update(event: &DfuEvent<'_>) -> bool {
+    match event {
+        DfuEvent::ControlWrite(data) => matches!(
+            DfuRequest::decode(data),
+            Ok(DfuRequest::Create { .. } | DfuRequest::Write { .. } | DfuRequest::Execute)
+        ),
+        DfuEvent::PacketWrite(_) => true,
+        DfuEvent::ControlNotifications(_) | DfuEvent::PacketNotifications => false,
+    }
+}
+
+/// Hand the received application over to the bootloader and reset.
+#[embassy_executor::task]
+async fn finish_dfu(config: DfuConfig<'static>) {
+    firmware::finish_update(&config).await;
+}
+
+/// Install the received SoftDevice and bootloader and reset.
+#[embassy_executor::task]
+async fn install_staged(config: DfuConfig<'static>, softdevice: u32, bootloader: u32) {
+    firmware::install_staged(&config, softdevice, bootloader).await;
+}
+
+/// Install the resource pack received as assets.
+#[embassy_executor::task]
+async fn install_assets(config: DfuConfig<'static>, fs: &'static FileSystem<'static>, size: u32) {
+    firmware::install_assets(&config, fs, size).await;
+}
+
+/// Erase the user data as requested by the phone, and reset.
+#[embassy_executor::task]
+async fn factory_reset(reset: FactoryReset) {
+    reset.run().await
+}
+
+/// Load the progress of an update interrupted by a reset, keeping the partition it was written to.
+pub async fn load_progress(kv: &SharedKv<'_>) {
+    let mut buf = [0; DfuProgress::SIZE];
+    match kv.lock().await.get(keys::DFU_PROGRESS, &mut buf) {
+        Ok(Some(len)) => {
+            if let Some(progress) = DfuProgress::decode(&buf[..len]) {
+                info!("Firmware update can resume at {}", progress.offset);
+                SAVED.lock(|saved| saved.set(Some(progress)));
+                maintenance::keep_dfu();
+            }
+        }
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Error loading DFU progress: {:?}", e);
+            return;
+        }
+    }
+
+    let mut command = Vec::<u8, MAX_SIZE>::new();
+    for key in COMMAND_KEYS {
+        let mut buf = [0; MAX_VALUE_SIZE];
+        match kv.lock().await.get(key, &mut buf) {
+            Ok(Some(len)) => {
+                let _ = command.extend_from_slice(&buf[..len]);
+                if len < MAX_VALUE_SIZE {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Error loading the DFU command object: {:?}", e);
+                return;
+            }
+        }
+    }
+    SAVED_COMMAND.lock(|saved| saved.replace(command));
+}
+
+/// Store the command object under `COMMAND_KEYS`. Every key is written, so none is left over from a longer one.
+async fn store_command(kv: &SharedKv<'_>, command: &[u8]) {
+    let mut chunks = command.chunks(MAX_VALUE_SIZE);
+    for key in COMMAND_KEYS {
+        if let Err(e) = kv.lock().await.set(key, chunks.next().unwrap_or_default()) {
+            warn!("Error storing the DFU command object: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// Store the progress and command object of updates, so they can resume after a reset.
+#[embassy_executor::task]
+pub async fn dfu_progress_task(kv: &'static SharedKv<'static>) {
+    profiled(Task::Dfu, async move {
+        loop {
+            let progress = match select(PROGRESS.wait(), COMMAND.wait()).await {
+                Either::First(progress) => progress,
+                Either::Second(command) => {
+                    store_command(kv, &command).await;
+                    continue;
+                }
+            };
+            // An empty value clears the progress.
+            let value = progress.map(|progress| progress.encode());
+            let value = value.as_ref().map_or(&[][..], |value| &value[..]);
+            if let Err(e) = kv.lock().await.set(keys::DFU_PROGRESS, value) {
+                warn!("Error storing DFU progress: {:?}", e);
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,53 @@
+use defmt::warn;
+use heapless::Vec;
+
+use crate::board;
+use crate::buildinfo::BUILD;
+
+const VALUE_SIZE: usize = 32;
+
+/// Firmware version reported to Gadgetbridge, which picks the protocols it speaks by the InfiniTime version, such as
+/// the Simple Weather Service from 1.14 on. It expects a bare `major.minor.patch`.
+#[cfg(feature = "gadgetbridge")]
+const FIRMWARE_REVISION: &str = "1.14.0";
+#[cfg(not(feature = "gadgetbridge"))]
+const FIRMWARE_REVISION: &str = BUILD.version;
+
+/// Standard Device Information Service, telling companion apps which board and firmware build they talk to.
+#[nrf_softdevice::gatt_service(uuid = "180A")]
+pub struct DeviceInformationService {
+    #[characteristic(uuid = "2A29", read)]
+    manufacturer_name: Vec<u8, VALUE_SIZE>,
+
+    #[characteristic(uuid = "2A24", read)]
+    model_number: Vec<u8, VALUE_SIZE>,
+
+    /// Version of the firmware, or the InfiniTime version it stands in for with the `gadgetbridge` feature.
+    #[characteristic(uuid = "2A26", read)]
+    firmware_revision: Vec<u8, VALUE_SIZE>,
+
+    /// Commit the firmware was built from.
+    #[characteristic(uuid = "2A28", read)]
+    software_revision: Vec<u8, VALUE_SIZE>,
+}
+
+impl DeviceInformationService {
+    pub(super) fn init(&self) {
+        let results = [
+            self.manufacturer_name_set(&text_value(board::MANUFACTURER)),
+            self.model_number_set(&text_value(board::MODEL)),
+            self.firmware_revision_set(&text_value(FIRMWARE_REVISION)),
+            self.software_revision_set(&text_value(BUILD.short_commit())),
+        ];
+        for result in results {
+            if let Err(e) = result {
+                warn!("Error setting device information: {:?}", e);
+            }
+        }
+    }
+}
+
+fn text_value(text: &str) -> Vec<u8, VALUE_SIZE> {
+    let len = text.len().min(VALUE_SIZE);
+    Vec::from_slice(&text.as_bytes()[..len]).unwrap_or_default()
+}
@@ -0,0 +1,120 @@
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use nrf_softdevice::ble::gatt_server::NotifyValueError;
+use nrf_softdevice::ble::Connection;
+use nrf_softdevice::RawError;
+
+use super::{payload_size, read_u32, value, ATT_MTU};
+use crate::export::{Frame, LogKind};
+use crate::Logs;
+
+/// Export of the activity and heart rate logs, see the data export section of the README.
+#[nrf_softdevice::gatt_service(uuid = "8c2a0001-7c3e-4f3a-9a7e-5761746368fe")]
+pub struct ExportService {
+    #[characteristic(uuid = "8c2a0002-7c3e-4f3a-9a7e-5761746368fe", write)]
+    control: Vec<u8, ATT_MTU>,
+
+    #[characteristic(uuid = "8c2a0003-7c3e-4f3a-9a7e-5761746368fe", notify)]
+    data: Vec<u8, ATT_MTU>,
+}
+
+/// Export requests are handled outside of the GATT callback, as reading the logs is async.
+static EXPORT_REQUESTS: Channel<CriticalSectionRawMutex, Vec<u8, ATT_MTU>, 1> = Channel::new();
+
+impl ExportService {
+    pub(super) fn handle(&self, event: ExportServiceEvent) {
+        match event {
+            ExportServiceEvent::ControlWrite(data) => {
+                if EXPORT_REQUESTS.try_send(data).is_err() {
+                    warn!("Export already in progress, dropping request");
+                }
+            }
+            ExportServiceEvent::DataCccdWrite { notifications } => {
+                info!("Enable export notifications: {}", notifications);
+            }
+        }
+    }
+
+    /// Process export requests for a connection until it is dropped.
+    pub(super) async fn run(&self, conn: &Connection, logs: Logs) {
+        loop {
+            let request = EXPORT_REQUESTS.receive().await;
+            match (
+                request.first().and_then(|l| LogKind::from_u8(*l)),
+                read_u32(&request, 1),
+            ) {
+                (Some(log), Some(start)) => {
+                    info!("Exporting {:?} log from record {}", log, start);
+                    self.export(conn, logs, log, start).await;
+                }
+                _ => warn!("Invalid export request"),
+            }
+        }
+    }
+
+    async fn export(&self, conn: &Connection, logs: Logs, log: LogKind, mut start: u32) {
+        let mut sequence: u16 = 0;
+        loop {
+            let mut frame = Frame::new(log, sequence, payload_size(conn));
+            let mut next = None;
+            let push = |id, data: &[u8]| {
+                if frame.push(id, data) {
+                    true
+                } else {
+                    next.replace(id);
+                    false
+                }
+            };
+            let result = match (log, logs.activity, logs.heart_rate) {
+                (LogKind::Activity, Some(activity), _) => activity.read_from(start, push).await,
+                (LogKind::HeartRate, _, Some(heart_rate)) => heart_rate.read_from(start, push).await,
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!("Error reading log for export: {:?}", e);
+            }
+
+            // Records never fit a frame if the phone kept a small MTU.
+            if frame.is_empty() && next.is_some() {
+                warn!("MTU too small for the export, {} bytes", payload_size(conn));
+                return;
+            }
+            if !self.send(conn, &frame.finish(next.is_none())).await {
+                return;
+            }
+            match next {
+                Some(id) => {
+                    start = id;
+                    sequence = sequence.wrapping_add(1);
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Send a frame, waiting for the softdevice to free buffers when frames are queued faster than they are sent.
+    async fn send(&self, conn: &Connection, frame: &[u8]) -> bool {
+        let value = match value(frame) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Error sending export frame: {:?}", e);
+                return false;
+            }
+        };
+        for _ in 0..50 {
+            match self.data_notify(conn, &value) {
+                Ok(_) => return true,
+                Err(NotifyValueError::Raw(RawError::Resources)) => Timer::after(Duration::from_millis(20)).await,
+                Err(e) => {
+                    warn!("Error sending export frame: {:?}", e);
+                    return false;
+                }
+            }
+        }
+        warn!("Timeout sending export frame");
+        false
+    }
+}
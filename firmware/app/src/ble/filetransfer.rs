@@ -0,0 +1,279 @@
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::{String, Vec};
+use nrf_softdevice::ble::Connection;
+
+use super::{payload_size, read_u32, ATT_MTU, MTU};
+use crate::fs::FileSystem;
+
+/// File transfer service compatible with the Adafruit BLE file transfer protocol used by InfiniTime companion apps.
+#[nrf_softdevice::gatt_service(uuid = "FEBB")]
+pub struct FileSystemService {
+    #[characteristic(uuid = "adaf0100-4669-6c65-5472-616e73666572", read)]
+    version: u16,
+
+    #[characteristic(uuid = "adaf0200-4669-6c65-5472-616e73666572", write, write_without_response, notify)]
+    transfer: Vec<u8, ATT_MTU>,
+}
+
+const FS_PROTOCOL_VERSION: u16 = 4;
+
+const FS_STATUS_OK: u8 = 0x01;
+const FS_STATUS_ERROR: u8 = 0x02;
+
+const FS_READ: u8 = 0x10;
+const FS_READ_RESPONSE: u8 = 0x11;
+const FS_READ_CONTINUE: u8 = 0x12;
+const FS_WRITE: u8 = 0x20;
+const FS_WRITE_RESPONSE: u8 = 0x21;
+const FS_WRITE_DATA: u8 = 0x22;
+const FS_WRITE_DATA_RESPONSE: u8 = 0x23;
+const FS_DELETE: u8 = 0x30;
+const FS_DELETE_RESPONSE: u8 = 0x31;
+const FS_MKDIR: u8 = 0x40;
+const FS_MKDIR_RESPONSE: u8 = 0x41;
+const FS_LIST: u8 = 0x50;
+const FS_LIST_RESPONSE: u8 = 0x51;
+const FS_MOVE: u8 = 0x60;
+const FS_MOVE_RESPONSE: u8 = 0x61;
+
+/// Transfer requests are handled outside of the GATT callback, as filesystem operations are async.
+static FS_REQUESTS: Channel<CriticalSectionRawMutex, Vec<u8, ATT_MTU>, 2> = Channel::new();
+
+impl FileSystemService {
+    pub(super) fn init(&self) {
+        if let Err(e) = self.version_set(&FS_PROTOCOL_VERSION) {
+            warn!("Error setting filesystem protocol version: {:?}", e);
+        }
+    }
+
+    pub(super) fn handle(&self, event: FileSystemServiceEvent) {
+        match event {
+            FileSystemServiceEvent::TransferWrite(data) => {
+                if FS_REQUESTS.try_send(data).is_err() {
+                    warn!("Filesystem request queue full, dropping request");
+                }
+            }
+            FileSystemServiceEvent::TransferCccdWrite { notifications } => {
+                info!("Enable filesystem notifications: {}", notifications);
+            }
+        }
+    }
+
+    fn respond(&self, conn: &Connection, response: &[u8]) {
+        match Vec::from_slice(response) {
+            Ok(value) => {
+                if let Err(e) = self.transfer_notify(conn, &value) {
+                    warn!("Error sending filesystem response: {:?}", e);
+                }
+            }
+            Err(_) => warn!("Filesystem response too large"),
+        }
+    }
+
+    /// Process file transfer requests for a connection until it is dropped.
+    pub(super) async fn run(&self, conn: &Connection, fs: &FileSystem<'_>) {
+        // Requests left over from a previous connection belong to its transfer.
+        while FS_REQUESTS.try_receive().is_ok() {}
+        let mut upload: Option<(String<64>, u32)> = None;
+        let mut download: Option<String<64>> = None;
+        loop {
+            let request = FS_REQUESTS.receive().await;
+            let Some(&command) = request.first() else {
+                continue;
+            };
+            match command {
+                FS_READ | FS_READ_CONTINUE => {
+                    if command == FS_READ {
+                        download = read_path(&request, 2, 12).and_then(|p| String::try_from(p).ok());
+                    }
+                    let offset = read_u32(&request, 4);
+                    let chunk_size = read_u32(&request, 8);
+                    let mut response: Vec<u8, ATT_MTU> = Vec::new();
+                    // The data follows a 16 byte header in a notification of the MTU of the connection.
+                    let mut buf = [0; MTU - 16];
+                    let room = payload_size(conn).saturating_sub(16);
+                    let chunk_size = (chunk_size.unwrap_or(0) as usize).min(room);
+                    let result = match (&download, offset) {
+                        (Some(path), Some(offset)) => fs
+                            .read(path, offset, &mut buf[..chunk_size])
+                            .await
+                            .map(|(read, total)| (offset, read, total)),
+                        _ => Err(crate::fs::Error::InvalidPath),
+                    };
+                    let (status, (offset, read, total)) = match result {
+                        Ok(r) => (FS_STATUS_OK, r),
+                        Err(e) => {
+                            warn!("Error reading file: {:?}", e);
+                            (FS_STATUS_ERROR, (0, 0, 0))
+                        }
+                    };
+                    let _ = response.extend_from_slice(&[FS_READ_RESPONSE, status, 0, 0]);
+                    let _ = response.extend_from_slice(&offset.to_le_bytes());
+                    let _ = response.extend_from_slice(&(total as u32).to_le_bytes());
+                    let _ = response.extend_from_slice(&(read as u32).to_le_bytes());
+                    let _ = response.extend_from_slice(&buf[..read]);
+                    self.respond(conn, &response);
+                }
+                FS_WRITE => {
+                    upload = None;
+                    let offset = read_u32(&request, 4).unwrap_or(0);
+                    let status = match (read_path(&request, 2, 20), read_u32(&request, 16)) {
+                        (Some(path), Some(total)) => match String::try_from(path) {
+                            Ok(path) => match fs.write(&path, offset, &[]).await {
+                                Ok(_) => {
+                                    info!("Receiving file {} ({} bytes)", path.as_str(), total);
+                                    upload.replace((path, total));
+                                    FS_STATUS_OK
+                                }
+                                Err(e) => {
+                                    warn!("Error creating file: {:?}", e);
+                                    FS_STATUS_ERROR
+                                }
+                            },
+                            Err(_) => FS_STATUS_ERROR,
+                        },
+                        _ => FS_STATUS_ERROR,
+                    };
+                    self.respond_write(conn, fs, FS_WRITE_RESPONSE, status, offset).await;
+                }
+                FS_WRITE_DATA => {
+                    let offset = read_u32(&request, 4).unwrap_or(0);
+                    let len = read_u32(&request, 8).unwrap_or(0) as usize;
+                    let status = match (&upload, request.get(12..12 + len)) {
+                        (Some((path, total)), Some(data)) if offset + len as u32 <= *total => {
+                            match fs.write(path, offset, data).await {
+                                Ok(_) if path == crate::resources::PACK_PATH && offset + len as u32 == *total => {
+                                    match crate::resources::install(fs).await {
+                                        Ok(_) => FS_STATUS_OK,
+                                        Err(_) => FS_STATUS_ERROR,
+                                    }
+                                }
+                                Ok(_) => FS_STATUS_OK,
+                                Err(e) => {
+                                    warn!("Error writing file: {:?}", e);
+                                    FS_STATUS_ERROR
+                                }
+                            }
+                        }
+                        _ => FS_STATUS_ERROR,
+                    };
+                    self.respond_write(conn, fs, FS_WRITE_DATA_RESPONSE, status, offset + len as u32)
+                        .await;
+                }
+                FS_DELETE => {
+                    let status = match read_path(&request, 2, 4) {
+                        Some(path) => status_of(fs.remove(path).await),
+                        None => FS_STATUS_ERROR,
+                    };
+                    self.respond(conn, &[FS_DELETE_RESPONSE, status]);
+                }
+                FS_MKDIR => {
+                    let status = match read_path(&request, 2, 16) {
+                        Some(path) => status_of(fs.create_dir(path).await),
+                        None => FS_STATUS_ERROR,
+                    };
+                    let mut response = [0; 16];
+                    response[0] = FS_MKDIR_RESPONSE;
+                    response[1] = status;
+                    self.respond(conn, &response);
+                }
+                FS_LIST => {
+                    let Some(path) = read_path(&request, 2, 4) else {
+                        self.respond_entry(conn, FS_STATUS_ERROR, 0, 0, None);
+                        continue;
+                    };
+                    let mut total = 0;
+                    let result = fs.read_dir(path, |_| total += 1).await;
+                    let mut index = 0;
+                    let result = match result {
+                        Ok(_) => {
+                            fs.read_dir(path, |entry| {
+                                self.respond_entry(conn, FS_STATUS_OK, index, total, Some(&entry));
+                                index += 1;
+                            })
+                            .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    self.respond_entry(conn, status_of(result), total, total, None);
+                }
+                FS_MOVE => {
+                    let old_len = read_u16(&request, 2).unwrap_or(0) as usize;
+                    let new_len = read_u16(&request, 4).unwrap_or(0) as usize;
+                    let old = request.get(6..6 + old_len).and_then(|p| core::str::from_utf8(p).ok());
+                    let new = request
+                        .get(7 + old_len..7 + old_len + new_len)
+                        .and_then(|p| core::str::from_utf8(p).ok());
+                    let status = match (old, new) {
+                        (Some(old), Some(new)) => status_of(fs.rename(old, new).await),
+                        _ => FS_STATUS_ERROR,
+                    };
+                    self.respond(conn, &[FS_MOVE_RESPONSE, status]);
+                }
+                _ => {
+                    warn!("Unknown filesystem command: 0x{:02x}", command);
+                }
+            }
+        }
+    }
+
+    async fn respond_write(&self, conn: &Connection, fs: &FileSystem<'_>, command: u8, status: u8, offset: u32) {
+        let free = fs.available().await.unwrap_or(0) as u32;
+        let mut response: Vec<u8, 20> = Vec::new();
+        let _ = response.extend_from_slice(&[command, status, 0, 0]);
+        let _ = response.extend_from_slice(&offset.to_le_bytes());
+        let _ = response.extend_from_slice(&0u64.to_le_bytes());
+        let _ = response.extend_from_slice(&free.to_le_bytes());
+        self.respond(conn, &response);
+    }
+
+    fn respond_entry(
+        &self,
+        conn: &Connection,
+        status: u8,
+        index: u32,
+        total: u32,
+        entry: Option<&crate::fs::Entry<'_>>,
+    ) {
+        let (name, flags, size) = match entry {
+            Some(entry) => (entry.name, entry.is_dir as u32, entry.size as u32),
+            None => ("", 0, 0),
+        };
+        let mut response: Vec<u8, ATT_MTU> = Vec::new();
+        let _ = response.extend_from_slice(&[FS_LIST_RESPONSE, status]);
+        let _ = response.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        let _ = response.extend_from_slice(&index.to_le_bytes());
+        let _ = response.extend_from_slice(&total.to_le_bytes());
+        let _ = response.extend_from_slice(&flags.to_le_bytes());
+        let _ = response.extend_from_slice(&0u64.to_le_bytes());
+        let _ = response.extend_from_slice(&size.to_le_bytes());
+        if response.extend_from_slice(name.as_bytes()).is_err() {
+            warn!("Filename too long for listing: {}", name);
+            return;
+        }
+        self.respond(conn, &response);
+    }
+}
+
+fn status_of<T>(result: Result<T, crate::fs::Error>) -> u8 {
+    match result {
+        Ok(_) => FS_STATUS_OK,
+        Err(e) => {
+            warn!("Filesystem error: {:?}", e);
+            FS_STATUS_ERROR
+        }
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+/// Read a path whose length is stored at `len_at` and whose bytes start at `path_at`.
+fn read_path(data: &[u8], len_at: usize, path_at: usize) -> Option<&str> {
+    let len = read_u16(data, len_at)? as usize;
+    data.get(path_at..path_at + len)
+        .and_then(|p| core::str::from_utf8(p).ok())
+}
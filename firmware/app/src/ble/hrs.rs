@@ -0,0 +1,67 @@
+use defmt::{info, warn};
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use nrf_softdevice::ble::Connection;
+
+use crate::heartrate::{self, Streaming};
+
+/// Flags of a measurement: the heart rate is a `u8`, and sensor contact is supported, detected if bit 1 is set.
+const CONTACT_SUPPORTED: u8 = 0x04;
+const CONTACT_DETECTED: u8 = 0x02;
+/// Body sensor location of a watch.
+const WRIST: u8 = 2;
+
+/// Whether the phone enabled measurement notifications, passed from the GATT callback to `HeartRateService::run`.
+static NOTIFICATIONS: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Standard Heart Rate Service, so fitness apps can use the watch as a heart rate sensor. The heart rate is measured
+/// continuously while notifications are enabled.
+#[nrf_softdevice::gatt_service(uuid = "180D")]
+pub struct HeartRateService {
+    /// Flags, then the heart rate in beats per minute, 0 without sensor contact.
+    #[characteristic(uuid = "2A37", notify)]
+    measurement: [u8; 2],
+
+    #[characteristic(uuid = "2A38", read)]
+    body_sensor_location: u8,
+}
+
+impl HeartRateService {
+    pub(super) fn init(&self) {
+        if let Err(e) = self.body_sensor_location_set(&WRIST) {
+            warn!("Error setting the body sensor location: {:?}", e);
+        }
+    }
+
+    pub(super) fn handle(&self, event: HeartRateServiceEvent) {
+        match event {
+            HeartRateServiceEvent::MeasurementCccdWrite { notifications } => {
+                info!("Enable heart rate notifications: {}", notifications);
+                NOTIFICATIONS.signal(notifications);
+            }
+        }
+    }
+
+    /// Stream the heart rate to a connection while it has notifications enabled, until it is dropped.
+    pub(super) async fn run(&self, conn: &Connection) {
+        NOTIFICATIONS.reset();
+        loop {
+            while !NOTIFICATIONS.wait().await {}
+            // Dropped, and the sensor powered down, once notifications are disabled or the connection ends.
+            let _streaming = Streaming::start();
+            let notify = async {
+                loop {
+                    let value = match heartrate::next_measurement().await {
+                        Some(bpm) => [CONTACT_SUPPORTED | CONTACT_DETECTED, bpm],
+                        None => [CONTACT_SUPPORTED, 0],
+                    };
+                    if let Err(e) = self.measurement_notify(conn, &value) {
+                        warn!("Error sending the heart rate: {:?}", e);
+                    }
+                }
+            };
+            select(notify, async { while NOTIFICATIONS.wait().await {} }).await;
+        }
+    }
+}
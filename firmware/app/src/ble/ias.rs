@@ -0,0 +1,72 @@
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use nrf_softdevice::ble::{gatt_client, Connection};
+
+use crate::events::{self, SensorEvent};
+
+/// Alert levels of the service. A mild alert is treated as a high one.
+const NO_ALERT: u8 = 0;
+const HIGH_ALERT: u8 = 2;
+
+/// Whether the UI wants the phone to ring, passed from `ring_phone` to `run`.
+static RING: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Immediate Alert Service of the watch, which the phone writes to find the watch.
+#[nrf_softdevice::gatt_service(uuid = "1802")]
+pub struct ImmediateAlertService {
+    #[characteristic(uuid = "2A06", write_without_response)]
+    alert_level: u8,
+}
+
+impl ImmediateAlertService {
+    pub(super) fn handle(&self, event: ImmediateAlertServiceEvent) {
+        match event {
+            ImmediateAlertServiceEvent::AlertLevelWrite(level) => {
+                info!("Find watch: alert level {}", level);
+                events::publish(SensorEvent::FindWatch(level != NO_ALERT));
+            }
+        }
+    }
+}
+
+/// Immediate Alert Service of the phone, which the watch writes to make it ring.
+#[nrf_softdevice::gatt_client(uuid = "1802")]
+pub struct ImmediateAlertClient {
+    #[characteristic(uuid = "2A06", write_without_response)]
+    alert_level: u8,
+}
+
+/// Ring the phone, or stop it ringing.
+pub fn ring_phone(ring: bool) {
+    RING.signal(ring);
+}
+
+/// Ring the phone as the UI asks, until the connection is dropped. The service is looked up on the first request, so
+/// that it does not hold up the other services at connection time.
+pub async fn run(conn: &Connection) {
+    RING.reset();
+    let mut client = None;
+    loop {
+        let ring = RING.wait().await;
+        if client.is_none() && ring {
+            match gatt_client::discover::<ImmediateAlertClient>(conn).await {
+                Ok(found) => client = Some(found),
+                Err(e) => warn!("No immediate alert service on peer: {:?}", e),
+            }
+        }
+        let Some(client) = &client else {
+            if ring {
+                events::publish(SensorEvent::PhoneNotFound);
+            }
+            continue;
+        };
+        let level = if ring { HIGH_ALERT } else { NO_ALERT };
+        if let Err(e) = client.alert_level_write_without_response(&level).await {
+            warn!("Error writing the alert level: {:?}", e);
+            if ring {
+                events::publish(SensorEvent::PhoneNotFound);
+            }
+        }
+    }
+}
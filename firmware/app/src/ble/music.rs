@@ -0,0 +1,64 @@
+use defmt::{info, warn};
+use heapless::Vec;
+use nrf_softdevice::ble::Connection;
+
+use super::ATT_MTU;
+use crate::music::{self, Control};
+use crate::notifications::utf8_prefix;
+
+/// Music service as InfiniTime has it, which Gadgetbridge sends what the phone plays to, and takes the controls of
+/// the Music screen from. Gadgetbridge also writes the position, the length and other details of the track, which
+/// the watch does not show, and skips them as the service lacks them.
+#[nrf_softdevice::gatt_service(uuid = "00000000-78fc-48fe-8e23-433b3a1942d0")]
+pub struct MusicService {
+    /// A control sent to the phone, see `event`.
+    #[characteristic(uuid = "00000001-78fc-48fe-8e23-433b3a1942d0", notify)]
+    event: u8,
+
+    /// 1 while playing, 0 while paused.
+    #[characteristic(uuid = "00000002-78fc-48fe-8e23-433b3a1942d0", write)]
+    status: u8,
+
+    #[characteristic(uuid = "00000003-78fc-48fe-8e23-433b3a1942d0", write)]
+    artist: Vec<u8, ATT_MTU>,
+
+    #[characteristic(uuid = "00000004-78fc-48fe-8e23-433b3a1942d0", write)]
+    track: Vec<u8, ATT_MTU>,
+}
+
+impl MusicService {
+    pub(super) fn handle(&self, event: MusicServiceEvent) {
+        match event {
+            MusicServiceEvent::EventCccdWrite { notifications } => {
+                info!("Enable music controls: {}", notifications);
+            }
+            MusicServiceEvent::StatusWrite(status) => music::set_playing(status != 0),
+            MusicServiceEvent::ArtistWrite(text) => music::set_artist(utf8_prefix(&text)),
+            MusicServiceEvent::TrackWrite(text) => music::set_track(utf8_prefix(&text)),
+        }
+    }
+
+    /// Send the controls of the Music screen to the phone, until the connection is dropped.
+    pub(super) async fn run(&self, conn: &Connection) {
+        music::clear_controls();
+        loop {
+            let control = music::next_control().await;
+            if let Err(e) = self.event_notify(conn, &event(control)) {
+                warn!("Error sending music control {}: {:?}", control, e);
+            }
+        }
+    }
+}
+
+/// The event of a control, as InfiniTime numbers them.
+fn event(control: Control) -> u8 {
+    match control {
+        Control::Open => 0xe0,
+        Control::Play => 0x00,
+        Control::Pause => 0x01,
+        Control::Next => 0x03,
+        Control::Previous => 0x04,
+        Control::VolumeUp => 0x05,
+        Control::VolumeDown => 0x06,
+    }
+}
@@ -0,0 +1,63 @@
+use heapless::Vec;
+use watchful_ui::Turn;
+
+use super::ATT_MTU;
+use crate::navigation;
+use crate::notifications::utf8_prefix;
+
+/// Navigation service as InfiniTime has it, which PureMaps and Gadgetbridge send the next turn of a route to. Each
+/// characteristic is written on its own, in UTF-8 but for the progress.
+#[nrf_softdevice::gatt_service(uuid = "00010000-78fc-48fe-8e23-433b3a1942d0")]
+pub struct NavigationService {
+    /// Name of the icon of the next turn, such as `turn-left` or `roundabout`.
+    #[characteristic(uuid = "00010001-78fc-48fe-8e23-433b3a1942d0", read, write)]
+    flags: Vec<u8, ATT_MTU>,
+
+    #[characteristic(uuid = "00010002-78fc-48fe-8e23-433b3a1942d0", read, write)]
+    narrative: Vec<u8, ATT_MTU>,
+
+    /// Distance to the next turn, with its unit.
+    #[characteristic(uuid = "00010003-78fc-48fe-8e23-433b3a1942d0", read, write)]
+    man_dist: Vec<u8, ATT_MTU>,
+
+    /// Percent of the route done.
+    #[characteristic(uuid = "00010004-78fc-48fe-8e23-433b3a1942d0", read, write)]
+    progress: u8,
+}
+
+impl NavigationService {
+    pub(super) fn handle(&self, event: NavigationServiceEvent) {
+        match event {
+            NavigationServiceEvent::FlagsWrite(icon) => navigation::set_turn(turn(utf8_prefix(&icon))),
+            NavigationServiceEvent::NarrativeWrite(text) => navigation::set_instruction(utf8_prefix(&text)),
+            NavigationServiceEvent::ManDistWrite(text) => navigation::set_distance(utf8_prefix(&text)),
+            NavigationServiceEvent::ProgressWrite(progress) => navigation::set_progress(progress),
+        }
+    }
+}
+
+/// The turn drawn for an icon name. The names are those of the OSRM maneuvers, which also tell the direction, such as
+/// `fork-slight-left`. Those without a direction are drawn straight ahead.
+fn turn(icon: &str) -> Turn {
+    if icon.starts_with("arrive") || icon == "flag" {
+        Turn::Arrive
+    } else if icon.starts_with("roundabout") || icon.starts_with("rotary") {
+        Turn::Roundabout
+    } else if icon.ends_with("uturn") {
+        Turn::UTurn
+    } else if icon.ends_with("sharp-left") {
+        Turn::SharpLeft
+    } else if icon.ends_with("slight-left") {
+        Turn::SlightLeft
+    } else if icon.ends_with("left") {
+        Turn::Left
+    } else if icon.ends_with("sharp-right") {
+        Turn::SharpRight
+    } else if icon.ends_with("slight-right") {
+        Turn::SlightRight
+    } else if icon.ends_with("right") {
+        Turn::Right
+    } else {
+        Turn::Straight
+    }
+}
@@ -0,0 +1,258 @@
+use core::fmt::Write;
+
+use defmt::{info, warn};
+#[cfg(feature = "log-ram")]
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, Vec};
+use nrf_softdevice::ble::gatt_server::NotifyValueError;
+use nrf_softdevice::ble::Connection;
+use nrf_softdevice::RawError;
+
+use super::{payload_size, value, ConnectionHandle, ATT_MTU, MTU};
+use crate::clock::{parse_date, parse_time};
+use crate::crash::CrashLog;
+use crate::device::{ChargeState, SharedBattery};
+use crate::power::{Feature, PowerManager};
+use crate::wakelock::{WakeLock, WakeLockKind};
+use crate::{events, CLOCK};
+
+const HELP: &str = "time [HH:MM[:SS]], date YYYY-MM-DD, battery, stats, crash [clear], log [tail], dfu, reboot\n";
+/// Interval at which `log tail` sends what was logged since, until the next command.
+#[cfg(feature = "log-ram")]
+const TAIL_INTERVAL: Duration = Duration::from_millis(500);
+/// Time for the answer to `reboot` to go out before the reset.
+const REBOOT_DELAY: Duration = Duration::from_millis(200);
+const STATS_SIZE: usize = 640;
+
+/// Line based debug shell over the Nordic UART Service, for when no debug probe is attached.
+#[nrf_softdevice::gatt_service(uuid = "6E400001-B5A3-F393-E0A9-E50E24DCCA9E")]
+pub struct NrfUartService {
+    #[characteristic(uuid = "6E400002-B5A3-F393-E0A9-E50E24DCCA9E", write)]
+    rx: Vec<u8, ATT_MTU>,
+
+    #[characteristic(uuid = "6E400003-B5A3-F393-E0A9-E50E24DCCA9E", notify)]
+    tx: Vec<u8, ATT_MTU>,
+}
+
+/// Commands received over the UART service, handled outside of the GATT callback.
+static UART_REQUESTS: Channel<CriticalSectionRawMutex, Vec<u8, ATT_MTU>, 2> = Channel::new();
+
+impl NrfUartService {
+    pub(super) fn handle(&self, _connection: &mut ConnectionHandle, event: NrfUartServiceEvent) {
+        match event {
+            NrfUartServiceEvent::TxCccdWrite { notifications } => {
+                info!("Enable logging: {}", notifications);
+            }
+            NrfUartServiceEvent::RxWrite(data) => {
+                if UART_REQUESTS.try_send(data).is_err() {
+                    warn!("UART command queue full, dropping command");
+                }
+            }
+        }
+    }
+
+    fn send(&self, conn: &Connection, text: &str) {
+        for chunk in text.as_bytes().chunks(payload_size(conn)) {
+            if let Err(e) = value(chunk).and_then(|chunk| self.tx_notify(conn, &chunk)) {
+                warn!("Error sending UART data: {:?}", e);
+                return;
+            }
+        }
+    }
+
+    /// Send a chunk of longer output, waiting for the softdevice to free buffers as it is sent at once.
+    async fn send_waiting(&self, conn: &Connection, data: &[u8]) -> bool {
+        let Ok(data) = value(data) else {
+            return false;
+        };
+        for _ in 0..50 {
+            match self.tx_notify(conn, &data) {
+                Ok(_) => return true,
+                Err(NotifyValueError::Raw(RawError::Resources)) => Timer::after(Duration::from_millis(20)).await,
+                Err(e) => {
+                    warn!("Error sending UART data: {:?}", e);
+                    return false;
+                }
+            }
+        }
+        false
+    }
+
+    /// Run the commands of a connection until it is dropped. Each is answered with text, send `help` for the list.
+    pub(super) async fn run(
+        &self,
+        conn: &Connection,
+        crash_log: Option<&CrashLog<'_>>,
+        battery: &SharedBattery,
+        power: &PowerManager,
+    ) {
+        // Commands left over from a previous connection were meant for it.
+        while UART_REQUESTS.try_receive().is_ok() {}
+        // Taken by the `dfu` command, and held until the connection is dropped.
+        let mut update_locks = None;
+        // A command received while the log was being tailed.
+        let mut next = None;
+        loop {
+            let request = match next.take() {
+                Some(request) => request,
+                None => UART_REQUESTS.receive().await,
+            };
+            let Ok(command) = core::str::from_utf8(&request) else {
+                continue;
+            };
+            let mut args = command.split_whitespace();
+            match (args.next(), args.next(), args.next()) {
+                (Some("help"), None, None) => self.send(conn, HELP),
+                (Some("time"), None, None) => {
+                    let now = CLOCK.get();
+                    let mut line: String<32> = String::new();
+                    let _ = writeln!(
+                        line,
+                        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                        now.year(),
+                        now.month() as u8,
+                        now.day(),
+                        now.hour(),
+                        now.minute(),
+                        now.second()
+                    );
+                    self.send(conn, &line);
+                }
+                (Some("time"), Some(time), None) => match parse_time(time) {
+                    Some(time) => {
+                        CLOCK.set(CLOCK.get().replace_time(time));
+                        events::request_redraw();
+                        self.send(conn, "ok\n");
+                    }
+                    None => self.send(conn, "expected HH:MM or HH:MM:SS\n"),
+                },
+                (Some("date"), Some(date), None) => match parse_date(date) {
+                    Some(date) => {
+                        CLOCK.set(CLOCK.get().replace_date(date));
+                        events::request_redraw();
+                        self.send(conn, "ok\n");
+                    }
+                    None => self.send(conn, "expected YYYY-MM-DD\n"),
+                },
+                (Some("battery"), None, None) => {
+                    let (level, charge) = {
+                        let mut battery = battery.lock().await;
+                        (battery.measure().await, battery.charge_state())
+                    };
+                    let charge = match charge {
+                        ChargeState::Discharging => "discharging",
+                        ChargeState::Charging => "charging",
+                        ChargeState::Full => "full",
+                    };
+                    let mut line: String<32> = String::new();
+                    let _ = writeln!(line, "{}% {}", level, charge);
+                    self.send(conn, &line);
+                }
+                (Some("stats"), None, None) => self.send_stats(conn).await,
+                (Some("crash"), None, None) => match crash_log {
+                    Some(crash_log) => self.send_crashes(conn, crash_log).await,
+                    None => self.send(conn, "no crash log\n"),
+                },
+                (Some("crash"), Some("clear"), None) => match crash_log {
+                    Some(crash_log) => match crash_log.clear().await {
+                        Ok(_) => self.send(conn, "ok\n"),
+                        Err(_) => self.send(conn, "error clearing crash log\n"),
+                    },
+                    None => self.send(conn, "no crash log\n"),
+                },
+                #[cfg(feature = "log-ram")]
+                (Some("log"), None, None) => self.send_log(conn).await,
+                #[cfg(feature = "log-ram")]
+                (Some("log"), Some("tail"), None) => loop {
+                    self.send_log(conn).await;
+                    if let Either::Second(request) = select(Timer::after(TAIL_INTERVAL), UART_REQUESTS.receive()).await
+                    {
+                        next = Some(request);
+                        break;
+                    }
+                },
+                (Some("dfu"), None, None) => {
+                    // The same locks as an update takes once started, so the transfer runs at full speed from
+                    // the start. The update itself still checks the battery.
+                    if update_locks.is_some() || power.allows(Feature::FirmwareUpdate) {
+                        update_locks.get_or_insert_with(|| {
+                            info!("Preparing for a firmware update");
+                            [
+                                WakeLock::acquire(WakeLockKind::Cpu),
+                                WakeLock::acquire(WakeLockKind::BleFast),
+                            ]
+                        });
+                        self.send(conn, "ready, start the update\n");
+                    } else {
+                        self.send(conn, "battery too low for an update\n");
+                    }
+                }
+                (Some("reboot"), None, None) => {
+                    self.send(conn, "rebooting\n");
+                    Timer::after(REBOOT_DELAY).await;
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                _ => self.send(conn, "unknown command, send help for the list\n"),
+            }
+        }
+    }
+
+    async fn send_crashes(&self, conn: &Connection, crash_log: &CrashLog<'_>) {
+        let mut count = 0;
+        let result = crash_log
+            .for_each(|id, record| {
+                let mut line: String<112> = String::new();
+                let _ = core::fmt::write(
+                    &mut line,
+                    format_args!(
+                        "#{} {:?} pc=0x{:08x} lr=0x{:08x} fw={:08x} {}\n",
+                        id,
+                        record.kind,
+                        record.pc,
+                        record.lr,
+                        record.commit,
+                        record.message.as_str()
+                    ),
+                );
+                self.send(conn, &line);
+                count += 1;
+            })
+            .await;
+        match result {
+            Ok(_) if count == 0 => self.send(conn, "no crashes\n"),
+            Ok(_) => {}
+            Err(_) => self.send(conn, "error reading crash log\n"),
+        }
+    }
+
+    /// Send the build, the uptime, and the CPU and RAM use of each task.
+    async fn send_stats(&self, conn: &Connection) {
+        let mut text: String<STATS_SIZE> = String::new();
+        let build = crate::buildinfo::BUILD;
+        let _ = writeln!(text, "Build: {} {}", build.version, build.short_commit());
+        let _ = writeln!(text, "Uptime: {} s", Instant::now().as_secs());
+        crate::profile::report(&mut text);
+        crate::memory::report(&mut text);
+        for chunk in text.as_bytes().chunks(payload_size(conn)) {
+            if !self.send_waiting(conn, chunk).await {
+                return;
+            }
+        }
+    }
+
+    /// Send what is in the RAM log, emptying it.
+    #[cfg(feature = "log-ram")]
+    async fn send_log(&self, conn: &Connection) {
+        let mut buf = [0; MTU];
+        let buf = &mut buf[..payload_size(conn)];
+        loop {
+            let len = crate::ramlog::read(buf);
+            if len == 0 || !self.send_waiting(conn, &buf[..len]).await {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use defmt::{info, warn};
+use heapless::Vec;
+use watchful_ui::{Condition, DayWeather, FORECAST_DAYS};
+
+use super::ATT_MTU;
+use crate::notifications::{truncated, utf8_prefix};
+use crate::weather::{self, Current, Forecast};
+
+/// Message types, the first byte of each write.
+const CURRENT: u8 = 0;
+const FORECAST: u8 = 1;
+/// Latest version of the messages. Version 1 adds the sunrise and sunset to the current weather, which the watch does
+/// not show.
+const VERSION: u8 = 1;
+const CURRENT_SIZE: usize = 49;
+const FORECAST_HEADER_SIZE: usize = 11;
+const FORECAST_DAY_SIZE: usize = 5;
+
+/// Simple Weather Service as InfiniTime has it, which Gadgetbridge and other companion apps send the current weather
+/// and the forecast to.
+#[nrf_softdevice::gatt_service(uuid = "00050000-78fc-48fe-8e23-433b3a1942d0")]
+pub struct SimpleWeatherService {
+    /// The message type, the version, the timestamp in local time as a little-endian `i64`, then the message.
+    /// Temperatures are in hundredths of a degree Celsius, as little-endian `i16`.
+    ///
+    /// The current weather has the temperature, the minimum and the maximum, the location in 32 bytes padded with NULs
+    /// and the icon. The forecast has the number of days, then the minimum, the maximum and the icon of each.
+    #[characteristic(uuid = "00050001-78fc-48fe-8e23-433b3a1942d0", write)]
+    weather: Vec<u8, ATT_MTU>,
+}
+
+impl SimpleWeatherService {
+    pub(super) fn handle(&self, event: SimpleWeatherServiceEvent) {
+        match event {
+            SimpleWeatherServiceEvent::WeatherWrite(data) => match data.first() {
+                Some(&CURRENT) => match decode_current(&data) {
+                    Some(current) => {
+                        info!("Weather: {} degrees", current.temperature);
+                        weather::set_current(current);
+                    }
+                    None => warn!("Malformed current weather"),
+                },
+                Some(&FORECAST) => match decode_forecast(&data) {
+                    Some(forecast) => {
+                        info!("Weather: {} days of forecast", forecast.days.len());
+                        weather::set_forecast(forecast);
+                    }
+                    None => warn!("Malformed weather forecast"),
+                },
+                _ => warn!("Unknown weather message"),
+            },
+        }
+    }
+}
+
+fn decode_current(data: &[u8]) -> Option<Current> {
+    if data.len() < CURRENT_SIZE || data[1] > VERSION {
+        return None;
+    }
+    let location = utf8_prefix(&data[16..48]).trim_end_matches('\0');
+    Some(Current {
+        timestamp: timestamp(data),
+        temperature: celsius(data, 10),
+        today: DayWeather {
+            min: celsius(data, 12),
+            max: celsius(data, 14),
+            condition: condition(data[48]),
+        },
+        location: truncated(location),
+    })
+}
+
+fn decode_forecast(data: &[u8]) -> Option<Forecast> {
+    if data.len() < FORECAST_HEADER_SIZE || data[1] > VERSION {
+        return None;
+    }
+    let count = data[10] as usize;
+    let days = data.get(FORECAST_HEADER_SIZE..FORECAST_HEADER_SIZE + count * FORECAST_DAY_SIZE)?;
+    Some(Forecast {
+        timestamp: timestamp(data),
+        days: days
+            .chunks(FORECAST_DAY_SIZE)
+            .take(FORECAST_DAYS)
+            .map(|day| DayWeather {
+                min: celsius(day, 0),
+                max: celsius(day, 2),
+                condition: condition(day[4]),
+            })
+            .collect(),
+    })
+}
+
+fn timestamp(data: &[u8]) -> i64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[2..10]);
+    i64::from_le_bytes(bytes)
+}
+
+/// The temperature at `at`, rounded from hundredths to whole degrees.
+fn celsius(data: &[u8], at: usize) -> i16 {
+    let hundredths = i16::from_le_bytes([data[at], data[at + 1]]) as i32;
+    let rounding = if hundredths < 0 { -50 } else { 50 };
+    ((hundredths + rounding) / 100) as i16
+}
+
+fn condition(icon: u8) -> Condition {
+    match icon {
+        0 => Condition::Clear,
+        1 => Condition::FewClouds,
+        2 => Condition::Clouds,
+        3 => Condition::HeavyClouds,
+        4 => Condition::Rain,
+        5 => Condition::Showers,
+        6 => Condition::Thunderstorm,
+        7 => Condition::Snow,
+        8 => Condition::Mist,
+        _ => Condition::Unknown,
+    }
+}
@@ -0,0 +1,21 @@
+//! Stand-in for the BLE stack in builds without the SoftDevice, see the `no-softdevice` feature. The rest of the
+//! firmware runs as if no phone ever connected.
+use defmt::debug;
+
+use crate::events::{self, BleCommand, SensorEvent};
+use crate::profile::{profiled, Task};
+
+/// Take the commands meant for the BLE task, so that they do not pile up. There is never a connection to end or
+/// advertising to restart, and never a phone to find.
+#[embassy_executor::task]
+pub async fn ble_task() {
+    profiled(Task::Ble, async move {
+        loop {
+            match events::next_ble_command().await {
+                BleCommand::FindPhone(true) => events::publish(SensorEvent::PhoneNotFound),
+                command => debug!("No BLE, ignoring {:?}", command),
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,203 @@
+use embassy_nrf::gpio::{AnyPin, Pin};
+use embassy_nrf::peripherals::{PPI_CH0, PPI_CH1, PPI_GROUP0, SAADC, TIMER1, TWISPI0, TWISPI1, UARTE0};
+use embassy_nrf::saadc::{AnyInput, Input as _};
+
+#[cfg(all(feature = "board-pinetime", feature = "board-nrf52dk"))]
+compile_error!("Only one of the board-pinetime and board-nrf52dk features can be enabled");
+#[cfg(not(any(feature = "board-pinetime", feature = "board-nrf52dk")))]
+compile_error!("One of the board-pinetime and board-nrf52dk features must be enabled");
+
+/// Pins and peripherals of the board the firmware is built for, selected with a `board-*` feature.
+///
+/// Both boards use the nRF52832, so only the pin assignment and the parts fitted differ. The SPI bus is shared by
+/// the display and the external flash, the I2C bus by the touch controller and the heart rate sensor.
+pub struct Board {
+    pub spi: TWISPI0,
+    pub spi_pins: SpiPins,
+    pub flash_cs: AnyPin,
+    pub display: DisplayPins,
+    pub i2c: TWISPI1,
+    pub i2c_pins: I2cPins,
+    /// The touch controller, if fitted.
+    pub touch: Option<TouchPins>,
+    pub button: AnyPin,
+    /// Driven high to power the button, on boards that need it.
+    pub button_enable: Option<AnyPin>,
+    pub saadc: SAADC,
+    pub battery: BatteryPins,
+    /// The vibration motor, or an LED standing in for it. Active low.
+    pub motor: AnyPin,
+    /// A UART wired to the host, on boards that have one, for the `serial-dfu` feature.
+    #[cfg_attr(not(feature = "serial-dfu"), allow(dead_code))]
+    pub serial: Option<SerialPort>,
+}
+
+pub struct SpiPins {
+    pub sck: AnyPin,
+    pub mosi: AnyPin,
+    pub miso: AnyPin,
+}
+
+pub struct I2cPins {
+    pub sda: AnyPin,
+    pub scl: AnyPin,
+}
+
+/// Pins of the ST7789 panel and its backlight.
+pub struct DisplayPins {
+    pub backlight: AnyPin,
+    pub reset: AnyPin,
+    pub cs: AnyPin,
+    pub dc: AnyPin,
+}
+
+pub struct TouchPins {
+    pub int: AnyPin,
+    pub reset: AnyPin,
+}
+
+/// The UART and what it needs to receive into a buffer while the CPU is busy, a timer counting received bytes and
+/// PPI channels to drive it.
+pub struct SerialPort {
+    pub uarte: UARTE0,
+    pub timer: TIMER1,
+    pub ppi: (PPI_CH0, PPI_CH1),
+    pub ppi_group: PPI_GROUP0,
+    pub rx: AnyPin,
+    pub tx: AnyPin,
+}
+
+pub struct BatteryPins {
+    /// Battery voltage through a divider, sampled by the SAADC.
+    pub voltage: AnyInput,
+    /// Charge controller status, low while charging.
+    pub charging: AnyPin,
+    /// Low while a charger is connected.
+    pub power_present: AnyPin,
+}
+
+#[cfg(feature = "board-pinetime")]
+mod pins {
+    use super::*;
+
+    pub const MANUFACTURER: &str = "PINE64";
+    pub const MODEL: &str = "PineTime";
+
+    /// The button is connected to P0.13 and reads high while pressed.
+    pub const BUTTON_PIN: u8 = 13;
+    pub const BUTTON_ACTIVE_HIGH: bool = true;
+    pub const CHARGING_PIN: u8 = 12;
+    pub const POWER_PRESENT_PIN: u8 = 19;
+    pub const TOUCH_INT_PIN: Option<u8> = Some(28);
+    /// Interrupt line of the accelerometer, raised on a wrist tilt.
+    pub const ACCEL_INT_PIN: Option<u8> = Some(8);
+    pub const HAS_BATTERY: bool = true;
+    pub const HAS_HEART_RATE: bool = true;
+
+    impl Board {
+        pub fn new(p: embassy_nrf::Peripherals) -> Self {
+            Self {
+                spi: p.TWISPI0,
+                spi_pins: SpiPins {
+                    sck: p.P0_02.degrade(),
+                    mosi: p.P0_03.degrade(),
+                    miso: p.P0_04.degrade(),
+                },
+                flash_cs: p.P0_05.degrade(),
+                display: DisplayPins {
+                    backlight: p.P0_22.degrade(),
+                    reset: p.P0_26.degrade(),
+                    cs: p.P0_25.degrade(),
+                    dc: p.P0_18.degrade(),
+                },
+                i2c: p.TWISPI1,
+                i2c_pins: I2cPins {
+                    sda: p.P0_06.degrade(),
+                    scl: p.P0_07.degrade(),
+                },
+                touch: Some(TouchPins {
+                    int: p.P0_28.degrade(),
+                    reset: p.P0_10.degrade(),
+                }),
+                button: p.P0_13.degrade(),
+                button_enable: Some(p.P0_15.degrade()),
+                saadc: p.SAADC,
+                battery: BatteryPins {
+                    voltage: p.P0_31.degrade_saadc(),
+                    charging: p.P0_12.degrade(),
+                    power_present: p.P0_19.degrade(),
+                },
+                motor: p.P0_16.degrade(),
+                // No UART is brought out of the case.
+                serial: None,
+            }
+        }
+    }
+}
+
+/// The nRF52-DK (PCA10040) with an ST7789 240x240 display and a SPI NOR flash wired to it. The flash uses the same
+/// pins as on the PineTime, so the bootloader runs unchanged. There is no touch controller, heart rate sensor or
+/// battery, and LED 1 stands in for the motor.
+#[cfg(feature = "board-nrf52dk")]
+mod pins {
+    use super::*;
+
+    pub const MANUFACTURER: &str = "Nordic Semiconductor";
+    pub const MODEL: &str = "nRF52-DK";
+
+    /// Button 1 is connected to P0.13 and pulls it low while pressed.
+    pub const BUTTON_PIN: u8 = 13;
+    pub const BUTTON_ACTIVE_HIGH: bool = false;
+    pub const CHARGING_PIN: u8 = 12;
+    pub const POWER_PRESENT_PIN: u8 = 11;
+    pub const TOUCH_INT_PIN: Option<u8> = None;
+    pub const ACCEL_INT_PIN: Option<u8> = None;
+    pub const HAS_BATTERY: bool = false;
+    pub const HAS_HEART_RATE: bool = false;
+
+    impl Board {
+        pub fn new(p: embassy_nrf::Peripherals) -> Self {
+            Self {
+                spi: p.TWISPI0,
+                spi_pins: SpiPins {
+                    sck: p.P0_02.degrade(),
+                    mosi: p.P0_03.degrade(),
+                    miso: p.P0_04.degrade(),
+                },
+                flash_cs: p.P0_05.degrade(),
+                display: DisplayPins {
+                    backlight: p.P0_22.degrade(),
+                    reset: p.P0_29.degrade(),
+                    cs: p.P0_25.degrade(),
+                    dc: p.P0_28.degrade(),
+                },
+                i2c: p.TWISPI1,
+                i2c_pins: I2cPins {
+                    sda: p.P0_26.degrade(),
+                    scl: p.P0_27.degrade(),
+                },
+                touch: None,
+                button: p.P0_13.degrade(),
+                button_enable: None,
+                saadc: p.SAADC,
+                battery: BatteryPins {
+                    voltage: p.P0_31.degrade_saadc(),
+                    charging: p.P0_12.degrade(),
+                    power_present: p.P0_11.degrade(),
+                },
+                motor: p.P0_17.degrade(),
+                // The virtual COM port of the on-board J-Link.
+                serial: Some(SerialPort {
+                    uarte: p.UARTE0,
+                    timer: p.TIMER1,
+                    ppi: (p.PPI_CH0, p.PPI_CH1),
+                    ppi_group: p.PPI_GROUP0,
+                    rx: p.P0_08.degrade(),
+                    tx: p.P0_06.degrade(),
+                }),
+            }
+        }
+    }
+}
+
+pub use pins::*;
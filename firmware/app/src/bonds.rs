@@ -0,0 +1,287 @@
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_embedded_hal::flash::partition::Error as PartitionError;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::Vec;
+use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
+use nrf_softdevice::ble::{
+    gatt_server, Address, AddressType, Connection, EncryptionInfo, IdentityKey, IdentityResolutionKey, MasterId,
+};
+use nrf_softdevice::raw;
+
+use crate::events::{self, SensorEvent};
+use crate::profile::{profiled, Task};
+use crate::BondPartition;
+
+/// Number of peers remembered, the least recently bonded peer is replaced when full.
+pub const MAX_BONDS: usize = 4;
+
+/// Client characteristic configuration descriptors of the GATT server, one per characteristic that notifies: the
+/// control point, packet and status of DFU, and one for each optional service that has them.
+const CCCDS: usize = 3
+    + cfg!(feature = "nus") as usize
+    + cfg!(feature = "fs") as usize
+    + cfg!(feature = "export") as usize
+    + cfg!(feature = "bas") as usize
+    + cfg!(feature = "hrs") as usize
+    + cfg!(feature = "ans") as usize
+    + cfg!(feature = "music") as usize;
+/// The SoftDevice stores the handle, length and value of each CCCD, 2 bytes each, then a CRC of 4 bytes.
+const SYS_ATTRS_SIZE: usize = CCCDS * 6 + 4;
+// Every bond is stored in a fixed slot so that a page can be decoded without any index.
+const SLOT_SIZE: usize = 128;
+/// Offset of the system attributes in a slot, after the keys, the address and their length.
+const SYS_ATTRS_AT: usize = 52;
+const _: () = assert!(
+    SYS_ATTRS_AT + SYS_ATTRS_SIZE <= SLOT_SIZE,
+    "the system attributes of every CCCD do not fit in a bond slot"
+);
+const SLOT_MAGIC: u8 = 0xB0;
+
+#[derive(Clone)]
+pub struct Bond {
+    master_id: MasterId,
+    key: EncryptionInfo,
+    peer_id: IdentityKey,
+    sys_attrs: Vec<u8, SYS_ATTRS_SIZE>,
+}
+
+impl Bond {
+    fn encode(&self, slot: &mut [u8; SLOT_SIZE]) {
+        let addr = self.peer_id.addr;
+        slot[0] = SLOT_MAGIC;
+        slot[1..3].copy_from_slice(&self.master_id.ediv.to_le_bytes());
+        slot[3..11].copy_from_slice(&self.master_id.rand);
+        slot[11..27].copy_from_slice(&self.key.ltk);
+        slot[27] = self.key.flags;
+        slot[28..44].copy_from_slice(&self.peer_id.irk.as_raw().irk);
+        slot[44] = addr.address_type() as u8;
+        slot[45..51].copy_from_slice(&addr.bytes());
+        slot[SYS_ATTRS_AT - 1] = self.sys_attrs.len() as u8;
+        slot[SYS_ATTRS_AT..SYS_ATTRS_AT + self.sys_attrs.len()].copy_from_slice(&self.sys_attrs);
+    }
+
+    fn decode(slot: &[u8]) -> Option<Self> {
+        if slot[0] != SLOT_MAGIC {
+            return None;
+        }
+        let mut rand = [0; 8];
+        rand.copy_from_slice(&slot[3..11]);
+        let mut ltk = [0; 16];
+        ltk.copy_from_slice(&slot[11..27]);
+        let mut irk = [0; 16];
+        irk.copy_from_slice(&slot[28..44]);
+        let mut addr = [0; 6];
+        addr.copy_from_slice(&slot[45..51]);
+        let address_type = AddressType::try_from(slot[44]).ok()?;
+        let sys_attrs_len = (slot[SYS_ATTRS_AT - 1] as usize).min(SYS_ATTRS_SIZE);
+
+        Some(Self {
+            master_id: MasterId {
+                ediv: u16::from_le_bytes([slot[1], slot[2]]),
+                rand,
+            },
+            key: EncryptionInfo { ltk, flags: slot[27] },
+            peer_id: IdentityKey {
+                irk: IdentityResolutionKey::from_raw(raw::ble_gap_irk_t { irk }),
+                addr: Address::new(address_type, addr),
+            },
+            sys_attrs: Vec::from_slice(&slot[SYS_ATTRS_AT..SYS_ATTRS_AT + sys_attrs_len]).ok()?,
+        })
+    }
+}
+
+fn raw_address(addr: Address) -> raw::ble_gap_addr_t {
+    raw::ble_gap_addr_t {
+        _bitfield_1: raw::ble_gap_addr_t::new_bitfield_1(0, addr.address_type() as u8),
+        addr: addr.bytes(),
+    }
+}
+
+/// Signalled whenever the bonds in RAM differ from what is stored in flash.
+static PERSIST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Security handler that remembers bonded peers across reboots and firmware updates.
+///
+/// Pairing is authenticated with a passkey the SoftDevice picks and the watch shows, for the user to type on the
+/// phone, so that the services that require it can not be reached by a device that paired without the user's consent.
+///
+/// The softdevice calls the handler from its event loop, where flash cannot be awaited. Changes are therefore
+/// made in RAM and written to flash by `bonds_task` through the softdevice flash queue.
+pub struct Bonder {
+    bonds: RefCell<Vec<Bond, MAX_BONDS>>,
+}
+
+impl Bonder {
+    pub const fn new() -> Self {
+        Self {
+            bonds: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Load bonds stored in flash.
+    pub async fn load(&self, flash: &mut BondPartition<'_>) {
+        let mut page = [0; SLOT_SIZE * MAX_BONDS];
+        if let Err(e) = flash.read(0, &mut page).await {
+            warn!("Error reading bonds: {:?}", defmt::Debug2Format(&e));
+            return;
+        }
+        let mut bonds = self.bonds.borrow_mut();
+        for slot in page.chunks(SLOT_SIZE) {
+            if let Some(bond) = Bond::decode(slot) {
+                let _ = bonds.push(bond);
+            }
+        }
+        info!("Loaded {} bonds", bonds.len());
+    }
+
+    /// Forget all bonded peers.
+    pub fn clear(&self) {
+        self.bonds.borrow_mut().clear();
+        PERSIST.signal(());
+    }
+
+    pub fn len(&self) -> usize {
+        self.bonds.borrow().len()
+    }
+
+    /// Hand the bonded peers to the SoftDevice, for advertising that only they can connect to. Phones hide behind
+    /// resolvable private addresses, so their identity resolution keys go along with the identity addresses.
+    pub fn apply_whitelist(&self) -> Result<(), u32> {
+        let bonds = self.bonds.borrow();
+        let addrs: Vec<raw::ble_gap_addr_t, MAX_BONDS> = bonds.iter().map(|b| raw_address(b.peer_id.addr)).collect();
+        let keys: Vec<raw::ble_gap_id_key_t, MAX_BONDS> = bonds
+            .iter()
+            .zip(&addrs)
+            .map(|(b, addr)| raw::ble_gap_id_key_t {
+                id_info: *b.peer_id.irk.as_raw(),
+                id_addr_info: *addr,
+            })
+            .collect();
+        let addr_ptrs: Vec<*const raw::ble_gap_addr_t, MAX_BONDS> = addrs.iter().map(|a| a as *const _).collect();
+        let key_ptrs: Vec<*const raw::ble_gap_id_key_t, MAX_BONDS> = keys.iter().map(|k| k as *const _).collect();
+        // The SoftDevice copies both lists, which only need to live through the calls.
+        let ret = unsafe {
+            raw::sd_ble_gap_device_identities_set(key_ptrs.as_ptr(), core::ptr::null(), key_ptrs.len() as u8)
+        };
+        if ret != raw::NRF_SUCCESS {
+            return Err(ret);
+        }
+        match unsafe { raw::sd_ble_gap_whitelist_set(addr_ptrs.as_ptr(), addr_ptrs.len() as u8) } {
+            raw::NRF_SUCCESS => Ok(()),
+            ret => Err(ret),
+        }
+    }
+
+    async fn store(&self, flash: &mut BondPartition<'_>) -> Result<(), PartitionError<nrf_softdevice::FlashError>> {
+        let mut page = [0xFF; SLOT_SIZE * MAX_BONDS];
+        for (bond, slot) in self.bonds.borrow().iter().zip(page.chunks_exact_mut(SLOT_SIZE)) {
+            if let Ok(slot) = slot.try_into() {
+                bond.encode(slot);
+            }
+        }
+        flash.erase(0, BondPartition::ERASE_SIZE as u32).await?;
+        flash.write(0, &page).await
+    }
+
+    fn find(&self, conn: &Connection) -> Option<usize> {
+        let addr = conn.peer_address();
+        self.bonds.borrow().iter().position(|b| b.peer_id.is_match(addr))
+    }
+}
+
+impl SecurityHandler for Bonder {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::DisplayOnly
+    }
+
+    fn request_mitm_protection(&self, _conn: &Connection) -> bool {
+        true
+    }
+
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        true
+    }
+
+    fn display_passkey(&self, passkey: &[u8; 6]) {
+        info!("Showing the pairing passkey");
+        events::publish(SensorEvent::Passkey(*passkey));
+    }
+
+    fn on_bonded(&self, conn: &Connection, master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) {
+        info!("Bonded with {:?}", conn.peer_address());
+        let mut bonds = self.bonds.borrow_mut();
+        let addr = conn.peer_address();
+        if let Some(existing) = bonds.iter().position(|b| b.peer_id.is_match(addr)) {
+            bonds.remove(existing);
+        } else if bonds.is_full() {
+            bonds.remove(0);
+        }
+        let _ = bonds.push(Bond {
+            master_id,
+            key,
+            peer_id,
+            sys_attrs: Vec::new(),
+        });
+        PERSIST.signal(());
+        events::publish(SensorEvent::Paired);
+    }
+
+    fn get_key(&self, _conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        self.bonds
+            .borrow()
+            .iter()
+            .find(|b| b.master_id == master_id)
+            .map(|b| b.key)
+    }
+
+    fn save_sys_attrs(&self, conn: &Connection) {
+        let Some(index) = self.find(conn) else {
+            return;
+        };
+        let mut buf = [0; SYS_ATTRS_SIZE];
+        match gatt_server::get_sys_attrs(conn, &mut buf) {
+            Ok(len) => {
+                let mut bonds = self.bonds.borrow_mut();
+                let sys_attrs = &mut bonds[index].sys_attrs;
+                if sys_attrs[..] != buf[..len] {
+                    if let Ok(new) = Vec::from_slice(&buf[..len]) {
+                        *sys_attrs = new;
+                        PERSIST.signal(());
+                    }
+                }
+            }
+            Err(e) => warn!("Error reading system attributes: {:?}", e),
+        }
+    }
+
+    fn load_sys_attrs(&self, conn: &Connection) {
+        let bonds = self.bonds.borrow();
+        let attrs = self
+            .find(conn)
+            .map(|i| &bonds[i].sys_attrs)
+            .filter(|a| !a.is_empty())
+            .map(|a| &a[..]);
+        if let Err(e) = gatt_server::set_sys_attrs(conn, attrs) {
+            warn!("Error restoring system attributes: {:?}", e);
+        }
+    }
+}
+
+/// Writes bond changes to flash.
+#[embassy_executor::task]
+pub async fn bonds_task(bonder: &'static Bonder, mut flash: BondPartition<'static>) {
+    profiled(Task::Bonds, async move {
+        loop {
+            PERSIST.wait().await;
+            match bonder.store(&mut flash).await {
+                Ok(_) => info!("Stored {} bonds", bonder.len()),
+                Err(e) => warn!("Error storing bonds: {:?}", defmt::Debug2Format(&e)),
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,77 @@
+/// What was built, from the environment `build.rs` sets up, so logs, crash reports and BLE clients can tell the exact
+/// commit a watch runs.
+#[derive(defmt::Format)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    /// Full hash of the commit built.
+    pub commit: &'static str,
+    /// Whether the tree had uncommitted changes, in which case the commit does not tell the whole story.
+    pub dirty: bool,
+    pub timestamp: &'static str,
+    /// Cargo profile, `debug` or `release`.
+    pub profile: &'static str,
+}
+
+pub const BUILD: BuildInfo = BuildInfo {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+    commit: env!("VERGEN_GIT_SHA"),
+    dirty: matches!(env!("VERGEN_GIT_DIRTY").as_bytes(), b"true"),
+    timestamp: env!("VERGEN_BUILD_TIMESTAMP"),
+    profile: env!("WATCHFUL_PROFILE"),
+};
+
+/// Hex digits of the commit hash shown to people, enough to tell commits apart.
+const SHORT_COMMIT: usize = 8;
+
+impl BuildInfo {
+    /// The commit hash cut to its first 8 hex digits.
+    pub const fn short_commit(&self) -> &'static str {
+        if self.commit.len() < SHORT_COMMIT {
+            return self.commit;
+        }
+        match core::str::from_utf8(self.commit.as_bytes().split_at(SHORT_COMMIT).0) {
+            Ok(short) => short,
+            Err(_) => self.commit,
+        }
+    }
+
+    /// The short commit hash as a number, stored with crash records. 0 if the hash is not known.
+    pub const fn commit_id(&self) -> u32 {
+        let digits = self.short_commit().as_bytes();
+        let mut id = 0;
+        let mut i = 0;
+        while i < digits.len() {
+            let digit = match digits[i] {
+                b @ b'0'..=b'9' => b - b'0',
+                b @ b'a'..=b'f' => b - b'a' + 10,
+                _ => return 0,
+            };
+            id = id << 4 | digit as u32;
+            i += 1;
+        }
+        id
+    }
+
+    /// Firmware version reported to DFU clients and compared with updates, `major * 10000 + minor * 100 + patch` as
+    /// `nrfutil pkg generate --application-version-string` encodes it. A pre-release suffix is left out.
+    pub const fn version_number(&self) -> u32 {
+        let digits = self.version.as_bytes();
+        let mut version = 0;
+        let mut part = 0;
+        let mut i = 0;
+        while i < digits.len() {
+            match digits[i] {
+                b'.' => {
+                    version = version * 100 + part;
+                    part = 0;
+                }
+                b @ b'0'..=b'9' => part = part * 10 + (b - b'0') as u32,
+                _ => break,
+            }
+            i += 1;
+        }
+        version * 100 + part
+    }
+}
@@ -0,0 +1,79 @@
+use core::mem::ManuallyDrop;
+
+use defmt::info;
+use embassy_futures::select::select;
+use embassy_nrf::gpio::{AnyPin, Input, Pull};
+use embassy_time::{Duration, Instant};
+
+use crate::board;
+use crate::device::{ChargeState, SharedBattery, SharedMotor};
+use crate::events::{self, SensorEvent};
+use crate::profile::{profiled, Task};
+use crate::settings::SettingsCache;
+
+const FULL_VIBRATION: Duration = Duration::from_millis(300);
+
+/// Wait for an edge on the charge status or power present pin.
+///
+/// The battery owns both pins behind a mutex, so second handles are used to wait for the edges. The handles are
+/// never dropped, as dropping them would disconnect the pins from the battery.
+async fn wait_pins() {
+    let mut charging = ManuallyDrop::new(Input::new(unsafe { AnyPin::steal(board::CHARGING_PIN) }, Pull::Up));
+    let mut power_present = ManuallyDrop::new(Input::new(unsafe { AnyPin::steal(board::POWER_PRESENT_PIN) }, Pull::Up));
+    select(charging.wait_for_any_edge(), power_present.wait_for_any_edge()).await;
+}
+
+/// Follow the charge state, logging each charge session and vibrating once charging completes if enabled in the
+/// settings, so the watch is unplugged promptly.
+#[embassy_executor::task]
+pub async fn charger_task(
+    battery: &'static SharedBattery,
+    motor: &'static SharedMotor,
+    settings: &'static SettingsCache,
+) {
+    profiled(Task::Charger, async move {
+        let mut state = battery.lock().await.charge_state();
+        let mut session: Option<(Instant, u32)> = None;
+        loop {
+            wait_pins().await;
+            let (next, level) = {
+                let mut battery = battery.lock().await;
+                (battery.charge_state(), battery.measure().await)
+            };
+            if next == state {
+                continue;
+            }
+            info!("Charge state {:?} -> {:?} at {}%", state, next, level);
+            match (state, next) {
+                (ChargeState::Discharging, _) => session = Some((Instant::now(), level)),
+                (ChargeState::Charging, ChargeState::Full) => {
+                    if let Some((start, start_level)) = session {
+                        info!(
+                            "Charge complete: {}% to {}% in {} minutes",
+                            start_level,
+                            level,
+                            start.elapsed().as_secs() / 60
+                        );
+                    }
+                    if settings.get().charge_alert {
+                        motor.lock().await.vibrate(FULL_VIBRATION).await;
+                    }
+                }
+                (_, ChargeState::Discharging) => {
+                    if let Some((start, start_level)) = session.take() {
+                        info!(
+                            "Charger removed: {}% to {}% in {} minutes",
+                            start_level,
+                            level,
+                            start.elapsed().as_secs() / 60
+                        );
+                    }
+                }
+                _ => {}
+            }
+            state = next;
+            events::publish(SensorEvent::ChargeState(state));
+        }
+    })
+    .await
+}
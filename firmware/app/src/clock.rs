@@ -1,12 +1,15 @@
 use core::cell::RefCell;
 use core::ops::Add;
 
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::blocking_mutex::Mutex;
 use embassy_time::{Duration, Ticker};
 
+use crate::health;
+use crate::profile::{profiled, Task};
+
 pub struct Clock {
-    time: Mutex<ThreadModeRawMutex, RefCell<time::PrimitiveDateTime>>,
+    time: Mutex<CriticalSectionRawMutex, RefCell<time::PrimitiveDateTime>>,
 }
 
 impl Clock {
@@ -32,12 +35,42 @@ impl Clock {
     }
 }
 
+/// A time of day written `HH:MM` or `HH:MM:SS`.
+#[cfg(any(not(feature = "no-softdevice"), feature = "shell"))]
+pub fn parse_time(text: &str) -> Option<time::Time> {
+    let mut parts = text.split(':');
+    let hour = parts.next()?.parse().ok()?;
+    let minute = parts.next()?.parse().ok()?;
+    let second = parts.next().map_or(Some(0), |s| s.parse().ok())?;
+    if parts.next().is_some() {
+        return None;
+    }
+    time::Time::from_hms(hour, minute, second).ok()
+}
+
+/// A date written `YYYY-MM-DD`.
+#[cfg(any(not(feature = "no-softdevice"), feature = "shell"))]
+pub fn parse_date(text: &str) -> Option<time::Date> {
+    let mut parts = text.split('-');
+    let year = parts.next()?.parse().ok()?;
+    let month: u8 = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    time::Date::from_calendar_date(year, time::Month::try_from(month).ok()?, day).ok()
+}
+
 #[embassy_executor::task]
 pub async fn clock(clock: &'static Clock) {
-    const TICK: Duration = Duration::from_secs(1);
-    let mut ticker = Ticker::every(TICK);
-    loop {
-        ticker.next().await;
-        clock.add(time::Duration::seconds(1));
-    }
+    profiled(Task::Clock, async move {
+        const TICK: Duration = Duration::from_secs(1);
+        let mut ticker = Ticker::every(TICK);
+        loop {
+            ticker.next().await;
+            clock.add(time::Duration::seconds(1));
+            health::heartbeat(Task::Clock, TICK * 5);
+        }
+    })
+    .await
 }
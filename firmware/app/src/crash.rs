@@ -0,0 +1,316 @@
+use core::fmt::Write as _;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+
+use cortex_m_rt::ExceptionFrame;
+use defmt::warn;
+use embassy_nrf::pac;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use heapless::String;
+
+use crate::buildinfo::BUILD;
+use crate::profile::{profiled, Task};
+use crate::ringlog::{self, RingLog};
+use crate::{layout, LogPartition};
+
+/// Start of the crash log region on the external flash.
+pub const LOG_OFFSET: u32 = layout::CRASH_LOG.start;
+/// Size of the crash log region.
+pub const LOG_SIZE: u32 = layout::CRASH_LOG.size;
+
+const MAGIC: u32 = 0xDEAD_C0DF;
+const MESSAGE_SIZE: usize = 48;
+/// Set in the kind byte of records followed by the commit they were recorded by. Older records have no commit.
+const HAS_COMMIT: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum CrashKind {
+    Panic = 1,
+    HardFault = 2,
+    Watchdog = 3,
+    /// An error the firmware recovered from, see `error::Error`.
+    Error = 4,
+}
+
+impl CrashKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Panic),
+            2 => Some(Self::HardFault),
+            3 => Some(Self::Watchdog),
+            4 => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// A crash as stored in the crash log.
+#[derive(Clone, PartialEq, defmt::Format)]
+pub struct CrashRecord {
+    pub kind: CrashKind,
+    pub pc: u32,
+    pub lr: u32,
+    /// The firmware that crashed, see `BuildInfo::commit_id`. 0 for records of firmware that did not store it.
+    pub commit: u32,
+    pub message: String<MESSAGE_SIZE>,
+}
+
+impl CrashRecord {
+    pub const MAX_SIZE: usize = 13 + MESSAGE_SIZE;
+
+    pub fn encode(&self, buf: &mut [u8; Self::MAX_SIZE]) -> usize {
+        buf[0] = self.kind as u8 | HAS_COMMIT;
+        buf[1..5].copy_from_slice(&self.pc.to_le_bytes());
+        buf[5..9].copy_from_slice(&self.lr.to_le_bytes());
+        buf[9..13].copy_from_slice(&self.commit.to_le_bytes());
+        buf[13..13 + self.message.len()].copy_from_slice(self.message.as_bytes());
+        13 + self.message.len()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 9 {
+            return None;
+        }
+        let (commit, message) = if data[0] & HAS_COMMIT != 0 {
+            (read_u32(data, 9)?, data.get(13..)?)
+        } else {
+            (0, &data[9..])
+        };
+        Some(Self {
+            kind: CrashKind::from_u8(data[0] & !HAS_COMMIT)?,
+            pc: read_u32(data, 1)?,
+            lr: read_u32(data, 5)?,
+            commit,
+            message: truncated(core::str::from_utf8(message).ok()?),
+        })
+    }
+}
+
+const _: () = assert!(CrashRecord::MAX_SIZE <= ringlog::MAX_RECORD_SIZE);
+
+/// The message cut on a character boundary to fit, as records written before the commit was stored held longer
+/// messages.
+fn truncated(message: &str) -> String<MESSAGE_SIZE> {
+    let mut truncated = String::new();
+    for c in message.chars() {
+        if truncated.push(c).is_err() {
+            break;
+        }
+    }
+    truncated
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Crash information kept in RAM that is not initialized at boot, so it survives the reset after a fault.
+#[repr(C)]
+struct Retained {
+    magic: u32,
+    kind: u8,
+    len: u8,
+    pc: u32,
+    lr: u32,
+    commit: u32,
+    message: [u8; MESSAGE_SIZE],
+}
+
+#[link_section = ".uninit.CRASH"]
+static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+
+struct MessageWriter<'a> {
+    buf: &'a mut [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl<'a> core::fmt::Write for MessageWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // Truncate on a character boundary so the message stays valid UTF-8.
+        for c in s.chars() {
+            let mut encoded = [0; 4];
+            let encoded = c.encode_utf8(&mut encoded).as_bytes();
+            if self.len + encoded.len() > self.buf.len() {
+                return Ok(());
+            }
+            self.buf[self.len..self.len + encoded.len()].copy_from_slice(encoded);
+            self.len += encoded.len();
+        }
+        Ok(())
+    }
+}
+
+fn retain(kind: CrashKind, pc: u32, lr: u32, message: core::fmt::Arguments<'_>) {
+    // Safety: only called from the panic and fault handlers, which never return, and by the supervisor right before
+    // the watchdog resets.
+    let retained = unsafe { &mut *core::ptr::addr_of_mut!(RETAINED) };
+    let retained = unsafe { &mut *retained.as_mut_ptr() };
+    let mut writer = MessageWriter {
+        buf: &mut retained.message,
+        len: 0,
+    };
+    let _ = writer.write_fmt(message);
+    retained.len = writer.len as u8;
+    retained.kind = kind as u8;
+    retained.pc = pc;
+    retained.lr = lr;
+    retained.commit = BUILD.commit_id();
+    retained.magic = MAGIC;
+}
+
+/// Record a panic, to be persisted to flash at next boot.
+pub fn record_panic(info: &PanicInfo) {
+    retain(CrashKind::Panic, 0, 0, format_args!("{}", info));
+}
+
+/// Record a hard fault, to be persisted to flash at next boot.
+pub fn record_hard_fault(ef: &ExceptionFrame) {
+    retain(
+        CrashKind::HardFault,
+        ef.pc(),
+        ef.lr(),
+        format_args!("xpsr=0x{:08x} r0=0x{:08x}", ef.xpsr(), ef.r0()),
+    );
+}
+
+/// Record a task that stopped sending heartbeats, to be persisted to flash after the watchdog resets.
+pub fn record_stall(task: Task) {
+    retain(
+        CrashKind::Watchdog,
+        0,
+        0,
+        format_args!("task {} stopped responding", task.name()),
+    );
+}
+
+/// Errors recovered from, waiting to be appended to the crash log. `error_task` is the only consumer.
+static ERRORS: Channel<CriticalSectionRawMutex, CrashRecord, 4> = Channel::new();
+
+/// Record an error the firmware recovered from, to be appended to the crash log.
+pub fn record_error(message: core::fmt::Arguments<'_>) {
+    let mut buf = [0; MESSAGE_SIZE];
+    let mut writer = MessageWriter { buf: &mut buf, len: 0 };
+    let _ = writer.write_fmt(message);
+    let len = writer.len;
+    let record = CrashRecord {
+        kind: CrashKind::Error,
+        pc: 0,
+        lr: 0,
+        commit: BUILD.commit_id(),
+        message: core::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|message| String::try_from(message).ok())
+            .unwrap_or_default(),
+    };
+    if ERRORS.try_send(record).is_err() {
+        warn!("Error queue full, not logging error");
+    }
+}
+
+/// Append the errors recovered from to the crash log.
+#[embassy_executor::task]
+pub async fn error_task(log: &'static CrashLog<'static>) {
+    profiled(Task::Errors, async move {
+        loop {
+            let record = ERRORS.receive().await;
+            if let Err(e) = log.append(&record).await {
+                warn!("Error logging error: {:?}", e);
+            }
+        }
+    })
+    .await
+}
+
+/// Take the crash recorded before the last reset, if any.
+fn take() -> Option<CrashRecord> {
+    let retained = unsafe { &mut *core::ptr::addr_of_mut!(RETAINED) };
+    let retained = unsafe { &mut *retained.as_mut_ptr() };
+    if retained.magic != MAGIC {
+        return None;
+    }
+    retained.magic = 0;
+    let len = (retained.len as usize).min(MESSAGE_SIZE);
+    let message = core::str::from_utf8(&retained.message[..len]).unwrap_or("");
+    Some(CrashRecord {
+        kind: CrashKind::from_u8(retained.kind)?,
+        pc: retained.pc,
+        lr: retained.lr,
+        commit: retained.commit,
+        message: String::try_from(message).unwrap_or_default(),
+    })
+}
+
+/// Check if the last reset was caused by the watchdog, clearing the reset reason.
+fn take_watchdog_reset() -> bool {
+    let power = unsafe { &*pac::POWER::ptr() };
+    let reason = power.resetreas.read();
+    let watchdog = reason.dog().is_detected();
+    power.resetreas.write(|w| unsafe { w.bits(reason.bits()) });
+    watchdog
+}
+
+/// Crashes persisted in flash.
+pub struct CrashLog<'a> {
+    log: Mutex<CriticalSectionRawMutex, RingLog<LogPartition<'a>>>,
+}
+
+impl<'a> CrashLog<'a> {
+    pub fn new(log: RingLog<LogPartition<'a>>) -> Self {
+        Self { log: Mutex::new(log) }
+    }
+
+    /// Move the crash recorded before the last reset into flash.
+    pub async fn persist(&self) {
+        // The reset reason is cleared even when the crash was recorded, so that it is not reported again.
+        let watchdog = take_watchdog_reset();
+        let record = take().or_else(|| {
+            watchdog.then(|| CrashRecord {
+                kind: CrashKind::Watchdog,
+                pc: 0,
+                lr: 0,
+                commit: BUILD.commit_id(),
+                message: String::new(),
+            })
+        });
+        if let Some(record) = record {
+            warn!("Recovered from crash: {:?}", record);
+            if let Err(e) = self.append(&record).await {
+                warn!("Error logging crash: {:?}", e);
+            }
+        }
+    }
+
+    pub async fn append(&self, record: &CrashRecord) -> Result<u32, ringlog::Error> {
+        let mut buf = [0; CrashRecord::MAX_SIZE];
+        let len = record.encode(&mut buf);
+        self.log.lock().await.append(&buf[..len])
+    }
+
+    /// Visit all crashes from oldest to newest together with their record id.
+    pub async fn for_each<F: FnMut(u32, CrashRecord)>(&self, mut f: F) -> Result<(), ringlog::Error> {
+        self.log.lock().await.for_each(|id, data| {
+            if let Some(record) = CrashRecord::decode(data) {
+                f(id, record);
+            }
+        })
+    }
+
+    /// Bytes used by the log and its capacity.
+    pub async fn usage(&self) -> Result<(u32, u32), ringlog::Error> {
+        self.log.lock().await.usage()
+    }
+
+    pub async fn erase_ahead(&self) -> Result<bool, ringlog::Error> {
+        self.log.lock().await.erase_ahead()
+    }
+
+    #[cfg(not(feature = "no-softdevice"))]
+    pub async fn clear(&self) -> Result<(), ringlog::Error> {
+        defmt::info!("Clearing crash log");
+        self.log.lock().await.clear()
+    }
+}
@@ -1,74 +1,81 @@
 use embassy_boot_nrf::FirmwareState;
-use display_interface_spi::SPIInterface;
 use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
-use embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice;
-use embassy_futures::select::{select, Either};
 use embassy_nrf::gpio::{AnyPin, Input, Output};
-use embassy_nrf::peripherals::{P0_10, P0_18, P0_25, P0_26, P0_28, TWISPI0, TWISPI1};
-use embassy_nrf::spim::Spim;
-use embassy_nrf::{saadc, twim};
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_nrf::peripherals::TWISPI1;
+use embassy_nrf::{pac, saadc, twim};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
 use embassy_time::{Duration, Timer};
-use mipidsi::models::ST7789;
 
+use crate::batterystats::BatteryStats;
 use crate::clock::Clock;
+use crate::display::Screen;
+use crate::factory::FactoryReset;
+use crate::fs::FileSystem;
+use crate::input::{Button, Touchpad};
+use crate::kv::SharedKv;
+use crate::power::{Gate, Gated, PowerManager};
+use crate::settings::SettingsCache;
+use crate::{board, Logs};
 
-pub type Touchpad<'a> =
-    cst816s::CST816S<I2cDevice<'a, NoopRawMutex, twim::Twim<'a, TWISPI1>>, Input<'a, P0_28>, Output<'a, P0_10>>;
-pub type Hrs<'a> = hrs3300::Hrs3300<I2cDevice<'a, NoopRawMutex, twim::Twim<'a, TWISPI1>>>;
-pub type Display<'a> = mipidsi::Display<
-    SPIInterface<SpiDevice<'a, NoopRawMutex, Spim<'a, TWISPI0>, Output<'a, P0_25>>, Output<'a, P0_18>>,
-    ST7789,
-    Output<'a, P0_26>,
->;
+pub type Hrs<'a> = hrs3300::Hrs3300<I2cDevice<'a, CriticalSectionRawMutex, Gated<twim::Twim<'a, TWISPI1>>>>;
 
 pub struct Device<'a> {
     pub clock: &'a Clock,
     pub screen: Screen<'static>,
     pub button: Button,
-    pub battery: Battery<'static>,
+    pub battery: &'static SharedBattery,
+    pub battery_stats: &'static BatteryStats,
+    pub motor: &'static SharedMotor,
     pub firmware: FirmwareState<'a, crate::StatePartition<'static>>,
-    pub touchpad: Touchpad<'static>,
-    pub hrs: Hrs<'static>,
+    /// None on boards without a touch controller.
+    pub touchpad: Option<Touchpad<'static>>,
+    pub fs: &'static FileSystem<'static>,
+    pub kv: Option<&'static SharedKv<'static>>,
+    pub settings: &'static SettingsCache,
+    pub power: &'static PowerManager,
+    pub logs: Logs,
+    pub factory_reset: FactoryReset,
 }
 
 impl<'a> Device<'a> {}
 
-pub struct Button {
-    pin: Input<'static, AnyPin>,
-}
+pub type SharedBattery = Mutex<CriticalSectionRawMutex, Battery<'static>>;
 
-impl Button {
-    pub fn new(pin: Input<'static, AnyPin>) -> Self {
-        Self { pin }
-    }
-    pub async fn wait(&mut self) {
-        self.pin.wait_for_any_edge().await;
-        if self.pin.is_high() {
-            match select(Timer::after(Duration::from_secs(8)), self.pin.wait_for_falling_edge()).await {
-                Either::First(_) => {
-                    if self.pin.is_high() {
-                        cortex_m::peripheral::SCB::sys_reset();
-                    }
-                }
-                Either::Second(_) => {}
-            }
-        }
-    }
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum ChargeState {
+    /// No charger connected.
+    Discharging,
+    Charging,
+    /// Charger connected, charging complete.
+    Full,
 }
 
 pub struct Battery<'a> {
     charging: Input<'a, AnyPin>,
+    power_present: Input<'a, AnyPin>,
     adc: saadc::Saadc<'a, 1>,
 }
 
 impl<'a> Battery<'a> {
-    pub fn new(adc: saadc::Saadc<'a, 1>, charging: Input<'a, AnyPin>) -> Self {
-        Self { adc, charging }
+    pub fn new(adc: saadc::Saadc<'a, 1>, charging: Input<'a, AnyPin>, power_present: Input<'a, AnyPin>) -> Self {
+        saadc::Saadc::<1>::set_enabled(false);
+        Self {
+            adc,
+            charging,
+            power_present,
+        }
     }
+    /// Battery level in percent. Boards without a battery, like the dev kit, always report a full one, so the power
+    /// budget never applies to them.
     pub async fn measure(&mut self) -> u32 {
+        if !board::HAS_BATTERY {
+            return 100;
+        }
         let mut buf = [0i16; 1];
+        saadc::Saadc::<1>::set_enabled(true);
         self.adc.sample(&mut buf).await;
+        saadc::Saadc::<1>::set_enabled(false);
         let voltage = buf[0] as u32 * (8 * 600) / 1024;
         //let voltage = buf[0] as u32 * 2000 / 1241;
         approximate_charge(voltage)
@@ -77,29 +84,61 @@ impl<'a> Battery<'a> {
     pub fn is_charging(&mut self) -> bool {
         self.charging.is_low()
     }
+
+    /// Whether a charger is connected, whether or not the battery is still charging.
+    pub fn is_powered(&mut self) -> bool {
+        self.power_present.is_low()
+    }
+
+    /// The charge controller releases its status pin once the battery is full, while the charger stays connected.
+    pub fn charge_state(&mut self) -> ChargeState {
+        if !self.is_powered() {
+            ChargeState::Discharging
+        } else if self.is_charging() {
+            ChargeState::Charging
+        } else {
+            ChargeState::Full
+        }
+    }
 }
 
-pub struct Screen<'a> {
-    display: Display<'a>,
-    backlight: Output<'a, AnyPin>,
+/// Pause between the pulses of a vibration pattern.
+const PULSE_GAP: Duration = Duration::from_millis(200);
+
+/// The vibration motor, driven through a transistor on P0.16.
+pub struct Motor {
+    pin: Output<'static, AnyPin>,
 }
 
-impl<'a> Screen<'a> {
-    pub fn new(display: Display<'a>, backlight: Output<'a, AnyPin>) -> Self {
-        Self { display, backlight }
+impl Motor {
+    pub fn new(pin: Output<'static, AnyPin>) -> Self {
+        Self { pin }
     }
 
-    pub fn display(&mut self) -> &mut Display<'a> {
-        &mut self.display
+    pub async fn vibrate(&mut self, duration: Duration) {
+        self.pin.set_low();
+        Timer::after(duration).await;
+        self.pin.set_high();
     }
 
-    pub fn on(&mut self) {
-        self.backlight.set_low();
+    /// Vibrate `count` times for `duration` each, so that alerts can be told apart without looking.
+    pub async fn pulses(&mut self, count: u8, duration: Duration) {
+        for i in 0..count {
+            if i > 0 {
+                Timer::after(PULSE_GAP).await;
+            }
+            self.vibrate(duration).await;
+        }
     }
+}
 
-    pub fn off(&mut self) {
-        self.backlight.set_high();
-    }
+/// The motor, shared by the tasks that alert the wearer.
+pub type SharedMotor = Mutex<CriticalSectionRawMutex, Motor>;
+
+/// Make connecting the charger wake the watch from System OFF. The power present pin goes low with a charger.
+pub fn enable_charger_wakeup() {
+    let p0 = unsafe { &*pac::P0::ptr() };
+    p0.pin_cnf[board::POWER_PRESENT_PIN as usize].modify(|_, w| w.sense().low());
 }
 
 fn approximate_charge(voltage_millis: u32) -> u32 {
@@ -0,0 +1,189 @@
+//! Nordic serial DFU over the UART, for updating a board on the bench without the SoftDevice, with the `serial-dfu`
+//! feature. See `nrf_dfu::slip` for the framing.
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_nrf::buffered_uarte::{self, BufferedUarte};
+use embassy_nrf::peripherals::UARTE0;
+use embassy_nrf::{bind_interrupts, uarte};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+use embedded_storage::nor_flash::ReadNorFlash;
+use heapless::Vec;
+use nrf_dfu::slip::{slip_encode, SlipDecoder};
+use nrf_dfu::{DfuEvent, DfuRequest, DfuSession, DfuStatus, DfuTransport};
+
+use crate::board::SerialPort;
+use crate::events::{self, SensorEvent, UpdateProgress};
+use crate::factory::FactoryReset;
+use crate::firmware::UpdateOwner;
+use crate::fs::FileSystem;
+use crate::profile::{profiled, Task};
+use crate::{firmware, DfuConfig};
+
+bind_interrupts!(struct Irqs {
+    UARTE0_UART0 => buffered_uarte::InterruptHandler<UARTE0>;
+});
+
+/// Largest request, the opcode and data of a Write.
+const MAX_REQUEST: usize = 128;
+/// MTU answered to MtuGet. nrfutil sends `(mtu - 1) / 2 - 1` bytes of data per Write, so that a request fits in
+/// `MAX_REQUEST` even if every byte of it is escaped on the wire.
+const MTU: u16 = (MAX_REQUEST as u16 + 1) * 2;
+/// Largest response, every byte escaped and the END byte.
+const MAX_FRAME: usize = 2 * 32 + 1;
+/// Time without a byte from the host after which an update it started is aborted, as the host is taken to be gone.
+/// nrfutil sends the image without pausing, erasing an object takes well under a second.
+const HOST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps the session's response to a request, to be sent once the request is handled.
+#[derive(Default)]
+struct SerialTransport {
+    response: RefCell<Option<Vec<u8, MAX_FRAME>>>,
+}
+
+impl DfuTransport for SerialTransport {
+    type Error = ();
+
+    fn notify(&self, data: &[u8]) -> Result<(), ()> {
+        *self.response.borrow_mut() = Some(slip_encode(data).ok_or(())?);
+        Ok(())
+    }
+
+    fn mtu(&self) -> u16 {
+        MTU
+    }
+}
+
+/// Serve DFU requests from the host on `port`.
+#[embassy_executor::task]
+pub async fn serial_dfu_task(port: SerialPort, config: DfuConfig<'static>, fs: &'static FileSystem<'static>) {
+    profiled(Task::Dfu, async move {
+        let mut uart_config = uarte::Config::default();
+        uart_config.baudrate = uarte::Baudrate::BAUD115200;
+        let (mut rx_buffer, mut tx_buffer) = ([0; 256], [0; MAX_FRAME]);
+        let mut uart = BufferedUarte::new(
+            port.uarte,
+            port.timer,
+            port.ppi.0,
+            port.ppi.1,
+            port.ppi_group,
+            Irqs,
+            port.rx,
+            port.tx,
+            uart_config,
+            &mut rx_buffer,
+            &mut tx_buffer,
+        );
+
+        let mut partition = config.dfu();
+        let mut staging = config.staging();
+        let mut assets = config.assets();
+        let mut target = firmware::dfu_target(partition.capacity() as u32);
+        // The serial port takes a cable to the watch, which is as trusted as an encrypted link.
+        target.set_allow_factory_reset(true);
+        let mut session = DfuSession::default();
+        let transport = SerialTransport::default();
+        // There is no CCCD to write, responses are always sent.
+        session.handle(
+            &mut target,
+            &mut partition,
+            &transport,
+            DfuEvent::ControlNotifications(true),
+        );
+        let mut decoder = SlipDecoder::<MAX_REQUEST>::default();
+        let mut shown = None;
+        info!("Serial DFU ready");
+
+        loop {
+            let mut buf = [0; 64];
+            let n = match select(uart.read(&mut buf), Timer::after(HOST_TIMEOUT)).await {
+                Either::First(Ok(n)) => n,
+                Either::First(Err(e)) => {
+                    warn!("Error reading the UART: {:?}", e);
+                    continue;
+                }
+                // The host may be unplugged mid-update, or noise taken for a Create, which would otherwise keep the
+                // partitions from BLE until the watch resets.
+                Either::Second(_) => {
+                    if firmware::owns_update(UpdateOwner::Serial) {
+                        warn!("Serial DFU host gone, aborting the update");
+                        if target.staged() {
+                            target.process(DfuRequest::Abort, &mut staging);
+                        } else if target.assets() {
+                            target.process(DfuRequest::Abort, &mut assets);
+                        } else {
+                            target.process(DfuRequest::Abort, &mut partition);
+                        }
+                        firmware::release_update(UpdateOwner::Serial);
+                        decoder = SlipDecoder::default();
+                        if shown.take().is_some() {
+                            events::publish(SensorEvent::FirmwareUpdateStopped);
+                        }
+                    }
+                    continue;
+                }
+            };
+            for &byte in &buf[..n] {
+                let Some(request) = decoder.push(byte) else {
+                    continue;
+                };
+                let event = DfuEvent::ControlWrite(request);
+                // An update under way over BLE owns the partitions until it is done, aborted or lost.
+                let status = if !firmware::claim_update(UpdateOwner::Serial, &event) {
+                    warn!("Firmware update refused, another one is under way");
+                    session.refuse(&transport, event);
+                    None
+                } else if target.staged() {
+                    session.handle(&mut target, &mut staging, &transport, event)
+                } else if target.assets() {
+                    session.handle(&mut target, &mut assets, &transport, event)
+                } else {
+                    session.handle(&mut target, &mut partition, &transport, event)
+                };
+                let response = transport.response.borrow_mut().take();
+                if let Some(response) = response {
+                    if let Err(e) = uart.write_all(&response).await {
+                        warn!("Error writing the UART: {:?}", e);
+                    }
+                }
+
+                // Shown on screen as for an update over BLE.
+                let progress = match (status, shown, target.transferred()) {
+                    (Some(status), Some(UpdateProgress { total, .. }), _) if status.is_done() => {
+                        Some(UpdateProgress { received: total, total })
+                    }
+                    (_, _, Some((received, total))) => Some(UpdateProgress { received, total }),
+                    _ => None,
+                };
+                if let Some(progress) = progress.filter(|progress| Some(*progress) != shown) {
+                    shown = Some(progress);
+                    events::publish(SensorEvent::FirmwareUpdate(progress));
+                }
+                if status.is_some_and(|status| status.is_done() || status == DfuStatus::Aborted) {
+                    firmware::release_update(UpdateOwner::Serial);
+                }
+                match status {
+                    Some(DfuStatus::DoneReset) => {
+                        let _ = uart.flush().await;
+                        firmware::finish_update(&config).await;
+                    }
+                    Some(DfuStatus::DoneStaged { softdevice, bootloader }) => {
+                        let _ = uart.flush().await;
+                        firmware::install_staged(&config, softdevice, bootloader).await;
+                    }
+                    Some(DfuStatus::DoneAssets { size }) => {
+                        firmware::install_assets(&config, fs, size).await;
+                    }
+                    Some(DfuStatus::FactoryReset) => {
+                        let _ = uart.flush().await;
+                        FactoryReset::new(fs, config.internal(), config.clone()).run().await;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,208 @@
+use core::convert::Infallible;
+
+use defmt::warn;
+use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
+use embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice;
+use embassy_nrf::gpio::{AnyPin, Level, Output, OutputDrive};
+use embassy_nrf::peripherals::TWISPI0;
+use embassy_nrf::spim::Spim;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Delay;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use embedded_hal::digital::OutputPin;
+use mipidsi::models::ST7789;
+use mipidsi::options::Orientation;
+
+use crate::board::DisplayPins;
+use crate::dma::{DmaBuffer, BUFFER_SIZE};
+use crate::error::{self, Error};
+use crate::power::Gated;
+use crate::{frametime, SpiBus};
+
+pub type Display<'a> = mipidsi::Display<
+    DmaInterface<
+        SpiDevice<'a, CriticalSectionRawMutex, Gated<Spim<'a, TWISPI0>>, Output<'a, AnyPin>>,
+        Output<'a, AnyPin>,
+    >,
+    ST7789,
+    Output<'a, AnyPin>,
+>;
+
+const WIDTH: u32 = 240;
+const HEIGHT: u32 = 240;
+
+/// Set up the panel, which shares the SPI bus with the external flash. If the panel does not respond, the watch runs
+/// headless.
+pub fn init(spi_bus: &'static SpiBus, pins: DisplayPins) -> Screen<'static> {
+    let backlight = Output::new(pins.backlight, Level::Low, OutputDrive::Standard); // Medium backlight
+    let rst = Output::new(pins.reset, Level::Low, OutputDrive::Standard);
+    let display_cs = Output::new(pins.cs, Level::High, OutputDrive::Standard); // Keep low while driving display
+    let display_spi = SpiDevice::new(spi_bus, display_cs);
+    let dc = Output::new(pins.dc, Level::Low, OutputDrive::Standard); // Data/clock
+    let buffer = DmaBuffer::take().ok_or("No DMA buffer left for the display");
+    let Some(buffer) = error::recover(buffer, Error::Display) else {
+        return Screen {
+            display: None,
+            backlight,
+        };
+    };
+    let di = DmaInterface {
+        spi: display_spi,
+        dc,
+        buffer,
+    };
+    let display = mipidsi::Builder::new(ST7789, di)
+        .display_size(WIDTH as u16, HEIGHT as u16)
+        .invert_colors(mipidsi::options::ColorInversion::Inverted)
+        .reset_pin(rst)
+        .init(&mut Delay);
+    let display = error::recover(display, Error::Display).map(|mut display| {
+        error::recover(display.set_orientation(Orientation::new()), Error::Display);
+        display
+    });
+    Screen { display, backlight }
+}
+
+/// Sends commands and pixels to the panel through a buffer from the DMA pool. Pixels are mostly computed while
+/// drawing, and images may be constants in flash, so everything is copied into the buffer and sent a buffer at a
+/// time.
+pub struct DmaInterface<SPI, DC> {
+    spi: SPI,
+    /// Low for commands, high for data.
+    dc: DC,
+    buffer: DmaBuffer,
+}
+
+impl<SPI: embedded_hal::spi::SpiDevice, DC: OutputPin> DmaInterface<SPI, DC> {
+    fn send(&mut self, data: DataFormat<'_>) -> Result<(), DisplayError> {
+        match data {
+            DataFormat::U8(bytes) => self.send_bytes(bytes.iter().copied()),
+            DataFormat::U16(words) => self.send_bytes(words.iter().flat_map(|w| w.to_ne_bytes())),
+            DataFormat::U16BE(words) => self.send_bytes(words.iter().flat_map(|w| w.to_be_bytes())),
+            DataFormat::U16LE(words) => self.send_bytes(words.iter().flat_map(|w| w.to_le_bytes())),
+            DataFormat::U8Iter(bytes) => self.send_bytes(bytes),
+            DataFormat::U16BEIter(words) => self.send_bytes(words.flat_map(u16::to_be_bytes)),
+            DataFormat::U16LEIter(words) => self.send_bytes(words.flat_map(u16::to_le_bytes)),
+            _ => Err(DisplayError::DataFormatNotImplemented),
+        }
+    }
+
+    fn send_bytes(&mut self, bytes: impl Iterator<Item = u8>) -> Result<(), DisplayError> {
+        let mut len = 0;
+        for byte in bytes {
+            self.buffer[len] = byte;
+            len += 1;
+            if len == BUFFER_SIZE {
+                self.flush(len)?;
+                len = 0;
+            }
+        }
+        if len > 0 {
+            self.flush(len)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, len: usize) -> Result<(), DisplayError> {
+        frametime::flushing(|| self.spi.write(&self.buffer[..len])).map_err(|_| DisplayError::BusWriteError)
+    }
+}
+
+impl<SPI: embedded_hal::spi::SpiDevice, DC: OutputPin> WriteOnlyDataCommand for DmaInterface<SPI, DC> {
+    fn send_commands(&mut self, commands: DataFormat<'_>) -> Result<(), DisplayError> {
+        self.dc.set_low().map_err(|_| DisplayError::DCError)?;
+        self.send(commands)
+    }
+
+    fn send_data(&mut self, data: DataFormat<'_>) -> Result<(), DisplayError> {
+        self.dc.set_high().map_err(|_| DisplayError::DCError)?;
+        self.send(data)
+    }
+}
+
+/// The display and its backlight.
+///
+/// Views draw to the screen itself. Drawing never fails: errors from the panel are reported and the drawing is
+/// dropped, and without a panel nothing is drawn.
+pub struct Screen<'a> {
+    display: Option<Display<'a>>,
+    backlight: Output<'a, AnyPin>,
+}
+
+impl<'a> Screen<'a> {
+    /// Whether the panel was initialized, the screen draws nothing otherwise.
+    pub fn is_ready(&self) -> bool {
+        self.display.is_some()
+    }
+
+    pub fn on(&mut self) {
+        self.backlight.set_low();
+    }
+
+    pub fn off(&mut self) {
+        self.backlight.set_high();
+    }
+
+    /// Turn off the backlight and put the panel to sleep. The panel keeps its memory while asleep, so it can still
+    /// be drawn to before calling `wake`.
+    pub fn sleep(&mut self) {
+        self.off();
+        if let Some(Err(e)) = self.display.as_mut().map(|display| display.sleep(&mut Delay)) {
+            warn!("Error putting display to sleep: {:?}", defmt::Debug2Format(&e));
+        }
+    }
+
+    pub fn wake(&mut self) {
+        if let Some(Err(e)) = self.display.as_mut().map(|display| display.wake(&mut Delay)) {
+            warn!("Error waking display: {:?}", defmt::Debug2Format(&e));
+        }
+    }
+}
+
+impl OriginDimensions for Screen<'_> {
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+impl DrawTarget for Screen<'_> {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I: IntoIterator<Item = Pixel<Rgb565>>>(&mut self, pixels: I) -> Result<(), Infallible> {
+        if let Some(display) = &mut self.display {
+            error::recover(frametime::drawing(|| display.draw_iter(pixels)), Error::Display);
+        }
+        Ok(())
+    }
+
+    fn fill_contiguous<I: IntoIterator<Item = Rgb565>>(
+        &mut self,
+        area: &Rectangle,
+        colors: I,
+    ) -> Result<(), Infallible> {
+        if let Some(display) = &mut self.display {
+            error::recover(
+                frametime::drawing(|| display.fill_contiguous(area, colors)),
+                Error::Display,
+            );
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), Infallible> {
+        if let Some(display) = &mut self.display {
+            error::recover(frametime::drawing(|| display.fill_solid(area, color)), Error::Display);
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Rgb565) -> Result<(), Infallible> {
+        if let Some(display) = &mut self.display {
+            error::recover(frametime::drawing(|| display.clear(color)), Error::Display);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::layout;
+
+/// Most bytes EasyDMA of the nRF52832 moves in one transfer.
+const EASY_DMA_SIZE: usize = 255;
+/// Size of each buffer, a whole number of RGB565 pixels that EasyDMA moves in one transfer.
+pub const BUFFER_SIZE: usize = 240;
+/// The display holds one for as long as it runs. The others are taken for a single flash write or BLE transfer.
+pub const BUFFERS: usize = 4;
+
+const _: () = assert!(BUFFER_SIZE <= EASY_DMA_SIZE && BUFFER_SIZE % 4 == 0);
+const _: () = assert!(BUFFERS <= u8::BITS as usize);
+
+#[repr(align(4))]
+struct Slot(UnsafeCell<[u8; BUFFER_SIZE]>);
+
+// Safety: a slot is only accessed through the `DmaBuffer` holding it.
+unsafe impl Sync for Slot {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Slot = Slot(UnsafeCell::new([0; BUFFER_SIZE]));
+/// Statics are placed in RAM, where EasyDMA can reach them, unlike constants and string literals left in flash.
+static SLOTS: [Slot; BUFFERS] = [EMPTY; BUFFERS];
+/// Bit n is set while slot n is taken.
+static TAKEN: AtomicU8 = AtomicU8::new(0);
+
+/// A buffer from a static pool, for data the SPI peripheral reads or writes with EasyDMA, instead of buffers on the
+/// stack. It is given back to the pool when dropped.
+pub struct DmaBuffer {
+    slot: usize,
+}
+
+impl DmaBuffer {
+    /// Take a free buffer, or `None` if all are in use.
+    pub fn take() -> Option<Self> {
+        let mut taken = TAKEN.load(Ordering::Acquire);
+        loop {
+            let slot = taken.trailing_ones() as usize;
+            if slot >= BUFFERS {
+                return None;
+            }
+            match TAKEN.compare_exchange_weak(taken, taken | 1 << slot, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => return Some(Self { slot }),
+                Err(current) => taken = current,
+            }
+        }
+    }
+}
+
+impl Deref for DmaBuffer {
+    type Target = [u8; BUFFER_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: the slot is taken by this buffer alone until it is dropped.
+        unsafe { &*SLOTS[self.slot].0.get() }
+    }
+}
+
+impl DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: the slot is taken by this buffer alone until it is dropped.
+        unsafe { &mut *SLOTS[self.slot].0.get() }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        TAKEN.fetch_and(!(1 << self.slot), Ordering::Release);
+    }
+}
+
+/// Whether EasyDMA can read `data`, which it cannot from flash.
+pub fn in_ram(data: &[u8]) -> bool {
+    let start = data.as_ptr() as u32;
+    data.is_empty() || (layout::RAM.contains(start) && start + data.len() as u32 <= layout::RAM.end())
+}
+
+/// Buffers taken, for the memory report.
+pub fn in_use() -> u32 {
+    TAKEN.load(Ordering::Relaxed).count_ones()
+}
@@ -0,0 +1,76 @@
+use core::fmt::Debug;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use defmt::warn;
+
+use crate::crash;
+
+/// Errors the firmware recovers from, by the part of the watch they affect.
+///
+/// The recovery policy is to keep the watch running with the affected part degraded, rather than panicking and
+/// resetting a device that is worn:
+/// - `Display`: the watch runs headless, BLE and the sensors keep working.
+/// - `Touch`: the UI is driven with the button only.
+/// - `HeartRate`: readings are skipped.
+/// - `Ble`: malformed requests are dropped, failed advertising is retried, and without a GATT server the watch runs
+///   without BLE.
+/// - `FirmwareState`: the firmware is left as it is, neither validated nor marked updated.
+/// - `Spawn`: the watch runs without the task.
+/// - `ExternalFlash`: the watch runs without the file system, settings, logs and firmware updates.
+/// - `Events`: a UI state without a sensor event subscription is not interrupted by events.
+///
+/// Each error is logged, and the first of each kind after boot is recorded in the crash log.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Error {
+    Display,
+    Touch,
+    HeartRate,
+    #[cfg(not(feature = "no-softdevice"))]
+    Ble,
+    FirmwareState,
+    Spawn,
+    /// The external flash holds the file system, settings, logs and firmware updates.
+    ExternalFlash,
+    Accelerometer,
+    Events,
+}
+
+impl Error {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Display => "display",
+            Self::Touch => "touch",
+            Self::HeartRate => "heart rate",
+            #[cfg(not(feature = "no-softdevice"))]
+            Self::Ble => "BLE",
+            Self::FirmwareState => "firmware state",
+            Self::Spawn => "spawn",
+            Self::ExternalFlash => "external flash",
+            Self::Accelerometer => "accelerometer",
+            Self::Events => "sensor events",
+        }
+    }
+}
+
+/// Kinds of error already recorded in the crash log since boot, one bit per kind.
+static RECORDED: AtomicU16 = AtomicU16::new(0);
+
+/// Report an error the caller recovers from.
+pub fn report<E: Debug>(error: Error, cause: E) {
+    warn!("{} error: {:?}", error.name(), defmt::Debug2Format(&cause));
+    let bit = 1 << error as u8;
+    if RECORDED.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+        crash::record_error(format_args!("{}: {:?}", error.name(), cause));
+    }
+}
+
+/// Report the error of `result`, if any, and carry on without its value.
+pub fn recover<T, E: Debug>(result: Result<T, E>, error: Error) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(cause) => {
+            report(error, cause);
+            None
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use defmt::warn;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use embassy_sync::signal::Signal;
+use watchful_ui::ButtonEvent;
+
+use crate::device::ChargeState;
+use crate::error::{self, Error};
+#[cfg(not(feature = "no-softdevice"))]
+use crate::notifications::Category;
+
+/// Events about the battery, the charger, activity, firmware updates, notifications, navigation, music, pairing and
+/// finding the watch or phone.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum SensorEvent {
+    /// The charger was connected or removed, or charging completed. Published by the charger task.
+    ChargeState(ChargeState),
+    /// The battery reached the critical level. Published by the power manager.
+    BatteryCritical,
+    /// The daily step goal was reached with this many steps. Published by the activity task, once a day.
+    StepGoalReached(u32),
+    /// A firmware update made progress. Published by the BLE task after each object of the image.
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    FirmwareUpdate(UpdateProgress),
+    /// The connection of a firmware update was lost before it was done, or assets that need no reset were installed.
+    /// Published by the BLE task.
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    FirmwareUpdateStopped,
+    /// The phone sent a notification, kept in `notifications`. Published by the BLE task.
+    #[cfg(not(feature = "no-softdevice"))]
+    Notification(Category),
+    /// The phone sent part of the next turn of a route, kept in `navigation`, with whether the instruction is a new
+    /// one. Published by the BLE task.
+    #[cfg(not(feature = "no-softdevice"))]
+    Navigation { new_instruction: bool },
+    /// The phone changed the track it plays or started or stopped playing, kept in `music`. Published by the BLE task.
+    Music,
+    /// The phone asked the watch to alert so it can be found, or to stop. Published by the BLE task.
+    FindWatch(bool),
+    /// Find Phone was asked for without a connected phone that has an Immediate Alert Service. Published by the BLE
+    /// task.
+    PhoneNotFound,
+    /// A phone is pairing and the passkey, six ASCII digits, must be shown for it to be typed on the phone. Published
+    /// by the BLE task.
+    #[cfg(not(feature = "no-softdevice"))]
+    Passkey([u8; 6]),
+    /// Pairing completed and the phone is bonded. Published by the BLE task.
+    #[cfg(not(feature = "no-softdevice"))]
+    Paired,
+}
+
+/// Progress of a firmware update.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct UpdateProgress {
+    /// Bytes of the image received and checked so far, `total` once the update is done.
+    pub received: u32,
+    pub total: u32,
+}
+
+/// Subscribers of sensor events: the UI loop, and the UI state on screen.
+const SENSOR_SUBSCRIBERS: usize = 2;
+
+/// Sensor events are broadcast, each subscriber sees every event published while it is subscribed. Events are
+/// published without waiting, a subscriber that falls behind loses the oldest ones.
+static SENSOR_EVENTS: PubSubChannel<CriticalSectionRawMutex, SensorEvent, 4, SENSOR_SUBSCRIBERS, 0> =
+    PubSubChannel::new();
+
+/// A subscription to the sensor events, which never sees any if there was no subscriber left to take.
+pub struct SensorSubscriber(
+    Option<Subscriber<'static, CriticalSectionRawMutex, SensorEvent, 4, SENSOR_SUBSCRIBERS, 0>>,
+);
+
+impl SensorSubscriber {
+    /// Wait for the next event, skipping the count of those lost.
+    pub async fn next_message_pure(&mut self) -> SensorEvent {
+        match &mut self.0 {
+            Some(subscriber) => subscriber.next_message_pure().await,
+            None => core::future::pending().await,
+        }
+    }
+}
+
+pub fn publish(event: SensorEvent) {
+    SENSOR_EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// Receive the sensor events published from now on, until the subscriber is dropped.
+pub fn subscribe() -> SensorSubscriber {
+    SensorSubscriber(error::recover(SENSOR_EVENTS.subscriber(), Error::Events))
+}
+
+/// Commands to the BLE task, which owns advertising and the connection.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum BleCommand {
+    /// Go back to fast advertising, e.g. because the user interacted with the watch.
+    AdvertiseFast,
+    /// End the current connection, if any, e.g. before powering off.
+    Disconnect,
+    /// End the current connection and stop advertising, until `Resume`.
+    Suspend,
+    Resume,
+    /// Make the phone ring so it can be found, or stop it ringing.
+    FindPhone(bool),
+    /// Advertise to any phone for a while, not only the bonded ones, so that a new phone can pair. Ends the current
+    /// connection, if any.
+    PairingMode,
+}
+
+static BLE_COMMANDS: Channel<CriticalSectionRawMutex, BleCommand, 4> = Channel::new();
+
+/// Queue a command for the BLE task without waiting.
+pub fn send_ble(command: BleCommand) {
+    if BLE_COMMANDS.try_send(command).is_err() {
+        warn!("BLE command queue full, dropping {:?}", command);
+    }
+}
+
+/// The next command for the BLE task. The BLE task is the only consumer.
+pub async fn next_ble_command() -> BleCommand {
+    BLE_COMMANDS.receive().await
+}
+
+/// Input injected by the debug shell, taken by the input functions as if it came from the button or touchpad.
+static INJECTED_BUTTON: Signal<CriticalSectionRawMutex, ButtonEvent> = Signal::new();
+static INJECTED_TOUCH: Signal<CriticalSectionRawMutex, cst816s::TouchEvent> = Signal::new();
+/// Set by the debug shell to redraw the current UI state.
+static REDRAW: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[cfg(feature = "shell")]
+pub fn inject_button(event: ButtonEvent) {
+    INJECTED_BUTTON.signal(event);
+}
+
+#[cfg(feature = "shell")]
+pub fn inject_touch(event: cst816s::TouchEvent) {
+    INJECTED_TOUCH.signal(event);
+}
+
+#[cfg(feature = "shell")]
+pub fn request_redraw() {
+    REDRAW.signal(());
+}
+
+/// Wait for a button event from the debug shell. Never returns without the shell.
+pub async fn injected_button() -> ButtonEvent {
+    INJECTED_BUTTON.wait().await
+}
+
+/// Wait for a touch event from the debug shell. Never returns without the shell.
+pub async fn injected_touch() -> cst816s::TouchEvent {
+    INJECTED_TOUCH.wait().await
+}
+
+/// Take the pending touch event from the debug shell, if any.
+pub fn take_injected_touch() -> Option<cst816s::TouchEvent> {
+    INJECTED_TOUCH.try_take()
+}
+
+/// Wait for the debug shell to ask for a redraw. Never returns without the shell.
+pub async fn wait_redraw() {
+    REDRAW.wait().await
+}
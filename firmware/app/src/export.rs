@@ -0,0 +1,85 @@
+use heapless::Vec;
+use nrf_dfu::crc::crc32;
+
+use crate::ble::MTU;
+
+/// Version of the export frame format, see the data export section of the README.
+pub const FRAME_VERSION: u8 = 1;
+
+// Record id and payload length.
+const RECORD_HEADER_SIZE: usize = 5;
+const CRC_SIZE: usize = 4;
+
+const FLAG_LAST: u8 = 0x01;
+
+/// Logs that can be exported.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum LogKind {
+    Activity = 1,
+    HeartRate = 2,
+}
+
+impl LogKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Activity),
+            2 => Some(Self::HeartRate),
+            _ => None,
+        }
+    }
+}
+
+/// A single notification of an export, holding as many log records as fit in the MTU of the connection.
+///
+/// Layout, all integers little endian:
+///
+/// | version u8 | log u8 | sequence u16 | flags u8 | count u8 | reserved u8 | records... | crc32 u32 |
+///
+/// Each record is `| id u32 | len u8 | payload |`, and the CRC covers everything before it.
+pub struct Frame {
+    buf: Vec<u8, MTU>,
+    /// Bytes the frame may take, up to `MTU`.
+    size: usize,
+}
+
+impl Frame {
+    pub fn new(log: LogKind, sequence: u16, size: usize) -> Self {
+        let mut buf = Vec::new();
+        let _ = buf.push(FRAME_VERSION);
+        let _ = buf.push(log as u8);
+        let _ = buf.extend_from_slice(&sequence.to_le_bytes());
+        let _ = buf.extend_from_slice(&[0, 0, 0]);
+        Self {
+            buf,
+            size: size.min(MTU),
+        }
+    }
+
+    /// Add a record, returning false if the frame is full.
+    pub fn push(&mut self, id: u32, payload: &[u8]) -> bool {
+        if self.buf.len() + RECORD_HEADER_SIZE + payload.len() + CRC_SIZE > self.size || self.buf[5] == 255 {
+            return false;
+        }
+        let _ = self.buf.extend_from_slice(&id.to_le_bytes());
+        let _ = self.buf.push(payload.len() as u8);
+        let _ = self.buf.extend_from_slice(payload);
+        self.buf[5] += 1;
+        true
+    }
+
+    /// Whether no record was added.
+    pub fn is_empty(&self) -> bool {
+        self.buf[5] == 0
+    }
+
+    /// Complete the frame, marking it as the last of the export if `last` is set.
+    pub fn finish(mut self, last: bool) -> Vec<u8, MTU> {
+        if last {
+            self.buf[4] |= FLAG_LAST;
+        }
+        let crc = crc32(&self.buf);
+        let _ = self.buf.extend_from_slice(&crc.to_le_bytes());
+        self.buf
+    }
+}
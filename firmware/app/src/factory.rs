@@ -0,0 +1,95 @@
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use watchful_ui::TextView;
+
+use crate::display::Screen;
+use crate::fs::FileSystem;
+use crate::input::Button;
+use crate::{activity, crash, heartrate, kv, layout, sleep, DfuConfig, InternalFlash, LogPartition};
+
+/// How long the button must be held while booting to request a factory reset.
+const BOOT_HOLD_TIME: Duration = Duration::from_secs(5);
+
+/// Erases all user data: files, settings, bonds, activity, heart rate, sleep and crash logs, any staged firmware
+/// update and received assets. The bootloader state and the running firmware are left untouched.
+#[derive(Clone)]
+pub struct FactoryReset {
+    fs: &'static FileSystem<'static>,
+    internal: &'static Mutex<CriticalSectionRawMutex, InternalFlash>,
+    dfu: DfuConfig<'static>,
+}
+
+impl FactoryReset {
+    pub fn new(
+        fs: &'static FileSystem<'static>,
+        internal: &'static Mutex<CriticalSectionRawMutex, InternalFlash>,
+        dfu: DfuConfig<'static>,
+    ) -> Self {
+        Self { fs, internal, dfu }
+    }
+
+    /// Erase all user data and reboot.
+    pub async fn run(&self) -> ! {
+        warn!("Factory reset");
+        if let Err(e) = self.fs.format().await {
+            warn!("Error formatting filesystem: {:?}", e);
+        }
+
+        for (offset, size) in [
+            (activity::LOG_OFFSET, activity::LOG_SIZE),
+            (heartrate::LOG_OFFSET, heartrate::LOG_SIZE),
+            (crash::LOG_OFFSET, crash::LOG_SIZE),
+            (sleep::LOG_OFFSET, sleep::LOG_SIZE),
+            (kv::KV_OFFSET, kv::KV_SIZE),
+            (layout::STAGING_HEADER.start, layout::STAGING_HEADER.size),
+            (layout::STAGING.start, layout::STAGING.size),
+            (layout::ASSETS.start, layout::ASSETS.size),
+        ] {
+            let mut region = LogPartition::new(self.dfu.external(), offset, size);
+            if let Err(e) = region.erase(0, size) {
+                warn!("Error erasing region at 0x{:x}: {:?}", offset, defmt::Debug2Format(&e));
+            }
+        }
+
+        let mut dfu = self.dfu.dfu();
+        let size = dfu.capacity() as u32;
+        if let Err(e) = dfu.erase(0, size) {
+            warn!("Error erasing DFU partition: {:?}", defmt::Debug2Format(&e));
+        }
+
+        let mut bonds = crate::bond_partition(self.internal);
+        let size = bonds.size();
+        if let Err(e) = bonds.erase(0, size).await {
+            warn!("Error erasing bonds: {:?}", defmt::Debug2Format(&e));
+        }
+
+        info!("Factory reset complete, resetting");
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+/// Tell the user what is going on before a reset started from the watch, which takes a few seconds.
+pub fn show(screen: &mut Screen<'_>) {
+    let _ = TextView::new("Factory reset", "Erasing all data, the watch will restart when done.").draw(screen);
+    screen.on();
+}
+
+/// Check if the button is held while booting, which requests a factory reset without going through the UI.
+pub async fn requested_at_boot(button: &Button) -> bool {
+    if !button.is_pressed() {
+        return false;
+    }
+    info!("Button held at boot, keep holding for factory reset");
+    let start = Instant::now();
+    while start.elapsed() < BOOT_HOLD_TIME {
+        if !button.is_pressed() {
+            return false;
+        }
+        Timer::after(Duration::from_millis(50)).await;
+    }
+    true
+}
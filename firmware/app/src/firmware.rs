@@ -0,0 +1,362 @@
+//! Installing and confirming firmware updates, whichever transport received them. The bootloader swaps an update in
+//! and marks it as on trial, and swaps it back out on the next reset unless the running image is marked as booted.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use core::cell::Cell;
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_boot::State;
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use embassy_boot_nrf::AlignedBuffer;
+use embassy_boot_nrf::FirmwareState;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use embassy_time::{Duration, Timer};
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::nor_flash::ReadNorFlash;
+#[cfg(not(feature = "no-softdevice"))]
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+#[cfg(not(feature = "no-softdevice"))]
+use nrf_dfu::crc::{crc32, crc32_update};
+#[cfg(not(feature = "no-softdevice"))]
+use nrf_softdevice::raw;
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use watchful_boot::StagingHeader;
+
+use crate::error::{self, Error};
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use crate::events::{self, SensorEvent};
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use crate::fs::FileSystem;
+#[cfg(not(feature = "no-softdevice"))]
+use crate::layout::Region;
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use crate::{layout, resources, DfuConfig};
+use crate::{ExternalFlash, StatePartition};
+
+/// Time for the response to the last Execute to reach the host before resetting into the bootloader, which
+/// otherwise reports the update as failed although it is swapped in.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+const RESET_DELAY: Duration = Duration::from_millis(500);
+/// Public key updates must be signed with, set with `WATCHFUL_DFU_KEY` when building. Only builds with the
+/// `unsigned-dfu` feature may leave it out, and then accept any update with a valid init packet.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+const DFU_KEY: Option<[u8; 64]> = match option_env!("WATCHFUL_DFU_KEY") {
+    Some(hex) => Some(parse_key(hex.as_bytes())),
+    None => None,
+};
+/// Where a bootloader update is copied for the MBR to install, the end of the application region, past the running
+/// image.
+#[cfg(not(feature = "no-softdevice"))]
+pub const BOOTLOADER_SOURCE: Region = Region::new(layout::APP.end() - layout::BOOTLOADER.size, layout::BOOTLOADER.size);
+/// UICR register the MBR reads the address of `layout::MBR_PARAMS` from, which the bootloader sets when flashed.
+#[cfg(not(feature = "no-softdevice"))]
+const UICR_MBR_PARAMS_PAGE: *const u32 = 0x1000_1018 as *const u32;
+
+/// Parts of the watch a new image must bring up to be kept.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct SelfTest {
+    pub display: bool,
+    pub external_flash: bool,
+    pub softdevice: bool,
+}
+
+impl SelfTest {
+    fn passed(&self) -> bool {
+        self.display && self.external_flash && self.softdevice
+    }
+}
+
+/// Whether the external flash answers a read.
+pub fn probe_flash(flash: &BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>>) -> bool {
+    let mut buf = [0; 4];
+    flash.lock(|flash| flash.borrow_mut().read(0, &mut buf)).is_ok()
+}
+
+/// Keep the running image, so the bootloader does not swap the previous one back in.
+pub async fn mark_booted(state: &mut FirmwareState<'_, StatePartition<'static>>) {
+    if error::recover(state.mark_booted().await, Error::FirmwareState).is_some() {
+        info!("Firmware marked as valid");
+    }
+}
+
+/// After an update, mark the new image as booted if it passed `test`. Otherwise reset, so the bootloader goes back
+/// to the previous image.
+pub async fn confirm(state: &mut FirmwareState<'_, StatePartition<'static>>, test: SelfTest) {
+    if error::recover(state.get_state().await, Error::FirmwareState) != Some(State::Swap) {
+        return;
+    }
+    if test.passed() {
+        mark_booted(state).await;
+    } else {
+        warn!("Self-test of the new firmware failed: {:?}, rolling back", test);
+        error::report(Error::FirmwareState, test);
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+/// A DFU target for this watch, writing applications of up to `capacity` bytes.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+pub fn dfu_target(capacity: u32) -> nrf_dfu::DfuTarget {
+    use nrf_dfu::initpacket::Capacity;
+    use nrf_dfu::{DfuTarget, FirmwareInfo, FirmwareType, HardwareInfo};
+
+    use crate::buildinfo::BUILD;
+
+    let p = unsafe { embassy_nrf::pac::Peripherals::steal() };
+    let hw_info = HardwareInfo {
+        part: p.FICR.info.part.read().part().bits(),
+        variant: p.FICR.info.variant.read().variant().bits(),
+        rom_size: layout::INTERNAL_FLASH_SIZE,
+        ram_size: layout::RAM_SIZE,
+        rom_page_size: layout::PAGE_SIZE,
+    };
+
+    // Regions of the images rather than their sizes, which only the bootloader knows.
+    let images = [
+        FirmwareInfo {
+            ftype: FirmwareType::Softdevice,
+            version: nrf_softdevice::raw::SD_MAJOR_VERSION * 1_000_000
+                + nrf_softdevice::raw::SD_MINOR_VERSION * 1000
+                + nrf_softdevice::raw::SD_BUGFIX_VERSION,
+            addr: layout::SOFTDEVICE.start,
+            len: layout::SOFTDEVICE.size,
+        },
+        FirmwareInfo {
+            ftype: FirmwareType::Application,
+            version: BUILD.version_number(),
+            addr: layout::APP.start,
+            len: layout::APP.size,
+        },
+        // The bootloader is not versioned.
+        FirmwareInfo {
+            ftype: FirmwareType::Bootloader,
+            version: 0,
+            addr: layout::BOOTLOADER.start,
+            len: layout::BOOTLOADER.size,
+        },
+    ];
+    let capacity = Capacity {
+        application: layout::APP.size.min(capacity),
+        softdevice: layout::SOFTDEVICE.size,
+        bootloader: layout::BOOTLOADER.size,
+        assets: layout::ASSETS.size,
+    };
+    let mut target = DfuTarget::new(capacity, images, hw_info);
+    if let Some(key) = DFU_KEY {
+        target.set_key(key);
+    }
+    target.set_allow_downgrade(cfg!(feature = "allow-downgrade"));
+    target
+}
+
+/// Parse the hex of `WATCHFUL_DFU_KEY`, which the build script checked.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+const fn parse_key(hex: &[u8]) -> [u8; 64] {
+    const fn digit(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            _ => c - b'A' + 10,
+        }
+    }
+    let mut key = [0; 64];
+    let mut i = 0;
+    while i < key.len() {
+        key[i] = (digit(hex[2 * i]) << 4) | digit(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
+
+/// Where an update is received from. The partitions an update is written to are shared by every transport, so they
+/// belong to one of them from the first Create until the update is done, aborted or lost, see `claim_update`.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum UpdateOwner {
+    /// A BLE connection, by its handle.
+    #[cfg(not(feature = "no-softdevice"))]
+    Ble(u16),
+    Serial,
+}
+
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+static UPDATE_OWNER: BMutex<CriticalSectionRawMutex, Cell<Option<UpdateOwner>>> = BMutex::new(Cell::new(None));
+
+/// Whether the DFU `event` of `owner` may be handled, rather than refused with "operation not permitted". A Create
+/// while no update is under way makes `owner` the owner of the update, and from then on only its events are taken,
+/// so that two hosts cannot interleave their objects, until it calls `release_update`.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+pub fn claim_update(owner: UpdateOwner, event: &nrf_dfu::DfuEvent<'_>) -> bool {
+    use nrf_dfu::{DfuEvent, DfuRequest};
+
+    let create = matches!(
+        event,
+        DfuEvent::ControlWrite(data) if matches!(DfuRequest::decode(data), Ok(DfuRequest::Create { .. }))
+    );
+    UPDATE_OWNER.lock(|current| match current.get() {
+        Some(current) => current == owner,
+        None => {
+            if create {
+                info!("Firmware update owned by {}", owner);
+                current.set(Some(owner));
+            }
+            true
+        }
+    })
+}
+
+/// Whether `owner` owns the update under way.
+#[cfg(feature = "serial-dfu")]
+pub fn owns_update(owner: UpdateOwner) -> bool {
+    UPDATE_OWNER.lock(|current| current.get() == Some(owner))
+}
+
+/// Let other transports start an update, once the update of `owner` is done, aborted or lost.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+pub fn release_update(owner: UpdateOwner) {
+    UPDATE_OWNER.lock(|current| {
+        if current.get() == Some(owner) {
+            current.set(None);
+        }
+    });
+}
+
+/// Mark the application received to the DFU partition for the bootloader, which swaps it with the running one on the
+/// next boot and swaps back unless it passes the self-test at boot, see `confirm`, and reset.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+pub async fn finish_update(config: &DfuConfig<'static>) {
+    let mut magic = AlignedBuffer([0; 4]);
+    let mut state = FirmwareState::new(config.state(), &mut magic.0);
+    match state.mark_updated().await {
+        Ok(_) => {
+            info!("Firmware updated, resetting");
+            Timer::after(RESET_DELAY).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        // The new firmware stays in the DFU partition and can be sent again.
+        Err(e) => error::report(Error::FirmwareState, e),
+    }
+}
+
+/// Install a SoftDevice and bootloader received to the staging region, and reset. The bootloader can not write over
+/// itself, so the new one is handed to the MBR, which finishes copying it after a power loss. The SoftDevice runs
+/// under the application, so it is left for the bootloader to install on the next boot.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+pub async fn install_staged(config: &DfuConfig<'static>, softdevice: u32, bootloader: u32) {
+    #[cfg(not(feature = "no-softdevice"))]
+    if bootloader > 0 && stage_bootloader(config, softdevice, bootloader).await.is_none() {
+        return;
+    }
+    #[cfg(feature = "no-softdevice")]
+    if bootloader > 0 {
+        warn!(
+            "Bootloader of {} bytes dropped, it is not used without the SoftDevice",
+            bootloader
+        );
+    }
+    if softdevice > 0 && stage_softdevice(config, softdevice).is_none() {
+        return;
+    }
+    info!("System update installed, resetting");
+    Timer::after(RESET_DELAY).await;
+    #[cfg(not(feature = "no-softdevice"))]
+    if bootloader > 0 {
+        copy_bootloader(bootloader);
+    }
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Install the resource pack of `size` bytes received to the asset partition. Nothing needs a reset, so the update
+/// screen is left once done.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+pub async fn install_assets(config: &DfuConfig<'static>, fs: &FileSystem<'_>, size: u32) {
+    match resources::install_assets(fs, &mut config.assets(), size).await {
+        Ok(()) => info!("Assets of {} bytes installed", size),
+        Err(e) => warn!("Error installing the received assets: {:?}", e),
+    }
+    events::publish(SensorEvent::FirmwareUpdateStopped);
+}
+
+/// Copy the bootloader of `size` bytes at `offset` of the staging region to `BOOTLOADER_SOURCE`, for
+/// `copy_bootloader`. The MBR only copies from the internal flash.
+#[cfg(not(feature = "no-softdevice"))]
+async fn stage_bootloader(config: &DfuConfig<'static>, offset: u32, size: u32) -> Option<()> {
+    // The MBR refuses to copy a bootloader without a page to keep its progress in.
+    if unsafe { core::ptr::read_volatile(UICR_MBR_PARAMS_PAGE) } != layout::MBR_PARAMS.start {
+        warn!("Bootloader update dropped, flash the bootloader with a debug probe once to take them");
+        return None;
+    }
+    if image_end() > BOOTLOADER_SOURCE.start {
+        warn!("Bootloader update dropped, the running image leaves no room for it");
+        return None;
+    }
+    let mut staging = config.staging();
+    let mut source = config.bootloader_source();
+    error::recover(source.erase(0, BOOTLOADER_SOURCE.size).await, Error::FirmwareState)?;
+    let mut crc = 0;
+    let mut buf = [0; 256];
+    for start in (0..size).step_by(buf.len()) {
+        let len = (size - start).min(buf.len() as u32) as usize;
+        buf.fill(0xFF);
+        error::recover(staging.read(offset + start, &mut buf[..len]), Error::ExternalFlash)?;
+        crc = crc32_update(crc, &buf[..len]);
+        let len = len.next_multiple_of(4);
+        error::recover(source.write(start, &buf[..len]).await, Error::FirmwareState)?;
+    }
+    // The internal flash is mapped, so the copy is checked where the MBR reads it.
+    let copied = unsafe { core::slice::from_raw_parts(BOOTLOADER_SOURCE.start as *const u8, size as usize) };
+    if crc32(copied) != crc {
+        warn!("Bootloader update does not read back as written");
+        return None;
+    }
+    Some(())
+}
+
+/// Have the MBR copy the bootloader of `size` bytes from `BOOTLOADER_SOURCE` over the running one, and reset. The MBR
+/// keeps its progress in `layout::MBR_PARAMS` and finishes the copy on the next boot if power is lost meanwhile, so
+/// the watch is never left without a bootloader. Only returns if the MBR refused the copy.
+#[cfg(not(feature = "no-softdevice"))]
+fn copy_bootloader(size: u32) {
+    let mut command = raw::sd_mbr_command_t {
+        command: raw::NRF_MBR_COMMANDS_SD_MBR_COMMAND_COPY_BL,
+        params: raw::sd_mbr_command_t__bindgen_ty_1 {
+            copy_bl: raw::sd_mbr_command_copy_bl_t {
+                bl_src: BOOTLOADER_SOURCE.start as *mut u32,
+                bl_len: size.div_ceil(4),
+            },
+        },
+    };
+    let result = unsafe { raw::sd_mbr_command(&mut command) };
+    error::report(Error::FirmwareState, result);
+}
+
+/// End of the running image in the internal flash, after the initial values of `.data`, see `cortex-m-rt`.
+#[cfg(not(feature = "no-softdevice"))]
+fn image_end() -> u32 {
+    extern "C" {
+        static __sidata: u32;
+        static __sdata: u32;
+        static __edata: u32;
+    }
+    unsafe {
+        let address = |symbol: &u32| symbol as *const u32 as u32;
+        address(&__sidata) + address(&__edata) - address(&__sdata)
+    }
+}
+
+/// Write the header that has the bootloader install the SoftDevice of `size` bytes at the start of the staging
+/// region, see `watchful_boot`.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+fn stage_softdevice(config: &DfuConfig<'static>, size: u32) -> Option<()> {
+    let crc = error::recover(
+        watchful_boot::image_crc(&mut config.staging(), size),
+        Error::ExternalFlash,
+    )?;
+    let mut header = config.staging_header();
+    error::recover(header.erase(0, layout::STAGING_HEADER.size), Error::ExternalFlash)?;
+    let fields = StagingHeader { size, crc }.encode();
+    error::recover(header.write(0, &fields), Error::ExternalFlash)
+}
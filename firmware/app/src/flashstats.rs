@@ -0,0 +1,236 @@
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Timer};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use heapless::String;
+
+use crate::dma::{self, DmaBuffer, BUFFER_SIZE};
+use crate::fs::{FileSystem, FS_OFFSET, FS_SIZE};
+use crate::kv::{keys, SharedKv};
+use crate::profile::{profiled, Task};
+use crate::{activity, crash, heartrate, kv, ringlog, sleep, Logs};
+
+/// How often erase counters are persisted.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Regions of the external flash that erases are attributed to.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Region {
+    Fs,
+    Activity,
+    HeartRate,
+    Crash,
+    Kv,
+    /// The firmware update area and anything else outside the other regions.
+    Dfu,
+    Sleep,
+}
+
+const REGIONS: [Region; 7] = [
+    Region::Fs,
+    Region::Activity,
+    Region::HeartRate,
+    Region::Crash,
+    Region::Kv,
+    Region::Dfu,
+    Region::Sleep,
+];
+
+impl Region {
+    fn of(address: u32) -> Self {
+        let within = |offset: u32, size: u32| address >= offset && address < offset + size;
+        if within(FS_OFFSET, FS_SIZE as u32) {
+            Self::Fs
+        } else if within(activity::LOG_OFFSET, activity::LOG_SIZE) {
+            Self::Activity
+        } else if within(heartrate::LOG_OFFSET, heartrate::LOG_SIZE) {
+            Self::HeartRate
+        } else if within(crash::LOG_OFFSET, crash::LOG_SIZE) {
+            Self::Crash
+        } else if within(kv::KV_OFFSET, kv::KV_SIZE) {
+            Self::Kv
+        } else if within(sleep::LOG_OFFSET, sleep::LOG_SIZE) {
+            Self::Sleep
+        } else {
+            Self::Dfu
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Fs => "fs",
+            Self::Activity => "act",
+            Self::HeartRate => "hr",
+            Self::Crash => "crash",
+            Self::Kv => "kv",
+            Self::Dfu => "dfu",
+            Self::Sleep => "sleep",
+        }
+    }
+}
+
+/// Sector erases per region since the watch was first used.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ERASES: AtomicU32 = AtomicU32::new(0);
+static ERASES: [AtomicU32; REGIONS.len()] = [NO_ERASES; REGIONS.len()];
+
+/// Number of sectors erased in `region`.
+pub fn erases(region: Region) -> u32 {
+    ERASES[region as usize].load(Ordering::Relaxed)
+}
+
+/// NOR flash wrapper counting sector erases per region of the external flash. Data to write that is not in RAM,
+/// which EasyDMA can not read, is copied through a DMA buffer.
+pub struct CountingFlash<F> {
+    inner: F,
+}
+
+impl<F> CountingFlash<F> {
+    pub fn new(inner: F) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut F {
+        &mut self.inner
+    }
+}
+
+impl<F: ErrorType> ErrorType for CountingFlash<F> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash> ReadNorFlash for CountingFlash<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.inner.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for CountingFlash<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        for sector in (from..to).step_by(F::ERASE_SIZE) {
+            ERASES[Region::of(sector) as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.erase(from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        if dma::in_ram(bytes) {
+            return self.inner.write(offset, bytes);
+        }
+        // Without a free buffer the write is left to the driver, which refuses data outside of RAM.
+        let Some(mut buffer) = DmaBuffer::take() else {
+            return self.inner.write(offset, bytes);
+        };
+        for (i, chunk) in bytes.chunks(BUFFER_SIZE).enumerate() {
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            self.inner
+                .write(offset + (i * BUFFER_SIZE) as u32, &buffer[..chunk.len()])?;
+        }
+        Ok(())
+    }
+}
+
+/// Restore the erase counters persisted by `flash_stats_task`.
+pub async fn load(kv: &SharedKv<'_>) {
+    let mut buf = [0; REGIONS.len() * 4];
+    match kv.lock().await.get(keys::FLASH_ERASES, &mut buf) {
+        Ok(Some(len)) => {
+            for (counter, stored) in ERASES.iter().zip(buf[..len].chunks_exact(4)) {
+                counter.fetch_add(
+                    u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]]),
+                    Ordering::Relaxed,
+                );
+            }
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Error loading flash statistics: {:?}", e),
+    }
+}
+
+/// Human readable summary of flash usage and wear, for the diagnostics screen.
+pub async fn report<const N: usize>(fs: &FileSystem<'_>, logs: Logs, kv: Option<&SharedKv<'_>>, text: &mut String<N>) {
+    match fs.available().await {
+        Ok(available) => {
+            let _ = writeln!(text, "Files: {}K free of {}K", available / 1024, FS_SIZE / 1024);
+        }
+        Err(e) => {
+            let _ = writeln!(text, "Files: error {:?}", e);
+        }
+    }
+
+    let activity = match logs.activity {
+        Some(log) => Some(log.usage().await),
+        None => None,
+    };
+    write_usage(text, "Activity", activity);
+    let heart_rate = match logs.heart_rate {
+        Some(log) => Some(log.usage().await),
+        None => None,
+    };
+    write_usage(text, "Heart rate", heart_rate);
+    let crash = match logs.crash {
+        Some(log) => Some(log.usage().await),
+        None => None,
+    };
+    write_usage(text, "Crash", crash);
+    let sleep = match logs.sleep {
+        Some(log) => Some(log.usage().await),
+        None => None,
+    };
+    write_usage(text, "Sleep", sleep);
+
+    if let Some(kv) = kv {
+        let _ = writeln!(text, "Settings: {} bytes free", kv.lock().await.free());
+    }
+
+    let _ = write!(text, "Erases:");
+    for region in REGIONS {
+        let _ = write!(text, " {} {}", region.name(), erases(region));
+    }
+}
+
+fn write_usage<const N: usize>(text: &mut String<N>, name: &str, usage: Option<Result<(u32, u32), ringlog::Error>>) {
+    let _ = match usage {
+        Some(Ok((used, size))) => writeln!(text, "{} log: {}%", name, used * 100 / size),
+        Some(Err(e)) => writeln!(text, "{} log: error {:?}", name, e),
+        None => writeln!(text, "{} log: unavailable", name),
+    };
+}
+
+/// Periodically log and persist the erase counters.
+#[embassy_executor::task]
+pub async fn flash_stats_task(kv: &'static SharedKv<'static>) {
+    profiled(Task::FlashStats, async move {
+        let mut stored = [0; REGIONS.len()];
+        loop {
+            let counts = REGIONS.map(erases);
+            info!(
+                "Flash erases: fs {} activity {} heart rate {} crash {} kv {} dfu {} sleep {}",
+                counts[0], counts[1], counts[2], counts[3], counts[4], counts[5], counts[6]
+            );
+            if counts != stored {
+                let mut buf = [0; REGIONS.len() * 4];
+                for (value, chunk) in counts.iter().zip(buf.chunks_exact_mut(4)) {
+                    chunk.copy_from_slice(&value.to_le_bytes());
+                }
+                match kv.lock().await.set(keys::FLASH_ERASES, &buf) {
+                    Ok(_) => stored = counts,
+                    Err(e) => warn!("Error storing flash statistics: {:?}", e),
+                }
+            }
+            Timer::after(PERSIST_INTERVAL).await;
+        }
+    })
+    .await
+}
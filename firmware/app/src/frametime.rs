@@ -0,0 +1,126 @@
+use core::future::Future;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::DWT;
+use defmt::{debug, info};
+
+use crate::profile::CPU_HZ;
+
+/// Frames aggregated in each report.
+const REPORT_FRAMES: u32 = 32;
+const CYCLES_PER_US: u32 = (CPU_HZ / 1_000_000) as u32;
+
+/// Cycles spent in the drawing methods of the screen, SPI writes included, during the current frame.
+static DRAW_CYCLES: AtomicU32 = AtomicU32::new(0);
+/// Cycles spent writing to the panel during the current frame.
+static FLUSH_CYCLES: AtomicU32 = AtomicU32::new(0);
+
+/// Phases of drawing a frame.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Phase {
+    /// Everything outside of the screen: the state reading what it shows, and the views laying it out.
+    Render,
+    /// The screen turning shapes, text and images into pixels.
+    Rasterize,
+    /// Sending the pixels to the panel over SPI.
+    Flush,
+}
+
+const PHASES: [Phase; 3] = [Phase::Render, Phase::Rasterize, Phase::Flush];
+
+#[derive(Clone, Copy)]
+struct Stats {
+    min: u32,
+    max: u32,
+    total: u64,
+}
+
+impl Stats {
+    const EMPTY: Self = Self {
+        min: u32::MAX,
+        max: 0,
+        total: 0,
+    };
+
+    fn add(&mut self, cycles: u32) {
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total += cycles as u64;
+    }
+}
+
+/// Timing of the frames the UI draws, logged for each frame at debug level and as min/avg/max every
+/// `REPORT_FRAMES` frames.
+///
+/// Times are measured with the DWT cycle counter from the start to the end of the frame. A frame that waits, such
+/// as for the battery to be measured, counts the time other tasks run meanwhile towards rendering.
+pub struct FrameTimes {
+    stats: [Stats; PHASES.len()],
+    frames: u32,
+}
+
+impl FrameTimes {
+    pub const fn new() -> Self {
+        Self {
+            stats: [Stats::EMPTY; PHASES.len()],
+            frames: 0,
+        }
+    }
+
+    /// Run `frame`, which draws to the screen, and account its time.
+    pub async fn measure<F: Future>(&mut self, frame: F) -> F::Output {
+        DRAW_CYCLES.store(0, Ordering::Relaxed);
+        FLUSH_CYCLES.store(0, Ordering::Relaxed);
+        let start = DWT::cycle_count();
+        let output = frame.await;
+        let total = DWT::cycle_count().wrapping_sub(start);
+        let draw = DRAW_CYCLES.load(Ordering::Relaxed);
+        let flush = FLUSH_CYCLES.load(Ordering::Relaxed);
+        let cycles = [total.saturating_sub(draw), draw.saturating_sub(flush), flush];
+        debug!(
+            "Frame: render {} us, rasterize {} us, flush {} us",
+            cycles[0] / CYCLES_PER_US,
+            cycles[1] / CYCLES_PER_US,
+            cycles[2] / CYCLES_PER_US
+        );
+        for (stats, cycles) in self.stats.iter_mut().zip(cycles) {
+            stats.add(cycles);
+        }
+        self.frames += 1;
+        if self.frames == REPORT_FRAMES {
+            self.log();
+            *self = Self::new();
+        }
+        output
+    }
+
+    fn log(&self) {
+        for (phase, stats) in PHASES.iter().zip(self.stats.iter()) {
+            info!(
+                "Frame {} over {} frames: min {} us, avg {} us, max {} us",
+                phase,
+                self.frames,
+                stats.min / CYCLES_PER_US,
+                (stats.total / self.frames as u64) as u32 / CYCLES_PER_US,
+                stats.max / CYCLES_PER_US
+            );
+        }
+    }
+}
+
+/// Run `f`, which draws to the screen, counting its time towards rasterizing and flushing the current frame.
+pub fn drawing<R>(f: impl FnOnce() -> R) -> R {
+    timed(&DRAW_CYCLES, f)
+}
+
+/// Run `f`, which writes to the panel, counting its time towards flushing the current frame.
+pub fn flushing<R>(f: impl FnOnce() -> R) -> R {
+    timed(&FLUSH_CYCLES, f)
+}
+
+fn timed<R>(counter: &AtomicU32, f: impl FnOnce() -> R) -> R {
+    let start = DWT::cycle_count();
+    let result = f();
+    counter.fetch_add(DWT::cycle_count().wrapping_sub(start), Ordering::Relaxed);
+    result
+}
@@ -0,0 +1,237 @@
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use littlefs2::consts;
+use littlefs2::driver::Storage;
+use littlefs2::fs::Filesystem;
+use littlefs2::io::prelude::*;
+use littlefs2::io::{self, SeekFrom};
+use littlefs2::path::PathBuf;
+
+use crate::{layout, ExternalFlash};
+
+/// Start of the filesystem region on the external flash.
+pub const FS_OFFSET: u32 = layout::FS.start;
+/// Size of the filesystem region on the external flash.
+pub const FS_SIZE: usize = layout::FS.size as usize;
+
+const BLOCK_SIZE: usize = 4096;
+const PATH_MAX: usize = 64;
+
+type FsPartition<'a> = BlockingPartition<'a, CriticalSectionRawMutex, ExternalFlash>;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum Error {
+    InvalidPath,
+    NotFound,
+    Exists,
+    NoSpace,
+    Corrupt,
+    Io,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e {
+            io::Error::NoSuchEntry => Self::NotFound,
+            io::Error::EntryAlreadyExisted => Self::Exists,
+            io::Error::NoSpace => Self::NoSpace,
+            io::Error::Corruption => Self::Corrupt,
+            io::Error::Invalid | io::Error::FilenameTooLong | io::Error::PathNotDir | io::Error::PathIsDir => {
+                Self::InvalidPath
+            }
+            _ => Self::Io,
+        }
+    }
+}
+
+/// A directory entry as seen by `FileSystem::read_dir`.
+#[cfg(not(feature = "no-softdevice"))]
+pub struct Entry<'e> {
+    pub name: &'e str,
+    pub is_dir: bool,
+    pub size: usize,
+}
+
+/// littlefs block device backed by a partition of the external flash.
+pub struct FsStorage<'a> {
+    partition: FsPartition<'a>,
+}
+
+impl<'a> Storage for FsStorage<'a> {
+    const READ_SIZE: usize = 16;
+    const WRITE_SIZE: usize = 16;
+    const BLOCK_SIZE: usize = BLOCK_SIZE;
+    const BLOCK_COUNT: usize = FS_SIZE / BLOCK_SIZE;
+    const BLOCK_CYCLES: isize = 500;
+    type CACHE_SIZE = consts::U256;
+    type LOOKAHEAD_SIZE = consts::U1;
+
+    fn read(&mut self, off: usize, buf: &mut [u8]) -> io::Result<usize> {
+        self.partition.read(off as u32, buf).map_err(|_| io::Error::Io)?;
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, off: usize, data: &[u8]) -> io::Result<usize> {
+        self.partition.write(off as u32, data).map_err(|_| io::Error::Io)?;
+        Ok(data.len())
+    }
+
+    fn erase(&mut self, off: usize, len: usize) -> io::Result<usize> {
+        self.partition
+            .erase(off as u32, (off + len) as u32)
+            .map_err(|_| io::Error::Io)?;
+        Ok(len)
+    }
+}
+
+/// Filesystem on the external flash, shared between tasks.
+///
+/// Every operation mounts the filesystem for the duration of the call, so no littlefs state is kept in RAM
+/// between calls. The async lock serializes users so that a BLE transfer and the UI never interleave.
+pub struct FileSystem<'a> {
+    flash: &'a BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>>,
+    lock: Mutex<CriticalSectionRawMutex, ()>,
+}
+
+impl<'a> FileSystem<'a> {
+    pub fn new(flash: &'a BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>>) -> Self {
+        Self {
+            flash,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn storage(&self) -> FsStorage<'a> {
+        FsStorage {
+            partition: FsPartition::new(self.flash, FS_OFFSET, FS_SIZE as u32),
+        }
+    }
+
+    fn with_fs<R>(&self, f: impl FnOnce(&Filesystem<'_, FsStorage<'a>>) -> io::Result<R>) -> Result<R, Error> {
+        let mut storage = self.storage();
+        Ok(Filesystem::mount_and_then(&mut storage, f)?)
+    }
+
+    /// Mount the filesystem, formatting it if no valid filesystem is found.
+    pub async fn mount(&self) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        let mut storage = self.storage();
+        if !Filesystem::is_mountable(&mut storage) {
+            warn!("No filesystem found, formatting");
+            Filesystem::format(&mut storage)?;
+        }
+        let (total, available) = self.with_fs(|fs| Ok((fs.total_space(), fs.available_space()?)))?;
+        info!("Filesystem mounted, {} of {} bytes available", available, total);
+        Ok(())
+    }
+
+    /// Erase all files.
+    pub async fn format(&self) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        let mut storage = self.storage();
+        Filesystem::format(&mut storage)?;
+        Ok(())
+    }
+
+    pub async fn available(&self) -> Result<usize, Error> {
+        let _guard = self.lock.lock().await;
+        self.with_fs(|fs| fs.available_space())
+    }
+
+    /// Read from `path` starting at `offset`, returning the number of bytes read and the total file size.
+    pub async fn read(&self, path: &str, offset: u32, buf: &mut [u8]) -> Result<(usize, usize), Error> {
+        let path = to_path(path)?;
+        let _guard = self.lock.lock().await;
+        self.with_fs(|fs| {
+            fs.open_file_and_then(&path, |file| {
+                let len = file.len()?;
+                file.seek(SeekFrom::Start(offset))?;
+                let read = file.read(buf)?;
+                Ok((read, len))
+            })
+        })
+    }
+
+    /// Write `data` to `path` at `offset`, creating the file if needed. Writing at offset 0 truncates the file.
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    pub async fn write(&self, path: &str, offset: u32, data: &[u8]) -> Result<(), Error> {
+        let path = to_path(path)?;
+        let _guard = self.lock.lock().await;
+        self.with_fs(|fs| {
+            fs.open_file_with_options_and_then(
+                |options| options.write(true).create(true).truncate(offset == 0),
+                &path,
+                |file| {
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.write_all(data)
+                },
+            )
+        })
+    }
+
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    pub async fn remove(&self, path: &str) -> Result<(), Error> {
+        let path = to_path(path)?;
+        let _guard = self.lock.lock().await;
+        self.with_fs(|fs| {
+            if fs.metadata(&path)?.is_dir() {
+                fs.remove_dir(&path)
+            } else {
+                fs.remove(&path)
+            }
+        })
+    }
+
+    #[cfg(not(feature = "no-softdevice"))]
+    pub async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let path = to_path(path)?;
+        let _guard = self.lock.lock().await;
+        self.with_fs(|fs| fs.create_dir_all(&path))
+    }
+
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        let from = to_path(from)?;
+        let to = to_path(to)?;
+        let _guard = self.lock.lock().await;
+        self.with_fs(|fs| fs.rename(&from, &to))
+    }
+
+    /// Call `f` for each entry in the directory at `path`, skipping `.` and `..`.
+    #[cfg(not(feature = "no-softdevice"))]
+    pub async fn read_dir<F: FnMut(Entry<'_>)>(&self, path: &str, mut f: F) -> Result<(), Error> {
+        let path = to_path(path)?;
+        let _guard = self.lock.lock().await;
+        self.with_fs(|fs| {
+            fs.read_dir_and_then(&path, |dir| {
+                for entry in dir {
+                    let entry = entry?;
+                    let name = entry.file_name().as_str();
+                    if name == "." || name == ".." {
+                        continue;
+                    }
+                    let metadata = entry.metadata();
+                    f(Entry {
+                        name,
+                        is_dir: metadata.is_dir(),
+                        size: metadata.len(),
+                    });
+                }
+                Ok(())
+            })
+        })
+    }
+}
+
+fn to_path(path: &str) -> Result<PathBuf, Error> {
+    if path.is_empty() || path.len() > PATH_MAX || path.contains('\0') {
+        return Err(Error::InvalidPath);
+    }
+    Ok(PathBuf::from(path))
+}
@@ -0,0 +1,33 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{Duration, Instant};
+
+use crate::profile::{Task, TASKS};
+
+/// Time since boot in seconds by which each task must send its next heartbeat, 0 for tasks that are not monitored.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_DEADLINE: AtomicU32 = AtomicU32::new(0);
+static DEADLINES: [AtomicU32; TASKS.len()] = [NO_DEADLINE; TASKS.len()];
+
+/// Tell the supervisor that `task` is making progress, and that its next heartbeat comes within `next`.
+///
+/// Tasks that loop on a timer send a heartbeat each time round, allowing for a few missed rounds. Tasks that wait
+/// for events with no upper bound are not monitored.
+pub fn heartbeat(task: Task, next: Duration) {
+    let deadline = (Instant::now() + next).as_secs() as u32;
+    DEADLINES[task as usize].store(deadline.max(1), Ordering::Relaxed);
+}
+
+/// The first task that missed its heartbeat, if any.
+pub fn overdue() -> Option<Task> {
+    let now = Instant::now().as_secs() as u32;
+    TASKS.into_iter().find(|task| {
+        let deadline = DEADLINES[*task as usize].load(Ordering::Relaxed);
+        deadline != 0 && now > deadline
+    })
+}
+
+/// Stop monitoring `task`, once it goes back to waiting for events with no upper bound.
+pub fn stop(task: Task) {
+    DEADLINES[task as usize].store(0, Ordering::Relaxed);
+}
@@ -0,0 +1,406 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::activity::{self, timestamp};
+use crate::clock::Clock;
+use crate::device::{Hrs, SharedMotor};
+use crate::error::{self, Error};
+use crate::power::{Feature, PowerManager, Subsystem};
+use crate::profile::{profiled, Task};
+use crate::ringlog::{self, RingLog};
+use crate::settings::SettingsCache;
+use crate::{layout, sleep, LogPartition};
+
+/// Start of the heart rate log region on the external flash.
+pub const LOG_OFFSET: u32 = layout::HEART_RATE_LOG.start;
+/// Size of the heart rate log region.
+pub const LOG_SIZE: u32 = layout::HEART_RATE_LOG.size;
+
+/// Maximum time per day the sensor may be powered for background sampling.
+const DAILY_BUDGET: Duration = Duration::from_secs(15 * 60);
+
+const SAMPLE_RATE_HZ: u64 = 10;
+const SAMPLES: usize = 100;
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Resolution of the daily heart rate graph.
+const GRAPH_SLOT_SECS: u32 = 600;
+const GRAPH_SLOTS: usize = 24 * 3600 / GRAPH_SLOT_SECS as usize;
+
+/// Lower bounds of the workout heart rate zones 1 to 5, in percent of the maximum heart rate.
+const ZONES: [u32; 5] = [50, 60, 70, 80, 90];
+/// How far in bpm the heart rate must go back below a zone boundary to leave the zone, so that a heart rate
+/// hovering at a boundary does not alert on every measurement.
+const ZONE_HYSTERESIS: u8 = 3;
+const ZONE_UP_PULSE: Duration = Duration::from_millis(150);
+const ZONE_DOWN_PULSE: Duration = Duration::from_millis(600);
+const LIMIT_PULSE: Duration = Duration::from_millis(400);
+
+pub type SharedHrs = Mutex<CriticalSectionRawMutex, Hrs<'static>>;
+
+static INTERVAL: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+static WORKOUT: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+static STREAM: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+/// Result of the last measurement, whatever it was taken for.
+static MEASURED: Signal<CriticalSectionRawMutex, Option<u8>> = Signal::new();
+/// Latest heart rate of the running workout, 0 if none was detected.
+static WORKOUT_BPM: AtomicU8 = AtomicU8::new(0);
+
+/// Set the background sampling interval in minutes, 0 disables background sampling.
+pub fn set_interval(minutes: u8) {
+    INTERVAL.signal(minutes);
+}
+
+/// Start or stop measuring continuously for a workout.
+pub fn set_workout(active: bool) {
+    WORKOUT.signal(active);
+}
+
+/// Measures continuously while held, for a phone that uses the watch as a heart rate sensor. Background samples are
+/// left out meanwhile, and a workout takes over the sensor until it ends.
+#[cfg(not(feature = "no-softdevice"))]
+pub struct Streaming(());
+
+#[cfg(not(feature = "no-softdevice"))]
+impl Streaming {
+    pub fn start() -> Self {
+        MEASURED.reset();
+        STREAM.signal(true);
+        Self(())
+    }
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+impl Drop for Streaming {
+    fn drop(&mut self) {
+        STREAM.signal(false);
+    }
+}
+
+/// Wait for the next measurement, `None` if no pulse was detected, as when the watch is not worn.
+#[cfg(not(feature = "no-softdevice"))]
+pub async fn next_measurement() -> Option<u8> {
+    MEASURED.wait().await
+}
+
+/// The latest heart rate measured for the running workout, if any.
+pub fn workout_bpm() -> Option<u8> {
+    match WORKOUT_BPM.load(Ordering::Relaxed) {
+        0 => None,
+        bpm => Some(bpm),
+    }
+}
+
+/// Measure the heart rate in beats per minute. The sensor must be powered through the `PowerManager`.
+pub async fn measure(hrs: &mut Hrs<'static>) -> Option<u8> {
+    Timer::after(SETTLE_TIME).await;
+    let mut samples: Vec<u32, SAMPLES> = Vec::new();
+    while !samples.is_full() {
+        match hrs.read_hrs() {
+            Ok(value) => {
+                let _ = samples.push(value);
+            }
+            Err(e) => {
+                error::report(Error::HeartRate, e);
+                break;
+            }
+        }
+        Timer::after(Duration::from_hz(SAMPLE_RATE_HZ)).await;
+    }
+
+    let bpm = estimate_bpm(&samples, SAMPLE_RATE_HZ as u32);
+    MEASURED.signal(bpm);
+    bpm
+}
+
+/// Estimate the pulse from raw PPG samples by counting beats of the signal around its moving average.
+fn estimate_bpm(samples: &[u32], rate_hz: u32) -> Option<u8> {
+    const WINDOW: usize = 8;
+    let mut above = false;
+    let mut first = None;
+    let mut last = 0;
+    let mut beats = 0;
+    for i in WINDOW..samples.len() {
+        let average = samples[i - WINDOW..i].iter().sum::<u32>() / WINDOW as u32;
+        let now_above = samples[i] > average;
+        if now_above && !above {
+            if first.is_none() {
+                first.replace(i);
+            } else {
+                beats += 1;
+                last = i;
+            }
+        }
+        above = now_above;
+    }
+
+    let first = first?;
+    if beats < 2 {
+        return None;
+    }
+    let bpm = beats * 60 * rate_hz / (last - first) as u32;
+    (30..=220).contains(&bpm).then_some(bpm as u8)
+}
+
+/// Heart rate samples persisted in flash.
+pub struct HeartRateLog<'a> {
+    log: Mutex<CriticalSectionRawMutex, RingLog<LogPartition<'a>>>,
+}
+
+impl<'a> HeartRateLog<'a> {
+    pub fn new(log: RingLog<LogPartition<'a>>) -> Self {
+        Self { log: Mutex::new(log) }
+    }
+
+    pub async fn append(&self, timestamp: u32, bpm: u8) -> Result<u32, ringlog::Error> {
+        let mut record = [0; 5];
+        record[0..4].copy_from_slice(&timestamp.to_le_bytes());
+        record[4] = bpm;
+        self.log.lock().await.append(&record)
+    }
+
+    /// Visit all samples from oldest to newest as (record id, timestamp, bpm).
+    pub async fn for_each<F: FnMut(u32, u32, u8)>(&self, mut f: F) -> Result<(), ringlog::Error> {
+        self.log.lock().await.for_each(|id, data| {
+            if data.len() == 5 {
+                f(id, u32::from_le_bytes([data[0], data[1], data[2], data[3]]), data[4]);
+            }
+        })
+    }
+
+    /// The most recent sample as (timestamp, bpm), if any.
+    pub async fn last(&self) -> Result<Option<(u32, u8)>, ringlog::Error> {
+        let mut last = None;
+        self.for_each(|_, timestamp, bpm| last = Some((timestamp, bpm))).await?;
+        Ok(last)
+    }
+
+    /// Visit encoded samples with an id of at least `start_id` until `f` returns false, used for data export.
+    #[cfg(not(feature = "no-softdevice"))]
+    pub async fn read_from<F: FnMut(u32, &[u8]) -> bool>(&self, start_id: u32, f: F) -> Result<(), ringlog::Error> {
+        self.log.lock().await.read_from(start_id, f)
+    }
+
+    /// Bytes used by the log and its capacity.
+    pub async fn usage(&self) -> Result<(u32, u32), ringlog::Error> {
+        self.log.lock().await.usage()
+    }
+
+    pub async fn erase_ahead(&self) -> Result<bool, ringlog::Error> {
+        self.log.lock().await.erase_ahead()
+    }
+
+    /// Samples of the 24 hours starting at `since`, bucketed for the daily graph.
+    pub async fn daily(&self, since: u32) -> Result<Vec<u16, GRAPH_SLOTS>, ringlog::Error> {
+        let mut slots = [0; GRAPH_SLOTS];
+        self.for_each(|_, timestamp, bpm| {
+            if timestamp >= since {
+                if let Some(slot) = slots.get_mut(((timestamp - since) / GRAPH_SLOT_SECS) as usize) {
+                    *slot = bpm as u16;
+                }
+            }
+        })
+        .await?;
+        Ok(Vec::from_slice(&slots).unwrap_or_default())
+    }
+}
+
+/// Zone 1 to 5 of `bpm` for a maximum heart rate of `max`, 0 below zone 1.
+fn zone(bpm: u8, max: u8) -> u8 {
+    ZONES
+        .iter()
+        .filter(|percent| bpm as u32 * 100 >= **percent * max as u32)
+        .count() as u8
+}
+
+/// Vibration alerts during a workout.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+enum ZoneAlert {
+    /// Entered a higher zone, one short pulse per zone.
+    Up(u8),
+    /// Dropped to a lower zone, one long pulse.
+    Down(u8),
+    /// Above the maximum heart rate, three pulses on every measurement until it comes down.
+    Limit,
+}
+
+impl ZoneAlert {
+    async fn vibrate(self, motor: &SharedMotor) {
+        let mut motor = motor.lock().await;
+        match self {
+            Self::Up(zone) => motor.pulses(zone, ZONE_UP_PULSE).await,
+            Self::Down(_) => motor.pulses(1, ZONE_DOWN_PULSE).await,
+            Self::Limit => motor.pulses(3, LIMIT_PULSE).await,
+        }
+    }
+}
+
+/// Follows the heart rate zone over a workout.
+struct Zones {
+    zone: u8,
+}
+
+impl Zones {
+    fn update(&mut self, bpm: u8, max: u8) -> Option<ZoneAlert> {
+        let previous = self.zone;
+        let current = zone(bpm, max);
+        if current > previous || zone(bpm.saturating_add(ZONE_HYSTERESIS), max) < previous {
+            self.zone = current;
+        }
+        if bpm > max {
+            Some(ZoneAlert::Limit)
+        } else if self.zone > previous {
+            Some(ZoneAlert::Up(self.zone))
+        } else if self.zone < previous {
+            Some(ZoneAlert::Down(self.zone))
+        } else {
+            None
+        }
+    }
+}
+
+/// Measure continuously until the workout ends, alerting on zone changes and above the maximum heart rate. This
+/// runs here rather than in the UI so that the alerts do not depend on the screen.
+async fn workout(hrs: &SharedHrs, power: &PowerManager, motor: &SharedMotor, settings: &SettingsCache) {
+    info!("Workout started");
+    let _power = power.acquire(Subsystem::HeartRate).await;
+    let mut hrs = hrs.lock().await;
+    let mut zones = Zones { zone: 0 };
+    let measuring = async {
+        loop {
+            let bpm = measure(&mut *hrs).await;
+            WORKOUT_BPM.store(bpm.unwrap_or(0), Ordering::Relaxed);
+            let Some(bpm) = bpm else {
+                continue;
+            };
+            let settings = settings.get();
+            match zones.update(bpm, settings.hr_max) {
+                Some(alert) if alert == ZoneAlert::Limit || settings.hr_zone_alerts => {
+                    info!("Heart rate {} alert: {:?}", bpm, alert);
+                    alert.vibrate(motor).await;
+                }
+                _ => {}
+            }
+        }
+    };
+    let ended = async { while WORKOUT.wait().await {} };
+    select(measuring, ended).await;
+    WORKOUT_BPM.store(0, Ordering::Relaxed);
+    info!("Workout ended");
+}
+
+/// Measure continuously until streaming stops, returning its next state, or until a workout starts or ends, returning
+/// the workout's, see `Streaming`.
+async fn stream(hrs: &SharedHrs, power: &PowerManager) -> Either<bool, bool> {
+    let _power = power.acquire(Subsystem::HeartRate).await;
+    let mut hrs = hrs.lock().await;
+    let measuring = async {
+        loop {
+            measure(&mut *hrs).await;
+        }
+    };
+    match select(select(STREAM.wait(), WORKOUT.wait()), measuring).await {
+        Either::First(changed) => changed,
+        // Measuring never ends.
+        Either::Second(_) => Either::First(false),
+    }
+}
+
+/// Samples the heart rate in the background every few minutes, and continuously during workouts and while streamed to
+/// a phone.
+#[embassy_executor::task]
+pub async fn heart_rate_task(
+    hrs: &'static SharedHrs,
+    power: &'static PowerManager,
+    log: Option<&'static HeartRateLog<'static>>,
+    motor: &'static SharedMotor,
+    settings: &'static SettingsCache,
+    clock: &'static Clock,
+) {
+    profiled(Task::HeartRate, async move {
+        let mut interval = 0;
+        let mut used = Duration::from_ticks(0);
+        let mut day = clock.get().date();
+        loop {
+            let next_sample = async move {
+                match interval {
+                    0 => core::future::pending().await,
+                    minutes => Timer::after(Duration::from_secs(minutes as u64 * 60)).await,
+                }
+            };
+            match select4(next_sample, INTERVAL.wait(), WORKOUT.wait(), STREAM.wait()).await {
+                Either4::First(_) => {}
+                Either4::Second(i) => {
+                    interval = i;
+                    continue;
+                }
+                Either4::Third(active) => {
+                    if active {
+                        workout(hrs, power, motor, settings).await;
+                    }
+                    continue;
+                }
+                Either4::Fourth(mut streaming) => {
+                    if !streaming {
+                        continue;
+                    }
+                    info!("Heart rate streaming started");
+                    while streaming {
+                        match stream(hrs, power).await {
+                            Either::First(active) => streaming = active,
+                            // Streaming carries on once the workout ends.
+                            Either::Second(true) => workout(hrs, power, motor, settings).await,
+                            Either::Second(false) => {}
+                        }
+                    }
+                    info!("Heart rate streaming stopped");
+                    continue;
+                }
+            }
+
+            let now = clock.get();
+            if now.date() != day {
+                day = now.date();
+                used = Duration::from_ticks(0);
+            }
+            if used >= DAILY_BUDGET {
+                info!("Heart rate sampling budget exhausted for today");
+                continue;
+            }
+            if !power.allows(Feature::BackgroundHeartRate) {
+                info!("Battery low, skipping background heart rate");
+                continue;
+            }
+
+            let start = Instant::now();
+            let bpm = {
+                let _power = power.acquire(Subsystem::HeartRate).await;
+                measure(&mut *hrs.lock().await).await
+            };
+            used += start.elapsed();
+
+            match bpm {
+                Some(bpm) => {
+                    info!("Background heart rate: {}", bpm);
+                    if sleep::still() {
+                        activity::add_resting_heart_rate(bpm);
+                    }
+                    if let Some(log) = log {
+                        if let Err(e) = log.append(timestamp(clock.get()), bpm).await {
+                            warn!("Error logging heart rate: {:?}", e);
+                        }
+                    }
+                }
+                None => info!("No heart rate detected"),
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,198 @@
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
+use embassy_futures::select::{select, Either};
+use embassy_nrf::gpio::{AnyPin, Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::peripherals::TWISPI1;
+use embassy_nrf::{pac, twim};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use watchful_ui::ButtonEvent;
+
+use crate::board::{self, TouchPins};
+use crate::error::{self, Error};
+use crate::power::Gated;
+use crate::variant::{self, TouchController};
+use crate::{events, I2cBus};
+
+pub type Touchpad<'a> = cst816s::CST816S<
+    I2cDevice<'a, CriticalSectionRawMutex, Gated<twim::Twim<'a, TWISPI1>>>,
+    Input<'a, AnyPin>,
+    Output<'a, AnyPin>,
+>;
+
+const LONG_PRESS_TIME: Duration = Duration::from_secs(2);
+const RESET_HOLD_TIME: Duration = Duration::from_secs(8);
+
+/// Touch event actions reported by the controller.
+const TOUCH_DOWN: u8 = 0;
+const TOUCH_UP: u8 = 1;
+/// Distance a touch must move, in pixels, to be a swipe rather than a tap.
+const SWIPE_DISTANCE: i32 = 40;
+/// Where the current touch started as `x << 16 | y`, for recognizing gestures on the CST716.
+const NO_TOUCH: u32 = u32::MAX;
+static TOUCH_START: AtomicU32 = AtomicU32::new(NO_TOUCH);
+
+pub struct Button {
+    pin: Input<'static, AnyPin>,
+}
+
+impl Button {
+    pub fn new(pin: AnyPin) -> Self {
+        let pull = if board::BUTTON_ACTIVE_HIGH {
+            Pull::Down
+        } else {
+            Pull::Up
+        };
+        Self {
+            pin: Input::new(pin, pull),
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pin.is_high() == board::BUTTON_ACTIVE_HIGH
+    }
+
+    async fn wait_for_release(&mut self) {
+        if board::BUTTON_ACTIVE_HIGH {
+            self.pin.wait_for_falling_edge().await
+        } else {
+            self.pin.wait_for_rising_edge().await
+        }
+    }
+
+    /// Wait for the button to be pressed and released, or for a button event from the debug shell. Holding the
+    /// button for 8 seconds resets the watch.
+    pub async fn wait(&mut self) -> ButtonEvent {
+        match select(self.wait_pressed(), events::injected_button()).await {
+            Either::First(event) | Either::Second(event) => event,
+        }
+    }
+
+    async fn wait_pressed(&mut self) -> ButtonEvent {
+        self.pin.wait_for_any_edge().await;
+        if self.is_pressed() {
+            let pressed = Instant::now();
+            match select(Timer::after(RESET_HOLD_TIME), self.wait_for_release()).await {
+                Either::First(_) => {
+                    if self.is_pressed() {
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                }
+                Either::Second(_) => {
+                    if pressed.elapsed() >= LONG_PRESS_TIME {
+                        return ButtonEvent::LongPress;
+                    }
+                }
+            }
+        }
+        ButtonEvent::ShortPress
+    }
+
+    /// Make pressing the button wake the watch from System OFF.
+    pub fn enable_wakeup(&self) {
+        let p0 = unsafe { &*pac::P0::ptr() };
+        p0.pin_cnf[board::BUTTON_PIN as usize].modify(|_, w| {
+            if board::BUTTON_ACTIVE_HIGH {
+                w.sense().high()
+            } else {
+                w.sense().low()
+            }
+        });
+    }
+}
+
+/// Set up the CST816S touch controller, which shares the I2C bus with the heart rate sensor. If the controller does
+/// not respond, the UI is driven with the button only.
+pub fn init_touchpad(i2c_bus: &'static I2cBus, pins: TouchPins) -> Option<Touchpad<'static>> {
+    let touch_int = Input::new(pins.int, Pull::Up);
+    let touch_rst = Output::new(pins.reset, Level::High, OutputDrive::Standard);
+
+    let i2c = I2cDevice::new(i2c_bus);
+    let mut touchpad = cst816s::CST816S::new(i2c, touch_int, touch_rst);
+    let setup = touchpad.setup(&mut embassy_time::Delay);
+    error::recover(setup, Error::Touch).map(|_| touchpad)
+}
+
+/// Wait for the touch controller to signal a touch on its interrupt line, or for a touch from the debug shell.
+///
+/// The touch driver owns the pin but only reads it when polled, so a second handle is used to wait for the edge.
+/// The handle is never dropped, as dropping it would disconnect the pin from the driver.
+pub async fn wait_touch() {
+    let touched = async {
+        let Some(pin) = board::TOUCH_INT_PIN else {
+            return core::future::pending().await;
+        };
+        let mut int = ManuallyDrop::new(Input::new(unsafe { AnyPin::steal(pin) }, Pull::Up));
+        int.wait_for_falling_edge().await;
+    };
+    // A touch from the debug shell wakes the watch, and is not a gesture for the next screen either.
+    select(touched, events::injected_touch()).await;
+}
+
+/// Read a pending touch event, from the debug shell or the touchpad if the board has one.
+pub fn read_touch(touchpad: &mut Option<Touchpad<'static>>) -> Option<cst816s::TouchEvent> {
+    events::take_injected_touch().or_else(|| {
+        touchpad
+            .as_mut()?
+            .read_one_touch_event(true)
+            .and_then(recognize_gesture)
+    })
+}
+
+/// The CST716 reports where a touch goes down and lifts up but not the gesture, so taps and swipes are recognized
+/// from how far the touch moved, and other events are left out. Events of other controllers are passed through.
+fn recognize_gesture(mut evt: cst816s::TouchEvent) -> Option<cst816s::TouchEvent> {
+    if variant::touch() != Some(TouchController::Cst716) {
+        return Some(evt);
+    }
+    match evt.action {
+        TOUCH_DOWN => {
+            TOUCH_START.store((evt.x as u32) << 16 | evt.y as u32 & 0xFFFF, Ordering::Relaxed);
+            None
+        }
+        TOUCH_UP => {
+            let start = TOUCH_START.swap(NO_TOUCH, Ordering::Relaxed);
+            let (dx, dy) = if start == NO_TOUCH {
+                (0, 0)
+            } else {
+                (evt.x - (start >> 16) as i32, evt.y - (start & 0xFFFF) as i32)
+            };
+            evt.gesture = if dx.abs() < SWIPE_DISTANCE && dy.abs() < SWIPE_DISTANCE {
+                cst816s::TouchGesture::SingleClick
+            } else if dx.abs() > dy.abs() {
+                if dx < 0 {
+                    cst816s::TouchGesture::SlideLeft
+                } else {
+                    cst816s::TouchGesture::SlideRight
+                }
+            } else if dy < 0 {
+                cst816s::TouchGesture::SlideUp
+            } else {
+                cst816s::TouchGesture::SlideDown
+            };
+            Some(evt)
+        }
+        _ => None,
+    }
+}
+
+/// Wait for a swipe or tap on the touchpad, or from the debug shell. Never returns without either.
+pub async fn wait_gesture(touchpad: &mut Option<Touchpad<'static>>) -> cst816s::TouchGesture {
+    let touched = async {
+        let Some(touchpad) = touchpad else {
+            return core::future::pending().await;
+        };
+        loop {
+            if let Some(evt) = touchpad.read_one_touch_event(true).and_then(recognize_gesture) {
+                return evt.gesture;
+            }
+            Timer::after(Duration::from_millis(20)).await;
+        }
+    };
+    match select(touched, events::injected_touch()).await {
+        Either::First(gesture) => gesture,
+        Either::Second(evt) => evt.gesture,
+    }
+}
@@ -0,0 +1,308 @@
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_storage::nor_flash::NorFlash;
+use nrf_dfu::crc::crc32;
+
+use crate::{layout, KvPartition};
+
+/// Start of the key-value store region on the external flash.
+pub const KV_OFFSET: u32 = layout::KV.start;
+/// Size of the key-value store region.
+pub const KV_SIZE: u32 = layout::KV.size;
+
+const SECTOR_MAGIC: u32 = 0x5356_4B57;
+// Magic and sequence number of the sector.
+const SECTOR_HEADER_SIZE: u32 = 8;
+// Key, length and CRC of the value.
+const RECORD_HEADER_SIZE: usize = 8;
+const ERASED_KEY: u16 = 0xFFFF;
+
+/// Largest value that can be stored under a single key.
+pub const MAX_VALUE_SIZE: usize = 64;
+
+/// Keys of the values stored in the key-value store. Keys must never be reused for a different kind of value.
+pub mod keys {
+    pub const SETTINGS: u16 = 1;
+    pub const FLASH_ERASES: u16 = 2;
+    pub const BATTERY_HEALTH: u16 = 3;
+    pub const STEPS: u16 = 4;
+    #[cfg(not(feature = "no-softdevice"))]
+    pub const DFU_PROGRESS: u16 = 5;
+    /// The DFU command object, `MAX_VALUE_SIZE` bytes under each of this key and the next 3.
+    #[cfg(not(feature = "no-softdevice"))]
+    pub const DFU_COMMAND: u16 = 6;
+}
+
+pub type SharedKv<'a> = Mutex<CriticalSectionRawMutex, KvStore<KvPartition<'a>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum Error {
+    Flash,
+    TooLarge,
+    /// The live values no longer fit in a single sector.
+    Full,
+}
+
+/// Log-structured key-value store for small values that change often.
+///
+/// Values are appended to the active sector, the most recent record for a key wins. When the active sector is
+/// full, the live values are copied to the next sector in the region, which then becomes active, so erases
+/// rotate through all sectors. The sector header of the new sector is written last, so a reset during
+/// compaction leaves the old sector active.
+pub struct KvStore<F: NorFlash> {
+    flash: F,
+    sectors: u32,
+    current: u32,
+    offset: u32,
+    seq: u32,
+    // Bytes of the active sector in use right after it was compacted, or when the store was opened.
+    live: u32,
+}
+
+struct RecordHeader {
+    key: u16,
+    len: u16,
+    crc: u32,
+}
+
+impl<F: NorFlash> KvStore<F> {
+    /// Open the store in `flash`, recovering the active sector from the existing contents.
+    pub fn new(flash: F) -> Result<Self, Error> {
+        let sectors = flash.capacity() as u32 / F::ERASE_SIZE as u32;
+        let mut store = Self {
+            flash,
+            sectors,
+            current: 0,
+            offset: SECTOR_HEADER_SIZE,
+            seq: 0,
+            live: SECTOR_HEADER_SIZE,
+        };
+
+        let mut newest: Option<(u32, u32)> = None;
+        for sector in 0..sectors {
+            if let Some(seq) = store.read_sector_header(sector)? {
+                if newest.map(|(_, s)| seq > s).unwrap_or(true) {
+                    newest.replace((sector, seq));
+                }
+            }
+        }
+
+        match newest {
+            Some((sector, seq)) => {
+                store.current = sector;
+                store.seq = seq;
+                let mut offset = SECTOR_HEADER_SIZE;
+                while let Some(header) = store.read_record_header(sector, offset)? {
+                    offset += record_size(header.len as usize);
+                }
+                store.offset = offset;
+                store.live = offset;
+            }
+            None => {
+                info!("Formatting key-value store");
+                store.format()?;
+            }
+        }
+        Ok(store)
+    }
+
+    /// Read the value stored under `key` into `buf`, returning its length.
+    pub fn get(&mut self, key: u16, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let Some(offset) = self.find(self.current, key)? else {
+            return Ok(None);
+        };
+        let header = self.read_record_header(self.current, offset)?.ok_or(Error::Flash)?;
+        let len = header.len as usize;
+        if len > buf.len() {
+            return Err(Error::TooLarge);
+        }
+        self.read_value(self.current, offset, &mut buf[..len])?;
+        Ok(Some(len))
+    }
+
+    /// Store `value` under `key`, replacing any previous value.
+    pub fn set(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let mut current = [0; MAX_VALUE_SIZE];
+        if let Some(len) = self.get(key, &mut current)? {
+            if current[..len] == *value {
+                return Ok(());
+            }
+        }
+
+        if self.offset + record_size(value.len()) > F::ERASE_SIZE as u32 {
+            self.compact(Some((key, value)))
+        } else {
+            let (sector, offset) = (self.current, self.offset);
+            self.write_record(sector, offset, key, value)?;
+            self.offset += record_size(value.len());
+            Ok(())
+        }
+    }
+
+    /// Move the live values to the next sector, dropping superseded records.
+    pub fn compact(&mut self, update: Option<(u16, &[u8])>) -> Result<(), Error> {
+        let next = (self.current + 1) % self.sectors;
+        let address = self.address(next, 0);
+        self.flash
+            .erase(address, address + F::ERASE_SIZE as u32)
+            .map_err(|_| Error::Flash)?;
+
+        let mut write_offset = SECTOR_HEADER_SIZE;
+        let mut buf = [0; MAX_VALUE_SIZE];
+        let mut offset = SECTOR_HEADER_SIZE;
+        while let Some(header) = self.read_record_header(self.current, offset)? {
+            let skip = update.map(|(key, _)| key == header.key).unwrap_or(false)
+                || self.find(self.current, header.key)? != Some(offset);
+            if !skip {
+                let value = &mut buf[..header.len as usize];
+                self.read_value(self.current, offset, value)?;
+                if crc32(value) == header.crc {
+                    if write_offset + record_size(value.len()) > F::ERASE_SIZE as u32 {
+                        return Err(Error::Full);
+                    }
+                    self.write_record(next, write_offset, header.key, value)?;
+                    write_offset += record_size(value.len());
+                }
+            }
+            offset += record_size(header.len as usize);
+        }
+
+        if let Some((key, value)) = update {
+            if write_offset + record_size(value.len()) > F::ERASE_SIZE as u32 {
+                return Err(Error::Full);
+            }
+            self.write_record(next, write_offset, key, value)?;
+            write_offset += record_size(value.len());
+        }
+
+        let seq = self.seq.wrapping_add(1);
+        self.write_sector_header(next, seq)?;
+        self.current = next;
+        self.seq = seq;
+        self.offset = write_offset;
+        self.live = write_offset;
+        Ok(())
+    }
+
+    /// Compact once the active sector is mostly full and at least a quarter of it holds superseded values, so that
+    /// setting a value does not stall on an erase. Returns true if the store was compacted.
+    pub fn compact_ahead(&mut self) -> Result<bool, Error> {
+        let quarter = F::ERASE_SIZE as u32 / 4;
+        if self.free() >= quarter || self.offset - self.live < quarter {
+            return Ok(false);
+        }
+        self.compact(None)?;
+        Ok(true)
+    }
+
+    /// Bytes left in the active sector before the next compaction.
+    pub fn free(&self) -> u32 {
+        F::ERASE_SIZE as u32 - self.offset
+    }
+
+    /// Erase all values.
+    pub fn format(&mut self) -> Result<(), Error> {
+        self.flash
+            .erase(0, self.sectors * F::ERASE_SIZE as u32)
+            .map_err(|_| Error::Flash)?;
+        self.write_sector_header(0, 0)?;
+        self.current = 0;
+        self.seq = 0;
+        self.offset = SECTOR_HEADER_SIZE;
+        self.live = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    /// Offset of the most recent valid record for `key` in `sector`.
+    fn find(&mut self, sector: u32, key: u16) -> Result<Option<u32>, Error> {
+        let mut found = None;
+        let mut offset = SECTOR_HEADER_SIZE;
+        let mut buf = [0; MAX_VALUE_SIZE];
+        while let Some(header) = self.read_record_header(sector, offset)? {
+            if header.key == key {
+                let value = &mut buf[..header.len as usize];
+                self.read_value(sector, offset, value)?;
+                if crc32(value) == header.crc {
+                    found.replace(offset);
+                } else {
+                    warn!("Skipping corrupt value for key {}", key);
+                }
+            }
+            offset += record_size(header.len as usize);
+        }
+        Ok(found)
+    }
+
+    fn write_record(&mut self, sector: u32, offset: u32, key: u16, value: &[u8]) -> Result<(), Error> {
+        let size = record_size(value.len()) as usize;
+        let mut buf = [0xFF; RECORD_HEADER_SIZE + MAX_VALUE_SIZE];
+        buf[0..2].copy_from_slice(&key.to_le_bytes());
+        buf[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        buf[4..8].copy_from_slice(&crc32(value).to_le_bytes());
+        buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + value.len()].copy_from_slice(value);
+        let address = self.address(sector, offset);
+        self.flash.write(address, &buf[..size]).map_err(|_| Error::Flash)
+    }
+
+    fn read_value(&mut self, sector: u32, offset: u32, value: &mut [u8]) -> Result<(), Error> {
+        self.flash
+            .read(self.address(sector, offset) + RECORD_HEADER_SIZE as u32, value)
+            .map_err(|_| Error::Flash)
+    }
+
+    fn write_sector_header(&mut self, sector: u32, seq: u32) -> Result<(), Error> {
+        let mut header = [0; SECTOR_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&seq.to_le_bytes());
+        let address = self.address(sector, 0);
+        self.flash.write(address, &header).map_err(|_| Error::Flash)
+    }
+
+    fn read_sector_header(&mut self, sector: u32) -> Result<Option<u32>, Error> {
+        let mut header = [0; SECTOR_HEADER_SIZE as usize];
+        self.flash
+            .read(self.address(sector, 0), &mut header)
+            .map_err(|_| Error::Flash)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if magic != SECTOR_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes([header[4], header[5], header[6], header[7]])))
+    }
+
+    fn read_record_header(&mut self, sector: u32, offset: u32) -> Result<Option<RecordHeader>, Error> {
+        if offset + RECORD_HEADER_SIZE as u32 > F::ERASE_SIZE as u32 {
+            return Ok(None);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE];
+        self.flash
+            .read(self.address(sector, offset), &mut header)
+            .map_err(|_| Error::Flash)?;
+        let key = u16::from_le_bytes([header[0], header[1]]);
+        let len = u16::from_le_bytes([header[2], header[3]]);
+        if key == ERASED_KEY
+            || len as usize > MAX_VALUE_SIZE
+            || offset + record_size(len as usize) > F::ERASE_SIZE as u32
+        {
+            // Erased flash or a corrupt length, either way this is the end of the sector.
+            return Ok(None);
+        }
+        Ok(Some(RecordHeader {
+            key,
+            len,
+            crc: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+        }))
+    }
+
+    fn address(&self, sector: u32, offset: u32) -> u32 {
+        sector * F::ERASE_SIZE as u32 + offset
+    }
+}
+
+fn record_size(len: usize) -> u32 {
+    ((RECORD_HEADER_SIZE + len + 3) & !3) as u32
+}
@@ -0,0 +1,152 @@
+/// Memory map of the watch. The regions of the internal flash and RAM must match `memory.x` of both the application
+/// and the bootloader, which `build.rs` checks by including this file. The asserts below fail the build if regions
+/// overlap, leave their device or are not aligned to erase pages.
+///
+/// Adding a region of the external flash means adding it to `EXTERNAL`, in address order.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Region {
+    pub start: u32,
+    pub size: u32,
+}
+
+impl Region {
+    pub const fn new(start: u32, size: u32) -> Self {
+        Self { start, size }
+    }
+
+    pub const fn end(&self) -> u32 {
+        self.start + self.size
+    }
+
+    pub const fn contains(&self, address: u32) -> bool {
+        address >= self.start && address < self.end()
+    }
+
+    const fn aligned(&self, page: u32) -> bool {
+        self.start % page == 0 && self.size % page == 0
+    }
+}
+
+const K: u32 = 1024;
+
+/// Erase page of the nRF52832 flash.
+pub const PAGE_SIZE: u32 = 4 * K;
+pub const INTERNAL_FLASH_SIZE: u32 = 512 * K;
+
+/// Master boot record, forwarding interrupts to the SoftDevice and starting the bootloader.
+pub const MBR: Region = Region::new(0x0000_0000, 4 * K);
+/// S132 SoftDevice.
+pub const SOFTDEVICE: Region = Region::new(MBR.end(), 148 * K);
+/// The running firmware, `ACTIVE` to the bootloader.
+pub const APP: Region = Region::new(SOFTDEVICE.end(), 320 * K);
+/// BLE bonds, kept outside of the application so they survive firmware swaps.
+pub const BONDS: Region = Region::new(APP.end(), 4 * K);
+pub const BOOTLOADER: Region = Region::new(BONDS.end(), 28 * K);
+/// Where the MBR keeps a bootloader copy under way, to finish it after a reset. The bootloader sets its address in the
+/// UICR, see `firmware::install_staged`.
+pub const MBR_PARAMS: Region = Region::new(BOOTLOADER.end(), 4 * K);
+pub const BOOTLOADER_STATE: Region = Region::new(MBR_PARAMS.end(), 4 * K);
+
+/// Without the SoftDevice (`no-softdevice` feature), the firmware starts at the bottom of the flash, in place of the
+/// MBR and SoftDevice, and has all of RAM. Everything above the application stays where it is.
+pub const APP_WITHOUT_SOFTDEVICE: Region = Region::new(MBR.start, APP.end());
+
+const INTERNAL: [Region; 7] = [MBR, SOFTDEVICE, APP, BONDS, BOOTLOADER, MBR_PARAMS, BOOTLOADER_STATE];
+
+pub const RAM_START: u32 = 0x2000_0000;
+pub const RAM_SIZE: u32 = 64 * K;
+/// RAM reserved for the SoftDevice with the configuration in `ble::enable_softdevice`. The SoftDevice logs the
+/// start address it needs when it is enabled. Raised by 2 kB for the link buffers of the 247 byte ATT MTU.
+pub const SOFTDEVICE_RAM: Region = Region::new(RAM_START, 0xC2F0);
+pub const APP_RAM: Region = Region::new(SOFTDEVICE_RAM.end(), RAM_SIZE - SOFTDEVICE_RAM.size);
+pub const RAM: Region = Region::new(RAM_START, RAM_SIZE);
+
+/// Erase sector of the external SPI NOR flash.
+pub const SECTOR_SIZE: u32 = 4 * K;
+pub const EXTERNAL_FLASH_SIZE: u32 = 4 * K * K;
+
+/// Firmware updates are written here before the bootloader swaps them in.
+pub const DFU: Region = Region::new(0x0000_0000, APP.size + 2 * PAGE_SIZE);
+/// Tells the bootloader a SoftDevice is waiting in `STAGING` to be installed.
+pub const STAGING_HEADER: Region = Region::new(0x0008_0000, SECTOR_SIZE);
+/// SoftDevice and bootloader updates, which are installed in place rather than swapped, so they are received here
+/// instead of the DFU partition. A SoftDevice comes first, followed by the bootloader.
+pub const STAGING: Region = Region::new(STAGING_HEADER.end(), SOFTDEVICE.size + BOOTLOADER.size);
+/// Activity log, 16 sectors is a bit more than a year of hourly records.
+pub const ACTIVITY_LOG: Region = Region::new(0x0010_0000, 64 * K);
+/// Heart rate log, about three weeks of 10 minute samples.
+pub const HEART_RATE_LOG: Region = Region::new(0x0011_0000, 64 * K);
+pub const CRASH_LOG: Region = Region::new(0x0012_0000, 16 * K);
+/// Key-value store, holding the settings among others.
+pub const KV: Region = Region::new(0x0013_0000, 16 * K);
+/// Sleep log, years of nightly sessions.
+pub const SLEEP_LOG: Region = Region::new(0x0014_0000, 16 * K);
+/// Resource packs sent with the DFU tooling are received here, then copied to the file system once checked.
+pub const ASSETS: Region = Region::new(0x0015_0000, 512 * K);
+/// littlefs file system.
+pub const FS: Region = Region::new(0x0020_0000, 2 * K * K);
+
+const EXTERNAL: [Region; 10] = [
+    DFU,
+    STAGING_HEADER,
+    STAGING,
+    ACTIVITY_LOG,
+    HEART_RATE_LOG,
+    CRASH_LOG,
+    KV,
+    SLEEP_LOG,
+    ASSETS,
+    FS,
+];
+
+/// Whether `regions` are in address order, do not overlap, are aligned to `page` and fit in `size` bytes from
+/// `start`.
+const fn valid(regions: &[Region], start: u32, size: u32, page: u32) -> bool {
+    let mut end = start;
+    let mut i = 0;
+    while i < regions.len() {
+        let region = regions[i];
+        if region.start < end || region.size == 0 || !region.aligned(page) {
+            return false;
+        }
+        end = region.end();
+        i += 1;
+    }
+    end <= start + size
+}
+
+/// Whether `regions` follow each other with no gap, from `start` on.
+const fn contiguous(regions: &[Region], start: u32) -> bool {
+    let mut end = start;
+    let mut i = 0;
+    while i < regions.len() {
+        if regions[i].start != end {
+            return false;
+        }
+        end = regions[i].end();
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(
+    valid(&INTERNAL, 0, INTERNAL_FLASH_SIZE, PAGE_SIZE),
+    "internal flash regions overlap, are unaligned or do not fit"
+);
+const _: () = assert!(contiguous(&INTERNAL, 0), "internal flash regions leave a gap");
+const _: () = assert!(
+    BOOTLOADER_STATE.end() == INTERNAL_FLASH_SIZE,
+    "the bootloader state must be the last page"
+);
+const _: () = assert!(
+    valid(&EXTERNAL, 0, EXTERNAL_FLASH_SIZE, SECTOR_SIZE),
+    "external flash regions overlap, are unaligned or do not fit"
+);
+const _: () = assert!(
+    DFU.size >= APP.size + PAGE_SIZE,
+    "the bootloader needs a page more than the application to swap firmware"
+);
+const _: () = assert!(
+    contiguous(&[SOFTDEVICE_RAM, APP_RAM], RAM_START) && APP_RAM.end() == RAM_START + RAM_SIZE,
+    "RAM regions do not cover the RAM"
+);
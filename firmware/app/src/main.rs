@@ -3,42 +3,119 @@
 
 use core::cell::RefCell;
 
-use defmt::info;
+use defmt::{info, warn};
+// With the shell, RTT is set up by the shell, which also provides the defmt logger.
+#[cfg(all(feature = "log-rtt", not(feature = "shell")))]
 use defmt_rtt as _;
-use display_interface_spi::SPIInterface;
 use embassy_boot_nrf::{AlignedBuffer, FirmwareState};
+#[cfg(feature = "no-softdevice")]
+use embassy_embedded_hal::adapter::BlockingAsync;
 use embassy_embedded_hal::flash::partition::{BlockingPartition, Partition};
 use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
 use embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice;
-use embassy_executor::Spawner;
-use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pin, Pull};
-use embassy_nrf::interrupt::Priority;
-use embassy_nrf::peripherals::{P0_05, TWISPI0, TWISPI1};
+use embassy_executor::{InterruptExecutor, SendSpawner, SpawnToken, Spawner};
+use embassy_nrf::gpio::{AnyPin, Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::interrupt::{InterruptExt, Priority};
+#[cfg(feature = "no-softdevice")]
+use embassy_nrf::nvmc::Nvmc;
+use embassy_nrf::peripherals::{TWISPI0, TWISPI1};
 use embassy_nrf::spim::Spim;
 use embassy_nrf::spis::MODE_3;
 use embassy_nrf::twim::Twim;
-use embassy_nrf::{bind_interrupts, pac, peripherals, saadc, spim, twim};
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_nrf::{bind_interrupts, interrupt, peripherals, saadc, spim, twim};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::blocking_mutex::Mutex as BMutex;
 use embassy_sync::mutex::Mutex;
-use embassy_time::{Delay, Duration, Timer};
-use heapless::Vec;
-use mipidsi::options::Orientation;
-use nrf_dfu_target::prelude::*;
-use nrf_softdevice::ble::{gatt_server, peripheral, Connection};
-use nrf_softdevice::{raw, Softdevice};
+use embassy_time::{Duration, Timer};
 #[cfg(feature = "panic-probe")]
 use panic_probe as _;
 use pinetime_flash::XtFlash;
 use static_cell::StaticCell;
 
+mod activity;
+mod batterystats;
+#[cfg(not(feature = "no-softdevice"))]
 mod ble;
+#[cfg(feature = "no-softdevice")]
+#[path = "ble_stub.rs"]
+mod ble;
+mod board;
+#[cfg(not(feature = "no-softdevice"))]
+mod bonds;
+mod buildinfo;
+mod charger;
 mod clock;
+mod crash;
 mod device;
+#[cfg(feature = "serial-dfu")]
+mod dfuserial;
+mod display;
+mod dma;
+mod error;
+mod events;
+#[cfg(feature = "export")]
+mod export;
+mod factory;
+mod firmware;
+mod flashstats;
+mod frametime;
+mod fs;
+mod health;
+mod heartrate;
+mod input;
+mod kv;
+mod layout;
+mod maintenance;
+mod memory;
+mod motion;
+mod music;
+#[cfg(not(feature = "no-softdevice"))]
+mod navigation;
+#[cfg(not(feature = "no-softdevice"))]
+mod notifications;
+mod power;
+mod profile;
+#[cfg(feature = "log-ram")]
+mod ramlog;
+mod resources;
+mod retained;
+mod ringlog;
+mod settings;
+#[cfg(feature = "shell")]
+mod shell;
+mod sleep;
+mod softdevice;
 mod state;
+mod ui;
+mod variant;
+mod wake;
+mod wakelock;
+mod wakestats;
+mod weather;
+use crate::activity::{activity_task, ActivityLog};
+use crate::batterystats::{battery_stats_task, BatteryStats};
+use crate::board::Board;
+#[cfg(not(feature = "no-softdevice"))]
+use crate::bonds::{bonds_task, Bonder};
+use crate::charger::charger_task;
 use crate::clock::clock;
-use crate::device::{Battery, Button, Device, Hrs, Screen};
-use crate::state::WatchState;
+use crate::crash::CrashLog;
+use crate::device::{Battery, Device, Hrs, Motor, SharedBattery, SharedMotor};
+use crate::error::Error;
+use crate::factory::FactoryReset;
+use crate::flashstats::{flash_stats_task, CountingFlash};
+use crate::fs::FileSystem;
+use crate::heartrate::{heart_rate_task, HeartRateLog, SharedHrs};
+use crate::input::Button;
+use crate::kv::{KvStore, SharedKv};
+use crate::maintenance::{maintenance_task, EraseAhead};
+use crate::motion::motion_task;
+use crate::power::{power_task, Feature, Gated, PowerManager};
+use crate::profile::{profile_task, profiled, Task};
+use crate::ringlog::RingLog;
+use crate::settings::{settings_task, Settings, SettingsCache};
+use crate::sleep::{sleep_task, SleepLog};
+use crate::wakelock::wake_lock_task;
 
 bind_interrupts!(struct Irqs {
     SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0 => spim::InterruptHandler<peripherals::TWISPI0>;
@@ -48,287 +125,539 @@ bind_interrupts!(struct Irqs {
 
 static CLOCK: clock::Clock = clock::Clock::new();
 
-type ExternalFlash = XtFlash<SpiDevice<'static, NoopRawMutex, Spim<'static, TWISPI0>, Output<'static, P0_05>>>;
+/// Name the watch advertises. With the `gadgetbridge` feature it is the one of InfiniTime, which Gadgetbridge tells
+/// supported watches by.
+#[cfg(not(feature = "no-softdevice"))]
+const DEVICE_NAME: &str = if cfg!(feature = "gadgetbridge") {
+    "InfiniTime"
+} else {
+    "Watchful Embassy"
+};
+
+/// Runs BLE event handling and the heart rate sensor at a higher priority than the UI on the thread mode executor, so
+/// that a slow redraw can not hold up GATT requests. SWI0 is one of the software interrupts the SoftDevice leaves to
+/// the application.
+static RADIO_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI0_EGU0() {
+    RADIO_EXECUTOR.on_interrupt()
+}
+
+type ExternalFlash = CountingFlash<
+    XtFlash<SpiDevice<'static, CriticalSectionRawMutex, Gated<Spim<'static, TWISPI0>>, Output<'static, AnyPin>>>,
+>;
 
+#[cfg(not(feature = "no-softdevice"))]
 type InternalFlash = nrf_softdevice::Flash;
-type StatePartition<'a> = Partition<'a, NoopRawMutex, InternalFlash>;
-type DfuPartition<'a> = BlockingPartition<'a, NoopRawMutex, ExternalFlash>;
+/// Without the SoftDevice, the flash controller is free to be used directly.
+#[cfg(feature = "no-softdevice")]
+type InternalFlash = BlockingAsync<Nvmc<'static>>;
+type StatePartition<'a> = Partition<'a, CriticalSectionRawMutex, InternalFlash>;
+#[cfg(not(feature = "no-softdevice"))]
+type BondPartition<'a> = Partition<'a, CriticalSectionRawMutex, InternalFlash>;
+type DfuPartition<'a> = EraseAhead<BlockingPartition<'a, CriticalSectionRawMutex, ExternalFlash>>;
+type LogPartition<'a> = BlockingPartition<'a, CriticalSectionRawMutex, ExternalFlash>;
+type KvPartition<'a> = BlockingPartition<'a, CriticalSectionRawMutex, ExternalFlash>;
+
+#[cfg(all(feature = "log-rtt", feature = "log-ram"))]
+compile_error!("Only one of the log-rtt and log-ram features can be enabled");
+#[cfg(not(any(feature = "log-rtt", feature = "log-ram")))]
+compile_error!("One of the log-rtt and log-ram features must be enabled");
+#[cfg(all(feature = "no-softdevice", any(feature = "nus", feature = "fs", feature = "export")))]
+compile_error!("The nus, fs and export BLE services can not be enabled with no-softdevice");
+
+/// Logs persisted in external flash, shared with the BLE services.
+#[derive(Clone, Copy)]
+pub struct Logs {
+    pub activity: Option<&'static ActivityLog<'static>>,
+    pub heart_rate: Option<&'static HeartRateLog<'static>>,
+    pub crash: Option<&'static CrashLog<'static>>,
+    pub sleep: Option<&'static SleepLog<'static>>,
+}
 
-static I2C_BUS: StaticCell<BMutex<NoopRawMutex, RefCell<Twim<'static, TWISPI1>>>> = StaticCell::new();
-static SPI_BUS: StaticCell<BMutex<NoopRawMutex, RefCell<Spim<'static, TWISPI0>>>> = StaticCell::new();
+/// The I2C bus shared by the touch controller and the heart rate sensor.
+pub type I2cBus = BMutex<CriticalSectionRawMutex, RefCell<Gated<Twim<'static, TWISPI1>>>>;
+/// The SPI bus shared by the display and the external flash.
+pub type SpiBus = BMutex<CriticalSectionRawMutex, RefCell<Gated<Spim<'static, TWISPI0>>>>;
+
+static I2C_BUS: StaticCell<I2cBus> = StaticCell::new();
+static SPI_BUS: StaticCell<SpiBus> = StaticCell::new();
 
 use core::panic::PanicInfo;
 
 #[cfg(not(feature = "panic-probe"))]
 #[inline(never)]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    crash::record_panic(info);
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[cortex_m_rt::exception]
+unsafe fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
+    crash::record_hard_fault(ef);
     cortex_m::peripheral::SCB::sys_reset();
 }
 
 #[embassy_executor::main]
 async fn main(s: Spawner) {
+    memory::paint();
+    #[cfg(feature = "shell")]
+    let shell_input = shell::init();
     let mut config = embassy_nrf::config::Config::default();
     config.gpiote_interrupt_priority = Priority::P2;
     config.time_interrupt_priority = Priority::P2;
-    let p = embassy_nrf::init(config);
-
-    let sd = enable_softdevice("Watchful Embassy");
-
-    static GATT: StaticCell<ble::PineTimeServer> = StaticCell::new();
-    let server = GATT.init(ble::PineTimeServer::new(sd).unwrap());
+    let board = Board::new(embassy_nrf::init(config));
+    profile::init();
+    // Below the GPIOTE and timer interrupts that wake its tasks, priorities 0, 1 and 4 belong to the SoftDevice.
+    interrupt::SWI0_EGU0.set_priority(Priority::P3);
+    let radio = RADIO_EXECUTOR.start(interrupt::SWI0_EGU0);
+    info!("{}", buildinfo::BUILD);
+
+    #[cfg(not(feature = "no-softdevice"))]
+    let sd = ble::enable_softdevice(DEVICE_NAME);
+
+    // Without a GATT server the watch runs without BLE.
+    #[cfg(not(feature = "no-softdevice"))]
+    let server = {
+        static GATT: StaticCell<ble::PineTimeServer> = StaticCell::new();
+        error::recover(ble::PineTimeServer::new(sd), Error::Ble).map(|server| {
+            let server: &'static ble::PineTimeServer = GATT.init(server);
+            server.init();
+            server
+        })
+    };
 
-    s.spawn(softdevice_task(sd)).unwrap();
-    s.spawn(watchdog_task()).unwrap();
-    s.spawn(clock(&CLOCK)).unwrap();
+    #[cfg(not(feature = "no-softdevice"))]
+    spawn_radio(radio, ToRadio::Softdevice(sd));
+    spawn(s, watchdog_task());
+    retained::restore(&CLOCK);
+    spawn(s, clock(&CLOCK));
+    spawn(s, profile_task());
 
     // Battery measurement
-    let mut bat_config = saadc::ChannelConfig::single_ended(p.P0_31);
+    let mut bat_config = saadc::ChannelConfig::single_ended(board.battery.voltage);
     bat_config.gain = saadc::Gain::GAIN1_4;
     bat_config.resistor = saadc::Resistor::BYPASS;
     bat_config.reference = saadc::Reference::INTERNAL;
     bat_config.time = saadc::Time::_40US;
     let mut adc_config = saadc::Config::default();
     adc_config.resolution = saadc::Resolution::_10BIT;
-    let saadc = saadc::Saadc::new(p.SAADC, Irqs, adc_config, [bat_config]);
-    let battery = Battery::new(saadc, Input::new(p.P0_12.degrade(), Pull::Up));
-
-    // Touch peripheral
+    let saadc = saadc::Saadc::new(board.saadc, Irqs, adc_config, [bat_config]);
+    static BATTERY: StaticCell<SharedBattery> = StaticCell::new();
+    let battery: &'static SharedBattery = BATTERY.init(Mutex::new(Battery::new(
+        saadc,
+        Input::new(board.battery.charging, Pull::Up),
+        Input::new(board.battery.power_present, Pull::Up),
+    )));
+    static BATTERY_STATS: StaticCell<BatteryStats> = StaticCell::new();
+    let battery_stats: &'static BatteryStats = BATTERY_STATS.init(BatteryStats::new());
+
+    // I2C bus for the heart rate sensor and the touch controller
     let mut twim_config = twim::Config::default();
     twim_config.frequency = twim::Frequency::K400;
-    let i2c = twim::Twim::new(p.TWISPI1, Irqs, p.P0_06, p.P0_07, twim_config);
-    let i2c_bus = I2C_BUS.init(BMutex::new(RefCell::new(i2c)));
+    let i2c = twim::Twim::new(board.i2c, Irqs, board.i2c_pins.sda, board.i2c_pins.scl, twim_config);
+    let i2c_bus = I2C_BUS.init(BMutex::new(RefCell::new(Gated::new(i2c))));
 
     let i2c = I2cDevice::new(i2c_bus);
-    let hrs = Hrs::new(i2c);
-
-    // setup touchpad external interrupt pin: P0.28/AIN4 (TP_INT)
-    let touch_int = Input::new(p.P0_28, Pull::Up);
-    // setup touchpad reset pin: P0.10/NFC2 (TP_RESET)
-    let touch_rst = Output::new(p.P0_10, Level::High, OutputDrive::Standard);
+    static HRS: StaticCell<SharedHrs> = StaticCell::new();
+    let hrs: &'static SharedHrs = HRS.init(Mutex::new(Hrs::new(i2c)));
 
-    let i2c = I2cDevice::new(i2c_bus);
-    let mut touchpad = cst816s::CST816S::new(i2c, touch_int, touch_rst);
-    touchpad.setup(&mut embassy_time::Delay).unwrap();
+    let touchpad = board.touch.and_then(|pins| input::init_touchpad(i2c_bus, pins));
+    variant::detect(i2c_bus, touchpad.is_some());
 
-    // Button enable
-    let _btn_enable = Output::new(p.P0_15, Level::High, OutputDrive::Standard);
+    let _btn_enable = board
+        .button_enable
+        .map(|pin| Output::new(pin, Level::High, OutputDrive::Standard));
+    let btn = Button::new(board.button);
 
-    let btn = Button::new(Input::new(p.P0_13.degrade(), Pull::Down));
+    static MOTOR: StaticCell<SharedMotor> = StaticCell::new();
+    let motor: &'static SharedMotor = MOTOR.init(Mutex::new(Motor::new(Output::new(
+        board.motor,
+        Level::High,
+        OutputDrive::Standard,
+    ))));
 
     let mut default_config = spim::Config::default();
     default_config.frequency = spim::Frequency::M8;
     default_config.mode = MODE_3;
 
-    let spim = spim::Spim::new(p.TWISPI0, Irqs, p.P0_02, p.P0_04, p.P0_03, default_config);
-    let spi_bus = SPI_BUS.init(BMutex::new(RefCell::new(spim)));
+    let spim = spim::Spim::new(
+        board.spi,
+        Irqs,
+        board.spi_pins.sck,
+        board.spi_pins.miso,
+        board.spi_pins.mosi,
+        default_config,
+    );
+    let spi_bus = SPI_BUS.init(BMutex::new(RefCell::new(Gated::new(spim))));
 
     // Create flash device
-    let flash_cs = Output::new(p.P0_05, Level::High, OutputDrive::Standard);
+    let flash_cs = Output::new(board.flash_cs, Level::High, OutputDrive::Standard);
     let flash_spi = SpiDevice::new(spi_bus, flash_cs);
-    let xt_flash = XtFlash::new(flash_spi).unwrap();
-    static EXTERNAL_FLASH: StaticCell<BMutex<NoopRawMutex, RefCell<ExternalFlash>>> = StaticCell::new();
+    // Without it the watch boots with no file system, settings or logs, every access to it failing.
+    let mut xt_flash = XtFlash::unprobed(flash_spi);
+    error::recover(xt_flash.probe(), Error::ExternalFlash);
+    let xt_flash = CountingFlash::new(xt_flash);
+    static EXTERNAL_FLASH: StaticCell<BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>>> = StaticCell::new();
     let external_flash = EXTERNAL_FLASH.init(BMutex::new(RefCell::new(xt_flash)));
+    static POWER: StaticCell<PowerManager> = StaticCell::new();
+    let power: &'static PowerManager = POWER.init(PowerManager::new(hrs, external_flash));
+
+    static FS: StaticCell<FileSystem<'static>> = StaticCell::new();
+    let fs: &'static FileSystem<'static> = FS.init(FileSystem::new(external_flash));
 
+    #[cfg(not(feature = "no-softdevice"))]
     let internal_flash = nrf_softdevice::Flash::take(sd);
-    static INTERNAL_FLASH: StaticCell<Mutex<NoopRawMutex, InternalFlash>> = StaticCell::new();
+    // The board does not take the flash controller, which belongs to the SoftDevice when it runs.
+    #[cfg(feature = "no-softdevice")]
+    let internal_flash = BlockingAsync::new(Nvmc::new(unsafe { peripherals::NVMC::steal() }));
+    static INTERNAL_FLASH: StaticCell<Mutex<CriticalSectionRawMutex, InternalFlash>> = StaticCell::new();
     let internal_flash = INTERNAL_FLASH.init(Mutex::new(internal_flash));
-
-    // DFU setup
     let dfu_config = DfuConfig::new(internal_flash, external_flash);
-    let mut magic = AlignedBuffer([0; 4]);
-    let fw: FirmwareState<'_, _> = FirmwareState::new(dfu_config.state(), &mut magic.0);
-
-    // Display
-    s.spawn(advertiser_task(s, sd, server, dfu_config.clone(), "Watchful Embassy"))
-        .unwrap();
-
-    let backlight = Output::new(p.P0_22.degrade(), Level::Low, OutputDrive::Standard); // Medium backlight
-    let rst = Output::new(p.P0_26, Level::Low, OutputDrive::Standard);
-    let display_cs = Output::new(p.P0_25, Level::High, OutputDrive::Standard); // Keep low while driving display
-    let display_spi = SpiDevice::new(spi_bus, display_cs);
-    let dc = Output::new(p.P0_18, Level::Low, OutputDrive::Standard); // Data/clock
-    let di = SPIInterface::new(display_spi, dc);
-    let mut display = mipidsi::Builder::new(mipidsi::models::ST7789, di)
-        .display_size(240, 240)
-        .invert_colors(mipidsi::options::ColorInversion::Inverted)
-        .reset_pin(rst)
-        .init(&mut Delay)
-        .unwrap();
-    display.set_orientation(Orientation::new()).unwrap();
-
-    let screen = Screen::new(display, backlight);
-    let mut device: Device<'_> = Device {
-        clock: &CLOCK,
-        screen,
-        button: btn,
-        battery,
-        firmware: fw,
-        touchpad,
-        hrs,
-    };
 
-    let mut state = WatchState::default();
-    state.draw(&mut device).await;
-    loop {
-        let mut next = state.next(&mut device).await;
-        defmt::info!("{:?} -> {:?}", state, next);
-        if next != state {
-            next.draw(&mut device).await;
-        }
-        state = next;
+    // Up before the factory reset check, so that the reset can be shown.
+    let mut screen = display::init(spi_bus, board.display);
+
+    // Checked before anything is read from flash, so that corrupt data can not prevent the reset.
+    let factory_reset = FactoryReset::new(fs, internal_flash, dfu_config.clone());
+    if factory::requested_at_boot(&btn).await {
+        factory::show(&mut screen);
+        factory_reset.run().await;
     }
-}
 
-pub async fn gatt_server_task(conn: Connection, server: &'static ble::PineTimeServer, dfu_config: DfuConfig<'static>) {
-    let p = unsafe { pac::Peripherals::steal() };
-    let part = p.FICR.info.part.read().part().bits();
-    let variant = p.FICR.info.variant.read().variant().bits();
-
-    let hw_info = HardwareInfo {
-        part,
-        variant,
-        rom_size: 0,
-        ram_size: 0,
-        rom_page_size: 0,
+    if let Err(e) = fs.mount().await {
+        warn!("Error mounting filesystem: {:?}", e);
+    }
+    resources::check(fs).await;
+    let kv_partition = KvPartition::new(external_flash, kv::KV_OFFSET, kv::KV_SIZE);
+    let kv = match KvStore::new(kv_partition) {
+        Ok(store) => {
+            static KV: StaticCell<SharedKv<'static>> = StaticCell::new();
+            let kv: &'static SharedKv<'static> = KV.init(Mutex::new(store));
+            Some(kv)
+        }
+        Err(e) => {
+            warn!("Error opening key-value store: {:?}", e);
+            None
+        }
     };
-
-    let fw_info = FirmwareInfo {
-        ftype: FirmwareType::Application,
-        version: 1,
-        addr: 0,
-        len: 0,
+    if let Some(kv) = kv {
+        flashstats::load(kv).await;
+        battery_stats.load(kv).await;
+        activity::load_steps(kv, &CLOCK).await;
+        spawn(s, flash_stats_task(kv));
+        #[cfg(not(feature = "no-softdevice"))]
+        {
+            ble::load_dfu_progress(kv).await;
+            spawn(s, ble::dfu_progress_task(kv));
+        }
+    }
+    let settings = match kv {
+        Some(kv) => settings::load(kv).await,
+        None => Settings::default(),
     };
-
-    let mut conn_handle = ble::ConnectionHandle {
-        connection: conn.clone(),
-        notify_control: false,
-        notify_packet: false,
+    info!("Settings: {:?}", settings);
+    static SETTINGS: StaticCell<SettingsCache> = StaticCell::new();
+    let settings: &'static SettingsCache = SETTINGS.init(SettingsCache::new(settings));
+    if let Some(kv) = kv {
+        spawn(s, settings_task(settings, kv));
+    }
+    #[cfg(feature = "shell")]
+    spawn(
+        s,
+        shell::shell_task(
+            shell_input,
+            shell::Shell {
+                clock: &CLOCK,
+                battery,
+                power,
+                settings,
+            },
+        ),
+    );
+    power.set_threshold(Feature::BackgroundHeartRate, settings.get().hr_min_battery);
+    power.set_threshold(Feature::FirmwareUpdate, settings.get().dfu_min_battery);
+    let sensor_events = events::subscribe();
+    spawn(s, battery_stats_task(battery_stats, battery, power, kv));
+    spawn(s, charger_task(battery, motor, settings));
+
+    // Activity history
+    let activity_partition = LogPartition::new(external_flash, activity::LOG_OFFSET, activity::LOG_SIZE);
+    let activity_log = match RingLog::new(activity_partition) {
+        Ok(log) => {
+            static ACTIVITY_LOG: StaticCell<ActivityLog<'static>> = StaticCell::new();
+            let activity_log: &'static ActivityLog<'static> = ACTIVITY_LOG.init(ActivityLog::new(log));
+            spawn(s, activity_task(activity_log, &CLOCK, kv, settings, motor));
+            Some(activity_log)
+        }
+        Err(e) => {
+            warn!("Error opening activity log: {:?}", e);
+            None
+        }
     };
+    // Steps are counted without the log, for the watch face and workouts.
+    spawn(s, motion_task(i2c_bus));
+
+    // Heart rate history
+    let hr_partition = LogPartition::new(external_flash, heartrate::LOG_OFFSET, heartrate::LOG_SIZE);
+    let hr_log = match RingLog::new(hr_partition) {
+        Ok(log) => {
+            static HR_LOG: StaticCell<HeartRateLog<'static>> = StaticCell::new();
+            let hr_log: &'static HeartRateLog<'static> = HR_LOG.init(HeartRateLog::new(log));
+            heartrate::set_interval(settings.get().hr_interval);
+            Some(hr_log)
+        }
+        Err(e) => {
+            warn!("Error opening heart rate log: {:?}", e);
+            None
+        }
+    };
+    // Workouts measure the heart rate even without the log.
+    if cfg!(feature = "hrs") && board::HAS_HEART_RATE {
+        spawn_radio(
+            radio,
+            ToRadio::HeartRate {
+                hrs,
+                power,
+                log: hr_log,
+                motor,
+                settings,
+            },
+        );
+    }
 
-    info!("Running GATT server");
-    let mut dfu = dfu_config.dfu();
-    let mut target = DfuTarget::new(dfu.size(), fw_info, hw_info);
-    let spawner = Spawner::for_current_executor().await;
-
-    let _ = gatt_server::run(&conn, server, |e| {
-        if let Some(DfuStatus::DoneReset) = server.handle(&mut target, &mut dfu, &mut conn_handle, e) {
-            let _ = spawner.spawn(finish_dfu(dfu_config.clone()));
+    // Sleep history
+    let sleep_partition = LogPartition::new(external_flash, sleep::LOG_OFFSET, sleep::LOG_SIZE);
+    let sleep_log = match RingLog::new(sleep_partition) {
+        Ok(log) => {
+            static SLEEP_LOG: StaticCell<SleepLog<'static>> = StaticCell::new();
+            let sleep_log: &'static SleepLog<'static> = SLEEP_LOG.init(SleepLog::new(log));
+            spawn(s, sleep_task(sleep_log, &CLOCK));
+            Some(sleep_log)
         }
-    })
-    .await;
-    info!("Disconnected");
-}
+        Err(e) => {
+            warn!("Error opening sleep log: {:?}", e);
+            None
+        }
+    };
 
-#[embassy_executor::task]
-pub async fn finish_dfu(config: DfuConfig<'static>) {
-    let mut magic = AlignedBuffer([0; 4]);
-    let mut state = FirmwareState::new(config.state(), &mut magic.0);
-    match state.mark_updated().await {
-        Ok(_) => {
-            info!("Firmware updated, resetting");
-            cortex_m::peripheral::SCB::sys_reset();
+    // Crash log
+    let crash_partition = LogPartition::new(external_flash, crash::LOG_OFFSET, crash::LOG_SIZE);
+    let crash_log = match RingLog::new(crash_partition) {
+        Ok(log) => {
+            static CRASH_LOG: StaticCell<CrashLog<'static>> = StaticCell::new();
+            let crash_log: &'static CrashLog<'static> = CRASH_LOG.init(CrashLog::new(log));
+            crash_log.persist().await;
+            spawn(s, crash::error_task(crash_log));
+            Some(crash_log)
         }
         Err(e) => {
-            panic!("Error marking firmware updated: {:?}", e);
+            warn!("Error opening crash log: {:?}", e);
+            None
         }
+    };
+
+    // Bonds
+    #[cfg(not(feature = "no-softdevice"))]
+    let bonder = {
+        static BONDER: StaticCell<Bonder> = StaticCell::new();
+        let bonder: &'static Bonder = BONDER.init(Bonder::new());
+        let mut bond_partition = bond_partition(internal_flash);
+        bonder.load(&mut bond_partition).await;
+        spawn_radio(radio, ToRadio::Bonds(bonder, bond_partition));
+        bonder
+    };
+
+    let logs = Logs {
+        activity: activity_log,
+        heart_rate: hr_log,
+        crash: crash_log,
+        sleep: sleep_log,
+    };
+    spawn(s, maintenance_task(logs, kv, dfu_config.clone(), power));
+    spawn(s, power_task(power));
+    spawn(s, wake_lock_task());
+
+    // DFU setup
+    let mut magic = AlignedBuffer([0; 4]);
+    let mut fw: FirmwareState<'_, _> = FirmwareState::new(dfu_config.state(), &mut magic.0);
+
+    #[cfg(not(feature = "no-softdevice"))]
+    if let Some(server) = server {
+        spawn_radio(
+            radio,
+            ToRadio::Ble {
+                sd,
+                server,
+                dfu_config: dfu_config.clone(),
+                fs,
+                logs,
+                battery,
+                motor,
+                power,
+                bonder,
+            },
+        );
     }
+    #[cfg(feature = "no-softdevice")]
+    spawn_radio(radio, ToRadio::Ble);
+    #[cfg(feature = "serial-dfu")]
+    if let Some(port) = board.serial {
+        spawn(s, dfuserial::serial_dfu_task(port, dfu_config.clone(), fs));
+    }
+
+    // An update is only kept once it brought the watch up, before the UI can hang or the user reset it.
+    let self_test = firmware::SelfTest {
+        display: screen.is_ready(),
+        external_flash: firmware::probe_flash(external_flash),
+        #[cfg(not(feature = "no-softdevice"))]
+        softdevice: server.is_some(),
+        #[cfg(feature = "no-softdevice")]
+        softdevice: true,
+    };
+    firmware::confirm(&mut fw, self_test).await;
+    let device: Device<'_> = Device {
+        clock: &CLOCK,
+        screen,
+        button: btn,
+        battery,
+        battery_stats,
+        motor,
+        firmware: fw,
+        touchpad,
+        fs,
+        kv,
+        settings,
+        power,
+        logs,
+        factory_reset,
+    };
+
+    ui::run(device, sensor_events).await
 }
 
-#[embassy_executor::task]
-pub async fn advertiser_task(
-    _spawner: Spawner,
-    sd: &'static Softdevice,
-    server: &'static ble::PineTimeServer,
-    dfu_config: DfuConfig<'static>,
-    name: &'static str,
-) {
-    let mut adv_data: Vec<u8, 31> = Vec::new();
-    #[rustfmt::skip]
-    adv_data.extend_from_slice(&[
-        0x02, 0x01, raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
-        0x03, 0x03, 0xFE, 0x59,
-        (1 + name.len() as u8), 0x09]).unwrap();
-
-    adv_data.extend_from_slice(name.as_bytes()).ok().unwrap();
-
-    #[rustfmt::skip]
-    let scan_data = &[
-        0x03, 0x03, 0x0A, 0x18,
-    ];
-
-    loop {
-        let config = peripheral::Config::default();
-        let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
-            adv_data: &adv_data[..],
-            scan_data,
-        };
-        info!("Advertising");
-        let conn = peripheral::advertise_connectable(sd, adv, &config).await.unwrap();
-
-        info!("Connection established");
-        Timer::after(Duration::from_secs(1)).await;
-        info!("Syncing time");
-        ble::sync_time(&conn, &CLOCK).await;
-
-        gatt_server_task(conn, server, dfu_config.clone()).await;
-    }
+/// Spawn a task, running without it if the task pool is exhausted.
+fn spawn<S>(s: Spawner, token: SpawnToken<S>) {
+    error::recover(s.spawn(token), Error::Spawn);
 }
 
-fn enable_softdevice(name: &'static str) -> &'static mut Softdevice {
-    let config = nrf_softdevice::Config {
-        clock: Some(raw::nrf_clock_lf_cfg_t {
-            source: raw::NRF_CLOCK_LF_SRC_RC as u8,
-            rc_ctiv: 4,
-            rc_temp_ctiv: 2,
-            accuracy: 7,
-        }),
-        conn_gap: Some(raw::ble_gap_conn_cfg_t {
-            conn_count: 2,
-            event_length: 24,
-        }),
-        conn_gatt: Some(raw::ble_gatt_conn_cfg_t {
-            att_mtu: crate::ble::ATT_MTU as u16,
-        }),
-        gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t { attr_tab_size: 32768 }),
-        gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
-            adv_set_count: 1,
-            periph_role_count: 3,
-            central_role_count: 1,
-            central_sec_count: 1,
-            _bitfield_1: Default::default(),
-        }),
-        gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
-            p_value: name.as_ptr() as *const u8 as _,
-            current_len: name.len() as u16,
-            max_len: name.len() as u16,
-            write_perm: unsafe { core::mem::zeroed() },
-            _bitfield_1: raw::ble_gap_cfg_device_name_t::new_bitfield_1(raw::BLE_GATTS_VLOC_STACK as u8),
-        }),
-        ..Default::default()
-    };
-    Softdevice::enable(&config)
+/// Tasks handed over to the radio executor, see `RADIO_EXECUTOR`.
+enum ToRadio {
+    #[cfg(not(feature = "no-softdevice"))]
+    Softdevice(&'static nrf_softdevice::Softdevice),
+    #[cfg(not(feature = "no-softdevice"))]
+    Bonds(&'static Bonder, BondPartition<'static>),
+    #[cfg(not(feature = "no-softdevice"))]
+    Ble {
+        sd: &'static nrf_softdevice::Softdevice,
+        server: &'static ble::PineTimeServer,
+        dfu_config: DfuConfig<'static>,
+        fs: &'static FileSystem<'static>,
+        logs: Logs,
+        battery: &'static SharedBattery,
+        motor: &'static SharedMotor,
+        power: &'static PowerManager,
+        bonder: &'static Bonder,
+    },
+    #[cfg(feature = "no-softdevice")]
+    Ble,
+    HeartRate {
+        hrs: &'static SharedHrs,
+        power: &'static PowerManager,
+        log: Option<&'static HeartRateLog<'static>>,
+        motor: &'static SharedMotor,
+        settings: &'static SettingsCache,
+    },
 }
 
-#[embassy_executor::task]
-async fn softdevice_task(sd: &'static Softdevice) {
-    sd.run().await;
+// Safety: everything the radio tasks share with the thread mode executor is behind `CriticalSectionRawMutex` locks,
+// signals and channels, or atomics. The rest, such as the SoftDevice handle and the bonds, is only used by the radio
+// tasks once handed over.
+unsafe impl Send for ToRadio {}
+
+/// Spawn a task on the radio executor, running without it if the task pool is exhausted.
+fn spawn_radio(radio: SendSpawner, task: ToRadio) {
+    error::recover(radio.spawn(radio_task(task)), Error::Spawn);
 }
 
-// Keeps our system alive
+/// Spawns a task handed over by `spawn_radio` from within the radio executor, where it needs not be `Send`.
+#[embassy_executor::task(pool_size = 4)]
+async fn radio_task(task: ToRadio) {
+    let s = Spawner::for_current_executor().await;
+    match task {
+        #[cfg(not(feature = "no-softdevice"))]
+        ToRadio::Softdevice(sd) => spawn(s, ble::softdevice_task(sd)),
+        #[cfg(not(feature = "no-softdevice"))]
+        ToRadio::Bonds(bonder, partition) => spawn(s, bonds_task(bonder, partition)),
+        #[cfg(not(feature = "no-softdevice"))]
+        ToRadio::Ble {
+            sd,
+            server,
+            dfu_config,
+            fs,
+            logs,
+            battery,
+            motor,
+            power,
+            bonder,
+        } => spawn(
+            s,
+            ble::advertiser_task(
+                s,
+                sd,
+                server,
+                dfu_config,
+                fs,
+                logs,
+                battery,
+                motor,
+                power,
+                bonder,
+                DEVICE_NAME,
+            ),
+        ),
+        #[cfg(feature = "no-softdevice")]
+        ToRadio::Ble => spawn(s, ble::ble_task()),
+        ToRadio::HeartRate {
+            hrs,
+            power,
+            log,
+            motor,
+            settings,
+        } => spawn(s, heart_rate_task(hrs, power, log, motor, settings, &CLOCK)),
+    }
+}
+
+/// Keeps our system alive while the monitored tasks send heartbeats, see `health`. Once one stops, the task is
+/// recorded in the crash log and the watchdog left to reset the watch.
 #[embassy_executor::task]
 async fn watchdog_task() {
-    let mut handle = unsafe { embassy_nrf::wdt::WatchdogHandle::steal(0) };
-    loop {
-        handle.pet();
-        Timer::after(Duration::from_secs(4)).await;
-    }
+    profiled(Task::Watchdog, async move {
+        let mut handle = unsafe { embassy_nrf::wdt::WatchdogHandle::steal(0) };
+        loop {
+            if let Some(task) = health::overdue() {
+                warn!("Task {:?} stopped responding, waiting for the watchdog", task);
+                crash::record_stall(task);
+                core::future::pending::<()>().await;
+            }
+            handle.pet();
+            Timer::after(Duration::from_secs(4)).await;
+        }
+    })
+    .await
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+fn bond_partition(internal: &Mutex<CriticalSectionRawMutex, InternalFlash>) -> BondPartition<'_> {
+    BondPartition::new(internal, layout::BONDS.start, layout::BONDS.size)
 }
 
 #[derive(Clone)]
 pub struct DfuConfig<'a> {
-    internal: &'a Mutex<NoopRawMutex, InternalFlash>,
-    external: &'a BMutex<NoopRawMutex, RefCell<ExternalFlash>>,
+    internal: &'a Mutex<CriticalSectionRawMutex, InternalFlash>,
+    external: &'a BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>>,
     state_start: u32,
     state_end: u32,
     dfu_start: u32,
@@ -337,42 +666,61 @@ pub struct DfuConfig<'a> {
 
 impl<'a> DfuConfig<'a> {
     pub fn new(
-        internal: &'a Mutex<NoopRawMutex, InternalFlash>,
-        external: &'a BMutex<NoopRawMutex, RefCell<ExternalFlash>>,
+        internal: &'a Mutex<CriticalSectionRawMutex, InternalFlash>,
+        external: &'a BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>>,
     ) -> Self {
-        extern "C" {
-            static __bootloader_state_start: u32;
-            static __bootloader_state_end: u32;
-            static __bootloader_dfu_start: u32;
-            static __bootloader_dfu_end: u32;
+        Self {
+            internal,
+            external,
+            state_start: layout::BOOTLOADER_STATE.start,
+            state_end: layout::BOOTLOADER_STATE.end(),
+            dfu_start: layout::DFU.start,
+            dfu_end: layout::DFU.end(),
         }
+    }
 
-        unsafe {
-            let dfu_start = &__bootloader_dfu_start as *const u32 as u32;
-            let dfu_end = &__bootloader_dfu_end as *const u32 as u32;
+    pub fn state(&self) -> StatePartition<'a> {
+        StatePartition::new(self.internal, self.state_start, self.state_end - self.state_start)
+    }
 
-            BlockingPartition::new(external, dfu_start, dfu_end - dfu_start);
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    pub fn internal(&self) -> &'a Mutex<CriticalSectionRawMutex, InternalFlash> {
+        self.internal
+    }
 
-            let state_start = &__bootloader_state_start as *const u32 as u32;
-            let state_end = &__bootloader_state_end as *const u32 as u32;
+    pub fn external(&self) -> &'a BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>> {
+        self.external
+    }
 
-            Partition::new(internal, state_start, state_end - state_start);
-            Self {
-                internal,
-                external,
-                state_start,
-                state_end,
-                dfu_start,
-                dfu_end,
-            }
-        }
+    pub fn dfu(&self) -> DfuPartition<'a> {
+        EraseAhead::new(BlockingPartition::new(
+            self.external,
+            self.dfu_start,
+            self.dfu_end - self.dfu_start,
+        ))
     }
 
-    pub fn state(&self) -> StatePartition<'a> {
-        StatePartition::new(self.internal, self.state_start, self.state_end - self.state_start)
+    /// Where SoftDevice and bootloader updates are received.
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    pub fn staging(&self) -> LogPartition<'a> {
+        LogPartition::new(self.external, layout::STAGING.start, layout::STAGING.size)
     }
 
-    pub fn dfu(&self) -> DfuPartition<'a> {
-        DfuPartition::new(self.external, self.dfu_start, self.dfu_end - self.dfu_start)
+    /// Where resource packs sent as DFU assets are received.
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    pub fn assets(&self) -> LogPartition<'a> {
+        LogPartition::new(self.external, layout::ASSETS.start, layout::ASSETS.size)
+    }
+
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    pub fn staging_header(&self) -> LogPartition<'a> {
+        LogPartition::new(self.external, layout::STAGING_HEADER.start, layout::STAGING_HEADER.size)
+    }
+
+    /// Where bootloader updates are copied for the MBR to install, see `firmware::install_staged`.
+    #[cfg(not(feature = "no-softdevice"))]
+    pub fn bootloader_source(&self) -> Partition<'a, CriticalSectionRawMutex, InternalFlash> {
+        let source = firmware::BOOTLOADER_SOURCE;
+        Partition::new(self.internal, source.start, source.size)
     }
 }
@@ -0,0 +1,217 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use defmt::{info, warn};
+use embassy_boot::State as FwState;
+use embassy_boot_nrf::{AlignedBuffer, FirmwareState};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+
+use crate::kv::SharedKv;
+use crate::power::{PowerManager, Subsystem};
+use crate::profile::{profiled, Task};
+use crate::{health, DfuConfig, Logs};
+
+/// How often maintenance is reconsidered when nothing changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Pause between units of work, so the UI and BLE stay responsive.
+const STEP_DELAY: Duration = Duration::from_millis(200);
+
+const DFU_SECTOR_WORDS: usize = 4;
+
+/// Sectors of the DFU partition known to be erased, one bit per sector.
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_ERASED: AtomicU32 = AtomicU32::new(0);
+static DFU_ERASED: [AtomicU32; DFU_SECTOR_WORDS] = [NOT_ERASED; DFU_SECTOR_WORDS];
+/// Set once the DFU partition is written, after which it is no longer erased ahead until the next boot.
+static DFU_WRITTEN: AtomicBool = AtomicBool::new(false);
+
+static CONDITIONS: Signal<CriticalSectionRawMutex, (bool, bool)> = Signal::new();
+
+/// Report whether the watch is idle and charging. Maintenance only runs while both hold.
+pub fn set_conditions(idle: bool, charging: bool) {
+    CONDITIONS.signal((idle, charging));
+}
+
+/// Keep the DFU partition from being erased ahead, as it holds an update to resume.
+#[cfg(not(feature = "no-softdevice"))]
+pub fn keep_dfu() {
+    DFU_WRITTEN.store(true, Ordering::Relaxed);
+}
+
+fn is_erased(sector: u32) -> bool {
+    DFU_ERASED[sector as usize / 32].load(Ordering::Relaxed) & (1 << (sector % 32)) != 0
+}
+
+fn set_erased(sector: u32, erased: bool) {
+    let word = &DFU_ERASED[sector as usize / 32];
+    if erased {
+        word.fetch_or(1 << (sector % 32), Ordering::Relaxed);
+    } else {
+        word.fetch_and(!(1 << (sector % 32)), Ordering::Relaxed);
+    }
+}
+
+/// DFU partition wrapper that skips erasing sectors the maintenance task already erased.
+pub struct EraseAhead<F> {
+    inner: F,
+}
+
+impl<F> EraseAhead<F> {
+    pub fn new(inner: F) -> Self {
+        Self { inner }
+    }
+}
+
+impl<F: NorFlash> EraseAhead<F> {
+    /// Erase the first sector not known to be erased, returning false if all sectors are.
+    fn erase_next(&mut self) -> Result<bool, F::Error> {
+        let sectors = (self.inner.capacity() / F::ERASE_SIZE).min(DFU_SECTOR_WORDS * 32) as u32;
+        let Some(sector) = (0..sectors).find(|s| !is_erased(*s)) else {
+            return Ok(false);
+        };
+        let size = F::ERASE_SIZE as u32;
+        self.inner.erase(sector * size, (sector + 1) * size)?;
+        set_erased(sector, true);
+        Ok(true)
+    }
+}
+
+impl<F: ErrorType> ErrorType for EraseAhead<F> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash> ReadNorFlash for EraseAhead<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.inner.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for EraseAhead<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        for address in (from..to).step_by(F::ERASE_SIZE) {
+            let sector = address / F::ERASE_SIZE as u32;
+            if !is_erased(sector) {
+                self.inner.erase(address, address + F::ERASE_SIZE as u32)?;
+            }
+            set_erased(sector, false);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        DFU_WRITTEN.store(true, Ordering::Relaxed);
+        let first = offset / F::ERASE_SIZE as u32;
+        let last = (offset + bytes.len() as u32).saturating_sub(1) / F::ERASE_SIZE as u32;
+        for sector in first..=last {
+            set_erased(sector, false);
+        }
+        self.inner.write(offset, bytes)
+    }
+}
+
+/// Erase the next DFU sector that is not yet erased, if the partition is not needed for a rollback.
+async fn erase_dfu_ahead(dfu_config: &DfuConfig<'static>) -> bool {
+    if DFU_WRITTEN.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    // Until the running firmware is validated, the partition holds the previous firmware to roll back to.
+    let mut magic = AlignedBuffer([0; 4]);
+    let mut state = FirmwareState::new(dfu_config.state(), &mut magic.0);
+    match state.get_state().await {
+        Ok(FwState::Boot) => {}
+        _ => return false,
+    }
+
+    match dfu_config.dfu().erase_next() {
+        Ok(erased) => erased,
+        Err(e) => {
+            warn!("Error erasing DFU sector: {:?}", defmt::Debug2Format(&e));
+            false
+        }
+    }
+}
+
+/// Do a single unit of maintenance, returning false when there is nothing left to do.
+async fn step(logs: Logs, kv: Option<&SharedKv<'_>>, dfu_config: &DfuConfig<'static>) -> bool {
+    let mut results = [None, None, None, None];
+    if let Some(log) = logs.activity {
+        results[0] = Some(log.erase_ahead().await);
+    }
+    if let Some(log) = logs.heart_rate {
+        results[1] = Some(log.erase_ahead().await);
+    }
+    if let Some(log) = logs.crash {
+        results[2] = Some(log.erase_ahead().await);
+    }
+    if let Some(log) = logs.sleep {
+        results[3] = Some(log.erase_ahead().await);
+    }
+    let mut worked = false;
+    for result in results.into_iter().flatten() {
+        match result {
+            Ok(erased) => worked |= erased,
+            Err(e) => warn!("Error erasing log ahead: {:?}", e),
+        }
+    }
+    if worked {
+        return true;
+    }
+
+    if let Some(kv) = kv {
+        match kv.lock().await.compact_ahead() {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(e) => warn!("Error compacting key-value store: {:?}", e),
+        }
+    }
+
+    erase_dfu_ahead(dfu_config).await
+}
+
+/// Pre-erases log and DFU sectors and compacts the key-value store while the watch is idle and charging, so that
+/// foreground writes do not stall on sector erases.
+#[embassy_executor::task]
+pub async fn maintenance_task(
+    logs: Logs,
+    kv: Option<&'static SharedKv<'static>>,
+    dfu_config: DfuConfig<'static>,
+    power: &'static PowerManager,
+) {
+    profiled(Task::Maintenance, async move {
+        let mut conditions = (false, false);
+        loop {
+            health::heartbeat(Task::Maintenance, CHECK_INTERVAL * 2);
+            if conditions == (true, true) {
+                let worked = {
+                    let _flash = power.acquire(Subsystem::ExternalFlash).await;
+                    step(logs, kv, &dfu_config).await
+                };
+                if worked {
+                    if let Some(c) = CONDITIONS.try_take() {
+                        conditions = c;
+                    }
+                    Timer::after(STEP_DELAY).await;
+                    continue;
+                }
+                info!("Flash maintenance done");
+            }
+            if let Either::First(c) = select(CONDITIONS.wait(), Timer::after(CHECK_INTERVAL)).await {
+                conditions = c;
+            }
+        }
+    })
+    .await
+}
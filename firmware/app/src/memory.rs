@@ -0,0 +1,175 @@
+use core::fmt::Write as _;
+use core::ptr::{addr_of, read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::{info, warn};
+use heapless::String;
+
+use crate::profile::{Task, TASKS};
+use crate::{dma, layout};
+
+/// Value the unused stack is painted with at boot.
+const PAINT: u32 = 0xCCCC_CCCC;
+/// Stack below the frame of `paint` that is left unpainted, in case an interrupt is using it.
+const PAINT_MARGIN: u32 = 64;
+/// Stack headroom under which a warning is logged.
+const LOW_HEADROOM: u32 = 1024;
+
+// Symbols of the cortex-m-rt linker script. Statics go from the start of the application RAM to the end of
+// `.uninit`, and the stack grows down from the end of RAM towards them.
+extern "C" {
+    static __sdata: u32;
+    static __edata: u32;
+    static __sbss: u32;
+    static __ebss: u32;
+    static __sheap: u32;
+    static _stack_start: u32;
+}
+
+fn app_ram_start() -> u32 {
+    unsafe { addr_of!(__sdata) as u32 }
+}
+
+fn stack_bottom() -> u32 {
+    unsafe { addr_of!(__sheap) as u32 }
+}
+
+fn stack_top() -> u32 {
+    unsafe { addr_of!(_stack_start) as u32 }
+}
+
+/// Lowest address the stack was seen to reach, 0 until the stack is painted.
+static LOWEST: AtomicU32 = AtomicU32::new(0);
+
+/// Deepest the stack reached while each task was polled, in bytes.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PEAK: AtomicU32 = AtomicU32::new(0);
+static PEAKS: [AtomicU32; TASKS.len()] = [NO_PEAK; TASKS.len()];
+
+/// Paint the unused stack, so the deepest it has reached can be found later. Called first thing in `main`.
+#[inline(never)]
+pub fn paint() {
+    let sp = cortex_m::register::msp::read() - PAINT_MARGIN;
+    let mut addr = stack_bottom();
+    while addr < sp {
+        unsafe { write_volatile(addr as *mut u32, PAINT) };
+        addr += 4;
+    }
+    LOWEST.store(sp, Ordering::Relaxed);
+}
+
+/// Account any new stack depth to `task`, after one of its polls.
+///
+/// All executor tasks run on the one main stack, so a task's peak is the deepest the stack reached while it was
+/// polled, including the executor and any interrupts taken during the poll. Only the words just below the previous
+/// peak are checked, so a poll costs a read unless the stack grew, and a frame that skips words it does not write
+/// may be undercounted. `usage` scans the whole stack.
+pub fn check(task: Task) {
+    let start = LOWEST.load(Ordering::Relaxed);
+    if start == 0 {
+        return;
+    }
+    let bottom = stack_bottom();
+    let mut lowest = start;
+    while lowest > bottom && unsafe { read_volatile((lowest - 4) as *const u32) } != PAINT {
+        lowest -= 4;
+    }
+    if lowest != start {
+        LOWEST.store(lowest, Ordering::Relaxed);
+        PEAKS[task as usize].fetch_max(stack_top() - lowest, Ordering::Relaxed);
+    }
+}
+
+/// RAM use, in bytes.
+pub struct Usage {
+    pub softdevice: u32,
+    pub data: u32,
+    pub bss: u32,
+    /// Statics not initialized at boot, such as the state retained through System OFF.
+    pub uninit: u32,
+    pub stack_size: u32,
+    /// Deepest the stack has been since boot.
+    pub stack_peak: u32,
+}
+
+impl Usage {
+    pub fn statics(&self) -> u32 {
+        self.data + self.bss + self.uninit
+    }
+
+    /// Stack never used since boot.
+    pub fn headroom(&self) -> u32 {
+        self.stack_size - self.stack_peak
+    }
+}
+
+/// Current RAM use. The stack peak is found by scanning for the lowest word that is no longer painted.
+pub fn usage() -> Usage {
+    let (sdata, edata, sbss, ebss) = unsafe {
+        (
+            addr_of!(__sdata) as u32,
+            addr_of!(__edata) as u32,
+            addr_of!(__sbss) as u32,
+            addr_of!(__ebss) as u32,
+        )
+    };
+    let bottom = stack_bottom();
+    let top = stack_top();
+    let mut lowest = bottom;
+    if LOWEST.load(Ordering::Relaxed) != 0 {
+        while lowest < top && unsafe { read_volatile(lowest as *const u32) } == PAINT {
+            lowest += 4;
+        }
+    }
+    Usage {
+        softdevice: app_ram_start() - layout::RAM_START,
+        data: edata - sdata,
+        bss: ebss - sbss,
+        uninit: bottom - ebss,
+        stack_size: top - bottom,
+        stack_peak: top - lowest,
+    }
+}
+
+/// Deepest stack seen when `task` was polled, in bytes.
+pub fn peak(task: Task) -> u32 {
+    PEAKS[task as usize].load(Ordering::Relaxed)
+}
+
+/// Log the RAM use and the stack peak of each task, warning when the stack is close to the statics.
+pub fn log() {
+    let usage = usage();
+    info!(
+        "RAM: softdevice {}, data {}, bss {}, uninit {}, stack {}/{} of {}",
+        usage.softdevice,
+        usage.data,
+        usage.bss,
+        usage.uninit,
+        usage.stack_peak,
+        usage.stack_size,
+        layout::RAM_SIZE
+    );
+    info!("DMA buffers: {} of {} in use", dma::in_use(), dma::BUFFERS);
+    for task in TASKS {
+        let peak = peak(task);
+        if peak > 0 {
+            info!("Stack {}: {}", task, peak);
+        }
+    }
+    if usage.headroom() < LOW_HEADROOM {
+        warn!("Stack headroom down to {} bytes", usage.headroom());
+    }
+}
+
+/// RAM use and per task stack peaks for the diagnostics screen, deepest first.
+pub fn report<const N: usize>(text: &mut String<N>) {
+    let usage = usage();
+    let _ = writeln!(text, "SoftDevice: {}K", usage.softdevice / 1024);
+    let _ = writeln!(text, "Statics: {} B", usage.statics());
+    let _ = writeln!(text, "Stack: {}/{} B", usage.stack_peak, usage.stack_size);
+    let mut tasks = TASKS;
+    tasks.sort_unstable_by_key(|task| core::cmp::Reverse(peak(*task)));
+    for task in tasks.iter().take_while(|task| peak(**task) > 0).take(5) {
+        let _ = writeln!(text, "{}: {} B", task.name(), peak(*task));
+    }
+}
@@ -0,0 +1,275 @@
+//! The accelerometer, read in batches from its FIFO, and the step counter fed from it. The samples also go to sleep
+//! tracking, see `sleep::add_samples`. Only the raw acceleration is used: the step counter of the BMA42x needs a
+//! configuration file from Bosch that is not distributed with the watch, and the SC7A20 has none.
+use defmt::info;
+use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_hal::i2c::I2c;
+
+use crate::error::{self, Error};
+use crate::profile::{profiled, Task};
+use crate::variant::{self, Accelerometer, ACCELEROMETER_ADDRESS};
+use crate::{activity, health, sleep, I2cBus};
+
+/// Samples per second. Steps are at most 4 per second, and a minute of sleep is classified from the variance.
+const SAMPLE_HZ: u32 = 25;
+/// Samples read per I2C transfer, short enough not to hold the bus from the touch controller for long.
+const BATCH: usize = 16;
+/// Time for the BMA42x to come out of a soft reset.
+const RESET_TIME: Duration = Duration::from_millis(2);
+/// Time for the BMA42x to wake up before registers can be written without the advanced power save delays.
+const WAKE_TIME: Duration = Duration::from_millis(1);
+
+const BMA_ACC_CONF: u8 = 0x40;
+/// 25 Hz, averaging 4 samples, low power mode.
+const BMA_ACC_CONF_25HZ: u8 = 0x26;
+const BMA_ACC_RANGE: u8 = 0x41;
+const BMA_RANGE_4G: u8 = 0x01;
+const BMA_FIFO_LENGTH: u8 = 0x24;
+const BMA_FIFO_DATA: u8 = 0x26;
+const BMA_FIFO_CONFIG_1: u8 = 0x49;
+/// Acceleration frames without headers.
+const BMA_FIFO_ACC: u8 = 0x40;
+const BMA_PWR_CONF: u8 = 0x7C;
+/// Advanced power save, with the FIFO still readable.
+const BMA_POWER_SAVE: u8 = 0x03;
+const BMA_PWR_CTRL: u8 = 0x7D;
+const BMA_ACC_ENABLE: u8 = 0x04;
+const BMA_CMD: u8 = 0x7E;
+const BMA_SOFT_RESET: u8 = 0xB6;
+
+const SC7A20_CTRL_REG1: u8 = 0x20;
+/// 25 Hz, normal mode, all axes.
+const SC7A20_25HZ: u8 = 0x37;
+const SC7A20_POWER_DOWN: u8 = 0x00;
+const SC7A20_CTRL_REG4: u8 = 0x23;
+/// Block data update, ±4 g.
+const SC7A20_RANGE_4G: u8 = 0x90;
+const SC7A20_CTRL_REG5: u8 = 0x24;
+const SC7A20_FIFO_ENABLE: u8 = 0x40;
+const SC7A20_FIFO_CTRL: u8 = 0x2E;
+const SC7A20_FIFO_STREAM: u8 = 0x80;
+const SC7A20_FIFO_SRC: u8 = 0x2F;
+/// First output register, with the bit that makes the address increment over the axes.
+const SC7A20_OUT: u8 = 0x28 | 0x80;
+
+/// Magnitude of the smoothed acceleration above its baseline, in mg, that makes a step, and below which the next
+/// one can start.
+const STEP_THRESHOLD: i32 = 100;
+const STEP_RELEASE: i32 = 30;
+/// Shortest and longest time between steps, in samples: 240 steps per minute, and 2 seconds.
+const MIN_STEP_SAMPLES: u32 = SAMPLE_HZ / 4;
+const MAX_STEP_SAMPLES: u32 = 2 * SAMPLE_HZ;
+/// Steps in a row before any is counted, so that waving the arm or turning over in bed is not taken for walking.
+const RUN_STEPS: u32 = 8;
+
+/// Signalled to put the accelerometer to sleep before powering off, and back once it is.
+static SUSPEND: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static SUSPENDED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+impl Accelerometer {
+    /// Time between FIFO reads, before the FIFO fills up: it holds 170 samples on the BMA42x and 32 on the SC7A20.
+    fn read_period(&self) -> Duration {
+        match self {
+            Self::Bma421 | Self::Bma425 => Duration::from_secs(4),
+            Self::Sc7a20 => Duration::from_secs(1),
+        }
+    }
+}
+
+struct Sensor<I> {
+    i2c: I,
+    part: Accelerometer,
+}
+
+impl<I: I2c> Sensor<I> {
+    /// Start sampling into the FIFO at `SAMPLE_HZ`, ±4 g.
+    async fn start(&mut self) -> Result<(), I::Error> {
+        match self.part {
+            Accelerometer::Bma421 | Accelerometer::Bma425 => {
+                self.write(BMA_CMD, BMA_SOFT_RESET)?;
+                Timer::after(RESET_TIME).await;
+                self.write(BMA_PWR_CONF, 0)?;
+                Timer::after(WAKE_TIME).await;
+                self.write(BMA_ACC_CONF, BMA_ACC_CONF_25HZ)?;
+                self.write(BMA_ACC_RANGE, BMA_RANGE_4G)?;
+                self.write(BMA_FIFO_CONFIG_1, BMA_FIFO_ACC)?;
+                self.write(BMA_PWR_CTRL, BMA_ACC_ENABLE)?;
+                self.write(BMA_PWR_CONF, BMA_POWER_SAVE)
+            }
+            Accelerometer::Sc7a20 => {
+                self.write(SC7A20_CTRL_REG1, SC7A20_25HZ)?;
+                self.write(SC7A20_CTRL_REG4, SC7A20_RANGE_4G)?;
+                self.write(SC7A20_CTRL_REG5, SC7A20_FIFO_ENABLE)?;
+                self.write(SC7A20_FIFO_CTRL, SC7A20_FIFO_STREAM)
+            }
+        }
+    }
+
+    /// Stop sampling, leaving the part in its lowest power state.
+    fn stop(&mut self) -> Result<(), I::Error> {
+        match self.part {
+            Accelerometer::Bma421 | Accelerometer::Bma425 => self.write(BMA_PWR_CTRL, 0),
+            Accelerometer::Sc7a20 => self.write(SC7A20_CTRL_REG1, SC7A20_POWER_DOWN),
+        }
+    }
+
+    /// Samples waiting in the FIFO.
+    fn available(&mut self) -> Result<usize, I::Error> {
+        match self.part {
+            Accelerometer::Bma421 | Accelerometer::Bma425 => {
+                let mut length = [0; 2];
+                self.i2c
+                    .write_read(ACCELEROMETER_ADDRESS, &[BMA_FIFO_LENGTH], &mut length)?;
+                Ok(u16::from_le_bytes([length[0], length[1] & 0x3F]) as usize / 6)
+            }
+            Accelerometer::Sc7a20 => {
+                let mut source = [0];
+                self.i2c
+                    .write_read(ACCELEROMETER_ADDRESS, &[SC7A20_FIFO_SRC], &mut source)?;
+                // The count stops at 31 of the 32 samples, with the overrun bit set once the last one is filled.
+                Ok((source[0] & 0x1F) as usize + (source[0] >> 6 & 1) as usize)
+            }
+        }
+    }
+
+    /// Read the next samples from the FIFO, up to `BATCH`, in mg.
+    fn read(&mut self, count: usize, samples: &mut [[i16; 3]; BATCH]) -> Result<usize, I::Error> {
+        let count = count.min(BATCH);
+        let mut buf = [0; BATCH * 6];
+        let register = match self.part {
+            Accelerometer::Bma421 | Accelerometer::Bma425 => BMA_FIFO_DATA,
+            Accelerometer::Sc7a20 => SC7A20_OUT,
+        };
+        self.i2c
+            .write_read(ACCELEROMETER_ADDRESS, &[register], &mut buf[..count * 6])?;
+        for (sample, bytes) in samples.iter_mut().zip(buf[..count * 6].chunks_exact(6)) {
+            for (axis, value) in sample.iter_mut().zip(bytes.chunks_exact(2)) {
+                *axis = milli_g(i16::from_le_bytes([value[0], value[1]]));
+            }
+        }
+        Ok(count)
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), I::Error> {
+        self.i2c.write(ACCELEROMETER_ADDRESS, &[register, value])
+    }
+}
+
+/// Acceleration in mg of a left-justified sample at ±4 g, as both parts report it.
+fn milli_g(raw: i16) -> i16 {
+    ((raw as i32 * 1000) >> 13) as i16
+}
+
+/// Counts steps from peaks of the magnitude of the acceleration above its slowly moving average, which follows
+/// gravity as the wrist turns. Steps are only counted once `RUN_STEPS` of them come at a walking pace.
+#[derive(Default)]
+struct StepCounter {
+    /// Moving averages of the magnitude in mg, times 4 and times 32.
+    smooth: i32,
+    baseline: i32,
+    above: bool,
+    /// Samples since the last step.
+    since: u32,
+    /// Steps in the current run, not yet counted while fewer than `RUN_STEPS`.
+    run: u32,
+}
+
+impl StepCounter {
+    /// Register `samples` in mg, returning the steps to count.
+    fn add(&mut self, samples: &[[i16; 3]]) -> u32 {
+        let mut steps = 0;
+        for sample in samples {
+            let magnitude = isqrt(sample.iter().map(|axis| (*axis as i32 * *axis as i32) as u32).sum()) as i32;
+            if self.baseline == 0 {
+                self.smooth = magnitude * 4;
+                self.baseline = magnitude * 32;
+            }
+            self.smooth += magnitude - self.smooth / 4;
+            self.baseline += magnitude - self.baseline / 32;
+            self.since = self.since.saturating_add(1);
+            let excess = self.smooth / 4 - self.baseline / 32;
+
+            if self.above {
+                self.above = excess > STEP_RELEASE;
+            } else if excess > STEP_THRESHOLD && self.since >= MIN_STEP_SAMPLES {
+                self.above = true;
+                if self.since > MAX_STEP_SAMPLES {
+                    self.run = 0;
+                }
+                self.since = 0;
+                self.run += 1;
+                steps += match self.run {
+                    RUN_STEPS => RUN_STEPS,
+                    run if run > RUN_STEPS => 1,
+                    _ => 0,
+                };
+            }
+        }
+        steps
+    }
+}
+
+fn isqrt(n: u32) -> u32 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = x / 2 + 1;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+/// Put the accelerometer to sleep before powering off. Returns at once if it does not run.
+pub async fn suspend() {
+    SUSPEND.signal(());
+    let _ = select(SUSPENDED.wait(), Timer::after(Duration::from_millis(100))).await;
+}
+
+/// Read the accelerometer found at boot, if any, count steps from it and pass its samples on to sleep tracking.
+#[embassy_executor::task]
+pub async fn motion_task(i2c_bus: &'static I2cBus) {
+    let Some(part) = variant::accelerometer() else {
+        info!("No accelerometer, steps and sleep are not tracked");
+        return;
+    };
+    profiled(Task::Motion, async move {
+        let mut sensor = Sensor {
+            i2c: I2cDevice::new(i2c_bus),
+            part,
+        };
+        if error::recover(sensor.start().await, Error::Accelerometer).is_none() {
+            return;
+        }
+        info!("Counting steps with the {:?}", part);
+        let mut steps = StepCounter::default();
+        let mut samples = [[0; 3]; BATCH];
+        loop {
+            health::heartbeat(Task::Motion, part.read_period() * 3);
+            if let Either::Second(_) = select(Timer::after(part.read_period()), SUSPEND.wait()).await {
+                error::recover(sensor.stop(), Error::Accelerometer);
+                SUSPENDED.signal(());
+                health::stop(Task::Motion);
+                return;
+            }
+            let Some(mut available) = error::recover(sensor.available(), Error::Accelerometer) else {
+                continue;
+            };
+            while available > 0 {
+                let Some(count) = error::recover(sensor.read(available, &mut samples), Error::Accelerometer) else {
+                    break;
+                };
+                activity::add_steps(steps.add(&samples[..count]));
+                sleep::add_samples(&samples[..count]);
+                available -= count;
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,95 @@
+//! What the phone plays, as its companion app tells it, kept for the Music screen, and the controls the screen sends
+//! back to the phone.
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use embassy_sync::channel::Channel;
+use heapless::String;
+use watchful_ui::TRACK_SIZE;
+
+#[cfg(not(feature = "no-softdevice"))]
+use crate::events::{self, SensorEvent};
+#[cfg(not(feature = "no-softdevice"))]
+use crate::notifications::truncated;
+
+#[derive(Clone, PartialEq)]
+pub struct NowPlaying {
+    pub artist: String<TRACK_SIZE>,
+    pub track: String<TRACK_SIZE>,
+    pub playing: bool,
+}
+
+/// What the Music screen asks the phone to do.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Control {
+    /// The screen was opened, for the phone to send what it plays.
+    Open,
+    Play,
+    Pause,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown,
+}
+
+static STATE: BMutex<CriticalSectionRawMutex, RefCell<NowPlaying>> = BMutex::new(RefCell::new(NowPlaying {
+    artist: String::new(),
+    track: String::new(),
+    playing: false,
+}));
+
+/// Controls on their way to the phone. Those sent without a phone to take them, or faster than it takes them, are
+/// dropped.
+static CONTROLS: Channel<CriticalSectionRawMutex, Control, 4> = Channel::new();
+
+#[cfg(not(feature = "no-softdevice"))]
+fn update(f: impl FnOnce(&mut NowPlaying)) {
+    STATE.lock(|state| f(&mut state.borrow_mut()));
+    events::publish(SensorEvent::Music);
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+pub fn set_artist(artist: &str) {
+    update(|state| state.artist = truncated(artist));
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+pub fn set_track(track: &str) {
+    update(|state| state.track = truncated(track));
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+pub fn set_playing(playing: bool) {
+    update(|state| state.playing = playing);
+}
+
+/// Forget the track, as when the phone that plays it is gone.
+#[cfg(not(feature = "no-softdevice"))]
+pub fn clear() {
+    update(|state| {
+        state.artist.clear();
+        state.track.clear();
+        state.playing = false;
+    });
+}
+
+pub fn now_playing() -> NowPlaying {
+    STATE.lock(|state| state.borrow().clone())
+}
+
+pub fn control(control: Control) {
+    let _ = CONTROLS.try_send(control);
+}
+
+/// The next control to send to the phone.
+#[cfg(not(feature = "no-softdevice"))]
+pub async fn next_control() -> Control {
+    CONTROLS.receive().await
+}
+
+/// Drop the controls left over from an earlier connection.
+#[cfg(not(feature = "no-softdevice"))]
+pub fn clear_controls() {
+    while CONTROLS.try_receive().is_ok() {}
+}
@@ -0,0 +1,76 @@
+//! Turn by turn navigation sent by the phone, of which the latest state is kept for the Navigation screen. Each part
+//! is written separately by the phone, and replaces the same part of the state.
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use heapless::String;
+use watchful_ui::{Turn, DISTANCE_SIZE, INSTRUCTION_SIZE};
+
+use crate::events::{self, SensorEvent};
+use crate::notifications::truncated;
+
+#[derive(Clone, PartialEq)]
+pub struct Navigation {
+    pub turn: Turn,
+    /// What to do at the next turn, such as the street to turn into. Empty once navigation ended.
+    pub instruction: String<INSTRUCTION_SIZE>,
+    /// Distance to the next turn, formatted by the phone with its unit.
+    pub distance: String<DISTANCE_SIZE>,
+    /// Percent of the route done.
+    pub progress: u8,
+}
+
+static STATE: BMutex<CriticalSectionRawMutex, RefCell<Navigation>> = BMutex::new(RefCell::new(Navigation {
+    turn: Turn::Straight,
+    instruction: String::new(),
+    distance: String::new(),
+    progress: 0,
+}));
+
+fn update(f: impl FnOnce(&mut Navigation)) {
+    STATE.lock(|state| f(&mut state.borrow_mut()));
+}
+
+pub fn set_turn(turn: Turn) {
+    update(|state| state.turn = turn);
+    events::publish(SensorEvent::Navigation { new_instruction: false });
+}
+
+/// Set the instruction for the next turn, telling the UI to show it if it is a new one.
+pub fn set_instruction(instruction: &str) {
+    let instruction: String<INSTRUCTION_SIZE> = truncated(instruction);
+    let mut changed = false;
+    update(|state| {
+        if state.instruction != instruction {
+            changed = !instruction.is_empty();
+            state.instruction = instruction;
+        }
+    });
+    events::publish(SensorEvent::Navigation {
+        new_instruction: changed,
+    });
+}
+
+pub fn set_distance(distance: &str) {
+    update(|state| state.distance = truncated(distance));
+    events::publish(SensorEvent::Navigation { new_instruction: false });
+}
+
+pub fn set_progress(progress: u8) {
+    update(|state| state.progress = progress.min(100));
+    events::publish(SensorEvent::Navigation { new_instruction: false });
+}
+
+/// Forget the route, as when the phone that navigates it is gone.
+pub fn clear() {
+    set_instruction("");
+}
+
+/// The route being navigated, if any.
+pub fn latest() -> Option<Navigation> {
+    STATE.lock(|state| {
+        let state = state.borrow();
+        (!state.instruction.is_empty()).then(|| state.clone())
+    })
+}
@@ -0,0 +1,154 @@
+//! Notifications pushed by the phone, kept in RAM for the UI to show. They are not persisted, a phone sends new ones
+//! as they come and keeps its own history.
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use heapless::{Deque, String};
+
+use crate::clock::Clock;
+use crate::events::{self, SensorEvent};
+
+/// Notifications kept, the oldest are dropped first.
+const KEPT: usize = 5;
+pub const TITLE_SIZE: usize = 32;
+pub const BODY_SIZE: usize = 160;
+
+/// Categories of the Alert Notification Service, which other protocols map theirs to.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Category {
+    Simple,
+    Email,
+    News,
+    Call,
+    MissedCall,
+    Sms,
+    VoiceMail,
+    Schedule,
+    HighPriority,
+    InstantMessage,
+    /// Any other category, such as the custom one of Gadgetbridge.
+    Other,
+}
+
+impl Category {
+    /// The category of an Alert Notification Service category id.
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            0 => Self::Simple,
+            1 => Self::Email,
+            2 => Self::News,
+            3 => Self::Call,
+            4 => Self::MissedCall,
+            5 => Self::Sms,
+            6 => Self::VoiceMail,
+            7 => Self::Schedule,
+            8 => Self::HighPriority,
+            9 => Self::InstantMessage,
+            _ => Self::Other,
+        }
+    }
+
+    /// Shown as the title of the notification.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Email => "Email",
+            Self::News => "News",
+            Self::Call => "Call",
+            Self::MissedCall => "Missed call",
+            Self::Sms => "Message",
+            Self::VoiceMail => "Voice mail",
+            Self::Schedule => "Calendar",
+            Self::InstantMessage => "Chat",
+            Self::Simple | Self::HighPriority | Self::Other => "Notification",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Notification {
+    pub category: Category,
+    /// Sender or app, empty if the phone sent none.
+    pub title: String<TITLE_SIZE>,
+    pub body: String<BODY_SIZE>,
+}
+
+impl Notification {
+    /// A notification with `title` and `body` cut to fit, at a character boundary.
+    pub fn new(category: Category, title: &str, body: &str) -> Self {
+        Self {
+            category,
+            title: truncated(title),
+            body: truncated(body),
+        }
+    }
+}
+
+/// `text` cut to fit, at a character boundary.
+pub fn truncated<const N: usize>(text: &str) -> String<N> {
+    let mut s = String::new();
+    for c in text.chars() {
+        if s.push(c).is_err() {
+            break;
+        }
+    }
+    s
+}
+
+/// The longest prefix of `data` that is valid UTF-8, as text cut to fit a packet may end within a character.
+pub fn utf8_prefix(data: &[u8]) -> &str {
+    match core::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&data[..e.valid_up_to()]).unwrap_or_default(),
+    }
+}
+
+struct Store {
+    recent: Deque<Notification, KEPT>,
+    /// Julian day of the last notification, and the notifications received on it.
+    day: i32,
+    count: u32,
+}
+
+static STORE: BMutex<CriticalSectionRawMutex, RefCell<Store>> = BMutex::new(RefCell::new(Store {
+    recent: Deque::new(),
+    day: 0,
+    count: 0,
+}));
+
+/// Keep a notification, dropping the oldest if the store is full, and tell the UI.
+pub fn push(notification: Notification, clock: &Clock) {
+    let day = clock.get().date().to_julian_day();
+    let category = notification.category;
+    STORE.lock(|store| {
+        let mut store = store.borrow_mut();
+        if store.recent.is_full() {
+            store.recent.pop_front();
+        }
+        let _ = store.recent.push_back(notification);
+        if store.day != day {
+            store.day = day;
+            store.count = 0;
+        }
+        store.count += 1;
+    });
+    events::publish(SensorEvent::Notification(category));
+}
+
+/// The most recent notification, if any.
+pub fn latest() -> Option<Notification> {
+    STORE.lock(|store| store.borrow().recent.back().cloned())
+}
+
+/// Notifications received today.
+pub fn count_today(clock: &Clock) -> u32 {
+    let day = clock.get().date().to_julian_day();
+    STORE.lock(|store| {
+        let store = store.borrow();
+        if store.day == day {
+            store.count
+        } else {
+            0
+        }
+    })
+}
@@ -0,0 +1,392 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_futures::select::select;
+use embassy_nrf::peripherals::{TWISPI0, TWISPI1};
+use embassy_nrf::spim::Spim;
+use embassy_nrf::twim::Twim;
+use embassy_nrf::{pac, saadc};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_hal::{i2c, spi};
+use watchful_ui::TextView;
+
+use crate::device::Device;
+use crate::events::{self, BleCommand, SensorEvent};
+use crate::heartrate::SharedHrs;
+use crate::profile::{profiled, Task};
+use crate::{health, softdevice, ExternalFlash};
+
+/// How often the external flash is put back into deep power-down after being woken by an access without a lock.
+const FLASH_IDLE_CHECK: Duration = Duration::from_secs(10);
+/// Time given to the BLE stack to close the connection before powering off.
+const DISCONNECT_TIME: Duration = Duration::from_millis(500);
+/// Battery level in percent at which the watch powers itself off, while there is still charge left to do so.
+const CRITICAL_LEVEL: u8 = 2;
+/// Battery level in percent at which the watch enters power reserve by itself.
+const RESERVE_LEVEL: u8 = 3;
+
+/// Subsystems that are only powered while something needs them.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Subsystem {
+    HeartRate,
+    /// The external flash wakes up on any access, a lock only keeps it from being powered down in between.
+    ExternalFlash,
+}
+
+const SUBSYSTEMS: [Subsystem; 2] = [Subsystem::HeartRate, Subsystem::ExternalFlash];
+
+/// Features the power budget denies while the battery is low and not charging.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Feature {
+    /// Periodic heart rate sampling. Workouts still measure the heart rate.
+    BackgroundHeartRate,
+    /// Starting a firmware update. An update already in progress is allowed to finish.
+    FirmwareUpdate,
+}
+
+const FEATURES: [Feature; 2] = [Feature::BackgroundHeartRate, Feature::FirmwareUpdate];
+
+/// Powers subsystems up while at least one `PowerLock` for them is held, and down once the last one is released.
+///
+/// Also holds the power budget: below a battery level set per feature, features are denied until the watch is
+/// charged.
+pub struct PowerManager {
+    users: [AtomicU8; SUBSYSTEMS.len()],
+    hrs_powered: Mutex<CriticalSectionRawMutex, bool>,
+    released: Signal<CriticalSectionRawMutex, ()>,
+    hrs: &'static SharedHrs,
+    flash: &'static BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>>,
+    /// Battery level in percent below which each feature is denied.
+    thresholds: [AtomicU8; FEATURES.len()],
+    battery_level: AtomicU8,
+    charging: AtomicBool,
+    /// In power reserve, BLE is off and all features are denied, the watch only shows the time on a button press.
+    reserve: AtomicBool,
+}
+
+impl PowerManager {
+    pub fn new(
+        hrs: &'static SharedHrs,
+        flash: &'static BMutex<CriticalSectionRawMutex, RefCell<ExternalFlash>>,
+    ) -> Self {
+        Self {
+            users: [AtomicU8::new(0), AtomicU8::new(0)],
+            hrs_powered: Mutex::new(false),
+            released: Signal::new(),
+            hrs,
+            flash,
+            thresholds: [AtomicU8::new(0), AtomicU8::new(0)],
+            battery_level: AtomicU8::new(100),
+            charging: AtomicBool::new(false),
+            reserve: AtomicBool::new(false),
+        }
+    }
+
+    /// Enter or leave power reserve. BLE is suspended while in reserve, ending the current connection.
+    pub fn set_reserve(&self, reserve: bool) {
+        if self.reserve.swap(reserve, Ordering::Relaxed) == reserve {
+            return;
+        }
+        info!("Power reserve {}", if reserve { "entered" } else { "left" });
+        events::send_ble(if reserve {
+            BleCommand::Suspend
+        } else {
+            BleCommand::Resume
+        });
+    }
+
+    pub fn in_reserve(&self) -> bool {
+        self.reserve.load(Ordering::Relaxed)
+    }
+
+    /// Deny `feature` while the battery is below `level` percent and not charging.
+    pub fn set_threshold(&self, feature: Feature, level: u8) {
+        self.thresholds[feature as usize].store(level, Ordering::Relaxed);
+    }
+
+    /// Report a battery measurement, which the power budget is based on. A critical level is published as a sensor
+    /// event on each report, until the watch is charged or powers off.
+    pub fn update_battery(&self, level: u8, charging: bool) {
+        let before = FEATURES.map(|feature| self.allows(feature));
+        let previous = self.battery_level.swap(level, Ordering::Relaxed);
+        if charging {
+            self.set_reserve(false);
+        } else if previous > RESERVE_LEVEL && level <= RESERVE_LEVEL {
+            self.set_reserve(true);
+        }
+        self.charging.store(charging, Ordering::Relaxed);
+        for (feature, before) in FEATURES.into_iter().zip(before) {
+            let after = self.allows(feature);
+            if after != before {
+                info!(
+                    "{:?} {} at battery {}%",
+                    feature,
+                    if after { "allowed" } else { "denied" },
+                    level
+                );
+            }
+        }
+        if !charging && level <= CRITICAL_LEVEL {
+            warn!("Battery critical at {}%", level);
+            events::publish(SensorEvent::BatteryCritical);
+        }
+    }
+
+    /// Whether the power budget allows `feature` at the last reported battery level.
+    pub fn allows(&self, feature: Feature) -> bool {
+        if self.in_reserve() {
+            return false;
+        }
+        self.charging.load(Ordering::Relaxed)
+            || self.battery_level.load(Ordering::Relaxed) >= self.thresholds[feature as usize].load(Ordering::Relaxed)
+    }
+
+    /// Power up `subsystem` if needed, keeping it powered until the returned lock is dropped.
+    pub async fn acquire(&'static self, subsystem: Subsystem) -> PowerLock {
+        self.users[subsystem as usize].fetch_add(1, Ordering::Relaxed);
+        self.apply(subsystem).await;
+        PowerLock {
+            manager: self,
+            subsystem,
+        }
+    }
+
+    /// Power down all subsystems regardless of their users, before powering off.
+    async fn shutdown(&self) {
+        let mut powered = self.hrs_powered.lock().await;
+        let mut hrs = self.hrs.lock().await;
+        if let Err(e) = hrs.disable_oscillator().and_then(|_| hrs.disable_hrs()) {
+            warn!("Error powering down heart rate sensor: {:?}", defmt::Debug2Format(&e));
+        }
+        *powered = false;
+        if let Err(e) = self.flash.lock(|flash| flash.borrow_mut().inner_mut().power_down()) {
+            warn!("Error powering down external flash: {:?}", defmt::Debug2Format(&e));
+        }
+    }
+
+    fn in_use(&self, subsystem: Subsystem) -> bool {
+        self.users[subsystem as usize].load(Ordering::Relaxed) > 0
+    }
+
+    /// Bring the power state of `subsystem` in line with its users.
+    async fn apply(&self, subsystem: Subsystem) {
+        let wanted = self.in_use(subsystem);
+        match subsystem {
+            Subsystem::HeartRate => {
+                let mut powered = self.hrs_powered.lock().await;
+                if *powered == wanted {
+                    return;
+                }
+                let mut hrs = self.hrs.lock().await;
+                let result = if wanted {
+                    hrs.init()
+                        .and_then(|_| hrs.enable_hrs())
+                        .and_then(|_| hrs.enable_oscillator())
+                } else {
+                    hrs.disable_oscillator().and_then(|_| hrs.disable_hrs())
+                };
+                if let Err(e) = result {
+                    warn!("Error switching heart rate sensor power: {:?}", defmt::Debug2Format(&e));
+                    return;
+                }
+                *powered = wanted;
+            }
+            Subsystem::ExternalFlash => {
+                let result = self.flash.lock(|flash| {
+                    let mut flash = flash.borrow_mut();
+                    let flash = flash.inner_mut();
+                    if flash.is_asleep() != wanted {
+                        return Ok(false);
+                    }
+                    if wanted {
+                        flash.wake_up().map(|_| true)
+                    } else {
+                        flash.power_down().map(|_| true)
+                    }
+                });
+                match result {
+                    Ok(true) => {}
+                    Ok(false) => return,
+                    Err(e) => {
+                        warn!("Error switching external flash power: {:?}", defmt::Debug2Format(&e));
+                        return;
+                    }
+                }
+            }
+        }
+        info!("{:?} powered {}", subsystem, if wanted { "up" } else { "down" });
+    }
+}
+
+/// Keeps a subsystem powered while held.
+pub struct PowerLock {
+    manager: &'static PowerManager,
+    subsystem: Subsystem,
+}
+
+impl Drop for PowerLock {
+    fn drop(&mut self) {
+        if self.manager.users[self.subsystem as usize].fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.manager.released.signal(());
+        }
+    }
+}
+
+/// Serial and analog peripherals that are only enabled while in use. An enabled peripheral keeps requesting the
+/// high frequency clock and draws current even between transfers.
+pub trait Gate {
+    fn set_enabled(enabled: bool);
+}
+
+impl Gate for Spim<'_, TWISPI0> {
+    fn set_enabled(enabled: bool) {
+        let spim = unsafe { &*pac::SPIM0::ptr() };
+        spim.enable.write(|w| {
+            if enabled {
+                w.enable().enabled()
+            } else {
+                w.enable().disabled()
+            }
+        });
+    }
+}
+
+impl Gate for Twim<'_, TWISPI1> {
+    fn set_enabled(enabled: bool) {
+        let twim = unsafe { &*pac::TWIM1::ptr() };
+        twim.enable.write(|w| {
+            if enabled {
+                w.enable().enabled()
+            } else {
+                w.enable().disabled()
+            }
+        });
+    }
+}
+
+impl Gate for saadc::Saadc<'_, 1> {
+    fn set_enabled(enabled: bool) {
+        let saadc = unsafe { &*pac::SAADC::ptr() };
+        saadc.enable.write(|w| {
+            if enabled {
+                w.enable().enabled()
+            } else {
+                w.enable().disabled()
+            }
+        });
+    }
+}
+
+/// Bus wrapper enabling the peripheral for each transfer and disabling it again afterwards. The peripheral keeps
+/// its configuration while disabled.
+pub struct Gated<B> {
+    bus: B,
+}
+
+impl<B: Gate> Gated<B> {
+    pub fn new(bus: B) -> Self {
+        B::set_enabled(false);
+        Self { bus }
+    }
+
+    fn with<R>(&mut self, f: impl FnOnce(&mut B) -> R) -> R {
+        B::set_enabled(true);
+        let result = f(&mut self.bus);
+        B::set_enabled(false);
+        result
+    }
+}
+
+impl<B: spi::ErrorType> spi::ErrorType for Gated<B> {
+    type Error = B::Error;
+}
+
+impl<B: spi::SpiBus + Gate> spi::SpiBus for Gated<B> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.with(|bus| bus.read(words))
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.with(|bus| bus.write(words))
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        self.with(|bus| bus.transfer(read, write))
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.with(|bus| bus.transfer_in_place(words))
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.with(|bus| bus.flush())
+    }
+}
+
+impl<B: i2c::ErrorType> i2c::ErrorType for Gated<B> {
+    type Error = B::Error;
+}
+
+impl<B: i2c::I2c + Gate> i2c::I2c for Gated<B> {
+    fn transaction(&mut self, address: u8, operations: &mut [i2c::Operation<'_>]) -> Result<(), Self::Error> {
+        self.with(|bus| bus.transaction(address, operations))
+    }
+}
+
+/// Power off the watch for storage or transport, until the button is pressed again.
+///
+/// Pending settings are saved, the BLE connection is closed and the sensors, flash and display are put in their
+/// lowest power state before the nRF52 enters System OFF, which also ends advertising. The time and step counts
+/// are kept in retained RAM. Pressing the button boots the watch from scratch.
+pub async fn power_off(device: &mut Device<'_>) -> ! {
+    warn!("Powering off");
+    if let Some(kv) = device.kv {
+        device.settings.flush(kv).await;
+    }
+    events::send_ble(BleCommand::Disconnect);
+    Timer::after(DISCONNECT_TIME).await;
+    // Pressing the button wakes the watch, so it must be released before powering off.
+    while device.button.is_pressed() {
+        Timer::after(Duration::from_millis(50)).await;
+    }
+    device.power.shutdown().await;
+    crate::motion::suspend().await;
+    device.screen.sleep();
+    device.button.enable_wakeup();
+    crate::retained::save(device.clock);
+    let ret = softdevice::system_off();
+    panic!("Error entering System OFF: {}", ret);
+}
+
+/// Power off before the battery is exhausted, telling the user why. Connecting the charger turns the watch on
+/// again, as does the button.
+pub async fn shutdown_critical(device: &mut Device<'_>) -> ! {
+    device.screen.wake();
+    let _ = TextView::new("Battery empty", "Charge the watch to turn it on again.").draw(&mut device.screen);
+    device.screen.on();
+    Timer::after(Duration::from_secs(3)).await;
+    crate::device::enable_charger_wakeup();
+    power_off(device).await
+}
+
+/// Power down subsystems once their last lock is released.
+#[embassy_executor::task]
+pub async fn power_task(power: &'static PowerManager) {
+    profiled(Task::Power, async move {
+        loop {
+            health::heartbeat(Task::Power, FLASH_IDLE_CHECK * 3);
+            for subsystem in SUBSYSTEMS {
+                if !power.in_use(subsystem) {
+                    power.apply(subsystem).await;
+                }
+            }
+            select(power.released.wait(), Timer::after(FLASH_IDLE_CHECK)).await;
+        }
+    })
+    .await
+}
@@ -0,0 +1,187 @@
+use core::fmt::Write as _;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+use cortex_m::peripheral::DWT;
+use defmt::info;
+use embassy_time::{Duration, Instant, Timer, TICK_HZ};
+use heapless::String;
+
+/// Length of the window CPU usage is averaged over. Short enough for the cycle counters not to overflow at 64 MHz.
+const WINDOW: Duration = Duration::from_secs(60);
+pub const CPU_HZ: u64 = 64_000_000;
+
+/// Executor tasks whose CPU time is accounted separately.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Task {
+    /// The UI state machine in `main`.
+    Ui,
+    /// Advertising and the GATT server of the current connection.
+    Ble,
+    Softdevice,
+    HeartRate,
+    Activity,
+    Sleep,
+    BatteryStats,
+    Maintenance,
+    Settings,
+    Bonds,
+    FlashStats,
+    Power,
+    WakeLock,
+    Clock,
+    Watchdog,
+    Charger,
+    Errors,
+    /// Storing the progress of firmware updates, and the serial DFU transport.
+    Dfu,
+    /// The debug shell, if built with the `shell` feature.
+    Shell,
+    /// Reading the accelerometer and counting steps.
+    Motion,
+}
+
+pub const TASKS: [Task; 20] = [
+    Task::Ui,
+    Task::Ble,
+    Task::Softdevice,
+    Task::HeartRate,
+    Task::Activity,
+    Task::Sleep,
+    Task::BatteryStats,
+    Task::Maintenance,
+    Task::Settings,
+    Task::Bonds,
+    Task::FlashStats,
+    Task::Power,
+    Task::WakeLock,
+    Task::Clock,
+    Task::Watchdog,
+    Task::Charger,
+    Task::Errors,
+    Task::Dfu,
+    Task::Shell,
+    Task::Motion,
+];
+
+impl Task {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ui => "ui",
+            Self::Ble => "ble",
+            Self::Softdevice => "sd",
+            Self::HeartRate => "hr",
+            Self::Activity => "act",
+            Self::Sleep => "sleep",
+            Self::BatteryStats => "bat",
+            Self::Maintenance => "maint",
+            Self::Settings => "set",
+            Self::Bonds => "bonds",
+            Self::FlashStats => "flash",
+            Self::Power => "power",
+            Self::WakeLock => "wake",
+            Self::Clock => "clock",
+            Self::Watchdog => "wdt",
+            Self::Charger => "chg",
+            Self::Errors => "err",
+            Self::Dfu => "dfu",
+            Self::Shell => "shell",
+            Self::Motion => "motion",
+        }
+    }
+}
+
+/// CPU cycles spent polling each task in the current window.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CYCLES: AtomicU32 = AtomicU32::new(0);
+static CYCLES: [AtomicU32; TASKS.len()] = [NO_CYCLES; TASKS.len()];
+
+/// Share of the CPU used by each task in the last complete window, in tenths of a percent.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_LOAD: AtomicU16 = AtomicU16::new(0);
+static LOAD: [AtomicU16; TASKS.len()] = [NO_LOAD; TASKS.len()];
+
+/// Start the DWT cycle counter used to time task polls.
+pub fn init() {
+    let mut cp = unsafe { cortex_m::Peripherals::steal() };
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+}
+
+/// Future wrapper adding the cycles spent in each poll of the inner future to the counter of a task.
+///
+/// Interrupts taken during a poll, including the SoftDevice's, are counted towards the task that was interrupted.
+pub struct Profiled<F> {
+    task: Task,
+    future: F,
+}
+
+/// Account the CPU time of `future` to `task`.
+pub fn profiled<F: Future>(task: Task, future: F) -> Profiled<F> {
+    Profiled { task, future }
+}
+
+impl<F: Future> Future for Profiled<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let task = self.task;
+        // Safety: the inner future is never moved out of `self`.
+        let future = unsafe { self.map_unchecked_mut(|p| &mut p.future) };
+        let start = DWT::cycle_count();
+        let result = future.poll(cx);
+        CYCLES[task as usize].fetch_add(DWT::cycle_count().wrapping_sub(start), Ordering::Relaxed);
+        crate::memory::check(task);
+        result
+    }
+}
+
+/// CPU used by `task` in the last window, in tenths of a percent.
+pub fn load(task: Task) -> u16 {
+    LOAD[task as usize].load(Ordering::Relaxed)
+}
+
+/// Per task CPU usage for the diagnostics screen, busiest first. The CPU is asleep for the rest of the time.
+pub fn report<const N: usize>(text: &mut String<N>) {
+    let mut tasks = TASKS;
+    tasks.sort_unstable_by_key(|task| core::cmp::Reverse(load(*task)));
+    let total: u16 = TASKS.iter().map(|task| load(*task)).sum();
+    let _ = writeln!(text, "Busy: {}.{}%", total / 10, total % 10);
+    for task in tasks.iter().take_while(|task| load(**task) > 0) {
+        let load = load(*task);
+        let _ = writeln!(text, "{}: {}.{}%", task.name(), load / 10, load % 10);
+    }
+}
+
+/// Turn the cycle counts of each window into CPU usage and log it, along with the RAM use whenever the stack has
+/// grown deeper.
+#[embassy_executor::task]
+pub async fn profile_task() {
+    let mut start = Instant::now();
+    let mut stack_peak = 0;
+    loop {
+        Timer::after(WINDOW).await;
+        let now = Instant::now();
+        let window = ((now - start).as_ticks() * CPU_HZ / TICK_HZ).max(1);
+        start = now;
+        let mut total = 0;
+        for task in TASKS {
+            let cycles = CYCLES[task as usize].swap(0, Ordering::Relaxed) as u64;
+            let load = (cycles * 1000 / window) as u16;
+            LOAD[task as usize].store(load, Ordering::Relaxed);
+            total += load;
+            if load > 0 {
+                info!("CPU {}: {}.{}%", task, load / 10, load % 10);
+            }
+        }
+        info!("CPU busy: {}.{}%", total / 10, total % 10);
+
+        let peak = crate::memory::usage().stack_peak;
+        if peak > stack_peak {
+            stack_peak = peak;
+            crate::memory::log();
+        }
+    }
+}
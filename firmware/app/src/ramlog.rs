@@ -0,0 +1,92 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use critical_section::{CriticalSection, Mutex, RestoreState};
+
+const SIZE: usize = 4096;
+
+struct Ring {
+    buf: [u8; SIZE],
+    /// Index of the oldest byte.
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.buf[(self.start + self.len) % SIZE] = b;
+            if self.len < SIZE {
+                self.len += 1;
+            } else {
+                self.start = (self.start + 1) % SIZE;
+            }
+        }
+    }
+
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.len);
+        for b in &mut out[..n] {
+            *b = self.buf[self.start];
+            self.start = (self.start + 1) % SIZE;
+        }
+        self.len -= n;
+        n
+    }
+}
+
+static RING: Mutex<RefCell<Ring>> = Mutex::new(RefCell::new(Ring {
+    buf: [0; SIZE],
+    start: 0,
+    len: 0,
+}));
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut RESTORE: RestoreState = RestoreState::invalid();
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+fn write(bytes: &[u8]) {
+    // Safety: only called by the logger, which holds the critical section.
+    let cs = unsafe { CriticalSection::new() };
+    RING.borrow_ref_mut(cs).push(bytes);
+}
+
+/// defmt logger keeping the log in RAM, for release builds without a debug probe.
+///
+/// The encoded defmt frames are kept in a ring buffer that overwrites the oldest bytes when full, and are read out
+/// over the BLE UART with the `log` command. The bytes are the same as defmt-rtt would send, so they are decoded on
+/// the host with `defmt-print -e <elf>`. Frames are zero-delimited, so a frame cut by overwriting is skipped by the
+/// decoder.
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        unsafe {
+            RESTORE = restore;
+            (*core::ptr::addr_of_mut!(ENCODER)).start_frame(write);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        (*core::ptr::addr_of_mut!(ENCODER)).end_frame(write);
+        TAKEN.store(false, Ordering::Relaxed);
+        critical_section::release(RESTORE);
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        (*core::ptr::addr_of_mut!(ENCODER)).write(bytes, write);
+    }
+}
+
+/// Move the oldest logged bytes into `buf`, returning how many were moved. Returns 0 when the log is empty.
+pub fn read(buf: &mut [u8]) -> usize {
+    critical_section::with(|cs| RING.borrow_ref_mut(cs).pop(buf))
+}
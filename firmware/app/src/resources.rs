@@ -0,0 +1,300 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::{info, warn};
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use embedded_storage::nor_flash::ReadNorFlash;
+use heapless::Vec;
+use nrf_dfu::crc::{crc32, crc32_update};
+
+use crate::fs::{self, FileSystem};
+
+/// Location of the resource pack in the filesystem. Uploading a file to this path over BLE installs a new pack.
+pub const PACK_PATH: &str = "/resources.pack";
+/// Where a pack received as DFU assets is copied before it replaces the installed one.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+const ASSETS_PATH: &str = "/resources.new";
+
+const MAGIC: [u8; 4] = *b"WRES";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 32;
+const NAME_SIZE: usize = 16;
+
+/// Maximum number of resources in a pack.
+pub const MAX_ENTRIES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum Error {
+    Fs(fs::Error),
+    /// The pack header or table of contents is not valid.
+    Format,
+    /// The pack was built for a different pack version.
+    Version(u16),
+    TooManyEntries,
+    /// The contents of a resource do not match its checksum.
+    Checksum,
+    NotFound,
+    BufferTooSmall,
+    /// The pack received as DFU assets could not be read back.
+    Flash,
+}
+
+impl From<fs::Error> for Error {
+    fn from(e: fs::Error) -> Self {
+        Self::Fs(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum ResourceKind {
+    Font = 1,
+    Icon = 2,
+    Image = 3,
+}
+
+impl ResourceKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Font),
+            2 => Some(Self::Icon),
+            3 => Some(Self::Image),
+            _ => None,
+        }
+    }
+}
+
+/// State of the installed resource pack.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum Status {
+    /// No pack is installed, the built-in assets are used.
+    Missing = 0,
+    Valid = 1,
+    /// The pack failed its integrity check, the built-in assets are used until a new pack is uploaded.
+    Corrupt = 2,
+}
+
+static STATUS: AtomicU8 = AtomicU8::new(Status::Missing as u8);
+
+fn set_status(status: Status) {
+    STATUS.store(status as u8, Ordering::Relaxed);
+}
+
+/// State of the resource pack as of the last check or upload.
+pub fn status() -> Status {
+    match STATUS.load(Ordering::Relaxed) {
+        1 => Status::Valid,
+        2 => Status::Corrupt,
+        _ => Status::Missing,
+    }
+}
+
+/// Table of contents entry describing a single resource in the pack.
+#[derive(Clone, PartialEq)]
+pub struct Entry {
+    name: [u8; NAME_SIZE],
+    pub kind: ResourceKind,
+    /// Offset of the resource data from the start of the pack.
+    pub offset: u32,
+    pub len: u32,
+    pub crc: u32,
+}
+
+impl Entry {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(NAME_SIZE);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let mut name = [0; NAME_SIZE];
+        name.copy_from_slice(&data[0..16]);
+        core::str::from_utf8(&name).ok()?;
+        Some(Self {
+            name,
+            kind: ResourceKind::from_u8(data[16])?,
+            offset: u32::from_le_bytes([data[20], data[21], data[22], data[23]]),
+            len: u32::from_le_bytes([data[24], data[25], data[26], data[27]]),
+            crc: u32::from_le_bytes([data[28], data[29], data[30], data[31]]),
+        })
+    }
+}
+
+/// Watch face assets (fonts, icons and background images) bundled in a single file on the filesystem.
+///
+/// The pack starts with a header (magic, version, entry count and CRC of the table of contents), followed by
+/// the table of contents and the resource data. Every entry carries the CRC of its data, so a partially
+/// uploaded or corrupted pack is detected before any of it is drawn.
+pub struct ResourcePack<'a> {
+    fs: &'a FileSystem<'a>,
+    entries: Vec<Entry, MAX_ENTRIES>,
+}
+
+impl<'a> ResourcePack<'a> {
+    /// Open the pack if it passed its integrity check, or None if the built-in assets should be used instead.
+    pub async fn get(fs: &'a FileSystem<'a>) -> Option<Self> {
+        if status() != Status::Valid {
+            return None;
+        }
+        Self::open(fs).await.ok()
+    }
+
+    /// Open the pack at `PACK_PATH`, reading its table of contents.
+    pub async fn open(fs: &'a FileSystem<'a>) -> Result<Self, Error> {
+        let mut header = [0; HEADER_SIZE];
+        let (read, total) = fs.read(PACK_PATH, 0, &mut header).await?;
+        if read < HEADER_SIZE || header[0..4] != MAGIC {
+            return Err(Error::Format);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(Error::Version(version));
+        }
+        let count = u16::from_le_bytes([header[6], header[7]]) as usize;
+        if count > MAX_ENTRIES {
+            return Err(Error::TooManyEntries);
+        }
+        let toc_crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+
+        let mut entries = Vec::new();
+        let mut crc = 0;
+        let mut data = [0; ENTRY_SIZE];
+        for i in 0..count {
+            let offset = (HEADER_SIZE + i * ENTRY_SIZE) as u32;
+            let (read, _) = fs.read(PACK_PATH, offset, &mut data).await?;
+            if read < ENTRY_SIZE {
+                return Err(Error::Format);
+            }
+            crc = crc32_update(crc, &data);
+            let entry = Entry::decode(&data).ok_or(Error::Format)?;
+            if entry.offset as usize + entry.len as usize > total {
+                return Err(Error::Format);
+            }
+            let _ = entries.push(entry);
+        }
+        if crc != toc_crc {
+            return Err(Error::Checksum);
+        }
+        Ok(Self { fs, entries })
+    }
+
+    fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.name() == name)
+    }
+
+    /// Read part of a resource, returning the number of bytes read.
+    async fn read(&self, entry: &Entry, offset: u32, buf: &mut [u8]) -> Result<usize, Error> {
+        if offset >= entry.len {
+            return Ok(0);
+        }
+        let len = buf.len().min((entry.len - offset) as usize);
+        let (read, _) = self.fs.read(PACK_PATH, entry.offset + offset, &mut buf[..len]).await?;
+        Ok(read)
+    }
+
+    /// Load a whole resource into `buf`, verifying its checksum.
+    pub async fn load<'b>(&self, name: &str, buf: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let entry = self.find(name).ok_or(Error::NotFound)?;
+        let len = entry.len as usize;
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+        let mut pos = 0;
+        while pos < len {
+            let read = self.read(entry, pos as u32, &mut buf[pos..len]).await?;
+            if read == 0 {
+                return Err(Error::Format);
+            }
+            pos += read;
+        }
+        if crc32(&buf[..len]) != entry.crc {
+            return Err(Error::Checksum);
+        }
+        Ok(&buf[..len])
+    }
+
+    /// Verify the checksum of every resource, reading the pack in small chunks.
+    pub async fn validate(&self) -> Result<(), Error> {
+        let mut chunk = [0; 128];
+        for entry in self.entries.iter() {
+            let mut crc = 0;
+            let mut pos = 0;
+            while pos < entry.len {
+                let read = self.read(entry, pos, &mut chunk).await?;
+                if read == 0 {
+                    return Err(Error::Format);
+                }
+                crc = crc32_update(crc, &chunk[..read]);
+                pos += read as u32;
+            }
+            if crc != entry.crc {
+                warn!("Resource {} is corrupt", entry.name());
+                return Err(Error::Checksum);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Verify the table of contents and the checksum of every resource of the installed pack.
+///
+/// A corrupt pack is kept so the problem can be shown to the user, but none of it is used.
+pub async fn check(fs: &FileSystem<'_>) -> Status {
+    let result = match ResourcePack::open(fs).await {
+        Ok(pack) => pack.validate().await.map(|_| pack.entries().len()),
+        Err(e) => Err(e),
+    };
+    let status = match result {
+        Ok(count) => {
+            info!("Resource pack with {} resources", count);
+            Status::Valid
+        }
+        Err(Error::Fs(fs::Error::NotFound)) => {
+            info!("No resource pack, using built-in assets");
+            Status::Missing
+        }
+        Err(e) => {
+            warn!("Resource pack is corrupt, using built-in assets: {:?}", e);
+            Status::Corrupt
+        }
+    };
+    set_status(status);
+    status
+}
+
+/// Validate a newly uploaded pack, removing it if it is not usable.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+pub async fn install(fs: &FileSystem<'_>) -> Result<(), Error> {
+    let result = match ResourcePack::open(fs).await {
+        Ok(pack) => pack.validate().await.map(|_| pack.entries().len()),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(count) => {
+            info!("Installed resource pack with {} resources", count);
+            set_status(Status::Valid);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Rejecting resource pack: {:?}", e);
+            let _ = fs.remove(PACK_PATH).await;
+            set_status(Status::Missing);
+            Err(e)
+        }
+    }
+}
+
+/// Copy a pack of `size` bytes received as DFU assets to `PACK_PATH` and install it. The installed pack is only
+/// replaced once the copy is whole, so a failed copy leaves it in use.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+pub async fn install_assets<F: ReadNorFlash>(fs: &FileSystem<'_>, assets: &mut F, size: u32) -> Result<(), Error> {
+    let mut buf = [0; 256];
+    for offset in (0..size).step_by(buf.len()) {
+        let chunk = &mut buf[..(size - offset).min(256) as usize];
+        assets.read(offset, chunk).map_err(|_| Error::Flash)?;
+        fs.write(ASSETS_PATH, offset, chunk).await?;
+    }
+    fs.rename(ASSETS_PATH, PACK_PATH).await?;
+    install(fs).await
+}
@@ -0,0 +1,62 @@
+use core::mem::MaybeUninit;
+
+use defmt::{info, warn};
+
+use crate::clock::Clock;
+use crate::{activity, softdevice};
+
+const MAGIC: u32 = 0x0FF5_7A7E;
+/// RAM blocks of the nRF52832.
+const RAM_BLOCKS: u8 = 8;
+/// Retention of both sections of a RAM block in System OFF.
+const RAM_RETENTION: u32 = 0b11 << 16;
+
+/// State kept in RAM that is retained through System OFF and not initialized at boot, so a watch that powered
+/// itself off comes back with its time and step counts.
+#[repr(C)]
+struct Retained {
+    magic: u32,
+    /// Seconds since the unix epoch in watch local time.
+    timestamp: i64,
+    hourly_steps: u32,
+    daily_steps: u32,
+}
+
+#[link_section = ".uninit.SHUTDOWN"]
+static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+
+/// Save the state before entering System OFF, and keep RAM powered so it survives.
+pub fn save(clock: &Clock) {
+    let (hourly_steps, daily_steps) = activity::step_counts();
+    // Safety: only called right before powering off, when nothing else accesses the retained state.
+    let retained = unsafe { &mut *core::ptr::addr_of_mut!(RETAINED) };
+    retained.write(Retained {
+        magic: MAGIC,
+        timestamp: clock.get().assume_utc().unix_timestamp(),
+        hourly_steps,
+        daily_steps,
+    });
+    for block in 0..RAM_BLOCKS {
+        if let Err(ret) = softdevice::set_ram_power(block, RAM_RETENTION) {
+            warn!("Error retaining RAM block {}: {}", block, ret);
+        }
+    }
+}
+
+/// Restore the state saved before the watch powered itself off, if any.
+///
+/// The clock does not run in System OFF, so the time restored is the time of the shutdown, until the time is
+/// synchronized again.
+pub fn restore(clock: &Clock) {
+    let retained = unsafe { &mut *core::ptr::addr_of_mut!(RETAINED) };
+    let retained = unsafe { &mut *retained.as_mut_ptr() };
+    if retained.magic != MAGIC {
+        return;
+    }
+    retained.magic = 0;
+    if let Ok(time) = time::OffsetDateTime::from_unix_timestamp(retained.timestamp) {
+        clock.set(time::PrimitiveDateTime::new(time.date(), time.time()));
+    }
+    activity::restore_steps(retained.hourly_steps, retained.daily_steps);
+    info!("Restored state from before power off");
+}
@@ -0,0 +1,278 @@
+use defmt::warn;
+use embedded_storage::nor_flash::NorFlash;
+use nrf_dfu::crc::crc32;
+
+const SECTOR_MAGIC: u32 = 0x474C_4657;
+// Magic, sequence number and id of the first record in the sector.
+const SECTOR_HEADER_SIZE: u32 = 12;
+// Length, reserved, record id and CRC of the payload.
+const RECORD_HEADER_SIZE: usize = 12;
+
+/// Largest payload that can be stored in a single record.
+pub const MAX_RECORD_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum Error {
+    Flash,
+    TooLarge,
+}
+
+/// Append-only log of small records in a flash region.
+///
+/// The region is written one erase sector at a time. When a sector is full, the log moves on to the next one,
+/// erasing the oldest sector in the region, so every sector sees the same number of erase cycles. Each record
+/// carries a monotonically increasing id and a CRC, so partially written records after a reset are skipped.
+pub struct RingLog<F: NorFlash> {
+    flash: F,
+    sectors: u32,
+    current: u32,
+    offset: u32,
+    seq: u32,
+    next_id: u32,
+    erased: Option<u32>,
+}
+
+struct RecordHeader {
+    len: u16,
+    id: u32,
+    crc: u32,
+}
+
+impl<F: NorFlash> RingLog<F> {
+    /// Open the log in `flash`, recovering the write position from the existing contents.
+    pub fn new(flash: F) -> Result<Self, Error> {
+        let sectors = flash.capacity() as u32 / F::ERASE_SIZE as u32;
+        let mut log = Self {
+            flash,
+            sectors,
+            current: 0,
+            offset: SECTOR_HEADER_SIZE,
+            seq: 0,
+            next_id: 0,
+            erased: None,
+        };
+
+        let mut newest: Option<(u32, u32, u32)> = None;
+        for sector in 0..sectors {
+            if let Some((seq, first_id)) = log.read_sector_header(sector)? {
+                if newest.map(|(_, s, _)| seq > s).unwrap_or(true) {
+                    newest.replace((sector, seq, first_id));
+                }
+            }
+        }
+
+        match newest {
+            Some((sector, seq, first_id)) => {
+                log.current = sector;
+                log.seq = seq;
+                log.next_id = first_id;
+                let mut offset = SECTOR_HEADER_SIZE;
+                while let Some(header) = log.read_record_header(sector, offset)? {
+                    log.next_id = header.id.wrapping_add(1);
+                    offset += record_size(header.len as usize);
+                }
+                log.offset = offset;
+            }
+            None => log.start_sector(0, 0)?,
+        }
+        Ok(log)
+    }
+
+    /// Id that will be assigned to the next appended record.
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+
+    /// Number of erase sectors in the region.
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    /// Append a record, returning its id.
+    pub fn append(&mut self, data: &[u8]) -> Result<u32, Error> {
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let size = record_size(data.len());
+        if self.offset + size > F::ERASE_SIZE as u32 {
+            let next = (self.current + 1) % self.sectors;
+            self.start_sector(next, self.seq.wrapping_add(1))?;
+        }
+
+        let id = self.next_id;
+        let mut buf = [0xFF; RECORD_HEADER_SIZE + MAX_RECORD_SIZE];
+        buf[0..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        buf[2..4].copy_from_slice(&[0, 0]);
+        buf[4..8].copy_from_slice(&id.to_le_bytes());
+        buf[8..12].copy_from_slice(&crc32(data).to_le_bytes());
+        buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + data.len()].copy_from_slice(data);
+
+        let address = self.address(self.current, self.offset);
+        self.flash
+            .write(address, &buf[..size as usize])
+            .map_err(|_| Error::Flash)?;
+        self.offset += size;
+        self.next_id = id.wrapping_add(1);
+        Ok(id)
+    }
+
+    /// Visit all valid records from oldest to newest.
+    pub fn for_each<C: FnMut(u32, &[u8])>(&mut self, mut f: C) -> Result<(), Error> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        for i in 1..=self.sectors {
+            let sector = (self.current + i) % self.sectors;
+            if self.read_sector_header(sector)?.is_none() {
+                continue;
+            }
+            let mut offset = SECTOR_HEADER_SIZE;
+            while let Some(header) = self.read_record_header(sector, offset)? {
+                let data = &mut buf[..header.len as usize];
+                self.flash
+                    .read(self.address(sector, offset) + RECORD_HEADER_SIZE as u32, data)
+                    .map_err(|_| Error::Flash)?;
+                if crc32(data) == header.crc {
+                    f(header.id, data);
+                } else {
+                    warn!("Skipping corrupt log record {}", header.id);
+                }
+                offset += record_size(header.len as usize);
+            }
+        }
+        Ok(())
+    }
+
+    /// Visit valid records with an id of at least `start_id` from oldest to newest, until `f` returns false.
+    ///
+    /// Sectors that only contain older records are skipped without reading their records.
+    #[cfg(not(feature = "no-softdevice"))]
+    pub fn read_from<C: FnMut(u32, &[u8]) -> bool>(&mut self, start_id: u32, mut f: C) -> Result<(), Error> {
+        let mut buf = [0; MAX_RECORD_SIZE];
+        for i in 1..=self.sectors {
+            let sector = (self.current + i) % self.sectors;
+            if self.read_sector_header(sector)?.is_none() {
+                continue;
+            }
+            if i < self.sectors {
+                let following = (sector + 1) % self.sectors;
+                if let Some((_, first_id)) = self.read_sector_header(following)? {
+                    if first_id <= start_id {
+                        continue;
+                    }
+                }
+            }
+            let mut offset = SECTOR_HEADER_SIZE;
+            while let Some(header) = self.read_record_header(sector, offset)? {
+                if header.id >= start_id {
+                    let data = &mut buf[..header.len as usize];
+                    self.flash
+                        .read(self.address(sector, offset) + RECORD_HEADER_SIZE as u32, data)
+                        .map_err(|_| Error::Flash)?;
+                    if crc32(data) == header.crc && !f(header.id, data) {
+                        return Ok(());
+                    }
+                }
+                offset += record_size(header.len as usize);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes used by records and the size of the region. Once the region is full, the oldest sector is reused.
+    pub fn usage(&mut self) -> Result<(u32, u32), Error> {
+        let mut used = 0;
+        for sector in 0..self.sectors {
+            if sector == self.current {
+                used += self.offset;
+            } else if self.read_sector_header(sector)?.is_some() {
+                used += F::ERASE_SIZE as u32;
+            }
+        }
+        Ok((used, self.sectors * F::ERASE_SIZE as u32))
+    }
+
+    /// Erase the sector the log moves to next once the current sector is mostly full, so that appending does not
+    /// stall on the erase. Returns true if a sector was erased.
+    pub fn erase_ahead(&mut self) -> Result<bool, Error> {
+        let next = (self.current + 1) % self.sectors;
+        if self.erased == Some(next) || self.offset < F::ERASE_SIZE as u32 * 3 / 4 {
+            return Ok(false);
+        }
+        let address = self.address(next, 0);
+        self.flash
+            .erase(address, address + F::ERASE_SIZE as u32)
+            .map_err(|_| Error::Flash)?;
+        self.erased.replace(next);
+        Ok(true)
+    }
+
+    /// Erase all records.
+    #[cfg(not(feature = "no-softdevice"))]
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.flash
+            .erase(0, self.sectors * F::ERASE_SIZE as u32)
+            .map_err(|_| Error::Flash)?;
+        self.erased = Some(0);
+        self.next_id = 0;
+        self.start_sector(0, 0)
+    }
+
+    fn start_sector(&mut self, sector: u32, seq: u32) -> Result<(), Error> {
+        let address = self.address(sector, 0);
+        if self.erased.take() != Some(sector) {
+            self.flash
+                .erase(address, address + F::ERASE_SIZE as u32)
+                .map_err(|_| Error::Flash)?;
+        }
+        let mut header = [0; SECTOR_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&seq.to_le_bytes());
+        header[8..12].copy_from_slice(&self.next_id.to_le_bytes());
+        self.flash.write(address, &header).map_err(|_| Error::Flash)?;
+        self.current = sector;
+        self.seq = seq;
+        self.offset = SECTOR_HEADER_SIZE;
+        Ok(())
+    }
+
+    fn read_sector_header(&mut self, sector: u32) -> Result<Option<(u32, u32)>, Error> {
+        let mut header = [0; SECTOR_HEADER_SIZE as usize];
+        self.flash
+            .read(self.address(sector, 0), &mut header)
+            .map_err(|_| Error::Flash)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if magic != SECTOR_MAGIC {
+            return Ok(None);
+        }
+        let seq = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let first_id = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        Ok(Some((seq, first_id)))
+    }
+
+    fn read_record_header(&mut self, sector: u32, offset: u32) -> Result<Option<RecordHeader>, Error> {
+        if offset + RECORD_HEADER_SIZE as u32 > F::ERASE_SIZE as u32 {
+            return Ok(None);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE];
+        self.flash
+            .read(self.address(sector, offset), &mut header)
+            .map_err(|_| Error::Flash)?;
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        if len as usize > MAX_RECORD_SIZE || offset + record_size(len as usize) > F::ERASE_SIZE as u32 {
+            // Erased flash or a corrupt length, either way this is the end of the sector.
+            return Ok(None);
+        }
+        Ok(Some(RecordHeader {
+            len,
+            id: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+            crc: u32::from_le_bytes([header[8], header[9], header[10], header[11]]),
+        }))
+    }
+
+    fn address(&self, sector: u32, offset: u32) -> u32 {
+        sector * F::ERASE_SIZE as u32 + offset
+    }
+}
+
+fn record_size(len: usize) -> u32 {
+    ((RECORD_HEADER_SIZE + len + 3) & !3) as u32
+}
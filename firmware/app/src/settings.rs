@@ -0,0 +1,265 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{info, warn};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+
+use crate::kv::{self, keys, SharedKv, MAX_VALUE_SIZE};
+use crate::profile::{profiled, Task};
+
+/// How long settings must stay unchanged before they are written to flash.
+const QUIET_PERIOD: Duration = Duration::from_secs(5);
+
+/// Version of the settings layout written by this firmware.
+///
+/// Fields are only ever appended to the payload, so any version can read the fields it knows about. Bump the
+/// version and add a step to `MIGRATIONS` whenever the meaning of an existing field changes.
+const VERSION: u16 = 1;
+const VERSION_SIZE: usize = 2;
+
+/// Steps upgrading settings from version N to N + 1, starting with version 1.
+const MIGRATIONS: [fn(&mut Settings); VERSION as usize - 1] = [];
+
+/// User configuration persisted across reboots and firmware updates.
+#[derive(Clone, PartialEq, defmt::Format)]
+pub struct Settings {
+    /// Background heart rate sampling interval in minutes, 0 disables sampling.
+    pub hr_interval: u8,
+    /// Battery level in percent below which background heart rate sampling is paused.
+    pub hr_min_battery: u8,
+    /// Battery level in percent below which firmware updates are refused.
+    pub dfu_min_battery: u8,
+    /// Vibrate once when charging completes.
+    pub charge_alert: bool,
+    /// Maximum heart rate in bpm. Workout heart rate zones are 50, 60, 70, 80 and 90% of it, and going above it
+    /// alerts until the heart rate comes down.
+    pub hr_max: u8,
+    /// Vibrate when the heart rate changes zone during a workout.
+    pub hr_zone_alerts: bool,
+    /// Daily step goal, celebrated once a day when reached. 0 disables the goal.
+    pub step_goal: u16,
+    /// Walking stride length in cm, used to estimate the distance covered in workouts.
+    pub stride_cm: u8,
+    /// Body weight in kg, used to estimate the energy spent in workouts.
+    pub weight_kg: u8,
+    /// Wake the watch by touching the screen.
+    pub wake_touch: bool,
+    /// Only wake on a double tap rather than any touch, to avoid waking from a brushing sleeve.
+    pub wake_double_tap: bool,
+    /// Wake the watch by raising the wrist.
+    pub wake_raise: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            hr_interval: 10,
+            hr_min_battery: 20,
+            dfu_min_battery: 30,
+            charge_alert: true,
+            hr_max: 185,
+            hr_zone_alerts: true,
+            step_goal: 10000,
+            stride_cm: 75,
+            weight_kg: 70,
+            wake_touch: true,
+            wake_double_tap: false,
+            wake_raise: true,
+        }
+    }
+}
+
+impl Settings {
+    fn encode(&self) -> Vec<u8, { MAX_VALUE_SIZE - VERSION_SIZE }> {
+        let mut payload = Vec::new();
+        let _ = payload.push(self.hr_interval);
+        let _ = payload.push(self.hr_min_battery);
+        let _ = payload.push(self.dfu_min_battery);
+        let _ = payload.push(self.charge_alert as u8);
+        let _ = payload.push(self.hr_max);
+        let _ = payload.push(self.hr_zone_alerts as u8);
+        let _ = payload.extend_from_slice(&self.step_goal.to_le_bytes());
+        let _ = payload.push(self.stride_cm);
+        let _ = payload.push(self.weight_kg);
+        let _ = payload.push(self.wake_touch as u8);
+        let _ = payload.push(self.wake_double_tap as u8);
+        let _ = payload.push(self.wake_raise as u8);
+        payload
+    }
+
+    /// Decode the fields present in `payload`, leaving fields added by later versions at their defaults.
+    fn decode(payload: &[u8]) -> Self {
+        let mut settings = Self::default();
+        let mut fields = payload.iter().copied();
+        if let Some(value) = fields.next() {
+            settings.hr_interval = value;
+        }
+        if let Some(value) = fields.next() {
+            settings.hr_min_battery = value;
+        }
+        if let Some(value) = fields.next() {
+            settings.dfu_min_battery = value;
+        }
+        if let Some(value) = fields.next() {
+            settings.charge_alert = value != 0;
+        }
+        if let Some(value) = fields.next() {
+            settings.hr_max = value;
+        }
+        if let Some(value) = fields.next() {
+            settings.hr_zone_alerts = value != 0;
+        }
+        if let (Some(low), Some(high)) = (fields.next(), fields.next()) {
+            settings.step_goal = u16::from_le_bytes([low, high]);
+        }
+        if let Some(value) = fields.next() {
+            settings.stride_cm = value;
+        }
+        if let Some(value) = fields.next() {
+            settings.weight_kg = value;
+        }
+        if let Some(value) = fields.next() {
+            settings.wake_touch = value != 0;
+        }
+        if let Some(value) = fields.next() {
+            settings.wake_double_tap = value != 0;
+        }
+        if let Some(value) = fields.next() {
+            settings.wake_raise = value != 0;
+        }
+        settings
+    }
+}
+
+/// Load settings, upgrading settings written by older firmware.
+///
+/// Missing or corrupt settings yield the defaults. Settings written by newer firmware are read as far as this
+/// version understands them, and left untouched in flash until they are changed.
+pub async fn load(kv: &SharedKv<'_>) -> Settings {
+    let mut buf = [0; MAX_VALUE_SIZE];
+    let result = kv.lock().await.get(keys::SETTINGS, &mut buf);
+    let data = match result {
+        Ok(Some(len)) => &buf[..len],
+        Ok(None) => {
+            info!("No settings stored, using defaults");
+            return Settings::default();
+        }
+        Err(e) => {
+            warn!("Error loading settings, using defaults: {:?}", e);
+            return Settings::default();
+        }
+    };
+
+    let version = match data {
+        [a, b, ..] => u16::from_le_bytes([*a, *b]),
+        _ => 0,
+    };
+    if version == 0 {
+        warn!("Invalid settings, using defaults");
+        return Settings::default();
+    }
+    let mut settings = Settings::decode(&data[VERSION_SIZE..]);
+    if version < VERSION {
+        info!("Migrating settings from version {} to {}", version, VERSION);
+        for step in &MIGRATIONS[version as usize - 1..] {
+            step(&mut settings);
+        }
+        if let Err(e) = store(kv, &settings).await {
+            warn!("Error storing migrated settings: {:?}", e);
+        }
+    } else if version > VERSION {
+        warn!("Settings written by newer firmware (version {})", version);
+    }
+    settings
+}
+
+/// Settings held in RAM and written back to flash once changes settle, so that rapid changes cost a single
+/// write and the UI never waits on flash.
+pub struct SettingsCache {
+    current: BMutex<CriticalSectionRawMutex, RefCell<Settings>>,
+    dirty: AtomicBool,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+    flush: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl SettingsCache {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            current: BMutex::new(RefCell::new(settings)),
+            dirty: AtomicBool::new(false),
+            changed: Signal::new(),
+            flush: Signal::new(),
+        }
+    }
+
+    /// The current settings, including changes not yet written to flash.
+    pub fn get(&self) -> Settings {
+        self.current.lock(|current| current.borrow().clone())
+    }
+
+    /// Change settings in RAM, scheduling a write once changes have settled.
+    pub fn update(&self, f: impl FnOnce(&mut Settings)) {
+        let changed = self.current.lock(|current| {
+            let mut current = current.borrow_mut();
+            let before = current.clone();
+            f(&mut current);
+            *current != before
+        });
+        if changed {
+            self.dirty.store(true, Ordering::Relaxed);
+            self.changed.signal(());
+        }
+    }
+
+    /// Write pending changes without waiting for the quiet period, e.g. before the watch goes to sleep.
+    pub fn request_flush(&self) {
+        if self.dirty.load(Ordering::Relaxed) {
+            self.flush.signal(());
+        }
+    }
+
+    /// Write pending changes to flash now.
+    pub async fn flush(&self, kv: &SharedKv<'_>) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let settings = self.get();
+        match store(kv, &settings).await {
+            Ok(_) => info!("Settings stored: {:?}", settings),
+            Err(e) => {
+                warn!("Error storing settings: {:?}", e);
+                self.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Write back settings changes after a quiet period, or immediately when a flush is requested.
+#[embassy_executor::task]
+pub async fn settings_task(cache: &'static SettingsCache, kv: &'static SharedKv<'static>) {
+    profiled(Task::Settings, async move {
+        loop {
+            if let Either::First(_) = select(cache.changed.wait(), cache.flush.wait()).await {
+                while let Either3::First(_) =
+                    select3(cache.changed.wait(), cache.flush.wait(), Timer::after(QUIET_PERIOD)).await
+                {
+                }
+            }
+            cache.flush(kv).await;
+        }
+    })
+    .await
+}
+
+/// Persist settings.
+pub async fn store(kv: &SharedKv<'_>, settings: &Settings) -> Result<(), kv::Error> {
+    let mut data: Vec<u8, MAX_VALUE_SIZE> = Vec::new();
+    let _ = data.extend_from_slice(&VERSION.to_le_bytes());
+    let _ = data.extend_from_slice(&settings.encode());
+    kv.lock().await.set(keys::SETTINGS, &data)
+}
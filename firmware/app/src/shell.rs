@@ -0,0 +1,184 @@
+use defmt::{info, warn};
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use rtt_target::{rtt_init, DownChannel};
+use watchful_ui::ButtonEvent;
+
+use crate::clock::{parse_date, parse_time, Clock};
+use crate::device::SharedBattery;
+use crate::events;
+use crate::power::{Feature, PowerManager};
+use crate::profile::{profiled, Task};
+use crate::settings::SettingsCache;
+use crate::wakelock::{self, WakeLockKind};
+
+/// RTT has no interrupt towards the target, so the down channel is polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const LINE_SIZE: usize = 64;
+
+/// Set up RTT with the defmt up channel and the shell's down channel. Must be called before anything is logged.
+pub fn init() -> DownChannel {
+    let channels = rtt_init! {
+        up: {
+            0: {
+                size: 1024,
+                name: "defmt"
+            }
+        }
+        down: {
+            0: {
+                size: LINE_SIZE,
+                name: "Shell"
+            }
+        }
+    };
+    rtt_target::set_defmt_channel(channels.up.0);
+    channels.down.0
+}
+
+/// Parts of the watch the shell inspects and controls. The UI is reached through injected input and redraws.
+pub struct Shell {
+    pub clock: &'static Clock,
+    pub battery: &'static SharedBattery,
+    pub power: &'static PowerManager,
+    pub settings: &'static SettingsCache,
+}
+
+/// Read commands typed into the RTT down channel, one per line, and log their result. Send `help` for the list.
+#[embassy_executor::task]
+pub async fn shell_task(mut input: DownChannel, shell: Shell) {
+    profiled(Task::Shell, async move {
+        let mut line: Vec<u8, LINE_SIZE> = Vec::new();
+        loop {
+            let mut buf = [0; LINE_SIZE];
+            let len = input.read(&mut buf);
+            if len == 0 {
+                Timer::after(POLL_INTERVAL).await;
+                continue;
+            }
+            for &b in &buf[..len] {
+                match b {
+                    b'\r' | b'\n' => {
+                        match core::str::from_utf8(&line) {
+                            Ok(command) if !command.trim().is_empty() => shell.run(command.trim()).await,
+                            Ok(_) => {}
+                            Err(_) => warn!("Shell: invalid UTF-8"),
+                        }
+                        line.clear();
+                    }
+                    _ => {
+                        if line.push(b).is_err() {
+                            warn!("Shell: line too long");
+                            line.clear();
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .await
+}
+
+impl Shell {
+    async fn run(&self, command: &str) {
+        let mut args = command.split_whitespace();
+        match (args.next(), args.next(), args.next()) {
+            (Some("help"), None, None) => info!(
+                "Commands: time HH:MM[:SS], date YYYY-MM-DD, button [long], tap X Y, swipe left|right|up|down, \
+                 redraw, hr MINUTES, reserve on|off, state, mem"
+            ),
+            (Some("time"), Some(time), None) => match parse_time(time) {
+                Some(time) => {
+                    self.clock.set(self.clock.get().replace_time(time));
+                    events::request_redraw();
+                    info!("ok");
+                }
+                None => warn!("Shell: expected HH:MM or HH:MM:SS"),
+            },
+            (Some("date"), Some(date), None) => match parse_date(date) {
+                Some(date) => {
+                    self.clock.set(self.clock.get().replace_date(date));
+                    events::request_redraw();
+                    info!("ok");
+                }
+                None => warn!("Shell: expected YYYY-MM-DD"),
+            },
+            (Some("button"), None, None) => events::inject_button(ButtonEvent::ShortPress),
+            (Some("button"), Some("long"), None) => events::inject_button(ButtonEvent::LongPress),
+            (Some("tap"), Some(x), Some(y)) => match (x.parse(), y.parse()) {
+                (Ok(x), Ok(y)) => events::inject_touch(touch(x, y, cst816s::TouchGesture::SingleClick)),
+                _ => warn!("Shell: expected tap X Y"),
+            },
+            (Some("swipe"), Some(direction), None) => {
+                let gesture = match direction {
+                    "left" => cst816s::TouchGesture::SlideLeft,
+                    "right" => cst816s::TouchGesture::SlideRight,
+                    "up" => cst816s::TouchGesture::SlideUp,
+                    "down" => cst816s::TouchGesture::SlideDown,
+                    _ => {
+                        warn!("Shell: expected swipe left|right|up|down");
+                        return;
+                    }
+                };
+                events::inject_touch(touch(120, 120, gesture));
+            }
+            (Some("redraw"), None, None) => events::request_redraw(),
+            (Some("hr"), Some(minutes), None) => match minutes.parse() {
+                Ok(minutes) => {
+                    crate::heartrate::set_interval(minutes);
+                    info!("ok");
+                }
+                Err(_) => warn!("Shell: expected hr MINUTES, 0 turns background sampling off"),
+            },
+            (Some("reserve"), Some(on @ ("on" | "off")), None) => {
+                self.power.set_reserve(on == "on");
+                events::request_redraw();
+                info!("ok");
+            }
+            (Some("state"), None, None) => self.dump().await,
+            (Some("mem"), None, None) => crate::memory::log(),
+            _ => warn!("Shell: unknown command, send help for the list"),
+        }
+    }
+
+    async fn dump(&self) {
+        info!("Build: {}", crate::buildinfo::BUILD);
+        info!(
+            "Parts: touch={:?} accelerometer={:?}",
+            crate::variant::touch(),
+            crate::variant::accelerometer()
+        );
+        info!("Time: {}", defmt::Debug2Format(&self.clock.get()));
+        let (level, charge) = {
+            let mut battery = self.battery.lock().await;
+            (battery.measure().await, battery.charge_state())
+        };
+        info!("Battery: {}% {:?}", level, charge);
+        info!(
+            "Power: reserve={} background_hr={} dfu={}",
+            self.power.in_reserve(),
+            self.power.allows(Feature::BackgroundHeartRate),
+            self.power.allows(Feature::FirmwareUpdate)
+        );
+        info!(
+            "Wake locks: display={} cpu={}",
+            wakelock::is_held(WakeLockKind::Display),
+            wakelock::is_held(WakeLockKind::Cpu)
+        );
+        #[cfg(not(feature = "no-softdevice"))]
+        info!("Wake lock: ble_fast={}", wakelock::is_held(WakeLockKind::BleFast));
+        info!("Settings: {:?}", self.settings.get());
+    }
+}
+
+fn touch(x: i32, y: i32, gesture: cst816s::TouchGesture) -> cst816s::TouchEvent {
+    cst816s::TouchEvent {
+        x,
+        y,
+        gesture,
+        action: 0,
+        finger_id: 0,
+        pressure: 0,
+        area: 0,
+    }
+}
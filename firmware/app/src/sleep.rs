@@ -0,0 +1,339 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use heapless::String;
+
+use crate::activity::timestamp;
+use crate::clock::Clock;
+use crate::profile::{profiled, Task};
+use crate::ringlog::{self, RingLog};
+use crate::{health, layout, LogPartition};
+
+/// Start of the sleep log region on the external flash.
+pub const LOG_OFFSET: u32 = layout::SLEEP_LOG.start;
+/// Size of the sleep log region.
+pub const LOG_SIZE: u32 = layout::SLEEP_LOG.size;
+
+/// Sleep is only tracked between these local hours, so that sitting still during the day is not taken for sleep.
+const NIGHT_START_HOUR: u8 = 21;
+const NIGHT_END_HOUR: u8 = 10;
+/// Motion is classified per epoch of this length.
+const EPOCH_SECS: u32 = 60;
+/// Variance of the acceleration over an epoch, summed over the axes in mg², below which the wearer is resting, and
+/// below which they are restless rather than awake.
+const REST_VARIANCE: u32 = 15 * 15;
+const RESTLESS_VARIANCE: u32 = 60 * 60;
+/// Consecutive resting epochs that start a session, and awake epochs that end it.
+const FALL_ASLEEP_EPOCHS: u16 = 15;
+const WAKE_UP_EPOCHS: u16 = 30;
+/// Sessions with less sleep than this, in epochs, are naps or a watch left on the table and are not logged.
+const MIN_ASLEEP_EPOCHS: u16 = 60;
+
+/// Sums of the accelerometer samples of the current epoch.
+struct Epoch {
+    count: u32,
+    sum: [i64; 3],
+    sum_squares: [i64; 3],
+}
+
+static EPOCH: BMutex<CriticalSectionRawMutex, RefCell<Epoch>> = BMutex::new(RefCell::new(Epoch {
+    count: 0,
+    sum: [0; 3],
+    sum_squares: [0; 3],
+}));
+
+/// Whether the wearer was resting during the last epoch, day or night.
+static STILL: AtomicBool = AtomicBool::new(false);
+
+/// Whether the wearer was resting during the last minute, for measurements that need stillness.
+pub fn still() -> bool {
+    STILL.load(Ordering::Relaxed)
+}
+
+/// Register a batch of accelerometer samples in mg, as read from the accelerometer FIFO.
+pub fn add_samples(samples: &[[i16; 3]]) {
+    EPOCH.lock(|epoch| {
+        let mut epoch = epoch.borrow_mut();
+        for sample in samples {
+            for (axis, value) in sample.iter().enumerate() {
+                let value = *value as i64;
+                epoch.sum[axis] += value;
+                epoch.sum_squares[axis] += value * value;
+            }
+        }
+        epoch.count += samples.len() as u32;
+    })
+}
+
+/// Variance of the samples since the last call, summed over the axes, or `None` without samples.
+fn take_variance() -> Option<u32> {
+    EPOCH.lock(|epoch| {
+        let mut epoch = epoch.borrow_mut();
+        let count = epoch.count as i64;
+        let variance = (count > 0).then(|| {
+            (0..3)
+                .map(|axis| (epoch.sum_squares[axis] - epoch.sum[axis] * epoch.sum[axis] / count) / count)
+                .sum::<i64>()
+                .clamp(0, u32::MAX as i64) as u32
+        });
+        *epoch = Epoch {
+            count: 0,
+            sum: [0; 3],
+            sum_squares: [0; 3],
+        };
+        variance
+    })
+}
+
+/// How still the wearer was during an epoch.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Period {
+    Rest,
+    Restless,
+    Awake,
+}
+
+impl Period {
+    fn classify(variance: u32) -> Self {
+        if variance < REST_VARIANCE {
+            Self::Rest
+        } else if variance < RESTLESS_VARIANCE {
+            Self::Restless
+        } else {
+            Self::Awake
+        }
+    }
+}
+
+/// A night of sleep in the sleep log. Timestamps are seconds since the unix epoch in watch local time, durations
+/// are in minutes.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct SleepRecord {
+    pub start: u32,
+    pub end: u32,
+    pub rest: u16,
+    pub restless: u16,
+    pub awake: u16,
+}
+
+impl SleepRecord {
+    pub const SIZE: usize = 14;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        buf[0..4].copy_from_slice(&self.start.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.end.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.rest.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.restless.to_le_bytes());
+        buf[12..14].copy_from_slice(&self.awake.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != Self::SIZE {
+            return None;
+        }
+        let u32_at = |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        Some(Self {
+            start: u32_at(0),
+            end: u32_at(4),
+            rest: u16_at(8),
+            restless: u16_at(10),
+            awake: u16_at(12),
+        })
+    }
+
+    /// Minutes spent asleep, resting or restless.
+    pub fn asleep(&self) -> u16 {
+        self.rest + self.restless
+    }
+}
+
+/// Sleep sessions persisted in flash.
+pub struct SleepLog<'a> {
+    log: Mutex<CriticalSectionRawMutex, RingLog<LogPartition<'a>>>,
+}
+
+impl<'a> SleepLog<'a> {
+    pub fn new(log: RingLog<LogPartition<'a>>) -> Self {
+        Self { log: Mutex::new(log) }
+    }
+
+    pub async fn append(&self, record: SleepRecord) -> Result<u32, ringlog::Error> {
+        self.log.lock().await.append(&record.encode())
+    }
+
+    /// The most recent session, if any.
+    pub async fn last(&self) -> Result<Option<SleepRecord>, ringlog::Error> {
+        let mut last = None;
+        self.log.lock().await.for_each(|_, data| {
+            if let Some(record) = SleepRecord::decode(data) {
+                last = Some(record);
+            }
+        })?;
+        Ok(last)
+    }
+
+    /// Bytes used by the log and its capacity.
+    pub async fn usage(&self) -> Result<(u32, u32), ringlog::Error> {
+        self.log.lock().await.usage()
+    }
+
+    pub async fn erase_ahead(&self) -> Result<bool, ringlog::Error> {
+        self.log.lock().await.erase_ahead()
+    }
+}
+
+/// Follows the epochs of a night to find where sleep starts and ends.
+struct Tracker {
+    session: Option<SleepRecord>,
+    /// Consecutive resting epochs before a session, or awake epochs during one.
+    run: u16,
+    run_start: u32,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Self {
+            session: None,
+            run: 0,
+            run_start: 0,
+        }
+    }
+
+    /// Add the epoch starting at `start`, returning the session it ends if the wearer has woken up.
+    fn add(&mut self, start: u32, period: Period) -> Option<SleepRecord> {
+        let Some(session) = self.session.as_mut() else {
+            if period != Period::Rest {
+                self.run = 0;
+                return None;
+            }
+            if self.run == 0 {
+                self.run_start = start;
+            }
+            self.run += 1;
+            if self.run >= FALL_ASLEEP_EPOCHS {
+                self.session = Some(SleepRecord {
+                    start: self.run_start,
+                    end: start + EPOCH_SECS,
+                    rest: self.run,
+                    restless: 0,
+                    awake: 0,
+                });
+                self.run = 0;
+            }
+            return None;
+        };
+
+        session.end = start + EPOCH_SECS;
+        match period {
+            Period::Rest => session.rest += 1,
+            Period::Restless => session.restless += 1,
+            Period::Awake => session.awake += 1,
+        }
+        if period != Period::Awake {
+            self.run = 0;
+            return None;
+        }
+        if self.run == 0 {
+            self.run_start = start;
+        }
+        self.run += 1;
+        if self.run < WAKE_UP_EPOCHS {
+            return None;
+        }
+        self.finish()
+    }
+
+    /// End the current session, if any, returning it if it is long enough to be logged.
+    fn finish(&mut self) -> Option<SleepRecord> {
+        let mut session = self.session.take()?;
+        // The trailing awake epochs are not part of the night.
+        if self.run > 0 {
+            session.end = self.run_start;
+            session.awake -= self.run;
+        }
+        self.run = 0;
+        (session.asleep() >= MIN_ASLEEP_EPOCHS).then_some(session)
+    }
+}
+
+fn at_night(time: time::PrimitiveDateTime) -> bool {
+    time.hour() >= NIGHT_START_HOUR || time.hour() < NIGHT_END_HOUR
+}
+
+/// Summary of the last night for the sleep screen.
+pub async fn report<const N: usize>(log: Option<&SleepLog<'_>>, text: &mut String<N>) {
+    let Some(log) = log else {
+        let _ = write!(text, "Sleep log unavailable");
+        return;
+    };
+    let record = match log.last().await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            let _ = write!(text, "No sleep recorded yet");
+            return;
+        }
+        Err(e) => {
+            let _ = write!(text, "Error reading sleep log: {:?}", e);
+            return;
+        }
+    };
+    let clock_time = |timestamp: u32| (timestamp / 3600 % 24, timestamp / 60 % 60);
+    let (start_hour, start_minute) = clock_time(record.start);
+    let (end_hour, end_minute) = clock_time(record.end);
+    let _ = writeln!(
+        text,
+        "{:02}:{:02} - {:02}:{:02}",
+        start_hour, start_minute, end_hour, end_minute
+    );
+    for (name, minutes) in [
+        ("Asleep", record.asleep()),
+        ("Rest", record.rest),
+        ("Restless", record.restless),
+        ("Awake", record.awake),
+    ] {
+        let _ = writeln!(text, "{}: {}h {:02}m", name, minutes / 60, minutes % 60);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn sleep_task(log: &'static SleepLog<'static>, clock: &'static Clock) {
+    profiled(Task::Sleep, async move {
+        let mut tracker = Tracker::new();
+        loop {
+            health::heartbeat(Task::Sleep, Duration::from_secs(3 * EPOCH_SECS as u64));
+            Timer::after(Duration::from_secs(EPOCH_SECS as u64)).await;
+            let period = take_variance().map(Period::classify);
+            STILL.store(period == Some(Period::Rest), Ordering::Relaxed);
+            let now = clock.get();
+            // Don't track anything until the clock has been synchronized.
+            if now.year() < 2000 {
+                continue;
+            }
+
+            let session = if !at_night(now) {
+                tracker.finish()
+            } else if let Some(period) = period {
+                tracker.add(timestamp(now) - EPOCH_SECS, period)
+            } else {
+                // No samples, the accelerometer is not running.
+                None
+            };
+            if let Some(session) = session {
+                info!("Sleep session: {:?}", session);
+                if let Err(e) = log.append(session).await {
+                    warn!("Error logging sleep session: {:?}", e);
+                }
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,65 @@
+//! Power management calls, which go through the SoftDevice while it is enabled and to the POWER peripheral in builds
+//! without it, see the `no-softdevice` feature.
+#[cfg(feature = "no-softdevice")]
+use embassy_nrf::pac;
+#[cfg(not(feature = "no-softdevice"))]
+use nrf_softdevice::raw;
+
+/// Enter System OFF. Only returns if that failed, with the SoftDevice error.
+#[cfg(not(feature = "no-softdevice"))]
+pub fn system_off() -> u32 {
+    unsafe { raw::sd_power_system_off() }
+}
+
+#[cfg(feature = "no-softdevice")]
+pub fn system_off() -> u32 {
+    power().systemoff.write(|w| w.systemoff().enter());
+    // With a debugger attached System OFF is only emulated, and the CPU keeps running.
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
+
+/// Set bits of the RAM power register of `block`.
+#[cfg(not(feature = "no-softdevice"))]
+pub fn set_ram_power(block: u8, value: u32) -> Result<(), u32> {
+    match unsafe { raw::sd_power_ram_power_set(block, value) } {
+        raw::NRF_SUCCESS => Ok(()),
+        ret => Err(ret),
+    }
+}
+
+#[cfg(feature = "no-softdevice")]
+pub fn set_ram_power(block: u8, value: u32) -> Result<(), u32> {
+    power().ram[block as usize].powerset.write(|w| unsafe { w.bits(value) });
+    Ok(())
+}
+
+/// Switch between the constant latency and low power modes.
+#[cfg(not(feature = "no-softdevice"))]
+pub fn set_constant_latency(enabled: bool) -> Result<(), u32> {
+    let mode = if enabled {
+        raw::NRF_POWER_MODES_NRF_POWER_MODE_CONSTLAT
+    } else {
+        raw::NRF_POWER_MODES_NRF_POWER_MODE_LOWPWR
+    };
+    match unsafe { raw::sd_power_mode_set(mode as u8) } {
+        raw::NRF_SUCCESS => Ok(()),
+        ret => Err(ret),
+    }
+}
+
+#[cfg(feature = "no-softdevice")]
+pub fn set_constant_latency(enabled: bool) -> Result<(), u32> {
+    if enabled {
+        power().tasks_constlat.write(|w| unsafe { w.bits(1) });
+    } else {
+        power().tasks_lowpwr.write(|w| unsafe { w.bits(1) });
+    }
+    Ok(())
+}
+
+#[cfg(feature = "no-softdevice")]
+fn power() -> &'static pac::power::RegisterBlock {
+    unsafe { &*pac::POWER::ptr() }
+}
@@ -1,13 +1,63 @@
-use defmt::info;
+use defmt::{info, warn};
 use embassy_boot::State as FwState;
-use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
 use embassy_time::{Duration, Instant, Timer};
 use embedded_graphics::prelude::*;
-use watchful_ui::{FirmwareDetails, MenuAction, MenuView, TimeView, WorkoutView};
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use watchful_ui::UpdateView;
+use watchful_ui::{
+    BatteryView, ButtonEvent, ChartView, FirmwareDetails, GoalView, MenuAction, MenuView, MusicView, Refresh, TextView,
+    TimeView, WeatherView, WorkoutView, ICON_BYTES, TEXT_SIZE,
+};
+#[cfg(not(feature = "no-softdevice"))]
+use watchful_ui::{NavigationView, PasskeyView};
 
-use crate::device::Device;
+use crate::activity::{ActivityRecord, WorkoutDistance, WorkoutKind, WorkoutSummary};
+use crate::buildinfo::BUILD;
+use crate::clock::Clock;
+use crate::device::{ChargeState, Device};
+use crate::error::{self, Error};
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use crate::events::UpdateProgress;
+use crate::events::{self, BleCommand, SensorEvent, SensorSubscriber};
+use crate::input::{read_touch, wait_gesture};
+use crate::power::Feature;
+use crate::resources::ResourcePack;
+use crate::wake::{self, WakeEvent};
+use crate::wakelock::{self, WakeLock, WakeLockKind};
+use crate::wakestats::{self, WakeSource};
+use crate::{factory, heartrate, music, resources, weather};
+#[cfg(not(feature = "no-softdevice"))]
+use crate::{navigation, notifications};
 
 const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the time stays on screen after a button press in power reserve.
+const RESERVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time between the frames of the step goal celebration.
+const GOAL_FRAME_TIME: Duration = Duration::from_millis(100);
+/// How long the progress of a firmware update stays on screen without the update moving on, as when the phone is
+/// gone.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the phone rings, or the watch vibrates to be found, unless stopped earlier.
+const FIND_TIMEOUT: Duration = Duration::from_secs(30);
+const FIND_VIBRATION: Duration = Duration::from_millis(300);
+const FIND_PAUSE: Duration = Duration::from_secs(1);
+/// How long the pairing passkey stays on screen, the time the phone has to complete pairing.
+#[cfg(not(feature = "no-softdevice"))]
+const PASSKEY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The apps in the main menu, each enabled with a feature, see Cargo.toml.
+const APPS: &[(&str, MenuAction)] = &[
+    #[cfg(feature = "hrs")]
+    ("Workout", MenuAction::Workout),
+    #[cfg(feature = "find-phone")]
+    ("Find Phone", MenuAction::FindPhone),
+];
+
+fn main_menu() -> MenuView {
+    MenuView::main(APPS)
+}
 
 #[derive(PartialEq, Clone, Copy)]
 pub struct Timeout {
@@ -22,8 +72,10 @@ impl Timeout {
             duration,
         }
     }
-    pub fn timer(&self) -> Timer {
-        Timer::after(self.time_left())
+    /// Wait for the timeout to expire and for any display wake locks to be released.
+    pub async fn timer(&self) {
+        Timer::after(self.time_left()).await;
+        wakelock::wait_released(WakeLockKind::Display).await;
     }
 
     fn time_left(&self) -> Duration {
@@ -36,13 +88,43 @@ impl Timeout {
     }
 }
 
+/// Time until the clock next changes in a way visible at `refresh`.
+fn until_refresh(clock: &Clock, refresh: Refresh) -> Duration {
+    let now = clock.get();
+    let millis = now.millisecond() as u64;
+    match refresh {
+        Refresh::Second => Duration::from_millis(1000 - millis),
+        Refresh::Minute => Duration::from_millis((60 - now.second() as u64) * 1000 - millis),
+    }
+}
+
 #[derive(PartialEq)]
 pub enum WatchState {
     Idle(IdleState),
     Time(TimeState),
     Menu(MenuState),
-    //  FindPhone,
+    FindPhone(FindPhoneState),
+    FindWatch(FindWatchState),
     Workout(WorkoutState),
+    WorkoutHistory(WorkoutHistoryState),
+    Chart(ChartState),
+    Battery(BatteryState),
+    Sleep(SleepState),
+    Today(TodayState),
+    Diagnostics(DiagnosticsState),
+    Reserve(ReserveState),
+    Goal(GoalState),
+    #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+    Dfu(DfuState),
+    #[cfg(not(feature = "no-softdevice"))]
+    Notification(NotificationState),
+    #[cfg(not(feature = "no-softdevice"))]
+    Navigation(NavigationState),
+    Weather(WeatherState),
+    Music(MusicState),
+    #[cfg(not(feature = "no-softdevice"))]
+    Passkey(PasskeyState),
+    Pairing(PairingState),
 }
 
 impl Default for WatchState {
@@ -57,7 +139,28 @@ impl defmt::Format for WatchState {
             Self::Idle(_) => defmt::write!(fmt, "Idle"),
             Self::Time(_) => defmt::write!(fmt, "Time"),
             Self::Menu(_) => defmt::write!(fmt, "Menu"),
+            Self::FindPhone(_) => defmt::write!(fmt, "FindPhone"),
+            Self::FindWatch(_) => defmt::write!(fmt, "FindWatch"),
             Self::Workout(_) => defmt::write!(fmt, "Workout"),
+            Self::WorkoutHistory(_) => defmt::write!(fmt, "WorkoutHistory"),
+            Self::Chart(_) => defmt::write!(fmt, "Chart"),
+            Self::Battery(_) => defmt::write!(fmt, "Battery"),
+            Self::Sleep(_) => defmt::write!(fmt, "Sleep"),
+            Self::Today(_) => defmt::write!(fmt, "Today"),
+            Self::Diagnostics(_) => defmt::write!(fmt, "Diagnostics"),
+            Self::Reserve(_) => defmt::write!(fmt, "Reserve"),
+            Self::Goal(_) => defmt::write!(fmt, "Goal"),
+            #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+            Self::Dfu(_) => defmt::write!(fmt, "Dfu"),
+            #[cfg(not(feature = "no-softdevice"))]
+            Self::Notification(_) => defmt::write!(fmt, "Notification"),
+            #[cfg(not(feature = "no-softdevice"))]
+            Self::Navigation(_) => defmt::write!(fmt, "Navigation"),
+            Self::Weather(_) => defmt::write!(fmt, "Weather"),
+            Self::Music(_) => defmt::write!(fmt, "Music"),
+            #[cfg(not(feature = "no-softdevice"))]
+            Self::Passkey(_) => defmt::write!(fmt, "Passkey"),
+            Self::Pairing(_) => defmt::write!(fmt, "Pairing"),
         }
     }
 }
@@ -68,7 +171,28 @@ impl WatchState {
             WatchState::Idle(state) => state.draw(device).await,
             WatchState::Time(state) => state.draw(device).await,
             WatchState::Menu(state) => state.draw(device).await,
+            WatchState::FindPhone(state) => state.draw(device).await,
+            WatchState::FindWatch(state) => state.draw(device).await,
             WatchState::Workout(state) => state.draw(device).await,
+            WatchState::WorkoutHistory(state) => state.draw(device).await,
+            WatchState::Chart(state) => state.draw(device).await,
+            WatchState::Battery(state) => state.draw(device).await,
+            WatchState::Sleep(state) => state.draw(device).await,
+            WatchState::Today(state) => state.draw(device).await,
+            WatchState::Diagnostics(state) => state.draw(device).await,
+            WatchState::Reserve(state) => state.draw(device).await,
+            WatchState::Goal(state) => state.draw(device).await,
+            #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+            WatchState::Dfu(state) => state.draw(device).await,
+            #[cfg(not(feature = "no-softdevice"))]
+            WatchState::Notification(state) => state.draw(device).await,
+            #[cfg(not(feature = "no-softdevice"))]
+            WatchState::Navigation(state) => state.draw(device).await,
+            WatchState::Weather(state) => state.draw(device).await,
+            WatchState::Music(state) => state.draw(device).await,
+            #[cfg(not(feature = "no-softdevice"))]
+            WatchState::Passkey(state) => state.draw(device).await,
+            WatchState::Pairing(state) => state.draw(device).await,
         }
     }
 
@@ -77,7 +201,28 @@ impl WatchState {
             WatchState::Idle(state) => state.next(device).await,
             WatchState::Time(state) => state.next(device).await,
             WatchState::Menu(state) => state.next(device).await,
+            WatchState::FindPhone(state) => state.next(device).await,
+            WatchState::FindWatch(state) => state.next(device).await,
             WatchState::Workout(state) => state.next(device).await,
+            WatchState::WorkoutHistory(state) => state.next(device).await,
+            WatchState::Chart(state) => state.next(device).await,
+            WatchState::Battery(state) => state.next(device).await,
+            WatchState::Sleep(state) => state.next(device).await,
+            WatchState::Today(state) => state.next(device).await,
+            WatchState::Diagnostics(state) => state.next(device).await,
+            WatchState::Reserve(state) => state.next(device).await,
+            WatchState::Goal(state) => state.next(device).await,
+            #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+            WatchState::Dfu(state) => state.next(device).await,
+            #[cfg(not(feature = "no-softdevice"))]
+            WatchState::Notification(state) => state.next(device).await,
+            #[cfg(not(feature = "no-softdevice"))]
+            WatchState::Navigation(state) => state.next(device).await,
+            WatchState::Weather(state) => state.next(device).await,
+            WatchState::Music(state) => state.next(device).await,
+            #[cfg(not(feature = "no-softdevice"))]
+            WatchState::Passkey(state) => state.next(device).await,
+            WatchState::Pairing(state) => state.next(device).await,
         }
     }
 }
@@ -90,12 +235,28 @@ impl IdleState {
     }
 
     pub async fn draw(&mut self, device: &mut Device<'_>) {
-        device.screen.off();
+        device.screen.sleep();
     }
 
+    /// Sleep until a wake event, see `wake::wait`. Nothing is polled while idle, so the CPU stays in System ON
+    /// sleep between interrupts.
+    ///
+    /// In power reserve only the button wakes the watch, to show the time.
     pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
-        device.button.wait().await;
-        WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await)
+        if device.power.in_reserve() {
+            let event = device.button.wait().await;
+            wakestats::record(WakeSource::Button, device.clock.get());
+            device.screen.wake();
+            return WatchState::Reserve(ReserveState::new(device, event == ButtonEvent::LongPress).await);
+        }
+        let event = wake::wait(device).await;
+        wakestats::record(event.source(), device.clock.get());
+        device.screen.wake();
+        events::send_ble(BleCommand::AdvertiseFast);
+        match event {
+            WakeEvent::Button(ButtonEvent::LongPress) => WatchState::Menu(MenuState::new(MenuView::power())),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
     }
 }
 
@@ -108,48 +269,172 @@ pub struct TimeState {
 impl TimeState {
     pub async fn new(device: &mut Device<'_>, timeout: Timeout) -> TimeState {
         let now = device.clock.get();
-        let battery_level = device.battery.measure().await;
-        let charging = device.battery.is_charging();
-        Self {
-            view: TimeView::new(now, battery_level, charging),
-            timeout,
+        let (battery_level, charge_state) = {
+            let mut battery = device.battery.lock().await;
+            (battery.measure().await, battery.charge_state())
+        };
+        device
+            .power
+            .update_battery(battery_level as u8, charge_state != ChargeState::Discharging);
+        // The voltage read while on the charger is not accurate enough to show a full battery as 100%.
+        let battery_level = if charge_state == ChargeState::Full {
+            100
+        } else {
+            battery_level
+        };
+        let mut view = TimeView::new(
+            now,
+            battery_level,
+            charge_state == ChargeState::Charging,
+            resources::status() == resources::Status::Corrupt,
+        );
+        let goal = device.settings.get().step_goal as u32;
+        if goal > 0 {
+            view = view.with_step_progress((crate::activity::steps_today() * 100 / goal).min(100) as u8);
+        }
+        if let Some(current) = weather::current(device.clock) {
+            view = view.with_temperature(current.temperature);
+        }
+        if let Some(pack) = ResourcePack::get(device.fs).await {
+            let mut icon = [0; ICON_BYTES];
+            match pack.load(view.battery_icon_name(), &mut icon).await {
+                Ok(icon) => view = view.with_battery_icon(icon),
+                Err(resources::Error::NotFound) => {}
+                Err(e) => warn!("Battery icon not loaded: {:?}", e),
+            }
         }
+        if device.power.in_reserve() {
+            view = view.with_notice("Power reserve");
+        } else if !device.power.allows(Feature::BackgroundHeartRate) {
+            view = view.with_notice("Battery low: HR paused");
+        } else if !device.power.allows(Feature::FirmwareUpdate) {
+            view = view.with_notice("Battery low: no updates");
+        }
+        Self { view, timeout }
     }
 
     pub async fn draw(&mut self, device: &mut Device<'_>) {
-        self.view.draw(device.screen.display()).unwrap();
+        let _ = self.view.draw(&mut device.screen);
         device.screen.on();
     }
 
+    /// Redraw when the time shown changes, the charge state changes, or the battery level changes, which is only
+    /// checked along with the time to avoid waking up more often than the face needs.
     pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let mut sensor_events = events::subscribe();
         loop {
-            match select3(
-                Timer::after(Duration::from_secs(2)),
+            match select4(
+                select(
+                    Timer::after(until_refresh(device.clock, self.view.refresh())),
+                    wait_charge_change(&mut sensor_events),
+                ),
                 self.timeout.timer(),
                 device.button.wait(),
+                wait_gesture(&mut device.touchpad),
             )
             .await
             {
-                Either3::First(_) => {
+                Either4::First(Either::Second(_)) => {
+                    return WatchState::Time(TimeState::new(device, self.timeout).await);
+                }
+                Either4::First(Either::First(_)) => {
                     let t = device.clock.get();
-                    let b = device.battery.measure().await;
-                    let l = device.battery.is_charging();
+                    let (b, state) = {
+                        let mut battery = device.battery.lock().await;
+                        (battery.measure().await, battery.charge_state())
+                    };
                     if t.minute() != self.view.time.minute()
-                        || b != self.view.battery_level
-                        || l != self.view.battery_charging
+                        || (state != ChargeState::Full && b != self.view.battery_level)
                     {
                         return WatchState::Time(TimeState::new(device, self.timeout).await);
                     }
                 }
-                Either3::Second(_) => {
+                Either4::Second(_) => {
                     return WatchState::Idle(IdleState::new(device));
                 }
-                Either3::Third(_) => return WatchState::Menu(MenuState::new(MenuView::main())),
+                Either4::Third(ButtonEvent::LongPress) => return WatchState::Menu(MenuState::new(MenuView::power())),
+                Either4::Third(_) => return WatchState::Menu(MenuState::new(main_menu())),
+                Either4::Fourth(cst816s::TouchGesture::SlideLeft) => {
+                    if let Some(state) = ChartState::heart_rate(device).await {
+                        return WatchState::Chart(state);
+                    }
+                }
+                Either4::Fourth(cst816s::TouchGesture::SlideRight) => {
+                    return WatchState::Battery(BatteryState::new(device).await);
+                }
+                Either4::Fourth(cst816s::TouchGesture::SlideUp) => {
+                    return WatchState::Sleep(SleepState::new(device).await);
+                }
+                Either4::Fourth(cst816s::TouchGesture::SlideDown) => {
+                    return WatchState::Today(TodayState::new(device).await);
+                }
+                Either4::Fourth(_) => {}
             }
         }
     }
 }
 
+/// The time shown in power reserve, with BLE and the sensors off. Holding the button leaves power reserve.
+#[derive(PartialEq)]
+pub struct ReserveState {
+    view: TimeView,
+    timeout: Timeout,
+}
+
+impl ReserveState {
+    /// `exit` leaves power reserve right away, when the button press that woke the watch was already held.
+    pub async fn new(device: &mut Device<'_>, exit: bool) -> Self {
+        if exit {
+            device.power.set_reserve(false);
+        }
+        let (battery_level, charge_state) = {
+            let mut battery = device.battery.lock().await;
+            (battery.measure().await, battery.charge_state())
+        };
+        // Connecting the charger leaves power reserve.
+        device
+            .power
+            .update_battery(battery_level as u8, charge_state != ChargeState::Discharging);
+        let view = TimeView::new(
+            device.clock.get(),
+            battery_level,
+            charge_state == ChargeState::Charging,
+            false,
+        )
+        .with_notice("Hold button to exit reserve");
+        Self {
+            view,
+            timeout: Timeout::new(RESERVE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        if device.power.in_reserve() {
+            let _ = self.view.draw(&mut device.screen);
+            device.screen.on();
+        }
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        if !device.power.in_reserve() {
+            events::send_ble(BleCommand::AdvertiseFast);
+            return WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await);
+        }
+        let mut sensor_events = events::subscribe();
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_charge_change(&mut sensor_events),
+        )
+        .await
+        {
+            Either3::Second(ButtonEvent::LongPress) => WatchState::Reserve(ReserveState::new(device, true).await),
+            Either3::Third(_) => WatchState::Reserve(ReserveState::new(device, false).await),
+            _ => WatchState::Idle(IdleState::new(device)),
+        }
+    }
+}
+
 #[derive(PartialEq)]
 pub struct MenuState {
     view: MenuView,
@@ -163,7 +448,7 @@ impl MenuState {
     }
 
     pub async fn draw(&mut self, device: &mut Device<'_>) {
-        self.view.draw(device.screen.display()).unwrap();
+        let _ = self.view.draw(&mut device.screen);
         device.screen.on();
     }
 
@@ -171,7 +456,7 @@ impl MenuState {
         match select3(self.timeout.timer(), device.button.wait(), async {
             let selected;
             loop {
-                if let Some(evt) = device.touchpad.read_one_touch_event(true) {
+                if let Some(evt) = read_touch(&mut device.touchpad) {
                     if let cst816s::TouchGesture::SingleClick = evt.gesture {
                         let touched = Point::new(evt.x, evt.y);
                         if let Some(s) =
@@ -193,58 +478,72 @@ impl MenuState {
         .await
         {
             Either3::First(_) => WatchState::Idle(IdleState::new(device)),
-            Either3::Second(_) => {
-                if let MenuView::Settings { .. } = &self.view {
-                    WatchState::Menu(MenuState::new(MenuView::main()))
-                } else if let MenuView::Firmware { .. } = &self.view {
+            Either3::Second(_) => match &self.view {
+                MenuView::Workout { .. } | MenuView::Settings { .. } => WatchState::Menu(MenuState::new(main_menu())),
+                MenuView::Firmware { .. } | MenuView::Reset { .. } | MenuView::ConfirmFactoryReset { .. } => {
                     WatchState::Menu(MenuState::new(MenuView::settings()))
-                } else {
-                    WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await)
                 }
-            }
+                _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+            },
             Either3::Third(selected) => match selected {
-                MenuAction::Workout => {
-                    defmt::info!("Not implemented");
-                    WatchState::Workout(WorkoutState {})
-                }
-                MenuAction::FindPhone => {
-                    defmt::info!("Not implemented");
-                    WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await)
+                MenuAction::Workout => WatchState::Menu(MenuState::new(MenuView::workout())),
+                MenuAction::WalkWorkout => WatchState::Workout(WorkoutState::new(WorkoutKind::Walk)),
+                MenuAction::RunWorkout => WatchState::Workout(WorkoutState::new(WorkoutKind::Run)),
+                MenuAction::WorkoutHistory => {
+                    WatchState::WorkoutHistory(WorkoutHistoryState::new(device, 0, false).await)
                 }
+                MenuAction::FindPhone => WatchState::FindPhone(FindPhoneState::new()),
                 MenuAction::Settings => WatchState::Menu(MenuState::new(MenuView::settings())),
+                MenuAction::Diagnostics => {
+                    WatchState::Diagnostics(DiagnosticsState::new(device, DiagnosticsPage::Crashes).await)
+                }
+                MenuAction::ResetOptions => WatchState::Menu(MenuState::new(MenuView::reset())),
                 MenuAction::Reset => {
+                    if let Some(kv) = device.kv {
+                        device.settings.flush(kv).await;
+                    }
                     cortex_m::peripheral::SCB::sys_reset();
                 }
+                MenuAction::PairingMode => {
+                    events::send_ble(BleCommand::PairingMode);
+                    WatchState::Pairing(PairingState::new())
+                }
+                MenuAction::FactoryReset => WatchState::Menu(MenuState::new(MenuView::confirm_factory_reset())),
+                MenuAction::ConfirmFactoryReset => {
+                    factory::show(&mut device.screen);
+                    device.factory_reset.run().await
+                }
+                MenuAction::ConfirmPowerOff => {
+                    let _ = TextView::new("Power off", "Press the button to turn the watch on again.")
+                        .draw(&mut device.screen);
+                    Timer::after(Duration::from_secs(2)).await;
+                    crate::power::power_off(device).await
+                }
+                MenuAction::PowerReserve => {
+                    device.power.set_reserve(true);
+                    WatchState::Reserve(ReserveState::new(device, false).await)
+                }
+                MenuAction::Cancel => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
                 MenuAction::FirmwareSettings => {
-                    let validated = FwState::Boot
-                        == device
-                            .firmware
-                            .get_state()
-                            .await
-                            .expect("Failed to read firmware state");
+                    // Shown as not validated if the state can not be read.
+                    let state = error::recover(device.firmware.get_state().await, Error::FirmwareState);
+                    let validated = state == Some(FwState::Boot);
                     WatchState::Menu(MenuState::new(MenuView::firmware_settings(
-                        firmware_details(&mut device.battery, validated).await,
+                        firmware_details(&mut *device.battery.lock().await, validated).await,
                     )))
                 }
                 MenuAction::ValidateFirmware => {
                     info!("Validate firmware");
-                    let validated = FwState::Boot
-                        == device
-                            .firmware
-                            .get_state()
-                            .await
-                            .expect("Failed to read firmware state");
+                    let Some(state) = error::recover(device.firmware.get_state().await, Error::FirmwareState) else {
+                        return WatchState::Menu(MenuState::new(main_menu()));
+                    };
+                    let validated = state == FwState::Boot;
                     if !validated {
-                        device
-                            .firmware
-                            .mark_booted()
-                            .await
-                            .expect("Failed to mark current firmware as good");
-                        info!("Firmware marked as valid");
-                        WatchState::Menu(MenuState::new(MenuView::main()))
+                        crate::firmware::mark_booted(&mut device.firmware).await;
+                        WatchState::Menu(MenuState::new(main_menu()))
                     } else {
                         WatchState::Menu(MenuState::new(MenuView::firmware_settings(
-                            firmware_details(&mut device.battery, validated).await,
+                            firmware_details(&mut *device.battery.lock().await, validated).await,
                         )))
                     }
                 }
@@ -254,55 +553,1066 @@ impl MenuState {
 }
 
 #[derive(PartialEq)]
-pub struct WorkoutState {}
+pub struct WorkoutState {
+    kind: WorkoutKind,
+}
 
 impl WorkoutState {
+    pub fn new(kind: WorkoutKind) -> Self {
+        Self { kind }
+    }
+
     pub async fn draw(&mut self, _device: &mut Device<'_>) {}
     pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let started = device.clock.get();
+        let settings = device.settings;
         let screen = &mut device.screen;
         let button = &mut device.button;
-        let hrs = &mut device.hrs;
-        hrs.init().unwrap();
-        hrs.enable_hrs().unwrap();
-        hrs.enable_oscillator().unwrap();
+        let _locks = [
+            WakeLock::acquire(WakeLockKind::Display),
+            WakeLock::acquire(WakeLockKind::Cpu),
+        ];
+        // The heart rate task measures and alerts on zone changes, the workout screen only shows the result.
+        heartrate::set_workout(true);
 
         let mut seconds = 0;
+        let mut distance = WorkoutDistance::new();
+        let (mut bpm_sum, mut bpm_count, mut max_bpm) = (0u32, 0u32, 0u8);
         let workout = async {
             loop {
-                let hr = hrs.read_hrs().unwrap();
-                WorkoutView::new(hr, time::Duration::new(seconds, 0))
-                    .draw(screen.display())
-                    .unwrap();
+                let hr = heartrate::workout_bpm().unwrap_or(0);
+                if hr > 0 {
+                    bpm_sum += hr as u32;
+                    bpm_count += 1;
+                    max_bpm = max_bpm.max(hr);
+                }
+                let _ = WorkoutView::new(hr as u32, time::Duration::new(seconds, 0))
+                    .with_distance(distance.distance(), distance.pace())
+                    .draw(&mut *screen);
                 screen.on();
                 Timer::after(Duration::from_secs(2)).await;
                 seconds += 2;
+                distance.update(2, settings.get().stride_cm);
             }
         };
 
-        let next = match select(button.wait(), workout).await {
-            Either::First(_) => WatchState::Menu(MenuState::new(MenuView::main())),
+        let state = match select(button.wait(), workout).await {
+            Either::First(_) => WatchState::Menu(MenuState::new(MenuView::workout())),
             Either::Second(state) => state,
         };
-        hrs.disable_oscillator().unwrap();
-        hrs.disable_hrs().unwrap();
-        next
+        heartrate::set_workout(false);
+
+        // Workouts before the clock is synchronized can not be placed in the history.
+        if let (Some(log), true) = (device.logs.activity, started.year() >= 2000) {
+            let duration = seconds as u32;
+            let workout = WorkoutSummary {
+                kind: self.kind,
+                timestamp: crate::activity::timestamp(started),
+                duration,
+                steps: distance.steps(),
+                distance: distance.distance(),
+                avg_bpm: bpm_sum.checked_div(bpm_count).unwrap_or(0) as u8,
+                max_bpm,
+                kcal: self.kind.kcal(duration, settings.get().weight_kg),
+            };
+            info!("Workout summary: {:?}", workout);
+            if let Err(e) = log.append(ActivityRecord::Workout(workout)).await {
+                defmt::warn!("Error logging workout: {:?}", e);
+            }
+        }
+        state
     }
 }
 
-async fn firmware_details(battery: &mut crate::device::Battery<'_>, validated: bool) -> FirmwareDetails {
-    const CARGO_NAME: &str = env!("CARGO_PKG_NAME");
-    const CARGO_VERSION: &str = env!("CARGO_PKG_VERSION");
-    const COMMIT: &str = env!("VERGEN_GIT_SHA");
-    const BUILD_TIMESTAMP: &str = env!("VERGEN_BUILD_TIMESTAMP");
+/// Recent workouts, newest first. Swiping moves the selection and tapping shows the details of the selected one.
+#[derive(PartialEq)]
+pub struct WorkoutHistoryState {
+    selected: usize,
+    count: usize,
+    detail: bool,
+    view: TextView,
+    timeout: Timeout,
+}
+
+impl WorkoutHistoryState {
+    pub async fn new(device: &mut Device<'_>, selected: usize, detail: bool) -> Self {
+        let mut text: heapless::String<TEXT_SIZE> = heapless::String::new();
+        let log = device.logs.activity;
+        let count = crate::activity::report_workouts(log, selected, &mut text).await;
+        if detail {
+            text.clear();
+            crate::activity::report_workout(log, selected, &mut text).await;
+        }
+        Self {
+            selected,
+            count,
+            detail,
+            view: TextView::new("Workouts", &text),
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
 
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let selected = match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => return WatchState::Idle(IdleState::new(device)),
+            Either3::Second(_) if self.detail => {
+                return WatchState::WorkoutHistory(WorkoutHistoryState::new(device, self.selected, false).await)
+            }
+            Either3::Second(_) => return WatchState::Menu(MenuState::new(MenuView::workout())),
+            Either3::Third(cst816s::TouchGesture::SingleClick) if !self.detail && self.count > 0 => {
+                return WatchState::WorkoutHistory(WorkoutHistoryState::new(device, self.selected, true).await)
+            }
+            Either3::Third(cst816s::TouchGesture::SlideUp) if !self.detail => {
+                (self.selected + 1).min(self.count.saturating_sub(1))
+            }
+            Either3::Third(cst816s::TouchGesture::SlideDown) if !self.detail => self.selected.saturating_sub(1),
+            Either3::Third(_) => self.selected,
+        };
+        WatchState::WorkoutHistory(WorkoutHistoryState::new(device, selected, false).await)
+    }
+}
+
+#[derive(PartialEq)]
+pub struct ChartState {
+    view: ChartView,
+    /// Whether swiping left shows the resting heart rate trend.
+    resting_next: bool,
+    timeout: Timeout,
+}
+
+impl ChartState {
+    /// Heart rate samples of the last 24 hours.
+    pub async fn heart_rate(device: &mut Device<'_>) -> Option<Self> {
+        let since = crate::activity::timestamp(device.clock.get()).saturating_sub(24 * 3600);
+        let values = device.logs.heart_rate?.daily(since).await.ok()?;
+        Some(Self {
+            view: ChartView::new("Heart rate", &values),
+            resting_next: true,
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        })
+    }
+
+    /// Steps per hour since midnight.
+    pub async fn steps(device: &mut Device<'_>) -> Option<Self> {
+        let now = device.clock.get();
+        let hours = device
+            .logs
+            .activity?
+            .hourly_steps(crate::activity::timestamp(now.date().midnight()))
+            .await
+            .ok()?;
+        let mut values = [0; 24];
+        for (value, steps) in values.iter_mut().zip(hours) {
+            *value = steps.min(u16::MAX as u32) as u16;
+        }
+        values[now.hour() as usize] = crate::activity::step_counts().0.min(u16::MAX as u32) as u16;
+        Some(Self {
+            view: ChartView::new("Steps", &values[..=now.hour() as usize]),
+            resting_next: false,
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        })
+    }
+
+    /// Resting heart rate of the last week, up to yesterday.
+    pub async fn resting_heart_rate(device: &mut Device<'_>) -> Option<Self> {
+        let today = crate::activity::timestamp(device.clock.get().date().midnight());
+        let since = today.saturating_sub(crate::activity::TREND_DAYS as u32 * 24 * 3600);
+        let values = device.logs.activity?.resting_heart_rates(since).await.ok()?;
+        Some(Self {
+            view: ChartView::new("Resting HR", &values),
+            resting_next: false,
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        })
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            Either3::Third(cst816s::TouchGesture::SlideLeft) if self.resting_next => {
+                match ChartState::resting_heart_rate(device).await {
+                    Some(state) => WatchState::Chart(state),
+                    None => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+                }
+            }
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// Battery level over the last day and estimated time remaining.
+#[derive(PartialEq)]
+pub struct BatteryState {
+    view: BatteryView,
+    timeout: Timeout,
+}
+
+impl BatteryState {
+    pub async fn new(device: &mut Device<'_>) -> Self {
+        let (level, charging) = {
+            let mut battery = device.battery.lock().await;
+            (battery.measure().await, battery.is_charging())
+        };
+        let stats = device.battery_stats;
+        Self {
+            view: BatteryView::new(level, charging, stats.remaining_hours(level), &stats.history()),
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// Summary of last night's sleep.
+#[derive(PartialEq)]
+pub struct SleepState {
+    view: TextView,
+    timeout: Timeout,
+}
+
+impl SleepState {
+    pub async fn new(device: &mut Device<'_>) -> Self {
+        let mut text: heapless::String<TEXT_SIZE> = heapless::String::new();
+        crate::sleep::report(device.logs.sleep, &mut text).await;
+        Self {
+            view: TextView::new("Sleep", &text),
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// Summary of the day so far: steps against the goal, heart rate and battery use. Swiping left shows the weather,
+/// swiping right the music, and swiping up the steps of each hour.
+#[derive(PartialEq)]
+pub struct TodayState {
+    view: TextView,
+    timeout: Timeout,
+}
+
+impl TodayState {
+    pub async fn new(device: &mut Device<'_>) -> Self {
+        let mut text: heapless::String<TEXT_SIZE> = heapless::String::new();
+        today_report(device, &mut text).await;
+        Self {
+            view: TextView::new("Today", &text),
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            #[cfg(feature = "weather")]
+            Either3::Third(cst816s::TouchGesture::SlideLeft) => WatchState::Weather(WeatherState::new(device)),
+            #[cfg(feature = "music")]
+            Either3::Third(cst816s::TouchGesture::SlideRight) => WatchState::Music(MusicState::open()),
+            Either3::Third(cst816s::TouchGesture::SlideUp) => match ChartState::steps(device).await {
+                Some(state) => WatchState::Chart(state),
+                None => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+            },
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// The current weather and the forecast sent by the phone.
+#[derive(PartialEq)]
+pub struct WeatherState {
+    view: WeatherView,
+    timeout: Timeout,
+}
+
+impl WeatherState {
+    pub fn new(device: &mut Device<'_>) -> Self {
+        let current = weather::current(device.clock);
+        let location = current.as_ref().map_or("", |current| current.location.as_str());
+        let mut view = WeatherView::new(location);
+        if let Some(current) = &current {
+            view = view.with_current(current.temperature, current.today);
+        }
+        if let Some(forecast) = weather::forecast(device.clock) {
+            // The forecast starts on the day it was sent, which may be past.
+            let today = device.clock.get().date();
+            let mut date =
+                time::OffsetDateTime::from_unix_timestamp(forecast.timestamp).map_or(today, |sent| sent.date());
+            for day in forecast.days {
+                if date >= today {
+                    view = view.with_day(date.weekday(), day);
+                }
+                date = date.next_day().unwrap_or(date);
+            }
+        }
+        Self {
+            view,
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// What the phone plays, with controls for it: tap to play or pause, swipe left for the next track and right for the
+/// previous one, up and down for the volume.
+#[derive(PartialEq)]
+pub struct MusicState {
+    view: MusicView,
+    playing: bool,
+    timeout: Timeout,
+}
+
+impl MusicState {
+    /// Open the screen, asking the phone for what it plays.
+    pub fn open() -> Self {
+        music::control(music::Control::Open);
+        Self::updated(Timeout::new(IDLE_TIMEOUT))
+    }
+
+    fn updated(timeout: Timeout) -> Self {
+        let now_playing = music::now_playing();
+        Self {
+            view: MusicView::new(&now_playing.artist, &now_playing.track, now_playing.playing),
+            playing: now_playing.playing,
+            timeout,
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let mut sensor_events = events::subscribe();
+        let update = async { while sensor_events.next_message_pure().await != SensorEvent::Music {} };
+        let control = match select4(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+            update,
+        )
+        .await
+        {
+            Either4::First(_) => return WatchState::Idle(IdleState::new(device)),
+            Either4::Second(_) => return WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+            Either4::Third(cst816s::TouchGesture::SingleClick) if self.playing => music::Control::Pause,
+            Either4::Third(cst816s::TouchGesture::SingleClick) => music::Control::Play,
+            Either4::Third(cst816s::TouchGesture::SlideLeft) => music::Control::Next,
+            Either4::Third(cst816s::TouchGesture::SlideRight) => music::Control::Previous,
+            Either4::Third(cst816s::TouchGesture::SlideUp) => music::Control::VolumeUp,
+            Either4::Third(cst816s::TouchGesture::SlideDown) => music::Control::VolumeDown,
+            // Other gestures are ignored, and a change of what the phone plays does not keep the screen on.
+            Either4::Third(_) | Either4::Fourth(_) => return WatchState::Music(Self::updated(self.timeout)),
+        };
+        music::control(control);
+        WatchState::Music(Self::updated(Timeout::new(IDLE_TIMEOUT)))
+    }
+}
+
+/// Steps and goal progress, resting and last heart rate, the battery used and the notifications received since
+/// midnight.
+async fn today_report(device: &mut Device<'_>, text: &mut heapless::String<TEXT_SIZE>) {
+    use core::fmt::Write;
+
+    let now = device.clock.get();
+    let steps = crate::activity::steps_today();
+    let _ = write!(text, "Steps: {}", steps);
+    match device.settings.get().step_goal as u32 {
+        0 => {
+            let _ = writeln!(text);
+        }
+        goal => {
+            let _ = writeln!(text, " / {} ({}%)", goal, steps * 100 / goal);
+        }
+    }
+
+    match crate::activity::resting_heart_rate() {
+        Some(bpm) => {
+            let _ = writeln!(text, "Resting HR: {}", bpm);
+        }
+        None => {
+            let _ = writeln!(text, "Resting HR: -");
+        }
+    }
+    let today = crate::activity::timestamp(now.date().midnight());
+    let last = match device.logs.heart_rate {
+        Some(log) => log.last().await.ok().flatten(),
+        None => None,
+    };
+    match last {
+        Some((timestamp, bpm)) if timestamp >= today => {
+            let _ = writeln!(
+                text,
+                "Last HR: {} at {:02}:{:02}",
+                bpm,
+                timestamp / 3600 % 24,
+                timestamp / 60 % 60
+            );
+        }
+        _ => {
+            let _ = writeln!(text, "Last HR: -");
+        }
+    }
+
+    let since_midnight = Duration::from_secs((now - now.date().midnight()).whole_seconds().max(0) as u64);
+    let _ = writeln!(text, "Battery used: {}%", device.battery_stats.used(since_midnight));
+    #[cfg(not(feature = "no-softdevice"))]
+    let _ = writeln!(text, "Notifications: {}", notifications::count_today(device.clock));
+}
+
+/// Full screen celebration of the daily step goal.
+#[derive(PartialEq)]
+pub struct GoalState {
+    view: GoalView,
+    timeout: Timeout,
+}
+
+impl GoalState {
+    pub fn new(steps: u32) -> Self {
+        Self {
+            view: GoalView::new(steps),
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        while self.view.next_frame() {
+            Timer::after(GOAL_FRAME_TIME).await;
+            let _ = self.view.draw(&mut device.screen);
+        }
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// The last notification from the phone, shown as it arrives.
+#[cfg(not(feature = "no-softdevice"))]
+#[derive(PartialEq)]
+pub struct NotificationState {
+    view: TextView,
+    timeout: Timeout,
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+impl NotificationState {
+    /// The most recent notification, if any.
+    pub fn latest() -> Option<Self> {
+        use core::fmt::Write;
+
+        let notification = notifications::latest()?;
+        let mut text = heapless::String::<TEXT_SIZE>::new();
+        if !notification.title.is_empty() {
+            let _ = writeln!(text, "{}", notification.title);
+        }
+        let _ = write!(text, "{}", notification.body);
+        Some(Self {
+            view: TextView::new(notification.category.name(), &text),
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        })
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// The next turn of the route the phone navigates, shown as each new instruction arrives and kept up to date.
+#[cfg(not(feature = "no-softdevice"))]
+#[derive(PartialEq)]
+pub struct NavigationState {
+    view: NavigationView,
+    timeout: Timeout,
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+impl NavigationState {
+    /// The route being navigated, if any.
+    pub fn latest() -> Option<Self> {
+        Self::updated(Timeout::new(IDLE_TIMEOUT))
+    }
+
+    fn updated(timeout: Timeout) -> Option<Self> {
+        let navigation = navigation::latest()?;
+        Some(Self {
+            view: NavigationView::new(
+                navigation.turn,
+                &navigation.instruction,
+                &navigation.distance,
+                navigation.progress,
+            ),
+            timeout,
+        })
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let mut sensor_events = events::subscribe();
+        let update = async {
+            loop {
+                if let SensorEvent::Navigation { new_instruction } = sensor_events.next_message_pure().await {
+                    return new_instruction;
+                }
+            }
+        };
+        match select4(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+            update,
+        )
+        .await
+        {
+            Either4::First(_) => WatchState::Idle(IdleState::new(device)),
+            // Updates of the distance do not keep the screen on, a new instruction does.
+            Either4::Fourth(new_instruction) => {
+                let timeout = if new_instruction {
+                    Timeout::new(IDLE_TIMEOUT)
+                } else {
+                    self.timeout
+                };
+                match Self::updated(timeout) {
+                    Some(next) => WatchState::Navigation(next),
+                    None => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+                }
+            }
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// Tells how to pair a new phone once pairing mode is on. The passkey takes over the screen when the phone pairs.
+#[derive(PartialEq)]
+pub struct PairingState {
+    view: TextView,
+    timeout: Timeout,
+}
+
+impl PairingState {
+    pub fn new() -> Self {
+        Self {
+            view: TextView::new(
+                "Pair phone",
+                "Any phone can find the watch for 5 minutes. Pair it from the phone, then enter the code the watch \
+                 shows.",
+            ),
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// The passkey of a phone that is pairing, until pairing completes or the phone gives up.
+#[cfg(not(feature = "no-softdevice"))]
+#[derive(PartialEq)]
+pub struct PasskeyState {
+    view: PasskeyView,
+    timeout: Timeout,
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+impl PasskeyState {
+    pub fn new(passkey: [u8; 6]) -> Self {
+        Self {
+            view: PasskeyView::new(passkey),
+            timeout: Timeout::new(PASSKEY_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let mut sensor_events = events::subscribe();
+        let paired = async { while sensor_events.next_message_pure().await != SensorEvent::Paired {} };
+        match select3(self.timeout.timer(), device.button.wait(), paired).await {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// Rings the phone while held, and stops it when dropped, also when another state takes over the screen.
+struct RingPhone;
+
+impl RingPhone {
+    fn start() -> Self {
+        events::send_ble(BleCommand::FindPhone(true));
+        Self
+    }
+}
+
+impl Drop for RingPhone {
+    fn drop(&mut self) {
+        events::send_ble(BleCommand::FindPhone(false));
+    }
+}
+
+/// Find Phone, ringing the phone until stopped, or telling that there is no phone to ring.
+#[derive(PartialEq)]
+pub struct FindPhoneState {
+    view: TextView,
+    ringing: bool,
+    timeout: Timeout,
+}
+
+impl FindPhoneState {
+    pub fn new() -> Self {
+        Self {
+            view: TextView::new("Find Phone", "Ringing your phone. Press the button or tap to stop."),
+            ringing: true,
+            timeout: Timeout::new(FIND_TIMEOUT),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self {
+            view: TextView::new("Find Phone", "No connected phone can ring."),
+            ringing: false,
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let mut sensor_events = events::subscribe();
+        let ringing = self.ringing;
+        let _ringing = ringing.then(RingPhone::start);
+        let not_found = async {
+            if !ringing {
+                core::future::pending::<()>().await;
+            }
+            while sensor_events.next_message_pure().await != SensorEvent::PhoneNotFound {}
+        };
+        match select4(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+            not_found,
+        )
+        .await
+        {
+            Either4::First(_) => WatchState::Idle(IdleState::new(device)),
+            Either4::Fourth(_) => WatchState::FindPhone(Self::not_found()),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// Shown while the phone looks for the watch, which vibrates until stopped on either side.
+#[derive(PartialEq)]
+pub struct FindWatchState {
+    view: TextView,
+    timeout: Timeout,
+}
+
+impl FindWatchState {
+    pub fn new() -> Self {
+        Self {
+            view: TextView::new(
+                "Find Watch",
+                "Your phone is looking for the watch. Press the button or tap to stop.",
+            ),
+            timeout: Timeout::new(FIND_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let mut sensor_events = events::subscribe();
+        let stopped = async { while sensor_events.next_message_pure().await != SensorEvent::FindWatch(false) {} };
+        let motor = device.motor;
+        let vibrate = async {
+            loop {
+                motor.lock().await.vibrate(FIND_VIBRATION).await;
+                Timer::after(FIND_PAUSE).await;
+            }
+        };
+        match select4(
+            select(self.timeout.timer(), vibrate),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+            stopped,
+        )
+        .await
+        {
+            Either4::First(_) => WatchState::Idle(IdleState::new(device)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// Progress of a firmware update, shown while the BLE task receives it and left when the connection is lost. The
+/// button leaves it until the next object of the image is received.
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+#[derive(PartialEq)]
+pub struct DfuState {
+    view: UpdateView,
+    /// Uptime and bytes received when the update was first shown, for the transfer rate.
+    start: (Instant, u32),
+    timeout: Timeout,
+}
+
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+impl DfuState {
+    pub fn new(progress: UpdateProgress) -> Self {
+        Self {
+            view: UpdateView::new(progress.received, progress.total, None),
+            start: (Instant::now(), progress.received),
+            timeout: Timeout::new(UPDATE_TIMEOUT),
+        }
+    }
+
+    fn update(&self, progress: UpdateProgress) -> Self {
+        let (since, from) = self.start;
+        let millis = (Instant::now() - since).as_millis();
+        let rate = match progress.received.checked_sub(from) {
+            Some(bytes) if bytes > 0 && millis > 0 => Some((bytes as u64 * 1000 / millis) as u32),
+            _ => None,
+        };
+        Self {
+            view: UpdateView::new(progress.received, progress.total, rate),
+            start: self.start,
+            timeout: Timeout::new(UPDATE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        let mut sensor_events = events::subscribe();
+        let progress = async {
+            loop {
+                match sensor_events.next_message_pure().await {
+                    SensorEvent::FirmwareUpdate(progress) => return Some(progress),
+                    SensorEvent::FirmwareUpdateStopped => return None,
+                    _ => {}
+                }
+            }
+        };
+        match select3(self.timeout.timer(), device.button.wait(), progress).await {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            Either3::Third(Some(progress)) => WatchState::Dfu(self.update(progress)),
+            _ => WatchState::Time(TimeState::new(device, Timeout::new(IDLE_TIMEOUT)).await),
+        }
+    }
+}
+
+/// Pages of the diagnostics screen, cycled by swiping.
+#[derive(PartialEq, Clone, Copy)]
+pub enum DiagnosticsPage {
+    Crashes,
+    Storage,
+    Cpu,
+    Memory,
+    Battery,
+    Wakeups,
+}
+
+impl DiagnosticsPage {
+    fn next(self) -> Self {
+        match self {
+            Self::Crashes => Self::Storage,
+            Self::Storage => Self::Cpu,
+            Self::Cpu => Self::Memory,
+            Self::Memory => Self::Battery,
+            Self::Battery => Self::Wakeups,
+            Self::Wakeups => Self::Crashes,
+        }
+    }
+
+    fn previous(self) -> Self {
+        match self {
+            Self::Crashes => Self::Wakeups,
+            Self::Storage => Self::Crashes,
+            Self::Cpu => Self::Storage,
+            Self::Memory => Self::Cpu,
+            Self::Battery => Self::Memory,
+            Self::Wakeups => Self::Battery,
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub struct DiagnosticsState {
+    page: DiagnosticsPage,
+    view: TextView,
+    timeout: Timeout,
+}
+
+impl DiagnosticsState {
+    pub async fn new(device: &mut Device<'_>, page: DiagnosticsPage) -> Self {
+        let mut text: heapless::String<TEXT_SIZE> = heapless::String::new();
+        let title = match page {
+            DiagnosticsPage::Crashes => {
+                crash_report(device, &mut text).await;
+                "Crashes"
+            }
+            DiagnosticsPage::Storage => {
+                crate::flashstats::report(device.fs, device.logs, device.kv, &mut text).await;
+                "Storage"
+            }
+            DiagnosticsPage::Cpu => {
+                crate::profile::report(&mut text);
+                "CPU"
+            }
+            DiagnosticsPage::Memory => {
+                crate::memory::report(&mut text);
+                "Memory"
+            }
+            DiagnosticsPage::Battery => {
+                device.battery_stats.report_health(&mut text);
+                "Battery health"
+            }
+            DiagnosticsPage::Wakeups => {
+                wakestats::report(device.clock.get(), &mut text);
+                "Wakeups"
+            }
+        };
+        Self {
+            page,
+            view: TextView::new(title, &text),
+            timeout: Timeout::new(IDLE_TIMEOUT),
+        }
+    }
+
+    pub async fn draw(&mut self, device: &mut Device<'_>) {
+        let _ = self.view.draw(&mut device.screen);
+        device.screen.on();
+    }
+
+    pub async fn next(&mut self, device: &mut Device<'_>) -> WatchState {
+        match select3(
+            self.timeout.timer(),
+            device.button.wait(),
+            wait_gesture(&mut device.touchpad),
+        )
+        .await
+        {
+            Either3::First(_) => WatchState::Idle(IdleState::new(device)),
+            Either3::Second(_) => WatchState::Menu(MenuState::new(MenuView::settings())),
+            Either3::Third(cst816s::TouchGesture::SlideLeft) => {
+                WatchState::Diagnostics(DiagnosticsState::new(device, self.page.next()).await)
+            }
+            Either3::Third(cst816s::TouchGesture::SlideRight) => {
+                WatchState::Diagnostics(DiagnosticsState::new(device, self.page.previous()).await)
+            }
+            Either3::Third(cst816s::TouchGesture::LongPress) if self.page == DiagnosticsPage::Crashes => {
+                if let Some(crash_log) = device.logs.crash {
+                    if let Err(e) = crash_log.clear().await {
+                        defmt::warn!("Error clearing crash log: {:?}", e);
+                    }
+                }
+                WatchState::Diagnostics(DiagnosticsState::new(device, self.page).await)
+            }
+            Either3::Third(_) => WatchState::Diagnostics(DiagnosticsState::new(device, self.page).await),
+        }
+    }
+}
+
+/// Summary of the most recent crashes, newest first. Long press clears the log.
+async fn crash_report(device: &mut Device<'_>, text: &mut heapless::String<TEXT_SIZE>) {
+    use core::fmt::Write;
+
+    const SHOWN: usize = 3;
+    let Some(crash_log) = device.logs.crash else {
+        let _ = text.push_str("Crash log unavailable");
+        return;
+    };
+
+    let mut recent: heapless::Deque<(u32, crate::crash::CrashRecord), SHOWN> = heapless::Deque::new();
+    let mut total = 0;
+    let result = crash_log
+        .for_each(|id, record| {
+            if recent.is_full() {
+                recent.pop_front();
+            }
+            let _ = recent.push_back((id, record));
+            total += 1;
+        })
+        .await;
+    if let Err(e) = result {
+        let _ = write!(text, "Error reading log: {:?}", e);
+        return;
+    }
+
+    if total == 0 {
+        let _ = text.push_str("No crashes recorded");
+        return;
+    }
+    let _ = writeln!(text, "{} recorded, hold to clear", total);
+    for (id, record) in recent.iter().rev() {
+        let _ = writeln!(
+            text,
+            "#{} {:?} pc={:08x} fw={:08x}",
+            id, record.kind, record.pc, record.commit
+        );
+        if !record.message.is_empty() {
+            let _ = writeln!(text, "{}", record.message.as_str());
+        }
+    }
+}
+
+/// Wait for the charger to be connected or removed, or charging to complete.
+async fn wait_charge_change(sensor_events: &mut SensorSubscriber) -> ChargeState {
+    loop {
+        if let SensorEvent::ChargeState(state) = sensor_events.next_message_pure().await {
+            return state;
+        }
+    }
+}
+
+async fn firmware_details(battery: &mut crate::device::Battery<'_>, validated: bool) -> FirmwareDetails {
     let battery_level = battery.measure().await;
     let battery_charging = battery.is_charging();
 
     FirmwareDetails::new(
-        CARGO_NAME,
-        CARGO_VERSION,
-        COMMIT,
-        BUILD_TIMESTAMP,
+        BUILD.name,
+        BUILD.version,
+        BUILD.short_commit(),
+        BUILD.timestamp,
+        BUILD.profile,
         battery_level,
         battery_charging,
         validated,
@@ -0,0 +1,126 @@
+use embassy_futures::select::{select3, Either3};
+
+use crate::device::Device;
+use crate::events::{self, SensorEvent, SensorSubscriber};
+use crate::frametime::FrameTimes;
+use crate::profile::{profiled, Task};
+#[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+use crate::state::DfuState;
+use crate::state::{FindWatchState, GoalState, WatchState};
+#[cfg(not(feature = "no-softdevice"))]
+use crate::state::{NavigationState, NotificationState, PasskeyState};
+use crate::{maintenance, power};
+
+/// Run the UI state machine on the calling task, drawing each new state.
+///
+/// Other tasks reach the UI through sensor events: the states wait for the ones they show, such as charge state
+/// changes, while the loop waits for a critical battery, which ends it by powering off, for the step goal to be
+/// reached, which interrupts any state but a workout with a celebration, and for firmware updates, notifications,
+/// navigation instructions, pairing passkeys and requests to find the watch from the phone, which likewise take over
+/// the screen.
+/// `sensor_events` is subscribed before the battery is first measured, so a critical level at boot is not missed. The
+/// debug shell can also have the current state redrawn.
+pub async fn run(mut device: Device<'_>, mut sensor_events: SensorSubscriber) -> ! {
+    let mut state = WatchState::default();
+    let mut frames = FrameTimes::new();
+    profiled(Task::Ui, async move {
+        frames.measure(state.draw(&mut device)).await;
+        loop {
+            // Leaving a workout early would lose its summary, the vibration alone celebrates the goal.
+            let celebrate = !matches!(state, WatchState::Workout(_));
+            #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+            let show_update = celebrate && !matches!(state, WatchState::Dfu(_));
+            #[cfg(all(feature = "no-softdevice", not(feature = "serial-dfu")))]
+            let show_update = celebrate;
+            // The Navigation screen keeps itself up to date, and the distance alone does not wake the watch.
+            #[cfg(not(feature = "no-softdevice"))]
+            let show_navigation = show_update && !matches!(state, WatchState::Navigation(_));
+            let interrupt = async {
+                loop {
+                    match sensor_events.next_message_pure().await {
+                        event @ SensorEvent::BatteryCritical => break event,
+                        event @ SensorEvent::StepGoalReached(_) if celebrate => break event,
+                        #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+                        event @ SensorEvent::FirmwareUpdate(_) if show_update => break event,
+                        // During a workout or an update, notifications are only kept.
+                        #[cfg(not(feature = "no-softdevice"))]
+                        event @ SensorEvent::Notification(_) if show_update => break event,
+                        event @ SensorEvent::FindWatch(true) if show_update => break event,
+                        // Pairing fails without the passkey, but a workout is not cut short for it.
+                        #[cfg(not(feature = "no-softdevice"))]
+                        event @ SensorEvent::Passkey(_) if celebrate => break event,
+                        #[cfg(not(feature = "no-softdevice"))]
+                        event @ SensorEvent::Navigation { new_instruction: true } if show_navigation => break event,
+                        _ => {}
+                    }
+                }
+            };
+            let mut next = match select3(state.next(&mut device), interrupt, events::wait_redraw()).await {
+                Either3::First(next) => next,
+                Either3::Second(SensorEvent::StepGoalReached(steps)) => {
+                    if matches!(state, WatchState::Idle(_)) {
+                        device.screen.wake();
+                    }
+                    WatchState::Goal(GoalState::new(steps))
+                }
+                #[cfg(any(not(feature = "no-softdevice"), feature = "serial-dfu"))]
+                Either3::Second(SensorEvent::FirmwareUpdate(progress)) => {
+                    if matches!(state, WatchState::Idle(_)) {
+                        device.screen.wake();
+                    }
+                    WatchState::Dfu(DfuState::new(progress))
+                }
+                #[cfg(not(feature = "no-softdevice"))]
+                Either3::Second(SensorEvent::Notification(_)) => match NotificationState::latest() {
+                    Some(next) => {
+                        if matches!(state, WatchState::Idle(_)) {
+                            device.screen.wake();
+                        }
+                        WatchState::Notification(next)
+                    }
+                    None => continue,
+                },
+                #[cfg(not(feature = "no-softdevice"))]
+                Either3::Second(SensorEvent::Navigation { .. }) => match NavigationState::latest() {
+                    Some(next) => {
+                        if matches!(state, WatchState::Idle(_)) {
+                            device.screen.wake();
+                        }
+                        WatchState::Navigation(next)
+                    }
+                    None => continue,
+                },
+                Either3::Second(SensorEvent::FindWatch(_)) => {
+                    if matches!(state, WatchState::Idle(_)) {
+                        device.screen.wake();
+                    }
+                    WatchState::FindWatch(FindWatchState::new())
+                }
+                #[cfg(not(feature = "no-softdevice"))]
+                Either3::Second(SensorEvent::Passkey(passkey)) => {
+                    if matches!(state, WatchState::Idle(_)) {
+                        device.screen.wake();
+                    }
+                    WatchState::Passkey(PasskeyState::new(passkey))
+                }
+                Either3::Second(_) => power::shutdown_critical(&mut device).await,
+                Either3::Third(_) => {
+                    frames.measure(state.draw(&mut device)).await;
+                    continue;
+                }
+            };
+            defmt::info!("{:?} -> {:?}", state, next);
+            if next != state {
+                frames.measure(next.draw(&mut device)).await;
+            }
+            state = next;
+            let idle = matches!(state, WatchState::Idle(_));
+            if idle {
+                device.settings.request_flush();
+            }
+            device.battery_stats.set_active(!idle);
+            maintenance::set_conditions(idle, device.battery.lock().await.is_powered());
+        }
+    })
+    .await
+}
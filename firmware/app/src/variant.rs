@@ -0,0 +1,134 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
+use embedded_hal::i2c::I2c;
+
+use crate::I2cBus;
+
+#[cfg(all(feature = "touch-cst816s", feature = "touch-cst716"))]
+compile_error!("Only one of the touch-* features can be enabled");
+#[cfg(any(
+    all(feature = "accel-bma421", feature = "accel-bma425"),
+    all(feature = "accel-bma421", feature = "accel-sc7a20"),
+    all(feature = "accel-bma425", feature = "accel-sc7a20"),
+))]
+compile_error!("Only one of the accel-* features can be enabled");
+
+/// Touch controllers fitted to PineTimes. Both speak the same protocol, but the CST716 of the early dev kits reports
+/// touch points without recognizing gestures, see `input::recognize_gesture`.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum TouchController {
+    Cst816s = 1,
+    Cst716 = 2,
+}
+
+/// Accelerometers fitted to PineTimes. The step counter needs to know which one it drives.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum Accelerometer {
+    Bma421 = 1,
+    Bma425 = 2,
+    Sc7a20 = 3,
+}
+
+/// The touch controller selected with a `touch-*` feature, or `None` to probe for it at boot.
+const TOUCH: Option<TouchController> = if cfg!(feature = "touch-cst816s") {
+    Some(TouchController::Cst816s)
+} else if cfg!(feature = "touch-cst716") {
+    Some(TouchController::Cst716)
+} else {
+    None
+};
+
+/// The accelerometer selected with an `accel-*` feature, or `None` to probe for it at boot.
+const ACCELEROMETER: Option<Accelerometer> = if cfg!(feature = "accel-bma421") {
+    Some(Accelerometer::Bma421)
+} else if cfg!(feature = "accel-bma425") {
+    Some(Accelerometer::Bma425)
+} else if cfg!(feature = "accel-sc7a20") {
+    Some(Accelerometer::Sc7a20)
+} else {
+    None
+};
+
+const TOUCH_ADDRESS: u8 = 0x15;
+const TOUCH_CHIP_ID: u8 = 0xA7;
+const CST716_CHIP_ID: u8 = 0x20;
+const CST816S_CHIP_IDS: [u8; 3] = [0xB4, 0xB5, 0xB6];
+
+pub const ACCELEROMETER_ADDRESS: u8 = 0x18;
+const BMA_CHIP_ID: u8 = 0x00;
+const BMA421_CHIP_ID: u8 = 0x11;
+const BMA425_CHIP_ID: u8 = 0x13;
+const SC7A20_WHO_AM_I: u8 = 0x0F;
+const SC7A20_ID: u8 = 0x11;
+
+/// Parts found at boot, 0 for none.
+static TOUCH_FOUND: AtomicU8 = AtomicU8::new(0);
+static ACCELEROMETER_FOUND: AtomicU8 = AtomicU8::new(0);
+
+/// Find out which touch controller and accelerometer are fitted, from the features or else by reading their chip
+/// ids. Called once the touch controller is reset, as it only answers on the bus for a while after a reset or touch.
+///
+/// A touch controller that answers with an unknown id is taken to be a CST816S, the most common part.
+pub fn detect(i2c_bus: &'static I2cBus, touch_fitted: bool) {
+    let mut i2c = I2cDevice::new(i2c_bus);
+    let touch = if !touch_fitted {
+        None
+    } else if TOUCH.is_some() {
+        TOUCH
+    } else {
+        match read(&mut i2c, TOUCH_ADDRESS, TOUCH_CHIP_ID) {
+            Some(CST716_CHIP_ID) => Some(TouchController::Cst716),
+            Some(id) => {
+                if !CST816S_CHIP_IDS.contains(&id) {
+                    warn!("Unknown touch controller id 0x{:02x}, assuming CST816S", id);
+                }
+                Some(TouchController::Cst816s)
+            }
+            None => Some(TouchController::Cst816s),
+        }
+    };
+    let accelerometer = ACCELEROMETER.or_else(|| match read(&mut i2c, ACCELEROMETER_ADDRESS, BMA_CHIP_ID)? {
+        BMA421_CHIP_ID => Some(Accelerometer::Bma421),
+        BMA425_CHIP_ID => Some(Accelerometer::Bma425),
+        _ if read(&mut i2c, ACCELEROMETER_ADDRESS, SC7A20_WHO_AM_I) == Some(SC7A20_ID) => Some(Accelerometer::Sc7a20),
+        id => {
+            warn!("Unknown accelerometer id 0x{:02x}", id);
+            None
+        }
+    });
+    info!("Touch controller: {:?}, accelerometer: {:?}", touch, accelerometer);
+    TOUCH_FOUND.store(touch.map_or(0, |touch| touch as u8), Ordering::Relaxed);
+    ACCELEROMETER_FOUND.store(
+        accelerometer.map_or(0, |accelerometer| accelerometer as u8),
+        Ordering::Relaxed,
+    );
+}
+
+fn read<I: I2c>(i2c: &mut I, address: u8, register: u8) -> Option<u8> {
+    let mut value = [0];
+    i2c.write_read(address, &[register], &mut value).ok()?;
+    Some(value[0])
+}
+
+/// The touch controller found at boot.
+pub fn touch() -> Option<TouchController> {
+    match TOUCH_FOUND.load(Ordering::Relaxed) {
+        1 => Some(TouchController::Cst816s),
+        2 => Some(TouchController::Cst716),
+        _ => None,
+    }
+}
+
+/// The accelerometer found at boot.
+pub fn accelerometer() -> Option<Accelerometer> {
+    match ACCELEROMETER_FOUND.load(Ordering::Relaxed) {
+        1 => Some(Accelerometer::Bma421),
+        2 => Some(Accelerometer::Bma425),
+        3 => Some(Accelerometer::Sc7a20),
+        _ => None,
+    }
+}
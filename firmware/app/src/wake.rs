@@ -0,0 +1,103 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::debug;
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_nrf::gpio::{AnyPin, Input, Pull};
+use embassy_time::{Duration, Instant, Timer};
+use watchful_ui::ButtonEvent;
+
+use crate::device::Device;
+use crate::input::{read_touch, wait_touch, Touchpad};
+use crate::wakestats::WakeSource;
+use crate::{activity, board, variant};
+
+/// Wake events this soon after the previous one are the same press, touch or raise, and are dropped.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// How long after a touch the controller can still be signaling it.
+const TAP_TIME: Duration = Duration::from_millis(50);
+/// Time after the first tap of a double tap within which the second must come.
+const DOUBLE_TAP_TIME: Duration = Duration::from_millis(400);
+/// Raises are ignored while steps were counted this recently, as arm swings while walking look like raises.
+const WALKING_WINDOW: Duration = Duration::from_secs(10);
+
+/// Uptime in ms of the last wake event, wrapping.
+static LAST_WAKE: AtomicU32 = AtomicU32::new(0);
+
+/// What woke the watch from idle.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum WakeEvent {
+    Button(ButtonEvent),
+    /// A touch, or a double tap if the settings ask for one.
+    Touch,
+    /// The wrist was raised to look at the watch.
+    Raise,
+}
+
+impl WakeEvent {
+    pub fn source(self) -> WakeSource {
+        match self {
+            Self::Button(_) => WakeSource::Button,
+            Self::Touch => WakeSource::Touch,
+            Self::Raise => WakeSource::Raise,
+        }
+    }
+}
+
+/// Wait for the button, or a touch or wrist raise if enabled in the settings, sleeping in between. The button always
+/// wakes the watch, so it can not be locked out by the settings.
+pub async fn wait(device: &mut Device<'_>) -> WakeEvent {
+    loop {
+        let settings = device.settings.get();
+        let touch = wait_tap(&mut device.touchpad, settings.wake_touch, settings.wake_double_tap);
+        let event = match select3(device.button.wait(), touch, wait_raise(settings.wake_raise)).await {
+            Either3::First(event) => WakeEvent::Button(event),
+            Either3::Second(_) => WakeEvent::Touch,
+            Either3::Third(_) => WakeEvent::Raise,
+        };
+        let now = Instant::now().as_millis() as u32;
+        let last = LAST_WAKE.swap(now, Ordering::Relaxed);
+        if now.wrapping_sub(last) < DEBOUNCE.as_millis() as u32 {
+            debug!("Ignoring {:?} right after the last wakeup", event);
+            continue;
+        }
+        return event;
+    }
+}
+
+/// Wait for a tap, or two in a row if `double` is set. The touches are read out, as they are not gestures for the
+/// next screen.
+async fn wait_tap(touchpad: &mut Option<Touchpad<'static>>, enabled: bool, double: bool) {
+    if !enabled {
+        return core::future::pending().await;
+    }
+    loop {
+        wait_touch().await;
+        Timer::after(TAP_TIME).await;
+        let _ = read_touch(touchpad);
+        if !double {
+            return;
+        }
+        if let Either::First(_) = select(wait_touch(), Timer::after(DOUBLE_TAP_TIME)).await {
+            Timer::after(TAP_TIME).await;
+            let _ = read_touch(touchpad);
+            return;
+        }
+    }
+}
+
+/// Wait for the accelerometer to signal a wrist tilt, ignoring those while walking.
+async fn wait_raise(enabled: bool) {
+    let pin = board::ACCEL_INT_PIN.filter(|_| enabled && variant::accelerometer().is_some());
+    let Some(pin) = pin else {
+        return core::future::pending().await;
+    };
+    // Nothing else drives the interrupt line, so it is only taken while waiting on it.
+    let mut int = Input::new(unsafe { AnyPin::steal(pin) }, Pull::None);
+    loop {
+        int.wait_for_rising_edge().await;
+        if !activity::walking(WALKING_WINDOW) {
+            return;
+        }
+        debug!("Ignoring wrist raise while walking");
+    }
+}
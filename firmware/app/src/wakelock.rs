@@ -0,0 +1,85 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::profile::{profiled, Task};
+use crate::softdevice;
+
+/// What a wake lock keeps from being throttled by the idle and power machinery.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum WakeLockKind {
+    /// Keeps the display on, the UI does not time out to idle.
+    Display,
+    /// Keeps the CPU in constant latency mode, so interrupts are served without wakeup delays.
+    Cpu,
+    /// Keeps BLE connections on a short connection interval.
+    #[cfg(not(feature = "no-softdevice"))]
+    BleFast,
+}
+
+const KINDS: usize = 2 + cfg!(not(feature = "no-softdevice")) as usize;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNHELD: AtomicU8 = AtomicU8::new(0);
+static HELD: [AtomicU8; KINDS] = [UNHELD; KINDS];
+
+// Each kind has a single consumer waiting for changes: the UI timeout, `wake_lock_task` and the BLE connection.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CHANGE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static CHANGED: [Signal<CriticalSectionRawMutex, ()>; KINDS] = [NO_CHANGE; KINDS];
+
+/// Held while an operation must not be slowed down, released when dropped.
+pub struct WakeLock {
+    kind: WakeLockKind,
+}
+
+impl WakeLock {
+    pub fn acquire(kind: WakeLockKind) -> Self {
+        if HELD[kind as usize].fetch_add(1, Ordering::Relaxed) == 0 {
+            CHANGED[kind as usize].signal(());
+        }
+        Self { kind }
+    }
+}
+
+impl Drop for WakeLock {
+    fn drop(&mut self) {
+        if HELD[self.kind as usize].fetch_sub(1, Ordering::Relaxed) == 1 {
+            CHANGED[self.kind as usize].signal(());
+        }
+    }
+}
+
+pub fn is_held(kind: WakeLockKind) -> bool {
+    HELD[kind as usize].load(Ordering::Relaxed) > 0
+}
+
+/// Wait until the first lock of `kind` is acquired or the last one released.
+pub async fn wait_change(kind: WakeLockKind) {
+    CHANGED[kind as usize].wait().await
+}
+
+/// Wait until no lock of `kind` is held.
+pub async fn wait_released(kind: WakeLockKind) {
+    while is_held(kind) {
+        wait_change(kind).await;
+    }
+}
+
+/// Switch the CPU power mode as CPU wake locks come and go.
+#[embassy_executor::task]
+pub async fn wake_lock_task() {
+    profiled(Task::WakeLock, async move {
+        loop {
+            let held = is_held(WakeLockKind::Cpu);
+            match softdevice::set_constant_latency(held) {
+                Ok(()) => info!("CPU constant latency {}", held),
+                Err(ret) => warn!("Error setting power mode: {}", ret),
+            }
+            wait_change(WakeLockKind::Cpu).await;
+        }
+    })
+    .await
+}
@@ -0,0 +1,95 @@
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use defmt::info;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use heapless::String;
+
+use crate::activity::timestamp;
+
+/// What woke the watch from idle.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum WakeSource {
+    Button,
+    Touch,
+    Raise,
+}
+
+const SOURCES: [WakeSource; 3] = [WakeSource::Button, WakeSource::Touch, WakeSource::Raise];
+
+impl WakeSource {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Button => "button",
+            Self::Touch => "touch",
+            Self::Raise => "raise",
+        }
+    }
+}
+
+struct Counts {
+    /// Wakeups per source for each hour of the day, covering the last 24 hours.
+    hours: [[u16; SOURCES.len()]; 24],
+    /// Hours since the epoch of the most recent hour counted.
+    current: u32,
+}
+
+impl Counts {
+    /// Move on to the hour `now`, clearing the counts of hours more than a day old.
+    fn advance(&mut self, now: time::PrimitiveDateTime) {
+        let hour = timestamp(now) / 3600;
+        if hour < self.current || hour - self.current >= 24 {
+            // The clock was set back, or nothing was counted for a day.
+            self.hours = [[0; SOURCES.len()]; 24];
+        } else {
+            for h in self.current + 1..=hour {
+                self.hours[(h % 24) as usize] = [0; SOURCES.len()];
+            }
+        }
+        self.current = hour;
+    }
+}
+
+static COUNTS: BMutex<CriticalSectionRawMutex, RefCell<Counts>> = BMutex::new(RefCell::new(Counts {
+    hours: [[0; SOURCES.len()]; 24],
+    current: 0,
+}));
+
+/// Count a wakeup from idle.
+pub fn record(source: WakeSource, now: time::PrimitiveDateTime) {
+    let count = COUNTS.lock(|counts| {
+        let mut counts = counts.borrow_mut();
+        counts.advance(now);
+        let count = &mut counts.hours[now.hour() as usize][source as usize];
+        *count = count.saturating_add(1);
+        *count
+    });
+    info!("Woken by {:?}, {} times this hour", source, count);
+}
+
+/// Wakeups over the last day per source, and the hours with the most wakeups, for the diagnostics screen.
+pub fn report<const N: usize>(now: time::PrimitiveDateTime, text: &mut String<N>) {
+    const SHOWN: usize = 4;
+    COUNTS.lock(|counts| {
+        let mut counts = counts.borrow_mut();
+        counts.advance(now);
+        let _ = writeln!(text, "Last 24 hours:");
+        for source in SOURCES {
+            let total: u32 = counts.hours.iter().map(|hour| hour[source as usize] as u32).sum();
+            let _ = writeln!(text, " {}: {}", source.name(), total);
+        }
+
+        let mut hours: [usize; 24] = core::array::from_fn(|hour| hour);
+        let total = |hour: usize| counts.hours[hour].iter().map(|count| *count as u32).sum::<u32>();
+        hours.sort_unstable_by_key(|hour| core::cmp::Reverse(total(*hour)));
+        let _ = writeln!(text, "Busiest hours:");
+        for hour in hours.iter().take(SHOWN).filter(|hour| total(**hour) > 0) {
+            let _ = write!(text, " {:02}:00", hour);
+            for source in SOURCES {
+                let _ = write!(text, " {} {}", source.name(), counts.hours[*hour][source as usize]);
+            }
+            let _ = writeln!(text);
+        }
+    })
+}
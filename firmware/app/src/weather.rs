@@ -0,0 +1,69 @@
+//! Weather pushed by the phone, kept in RAM for the Weather screen and the watch face. The current weather and the
+//! forecast each carry the time the phone sent them at, and are no longer shown once out of date.
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use heapless::{String, Vec};
+use watchful_ui::{DayWeather, FORECAST_DAYS, LOCATION_SIZE};
+
+use crate::clock::Clock;
+
+/// Age in seconds after which the current weather is no longer shown, phones send it about every half hour.
+const CURRENT_VALID: i64 = 2 * 60 * 60;
+/// Age in seconds after which the forecast is no longer shown.
+const FORECAST_VALID: i64 = 24 * 60 * 60;
+
+#[derive(Clone, PartialEq)]
+pub struct Current {
+    /// Local time the weather was sent at, in seconds since 1970.
+    pub timestamp: i64,
+    /// Temperature in degrees Celsius.
+    pub temperature: i16,
+    pub today: DayWeather,
+    pub location: String<LOCATION_SIZE>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Forecast {
+    /// Local time the forecast was sent at, in seconds since 1970. The first day is the day of the timestamp.
+    pub timestamp: i64,
+    pub days: Vec<DayWeather, FORECAST_DAYS>,
+}
+
+struct Store {
+    current: Option<Current>,
+    forecast: Option<Forecast>,
+}
+
+static STORE: BMutex<CriticalSectionRawMutex, RefCell<Store>> = BMutex::new(RefCell::new(Store {
+    current: None,
+    forecast: None,
+}));
+
+#[cfg(not(feature = "no-softdevice"))]
+pub fn set_current(current: Current) {
+    STORE.lock(|store| store.borrow_mut().current = Some(current));
+}
+
+#[cfg(not(feature = "no-softdevice"))]
+pub fn set_forecast(forecast: Forecast) {
+    STORE.lock(|store| store.borrow_mut().forecast = Some(forecast));
+}
+
+fn fresh(timestamp: i64, valid: i64, clock: &Clock) -> bool {
+    let now = clock.get().assume_utc().unix_timestamp();
+    now - timestamp < valid
+}
+
+/// The current weather, unless out of date.
+pub fn current(clock: &Clock) -> Option<Current> {
+    let current = STORE.lock(|store| store.borrow().current.clone())?;
+    fresh(current.timestamp, CURRENT_VALID, clock).then_some(current)
+}
+
+/// The forecast, unless out of date.
+pub fn forecast(clock: &Clock) -> Option<Forecast> {
+    let forecast = STORE.lock(|store| store.borrow().forecast.clone())?;
+    fresh(forecast.timestamp, FORECAST_VALID, clock).then_some(forecast)
+}
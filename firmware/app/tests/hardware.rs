@@ -0,0 +1,412 @@
+//! Tests run on the watch or the nRF52-DK with `cargo test`, see the README.
+//!
+//! The firmware is a binary, so the modules under test are included from `src`. Tests that write to flash use areas
+//! nothing else does: the unused space after the key-value store on the external flash, and the end of the
+//! application region on the internal flash, which holds the test binary while it runs.
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use defmt_rtt as _;
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice;
+use embassy_nrf::gpio::{AnyPin, Level, Output, OutputDrive};
+use embassy_nrf::nvmc::Nvmc;
+use embassy_nrf::peripherals::{self, TWISPI0, TWISPI1};
+use embassy_nrf::spim::{self, Spim};
+use embassy_nrf::spis::MODE_3;
+use embassy_nrf::{bind_interrupts, twim};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BMutex;
+use panic_probe as _;
+use pinetime_flash::XtFlash;
+use static_cell::StaticCell;
+
+#[allow(dead_code)]
+#[path = "../src/board.rs"]
+mod board;
+#[allow(dead_code)]
+#[path = "../src/layout.rs"]
+mod layout;
+#[allow(dead_code)]
+#[path = "../src/ringlog.rs"]
+mod ringlog;
+
+bind_interrupts!(struct Irqs {
+    SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0 => spim::InterruptHandler<peripherals::TWISPI0>;
+    SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1 => twim::InterruptHandler<peripherals::TWISPI1>;
+});
+
+type ExternalFlash = XtFlash<SpiDevice<'static, NoopRawMutex, Spim<'static, TWISPI0>, Output<'static, AnyPin>>>;
+
+/// Sectors of the external flash the tests erase and write, between the key-value store and the file system.
+const EXTERNAL_SCRATCH: layout::Region = layout::Region::new(layout::KV.end(), 2 * layout::SECTOR_SIZE);
+const _: () = assert!(EXTERNAL_SCRATCH.end() <= layout::FS.start);
+
+/// Pages of the internal flash the DFU test writes to, at the end of the application region.
+const INTERNAL_SCRATCH: layout::Region =
+    layout::Region::new(layout::APP.end() - 2 * layout::PAGE_SIZE, 2 * layout::PAGE_SIZE);
+
+pub struct Peripherals {
+    external: &'static BMutex<NoopRawMutex, RefCell<ExternalFlash>>,
+    internal: &'static BMutex<NoopRawMutex, RefCell<Nvmc<'static>>>,
+    i2c: twim::Twim<'static, TWISPI1>,
+    touch: Option<board::TouchPins>,
+}
+
+#[embedded_test::tests]
+mod tests {
+    use embedded_hal::i2c::I2c;
+    use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+    use nrf_dfu::crc::crc32;
+    use nrf_dfu::initpacket::Capacity;
+    use nrf_dfu::sha256::sha256;
+    use nrf_dfu::{DfuEvent, DfuSession, DfuTarget, DfuTransport, FirmwareInfo, FirmwareType, HardwareInfo};
+
+    use super::*;
+
+    #[init]
+    fn init() -> Peripherals {
+        let p = embassy_nrf::init(Default::default());
+        // The board takes all peripherals it uses, the flash controller is not one of them.
+        let nvmc = Nvmc::new(unsafe { peripherals::NVMC::steal() });
+        let board = board::Board::new(p);
+
+        let mut config = spim::Config::default();
+        config.frequency = spim::Frequency::M8;
+        config.mode = MODE_3;
+        let spim = Spim::new(
+            board.spi,
+            Irqs,
+            board.spi_pins.sck,
+            board.spi_pins.miso,
+            board.spi_pins.mosi,
+            config,
+        );
+        static SPI_BUS: StaticCell<BMutex<NoopRawMutex, RefCell<Spim<'static, TWISPI0>>>> = StaticCell::new();
+        let spi_bus = SPI_BUS.init(BMutex::new(RefCell::new(spim)));
+        let cs = Output::new(board.flash_cs, Level::High, OutputDrive::Standard);
+        let flash = XtFlash::new(SpiDevice::new(spi_bus, cs)).unwrap();
+        static EXTERNAL: StaticCell<BMutex<NoopRawMutex, RefCell<ExternalFlash>>> = StaticCell::new();
+        static INTERNAL: StaticCell<BMutex<NoopRawMutex, RefCell<Nvmc<'static>>>> = StaticCell::new();
+
+        let mut config = twim::Config::default();
+        config.frequency = twim::Frequency::K400;
+        let i2c = twim::Twim::new(board.i2c, Irqs, board.i2c_pins.sda, board.i2c_pins.scl, config);
+
+        Peripherals {
+            external: EXTERNAL.init(BMutex::new(RefCell::new(flash))),
+            internal: INTERNAL.init(BMutex::new(RefCell::new(nvmc))),
+            i2c,
+            touch: board.touch,
+        }
+    }
+
+    #[test]
+    fn external_flash_erase_write_read(p: Peripherals) {
+        let mut flash = BlockingPartition::new(p.external, EXTERNAL_SCRATCH.start, EXTERNAL_SCRATCH.size);
+        let sector = layout::SECTOR_SIZE;
+        flash.erase(0, sector).unwrap();
+
+        let mut buf = [0; 256];
+        flash.read(0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0xFF));
+
+        let mut data = [0; 256];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        // Across a page boundary of the NOR flash.
+        flash.write(128, &data).unwrap();
+        flash.read(128, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        flash.erase(0, sector).unwrap();
+        flash.read(128, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn ring_log_wraps_and_recovers(p: Peripherals) {
+        let mut flash = BlockingPartition::new(p.external, EXTERNAL_SCRATCH.start, EXTERNAL_SCRATCH.size);
+        flash.erase(0, EXTERNAL_SCRATCH.size).unwrap();
+
+        let mut log = ringlog::RingLog::new(flash).unwrap();
+        let record = [0xA5; ringlog::MAX_RECORD_SIZE];
+        // Enough records to fill both sectors, so the oldest is erased to make room.
+        // Records have a 12 byte header.
+        let records = 3 * layout::SECTOR_SIZE / (ringlog::MAX_RECORD_SIZE as u32 + 12);
+        for id in 0..records {
+            assert_eq!(log.append(&record).unwrap(), id);
+        }
+
+        // Reopened as after a reset, the log carries on from where it was.
+        let flash = BlockingPartition::new(p.external, EXTERNAL_SCRATCH.start, EXTERNAL_SCRATCH.size);
+        let mut log = ringlog::RingLog::new(flash).unwrap();
+        assert_eq!(log.next_id(), records);
+        let mut expected = None;
+        log.for_each(|id, data| {
+            assert_eq!(data, &record[..]);
+            if let Some(expected) = expected {
+                assert_eq!(id, expected);
+            }
+            expected = Some(id + 1);
+        })
+        .unwrap();
+        assert_eq!(expected, Some(records));
+    }
+
+    #[test]
+    fn touch_controller_answers(mut p: Peripherals) {
+        let Some(pins) = p.touch.take() else {
+            defmt::info!("No touch controller on this board");
+            return;
+        };
+        // The controller only answers on the bus for a while after a reset.
+        let mut reset = Output::new(pins.reset, Level::Low, OutputDrive::Standard);
+        cortex_m::asm::delay(64_000 * 5);
+        reset.set_high();
+        cortex_m::asm::delay(64_000 * 50);
+
+        let mut id = [0];
+        p.i2c.write_read(0x15, &[0xA7], &mut id).unwrap();
+        defmt::info!("Touch controller id 0x{:02x}", id[0]);
+        assert!([0xB4, 0xB5, 0xB6, 0x20].contains(&id[0]));
+    }
+
+    /// Keeps the last notification.
+    struct Notifications(RefCell<heapless::Vec<u8, 32>>);
+
+    impl DfuTransport for Notifications {
+        type Error = ();
+
+        fn notify(&self, data: &[u8]) -> Result<(), ()> {
+            *self.0.borrow_mut() = heapless::Vec::from_slice(data)?;
+            Ok(())
+        }
+
+        fn mtu(&self) -> u16 {
+            23
+        }
+    }
+
+    impl Notifications {
+        fn last(&self) -> heapless::Vec<u8, 32> {
+            self.0.borrow().clone()
+        }
+    }
+
+    /// Write a request to the control point, returning the response notified.
+    fn control<F: NorFlash>(
+        session: &mut DfuSession,
+        target: &mut DfuTarget,
+        flash: &mut F,
+        notifications: &Notifications,
+        request: &[u8],
+    ) -> heapless::Vec<u8, 32> {
+        session.handle(target, flash, notifications, DfuEvent::ControlWrite(request));
+        notifications.last()
+    }
+
+    /// A plain init packet as nrfutil builds it for an application of `image`.
+    fn init_packet(image: &[u8]) -> heapless::Vec<u8, 64> {
+        let size = image.len() as u32;
+        let mut hash = sha256(image);
+        hash.reverse();
+        let mut init = heapless::Vec::<u8, 64>::new();
+        // fw_version 1, hw_version 52, application, app_size.
+        init.extend_from_slice(&[0x08, 0x01, 0x10, 0x34, 0x20, 0x00, 0x38])
+            .unwrap();
+        init.extend_from_slice(&[(size as u8) | 0x80, (size >> 7) as u8])
+            .unwrap();
+        // SHA-256 hash.
+        init.extend_from_slice(&[0x42, 36, 0x08, 0x03, 0x12, 32]).unwrap();
+        init.extend_from_slice(&hash).unwrap();
+        let mut packet = heapless::Vec::new();
+        // Command with op_code INIT and the init command.
+        packet
+            .extend_from_slice(&[0x12, init.len() as u8 + 4, 0x08, 0x01, 0x12, init.len() as u8])
+            .unwrap();
+        packet.extend_from_slice(&init).unwrap();
+        packet
+    }
+
+    /// A target running application `version`, writing to the internal scratch pages, and a session with
+    /// notifications enabled.
+    fn dfu_target<F: NorFlash>(flash: &mut F, notifications: &Notifications, version: u32) -> (DfuTarget, DfuSession) {
+        let hw_info = HardwareInfo {
+            part: 0x52832,
+            variant: 0,
+            rom_size: 0,
+            ram_size: 0,
+            rom_page_size: 0,
+        };
+        let image = |ftype, version| FirmwareInfo {
+            ftype,
+            version,
+            addr: 0,
+            len: 0,
+        };
+        let images = [
+            image(FirmwareType::Softdevice, 7002000),
+            image(FirmwareType::Application, version),
+            image(FirmwareType::Bootloader, 0),
+        ];
+        let capacity = Capacity {
+            application: INTERNAL_SCRATCH.size,
+            softdevice: layout::SOFTDEVICE.size,
+            bootloader: layout::BOOTLOADER.size,
+            assets: 0,
+        };
+        let mut target = DfuTarget::new(capacity, images, hw_info);
+        let mut session = DfuSession::default();
+        session.handle(&mut target, flash, notifications, DfuEvent::ControlNotifications(true));
+        (target, session)
+    }
+
+    /// Send `init` in a command object and execute it, returning the response to Execute.
+    fn send_init<F: NorFlash>(
+        session: &mut DfuSession,
+        target: &mut DfuTarget,
+        flash: &mut F,
+        notifications: &Notifications,
+        init: &[u8],
+    ) -> heapless::Vec<u8, 32> {
+        let command = [0x01, 0x01, init.len() as u8, 0, 0, 0];
+        let response = control(session, target, flash, notifications, &command);
+        assert_eq!(&response[..3], &[0x60, 0x01, 0x01]);
+        session.handle(target, flash, notifications, DfuEvent::PacketWrite(init));
+        control(session, target, flash, notifications, &[0x04])
+    }
+
+    /// Create a data object of `data`, write and execute it, returning the response to Execute.
+    fn send_object<F: NorFlash>(
+        session: &mut DfuSession,
+        target: &mut DfuTarget,
+        flash: &mut F,
+        notifications: &Notifications,
+        data: &[u8],
+    ) -> heapless::Vec<u8, 32> {
+        let size = (data.len() as u32).to_le_bytes();
+        let create = [0x01, 0x02, size[0], size[1], size[2], size[3]];
+        let response = control(session, target, flash, notifications, &create);
+        assert_eq!(&response[..3], &[0x60, 0x01, 0x01]);
+        session.handle(target, flash, notifications, DfuEvent::PacketWrite(data));
+        control(session, target, flash, notifications, &[0x04])
+    }
+
+    #[test]
+    fn dfu_session_writes_object(p: Peripherals) {
+        let mut flash = BlockingPartition::new(p.internal, INTERNAL_SCRATCH.start, INTERNAL_SCRATCH.size);
+        flash.erase(0, INTERNAL_SCRATCH.size).unwrap();
+        let notifications = Notifications(RefCell::new(heapless::Vec::new()));
+        let (mut target, mut session) = dfu_target(&mut flash, &notifications, 1);
+
+        let data = [0x5A; 256];
+        let size = (data.len() as u32).to_le_bytes();
+        // Data objects are refused before an init packet.
+        let create = [0x01, 0x02, size[0], size[1], size[2], size[3]];
+        let response = control(&mut session, &mut target, &mut flash, &notifications, &create);
+        assert_eq!(&response[..], &[0x60, 0x01, 0x08]);
+
+        // Send the init packet in a command object and execute it.
+        let init = init_packet(&data);
+        let response = send_init(&mut session, &mut target, &mut flash, &notifications, &init);
+        assert_eq!(&response[..3], &[0x60, 0x04, 0x01]);
+
+        // Objects larger than the image are refused.
+        let response = control(
+            &mut session,
+            &mut target,
+            &mut flash,
+            &notifications,
+            &[0x01, 0x02, 0, 2, 0, 0],
+        );
+        assert_eq!(&response[..], &[0x60, 0x01, 0x04]);
+
+        // Create a data object, send it in packets the size of the default MTU, which the internal flash can not
+        // write one by one, and ask for the checksum, as a phone does.
+        let response = control(&mut session, &mut target, &mut flash, &notifications, &create);
+        assert_eq!(&response[..3], &[0x60, 0x01, 0x01]);
+        for packet in data.chunks(20) {
+            session.handle(&mut target, &mut flash, &notifications, DfuEvent::PacketWrite(packet));
+        }
+        let response = control(&mut session, &mut target, &mut flash, &notifications, &[0x03]);
+        assert_eq!(&response[..3], &[0x60, 0x03, 0x01]);
+        assert_eq!(&response[3..7], &size);
+        assert_eq!(&response[7..11], &crc32(&data).to_le_bytes());
+
+        let mut written = [0; 256];
+        flash.read(0, &mut written).unwrap();
+        assert_eq!(written, data);
+
+        // Writes past the end of the object are refused and leave the flash alone.
+        session.handle(&mut target, &mut flash, &notifications, DfuEvent::PacketWrite(&[0; 4]));
+        assert_eq!(&notifications.last()[..], &[0x60, 0x08, 0x05]);
+
+        // The image matches the hash of the init packet, so it is executed.
+        let response = control(&mut session, &mut target, &mut flash, &notifications, &[0x04]);
+        assert_eq!(&response[..3], &[0x60, 0x04, 0x01]);
+
+        // An init packet for another image is accepted, but the image then fails the hash check.
+        let init = init_packet(&[0xA5; 256]);
+        send_init(&mut session, &mut target, &mut flash, &notifications, &init);
+        let response = send_object(&mut session, &mut target, &mut flash, &notifications, &data);
+        assert_eq!(&response[..], &[0x60, 0x04, 0x0B, 0x0A]);
+
+        // Refused updates answer with "operation not permitted".
+        let mut session = DfuSession::default();
+        session.refuse(&notifications, DfuEvent::ControlNotifications(true));
+        session.refuse(&notifications, DfuEvent::ControlWrite(&[0x01, 0x02, 0, 1, 0, 0]));
+        assert_eq!(&notifications.last()[..], &[0x60, 0x01, 0x08]);
+    }
+
+    #[test]
+    fn dfu_session_resumes(p: Peripherals) {
+        let mut flash = BlockingPartition::new(p.internal, INTERNAL_SCRATCH.start, INTERNAL_SCRATCH.size);
+        flash.erase(0, INTERNAL_SCRATCH.size).unwrap();
+        let notifications = Notifications(RefCell::new(heapless::Vec::new()));
+        let (mut target, mut session) = dfu_target(&mut flash, &notifications, 1);
+
+        // Send the first of two objects, then lose the connection.
+        let mut image = [0x5A; 512];
+        image[256..].fill(0xA5);
+        let init = init_packet(&image);
+        send_init(&mut session, &mut target, &mut flash, &notifications, &init);
+        let response = send_object(&mut session, &mut target, &mut flash, &notifications, &image[..256]);
+        assert_eq!(&response[..3], &[0x60, 0x04, 0x01]);
+        let progress = target.progress().unwrap();
+        assert_eq!(progress.offset, 256);
+
+        // The next connection sends the same init packet, and Select tells it where to continue.
+        let (mut target, mut session) = dfu_target(&mut flash, &notifications, 1);
+        target.resume(progress);
+        send_init(&mut session, &mut target, &mut flash, &notifications, &init);
+        let response = control(&mut session, &mut target, &mut flash, &notifications, &[0x06, 0x02]);
+        assert_eq!(&response[..3], &[0x60, 0x06, 0x01]);
+        assert_eq!(&response[7..11], &256u32.to_le_bytes());
+        assert_eq!(&response[11..15], &crc32(&image[..256]).to_le_bytes());
+        let response = send_object(&mut session, &mut target, &mut flash, &notifications, &image[256..]);
+        assert_eq!(&response[..3], &[0x60, 0x04, 0x01]);
+        assert!(target.progress().is_none());
+    }
+
+    #[test]
+    fn dfu_session_refuses_downgrade(p: Peripherals) {
+        let mut flash = BlockingPartition::new(p.internal, INTERNAL_SCRATCH.start, INTERNAL_SCRATCH.size);
+        let notifications = Notifications(RefCell::new(heapless::Vec::new()));
+        let (mut target, mut session) = dfu_target(&mut flash, &notifications, 2);
+
+        // The running version is reported for the application, and an unknown type past the bootloader.
+        let response = control(&mut session, &mut target, &mut flash, &notifications, &[0x0B, 0x01]);
+        assert_eq!(&response[..8], &[0x60, 0x0B, 0x01, 0x01, 2, 0, 0, 0]);
+        let response = control(&mut session, &mut target, &mut flash, &notifications, &[0x0B, 0x03]);
+        assert_eq!(&response[..4], &[0x60, 0x0B, 0x01, 0xFF]);
+
+        // The init packet is for version 1, refused with a firmware version failure.
+        let init = init_packet(&[0x5A; 256]);
+        let response = send_init(&mut session, &mut target, &mut flash, &notifications, &init);
+        assert_eq!(&response[..], &[0x60, 0x04, 0x0B, 0x05]);
+    }
+}
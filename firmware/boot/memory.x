@@ -1,17 +1,26 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* Must match app/src/layout.rs, the build script of the application checks it */
   MBR                               : ORIGIN = 0x00000000, LENGTH = 4K
   SOFTDEVICE                        : ORIGIN = 0x00001000, LENGTH = 148K
-  ACTIVE                            : ORIGIN = 0x00026000, LENGTH = 324K
-  FLASH                             : ORIGIN = 0x00077000, LENGTH = 32K
+  ACTIVE                            : ORIGIN = 0x00026000, LENGTH = 320K
+  /* Bonds are kept outside of ACTIVE so that they survive firmware swaps */
+  BONDS                             : ORIGIN = 0x00076000, LENGTH = 4K
+  FLASH                             : ORIGIN = 0x00077000, LENGTH = 28K
+  /* Where the MBR keeps a bootloader copy under way, which the application has it make */
+  MBR_PARAMS                        : ORIGIN = 0x0007E000, LENGTH = 4K
   BOOTLOADER_STATE                  : ORIGIN = 0x0007F000, LENGTH = 4K
 
   DFU                               : ORIGIN = 0x00000000, LENGTH = 328K
+  /* SoftDevice and bootloader updates, installed in place rather than swapped */
+  STAGING_HEADER                    : ORIGIN = 0x00080000, LENGTH = 4K
+  STAGING                           : ORIGIN = 0x00081000, LENGTH = 176K
 
   RAM                         (rwx) : ORIGIN = 0x20000008, LENGTH = 0xfff8
   /* NOTE: Disable when building reloader */
   uicr_bootloader_start_address (r) : ORIGIN = 0x10001014, LENGTH = 0x4
+  uicr_mbr_params_page          (r) : ORIGIN = 0x10001018, LENGTH = 0x4
 }
 
 __bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
@@ -23,7 +32,17 @@ __bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE);
 __bootloader_dfu_start = ORIGIN(DFU);
 __bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
 
+__softdevice_start = ORIGIN(SOFTDEVICE);
+__softdevice_end = ORIGIN(SOFTDEVICE) + LENGTH(SOFTDEVICE);
+
+__staging_header_start = ORIGIN(STAGING_HEADER);
+__staging_header_end = ORIGIN(STAGING_HEADER) + LENGTH(STAGING_HEADER);
+
+__staging_start = ORIGIN(STAGING);
+__staging_end = ORIGIN(STAGING) + LENGTH(STAGING);
+
 __bootloader_start = ORIGIN(FLASH);
+__mbr_params_page = ORIGIN(MBR_PARAMS);
 
 /* NOTE: Disable when building reloader */
 SECTIONS
@@ -32,4 +51,9 @@ SECTIONS
   {
     LONG(__bootloader_start)
   } > uicr_bootloader_start_address
+
+  .uicr_mbr_params_page :
+  {
+    LONG(__mbr_params_page)
+  } > uicr_mbr_params_page
 }
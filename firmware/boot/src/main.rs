@@ -56,6 +56,8 @@ fn main() -> ! {
         let external_flash = external_flash.unwrap();
         let external_flash = Mutex::new(RefCell::new(external_flash));
 
+        install_softdevice(&internal_flash, &external_flash);
+
         let config = create_flash_config(&internal_flash, &external_flash);
         active_offset = config.active.offset();
 
@@ -107,6 +109,34 @@ pub fn create_flash_config<'a, 'b, 'c>(
     BootLoaderConfig { active, dfu, state }
 }
 
+/// Install a SoftDevice the application staged, before anything runs on top of it, see `watchful_boot`.
+fn install_softdevice<'a, 'b, 'c>(
+    internal: &'a Mutex<NoopRawMutex, RefCell<WatchdogFlash<Nvmc<'b>>>>,
+    external: &'a Mutex<NoopRawMutex, RefCell<ExternalFlash<'b, 'c>>>,
+) {
+    extern "C" {
+        static __softdevice_start: u32;
+        static __softdevice_end: u32;
+        static __staging_header_start: u32;
+        static __staging_header_end: u32;
+        static __staging_start: u32;
+        static __staging_end: u32;
+    }
+
+    let (mut softdevice, mut header, mut staging) = unsafe {
+        let region = |start: &u32, end: &u32| (start as *const u32 as u32, end as *const u32 as u32);
+        let (sd_start, sd_end) = region(&__softdevice_start, &__softdevice_end);
+        let (header_start, header_end) = region(&__staging_header_start, &__staging_header_end);
+        let (staging_start, staging_end) = region(&__staging_start, &__staging_end);
+        (
+            BlockingPartition::new(internal, sd_start, sd_end - sd_start),
+            BlockingPartition::new(external, header_start, header_end - header_start),
+            BlockingPartition::new(external, staging_start, staging_end - staging_start),
+        )
+    };
+    watchful_boot::install_softdevice(&mut softdevice, &mut header, &mut staging);
+}
+
 #[no_mangle]
 #[cfg_attr(target_os = "none", link_section = ".HardFault.user")]
 unsafe extern "C" fn HardFault() {
@@ -0,0 +1,27 @@
+//! Decode control point writes and notifications, init packets and SLIP frames from any bytes. Whatever decodes must
+//! encode back to the bytes it came from.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nrf_dfu::initpacket::InitPacket;
+use nrf_dfu::slip::SlipDecoder;
+use nrf_dfu::{DfuRequest, DfuResponse};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = DfuRequest::decode(data) {
+        let encoded = request.encode::<1024>();
+        if data.len() <= 1024 {
+            assert!(data.starts_with(&encoded.unwrap()));
+        }
+    }
+    if let Some(response) = DfuResponse::decode(data) {
+        assert!(data.starts_with(&response.encode()));
+    }
+    let _ = InitPacket::decode(data);
+    let mut decoder = SlipDecoder::<256>::default();
+    for &byte in data {
+        if let Some(frame) = decoder.push(byte) {
+            let _ = DfuRequest::decode(frame);
+        }
+    }
+});
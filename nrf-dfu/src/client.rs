@@ -0,0 +1,221 @@
+//! Host side of the Nordic Secure DFU protocol, sending an init packet and an image to a `DfuTarget` or any other
+//! device taking Nordic DFU, the way nrfutil does.
+//!
+//! The client does no I/O: `next_request` gives the next request to send, and `handle_response` takes the control
+//! point notification it was answered with, so the same client works over BLE, a serial port or a loopback to a
+//! target. An update interrupted earlier is taken up where the device left off, as its Select responses tell.
+use crate::crc::{crc32, crc32_update};
+use crate::target::{DfuRequest, DfuResponse, DfuResult, ObjectType, ResponseBody};
+
+/// Why an update could not be sent.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClientError {
+    /// The notification is not a response, or not to the request sent.
+    UnexpectedResponse,
+    /// The device answered a request with an error.
+    Refused(DfuResponse),
+    /// The init packet is larger than the command objects the device takes.
+    InitTooLarge,
+    /// The offset or CRC the device reported is not of the data sent.
+    CrcMismatch,
+}
+
+/// Step of the update, the request sent next.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Step {
+    SelectCommand,
+    CreateCommand,
+    WriteCommand,
+    CheckCommand,
+    ExecuteCommand,
+    SelectData,
+    CreateData,
+    WriteData,
+    CheckData,
+    ExecuteData,
+    Done,
+}
+
+/// Sends an update to a device, see the module documentation.
+pub struct DfuClient<'a> {
+    init: &'a [u8],
+    image: &'a [u8],
+    /// Largest Write, over BLE the ATT MTU less 3 bytes.
+    packet_size: usize,
+    step: Step,
+    /// Opcode of the request sent and not yet answered.
+    awaiting: Option<u8>,
+    /// Largest data object the device takes.
+    max_size: u32,
+    /// Bytes of the init packet or the image sent so far, and the CRC of the image up to there.
+    offset: u32,
+    crc: u32,
+    /// End of the data object being sent.
+    object_end: u32,
+    /// End of the last executed data object.
+    executed: u32,
+}
+
+impl<'a> DfuClient<'a> {
+    /// A client sending the `init` packet and `image` of an update package, in Writes of up to `packet_size` bytes.
+    pub fn new(init: &'a [u8], image: &'a [u8], packet_size: usize) -> Self {
+        Self {
+            init,
+            image,
+            packet_size: packet_size.max(1),
+            step: Step::SelectCommand,
+            awaiting: None,
+            max_size: 0,
+            offset: 0,
+            crc: 0,
+            object_end: 0,
+            executed: 0,
+        }
+    }
+
+    /// Whether the whole image was sent and executed.
+    pub fn is_done(&self) -> bool {
+        self.step == Step::Done
+    }
+
+    /// Bytes of the image executed so far and the size of the image.
+    pub fn progress(&self) -> (u32, u32) {
+        (self.executed, self.image.len() as u32)
+    }
+
+    /// The next request to send, `None` while the response to the last one is awaited or once the update is done.
+    /// Over BLE, the data of Write requests goes to the packet characteristic and the rest to the control point.
+    pub fn next_request(&mut self) -> Option<DfuRequest<'a>> {
+        if self.awaiting.is_some() {
+            return None;
+        }
+        let request = match self.step {
+            Step::SelectCommand => DfuRequest::Select {
+                obj_type: ObjectType::Command,
+            },
+            Step::CreateCommand => DfuRequest::Create {
+                obj_type: ObjectType::Command,
+                obj_size: self.init.len() as u32,
+            },
+            Step::WriteCommand => {
+                let start = self.offset as usize;
+                let end = (start + self.packet_size).min(self.init.len());
+                self.offset = end as u32;
+                if self.offset as usize == self.init.len() {
+                    self.step = Step::CheckCommand;
+                }
+                return Some(DfuRequest::Write {
+                    data: &self.init[start..end],
+                });
+            }
+            Step::CreateData => DfuRequest::Create {
+                obj_type: ObjectType::Data,
+                obj_size: self.object_end - self.offset,
+            },
+            Step::WriteData => {
+                let start = self.offset as usize;
+                let end = (start + self.packet_size).min(self.object_end as usize);
+                let data = &self.image[start..end];
+                self.offset = end as u32;
+                self.crc = crc32_update(self.crc, data);
+                if self.offset == self.object_end {
+                    self.step = Step::CheckData;
+                }
+                return Some(DfuRequest::Write { data });
+            }
+            Step::CheckCommand | Step::CheckData => DfuRequest::Crc,
+            Step::ExecuteCommand | Step::ExecuteData => DfuRequest::Execute,
+            Step::SelectData => DfuRequest::Select {
+                obj_type: ObjectType::Data,
+            },
+            Step::Done => return None,
+        };
+        self.awaiting = Some(request.opcode());
+        Some(request)
+    }
+
+    /// Take a control point notification from the device. Receipt notifications are ignored, the client checks the
+    /// CRC once each object is sent.
+    pub fn handle_response(&mut self, data: &[u8]) -> Result<(), ClientError> {
+        let response = DfuResponse::decode(data).ok_or(ClientError::UnexpectedResponse)?;
+        if response.result() != DfuResult::Success {
+            return Err(ClientError::Refused(response));
+        }
+        if self.awaiting != Some(response.opcode()) {
+            return match response.body() {
+                ResponseBody::Crc { .. } => Ok(()),
+                _ => Err(ClientError::UnexpectedResponse),
+            };
+        }
+        self.awaiting = None;
+
+        self.step = match (self.step, response.body()) {
+            (Step::SelectCommand, ResponseBody::Select { max_size, offset, crc }) => {
+                if self.init.len() as u32 > max_size {
+                    return Err(ClientError::InitTooLarge);
+                }
+                // The device still has the init packet, it only needs to be executed.
+                if offset == self.init.len() as u32 && crc == crc32(self.init) {
+                    Step::ExecuteCommand
+                } else {
+                    Step::CreateCommand
+                }
+            }
+            (Step::CreateCommand, _) => {
+                self.offset = 0;
+                Step::WriteCommand
+            }
+            (Step::CheckCommand, ResponseBody::Crc { offset, crc }) => {
+                if offset != self.init.len() as u32 || crc != crc32(self.init) {
+                    return Err(ClientError::CrcMismatch);
+                }
+                Step::ExecuteCommand
+            }
+            (Step::ExecuteCommand, _) => Step::SelectData,
+            (Step::SelectData, ResponseBody::Select { max_size, offset, crc }) => {
+                if max_size == 0 {
+                    return Err(ClientError::UnexpectedResponse);
+                }
+                self.max_size = max_size;
+                let image = self.image.get(..offset as usize);
+                let received = image.filter(|image| crc32(image) == crc).map_or(0, |_| offset);
+                // A partly sent object is sent again from its start, a whole one may not have been executed yet.
+                self.executed = received - received % max_size;
+                if received > 0 && received % max_size == 0 {
+                    (self.offset, self.crc) = (received, crc);
+                    self.object_end = received;
+                    Step::ExecuteData
+                } else {
+                    self.offset = self.executed;
+                    self.crc = crc32(&self.image[..self.executed as usize]);
+                    self.next_object()
+                }
+            }
+            (Step::CreateData, _) => Step::WriteData,
+            (Step::CheckData, ResponseBody::Crc { offset, crc }) => {
+                if offset != self.object_end || crc != self.crc {
+                    return Err(ClientError::CrcMismatch);
+                }
+                Step::ExecuteData
+            }
+            (Step::ExecuteData, _) => {
+                self.executed = self.object_end;
+                self.next_object()
+            }
+            _ => return Err(ClientError::UnexpectedResponse),
+        };
+        Ok(())
+    }
+
+    /// Start the data object after the last executed one, if any is left. `offset` and `crc` are at its start.
+    fn next_object(&mut self) -> Step {
+        let len = self.image.len() as u32;
+        if self.executed >= len {
+            return Step::Done;
+        }
+        self.object_end = (self.executed + self.max_size).min(len);
+        Step::CreateData
+    }
+}
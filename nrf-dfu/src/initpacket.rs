@@ -0,0 +1,331 @@
+//! Nordic DFU init packet, the protobuf `dfu-cc.proto` message nrfutil writes to the command object. Only the fields
+//! the target checks are kept.
+use heapless::Vec;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+
+/// Largest init packet accepted, nrfutil's are about 140 bytes with a signature.
+pub const MAX_SIZE: usize = 256;
+
+/// Hardware version of packages for nRF52 devices, `nrfutil pkg generate --hw-version 52`.
+pub const HW_VERSION: u32 = 52;
+
+/// Largest image of each kind the device has room for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capacity {
+    pub application: u32,
+    pub softdevice: u32,
+    pub bootloader: u32,
+    /// Largest asset image, an external application package (`nrfutil pkg generate --external-app`) holding
+    /// resources rather than firmware. Assets are refused if this is 0.
+    pub assets: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FirmwareType {
+    Application,
+    Softdevice,
+    Bootloader,
+    SoftdeviceBootloader,
+    ExternalApplication,
+}
+
+impl FirmwareType {
+    fn from_u64(value: u64) -> Option<Self> {
+        Some(match value {
+            0 => Self::Application,
+            1 => Self::Softdevice,
+            2 => Self::Bootloader,
+            3 => Self::SoftdeviceBootloader,
+            4 => Self::ExternalApplication,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HashType {
+    None,
+    Crc,
+    Sha128,
+    Sha256,
+    Sha512,
+}
+
+impl HashType {
+    fn from_u64(value: u64) -> Option<Self> {
+        Some(match value {
+            0 => Self::None,
+            1 => Self::Crc,
+            2 => Self::Sha128,
+            3 => Self::Sha256,
+            4 => Self::Sha512,
+            _ => return None,
+        })
+    }
+}
+
+/// Why an init packet was not accepted.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InitError {
+    /// Not a protobuf message, or an init command is missing.
+    Malformed,
+    /// A firmware type the target does not take.
+    UnsupportedType,
+    HwVersion,
+    /// Older than the running application.
+    FwVersion,
+    /// The image is empty or does not fit.
+    Size,
+    /// The image has no SHA-256 hash.
+    Hash,
+    /// The packet is not signed.
+    SignatureMissing,
+    /// The packet is signed other than with ECDSA P-256.
+    SignatureType,
+    /// The signature is not of the command by the key of the firmware.
+    Signature,
+}
+
+/// The init command of an update.
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InitPacket {
+    pub fw_version: u32,
+    pub hw_version: Option<u32>,
+    pub fw_type: FirmwareType,
+    pub sd_size: u32,
+    pub bl_size: u32,
+    pub app_size: u32,
+    pub hash_type: HashType,
+    /// The hash as sent, which nrfutil stores byte-reversed.
+    pub hash: Vec<u8, 64>,
+    pub is_debug: bool,
+    /// Whether the init command came in a `SignedCommand`, checked with `verify`.
+    pub signed: bool,
+}
+
+impl InitPacket {
+    /// Parse an init packet, which holds the init command in a `Command`, possibly wrapped in a `SignedCommand`.
+    pub fn decode(data: &[u8]) -> Result<Self, InitError> {
+        let mut command = None;
+        for field in Fields(data) {
+            match field? {
+                // signed_command
+                (1, Value::Bytes(signed)) => command = Some((signed_command(signed)?.0, true)),
+                // command
+                (2, Value::Bytes(c)) => command = Some((c, false)),
+                _ => {}
+            }
+        }
+        let (command, signed) = command.ok_or(InitError::Malformed)?;
+        let mut init = None;
+        for field in Fields(command) {
+            match field? {
+                // op_code, only INIT is defined.
+                (1, Value::Varint(op)) if op != 1 => return Err(InitError::Malformed),
+                (2, Value::Bytes(i)) => init = Some(i),
+                _ => {}
+            }
+        }
+        let mut packet = Self::decode_init(init.ok_or(InitError::Malformed)?)?;
+        packet.signed = signed;
+        Ok(packet)
+    }
+
+    fn decode_init(data: &[u8]) -> Result<Self, InitError> {
+        let mut packet = Self {
+            fw_version: 0,
+            hw_version: None,
+            fw_type: FirmwareType::Application,
+            sd_size: 0,
+            bl_size: 0,
+            app_size: 0,
+            hash_type: HashType::None,
+            hash: Vec::new(),
+            is_debug: false,
+            signed: false,
+        };
+        for field in Fields(data) {
+            match field? {
+                (1, Value::Varint(v)) => packet.fw_version = v as u32,
+                (2, Value::Varint(v)) => packet.hw_version = Some(v as u32),
+                (4, Value::Varint(v)) => {
+                    packet.fw_type = FirmwareType::from_u64(v).ok_or(InitError::UnsupportedType)?
+                }
+                (5, Value::Varint(v)) => packet.sd_size = v as u32,
+                (6, Value::Varint(v)) => packet.bl_size = v as u32,
+                (7, Value::Varint(v)) => packet.app_size = v as u32,
+                (8, Value::Bytes(hash)) => {
+                    for field in Fields(hash) {
+                        match field? {
+                            (1, Value::Varint(v)) => packet.hash_type = HashType::from_u64(v).ok_or(InitError::Hash)?,
+                            (2, Value::Bytes(h)) => packet.hash = Vec::from_slice(h).map_err(|_| InitError::Hash)?,
+                            _ => {}
+                        }
+                    }
+                }
+                (9, Value::Varint(v)) => packet.is_debug = v != 0,
+                _ => {}
+            }
+        }
+        Ok(packet)
+    }
+
+    /// Size of the image, which for a SoftDevice and bootloader update is the SoftDevice followed by the bootloader.
+    pub fn image_size(&self) -> u32 {
+        match self.fw_type {
+            FirmwareType::Application | FirmwareType::ExternalApplication => self.app_size,
+            FirmwareType::Softdevice => self.sd_size,
+            FirmwareType::Bootloader => self.bl_size,
+            FirmwareType::SoftdeviceBootloader => self.sd_size.saturating_add(self.bl_size),
+        }
+    }
+
+    /// Check the update is for an nRF52, with images that fit in `capacity` and a SHA-256 hash.
+    pub fn validate(&self, capacity: &Capacity) -> Result<(), InitError> {
+        if self.hw_version.is_some_and(|v| v != HW_VERSION) {
+            return Err(InitError::HwVersion);
+        }
+        let fits = |size: u32, capacity: u32| size != 0 && size <= capacity;
+        let sizes = match self.fw_type {
+            FirmwareType::Application => fits(self.app_size, capacity.application),
+            FirmwareType::Softdevice => fits(self.sd_size, capacity.softdevice),
+            FirmwareType::Bootloader => fits(self.bl_size, capacity.bootloader),
+            FirmwareType::SoftdeviceBootloader => {
+                fits(self.sd_size, capacity.softdevice) && fits(self.bl_size, capacity.bootloader)
+            }
+            FirmwareType::ExternalApplication if capacity.assets == 0 => return Err(InitError::UnsupportedType),
+            FirmwareType::ExternalApplication => fits(self.app_size, capacity.assets),
+        };
+        if !sizes {
+            return Err(InitError::Size);
+        }
+        if self.hash_type != HashType::Sha256 || self.hash.len() != 32 {
+            return Err(InitError::Hash);
+        }
+        Ok(())
+    }
+
+    /// Check an application update is not older than the `running` version, unless it is a debug package
+    /// (`nrfutil pkg generate --debug-mode`). SoftDevices and bootloaders are not versioned like the application.
+    pub fn check_version(&self, running: u32) -> Result<(), InitError> {
+        if self.fw_type == FirmwareType::Application && !self.is_debug && self.fw_version < running {
+            return Err(InitError::FwVersion);
+        }
+        Ok(())
+    }
+
+    /// Check the signature of the init packet `data` this was decoded from, an ECDSA P-256 signature of the command
+    /// with `key`, the public key's X and Y.
+    pub fn verify(data: &[u8], key: &[u8; 64]) -> Result<(), InitError> {
+        let mut signed = None;
+        for field in Fields(data) {
+            if let (1, Value::Bytes(s)) = field? {
+                signed = Some(signed_command(s)?);
+            }
+        }
+        let (command, signature) = signed.ok_or(InitError::SignatureMissing)?;
+        let mut point = [0x04; 65];
+        point[1..].copy_from_slice(key);
+        let key = VerifyingKey::from_sec1_bytes(&point).map_err(|_| InitError::Signature)?;
+        // nrfutil sends r and s little-endian.
+        let mut be = [0; 64];
+        for (half, out) in signature.chunks_exact(32).zip(be.chunks_exact_mut(32)) {
+            out.iter_mut().zip(half.iter().rev()).for_each(|(o, b)| *o = *b);
+        }
+        let signature = Signature::from_slice(&be).map_err(|_| InitError::Signature)?;
+        key.verify(command, &signature).map_err(|_| InitError::Signature)
+    }
+
+    /// Whether `digest`, a SHA-256 computed over the image, matches the hash of the init packet.
+    pub fn matches(&self, digest: &[u8; 32]) -> bool {
+        self.hash.len() == 32 && self.hash.iter().eq(digest.iter().rev())
+    }
+}
+
+/// The command and its signature from a `SignedCommand`, which must be an ECDSA P-256 signature.
+fn signed_command(data: &[u8]) -> Result<(&[u8], &[u8]), InitError> {
+    let (mut command, mut signature) = (None, None);
+    for field in Fields(data) {
+        match field? {
+            (1, Value::Bytes(c)) => command = Some(c),
+            // signature_type, ECDSA_P256_SHA256 or ED25519.
+            (2, Value::Varint(t)) if t != 0 => return Err(InitError::SignatureType),
+            (3, Value::Bytes(s)) if s.len() == 64 => signature = Some(s),
+            (3, Value::Bytes(_)) => return Err(InitError::SignatureType),
+            _ => {}
+        }
+    }
+    Ok((
+        command.ok_or(InitError::Malformed)?,
+        signature.ok_or(InitError::SignatureMissing)?,
+    ))
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// Fixed size fields, which `dfu-cc.proto` does not use.
+    Fixed,
+}
+
+/// Protobuf fields of a message, as field number and value.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> Result<u64, InitError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&b, rest) = self.0.split_first().ok_or(InitError::Malformed)?;
+            self.0 = rest;
+            value |= ((b & 0x7F) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(InitError::Malformed)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], InitError> {
+        if len > self.0.len() {
+            return Err(InitError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u32, Value<'a>), InitError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let field = (|| {
+            let key = self.varint()?;
+            let value = match key & 0x7 {
+                0 => Value::Varint(self.varint()?),
+                1 => self.take(8).map(|_| Value::Fixed)?,
+                2 => {
+                    let len = self.varint()?;
+                    Value::Bytes(self.take(len.try_into().map_err(|_| InitError::Malformed)?)?)
+                }
+                5 => self.take(4).map(|_| Value::Fixed)?,
+                _ => return Err(InitError::Malformed),
+            };
+            Ok(((key >> 3) as u32, value))
+        })();
+        if field.is_err() {
+            // Stop after an error, the rest can not be parsed.
+            self.0 = &[];
+        }
+        Some(field)
+    }
+}
@@ -0,0 +1,25 @@
+//! Nordic Secure DFU target, taking firmware updates from nRF Connect or nrfutil into a `NorFlash` partition.
+//!
+//! `DfuSession` decodes the requests a transport received and answers them through `DfuTransport`, and `DfuTarget`
+//! checks the init packet and writes the image. The transport, and installing the update once received, are left to
+//! the firmware: over BLE the requests are writes to the control point and packet characteristics of the DFU
+//! service, over a serial port they come in SLIP frames, see `slip`.
+//!
+//! `DfuClient` is the other end, sending an update package to a device.
+//!
+//! Logs go to `defmt` or `log`, with the feature of the same name.
+#![cfg_attr(not(test), no_std)]
+
+mod fmt;
+
+mod client;
+pub mod crc;
+pub mod initpacket;
+mod session;
+pub mod sha256;
+pub mod slip;
+mod target;
+
+pub use client::*;
+pub use session::*;
+pub use target::*;
@@ -0,0 +1,98 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::target::{DfuRequest, DfuResponse, DfuResult, DfuStatus, DfuTarget};
+
+/// A write from the host, as the transport received it.
+pub enum DfuEvent<'a> {
+    ControlWrite(&'a [u8]),
+    ControlNotifications(bool),
+    PacketWrite(&'a [u8]),
+    /// Responses to packet writes go to the control point, so packet notifications are not used.
+    PacketNotifications,
+}
+
+/// The link requests come in over, such as the DFU service of a BLE connection or a serial port.
+pub trait DfuTransport {
+    type Error;
+
+    /// Send a response to the host, as a control point notification over BLE.
+    fn notify(&self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Largest request the link carries, answered to MtuGet. Over BLE this is the ATT MTU of the connection, which
+    /// may change at any time.
+    fn mtu(&self) -> u16;
+}
+
+/// The DFU state of a connection. Requests are decoded and passed to the target, and the responses sent back
+/// through a `DfuTransport`, so the session does not depend on the BLE stack.
+#[derive(Default)]
+pub struct DfuSession {
+    notify_control: bool,
+}
+
+impl DfuSession {
+    /// Handle a write from the host, returning the target's status after a request.
+    pub fn handle<DFU: NorFlash, T: DfuTransport>(
+        &mut self,
+        target: &mut DfuTarget,
+        dfu: &mut DFU,
+        transport: &T,
+        event: DfuEvent<'_>,
+    ) -> Option<DfuStatus> {
+        match event {
+            DfuEvent::ControlWrite(data) => match DfuRequest::decode(data) {
+                Ok(request) => return Some(self.process(target, dfu, transport, request)),
+                // Answered as the host waits for a response to each request, unless there is not even an opcode.
+                Err(result) => match data.first() {
+                    Some(&opcode) => {
+                        warn!("Malformed DFU request {}: {:?}", opcode, result);
+                        self.respond(transport, &DfuResponse::new(opcode, result));
+                    }
+                    None => warn!("Dropping empty DFU request"),
+                },
+            },
+            DfuEvent::PacketWrite(data) => {
+                return Some(self.process(target, dfu, transport, DfuRequest::Write { data }));
+            }
+            DfuEvent::ControlNotifications(enabled) => self.notify_control = enabled,
+            DfuEvent::PacketNotifications => {}
+        }
+        None
+    }
+
+    /// Answer control requests with "operation not permitted", for when updates are not allowed, such as on a low
+    /// battery or while another host owns the update.
+    pub fn refuse<T: DfuTransport>(&mut self, transport: &T, event: DfuEvent<'_>) {
+        match event {
+            DfuEvent::ControlWrite(data) => {
+                let Some(&opcode) = data.first() else {
+                    return;
+                };
+                self.respond(transport, &DfuResponse::new(opcode, DfuResult::OpNotPermitted));
+            }
+            DfuEvent::ControlNotifications(enabled) => self.notify_control = enabled,
+            DfuEvent::PacketWrite(_) | DfuEvent::PacketNotifications => {}
+        }
+    }
+
+    fn process<DFU: NorFlash, T: DfuTransport>(
+        &mut self,
+        target: &mut DfuTarget,
+        dfu: &mut DFU,
+        transport: &T,
+        request: DfuRequest<'_>,
+    ) -> DfuStatus {
+        target.set_mtu(transport.mtu());
+        let (response, status) = target.process(request, dfu);
+        if let Some(response) = response {
+            self.respond(transport, &response);
+        }
+        status
+    }
+
+    fn respond<T: DfuTransport>(&self, transport: &T, response: &DfuResponse) {
+        if self.notify_control && transport.notify(&response.encode()).is_err() {
+            warn!("Error sending a DFU response");
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! SLIP framing (RFC 1055) of the Nordic serial DFU transport. Requests and responses are the same as over BLE,
+//! each in a frame, and data is sent as Write requests on the same channel instead of a packet characteristic.
+use heapless::Vec;
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// Reassembles SLIP frames from the bytes received.
+#[derive(Default)]
+pub struct SlipDecoder<const N: usize> {
+    frame: Vec<u8, N>,
+    escaped: bool,
+    /// The frame did not fit and is dropped at its END byte.
+    overflow: bool,
+    /// `frame` was returned and is cleared by the next byte.
+    complete: bool,
+}
+
+impl<const N: usize> SlipDecoder<N> {
+    /// Take the next byte received, returning the frame it ends, if any. Empty frames, which nrfutil sends to
+    /// flush the line, and frames longer than `N` bytes are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        if self.complete {
+            self.frame.clear();
+            self.complete = false;
+        }
+        let byte = match (byte, self.escaped) {
+            (END, _) => {
+                self.escaped = false;
+                if core::mem::take(&mut self.overflow) || self.frame.is_empty() {
+                    self.frame.clear();
+                    return None;
+                }
+                self.complete = true;
+                return Some(&self.frame);
+            }
+            (ESC, false) => {
+                self.escaped = true;
+                return None;
+            }
+            (ESC_END, true) => END,
+            (ESC_ESC, true) => ESC,
+            (byte, _) => byte,
+        };
+        self.escaped = false;
+        if self.frame.push(byte).is_err() {
+            self.overflow = true;
+        }
+        None
+    }
+}
+
+/// Encode `data` as a SLIP frame, if it fits in `N` bytes.
+pub fn slip_encode<const N: usize>(data: &[u8]) -> Option<Vec<u8, N>> {
+    let mut frame = Vec::new();
+    for &byte in data {
+        match byte {
+            END => frame.extend_from_slice(&[ESC, ESC_END]).ok()?,
+            ESC => frame.extend_from_slice(&[ESC, ESC_ESC]).ok()?,
+            byte => frame.push(byte).ok()?,
+        }
+    }
+    frame.push(END).ok()?;
+    Some(frame)
+}
@@ -0,0 +1,1114 @@
+//! Nordic Secure DFU target: the control point requests and responses, and the command and data objects written to
+//! the DFU partition.
+//!
+//! The host sends the init packet as the command object, then the image as data objects of at most
+//! `DATA_OBJECT_SIZE`, each created, written, checked with Crc and executed. Offsets and CRCs of data objects cover
+//! the whole image received so far, so the host can pick up where an executed object left off, also in a later
+//! connection through `DfuProgress`.
+//!
+//! Applications are written to the DFU partition, for the bootloader to swap in. SoftDevices and bootloaders can not
+//! be swapped back out, so they are written to a staging region instead and installed once received whole, see
+//! `DfuStatus::DoneStaged`.
+//!
+//! Companion apps can push resources such as fonts and icons with the same tooling, packaged as an external
+//! application. These are written to an asset partition, see `assets`, and handed over once received whole, see
+//! `DfuStatus::DoneAssets`.
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Vec;
+
+use crate::crc::{crc32, crc32_update};
+use crate::initpacket::{self, Capacity, InitError, InitPacket};
+use crate::sha256::Sha256;
+
+/// Version of the DFU protocol, answered to ProtocolVersion.
+const PROTOCOL_VERSION: u8 = 1;
+/// Largest data object, an erase sector of common NOR flashes.
+pub const DATA_OBJECT_SIZE: u32 = 4096;
+/// Data is written to the DFU partition a page of a NOR flash at a time, so that writes are aligned whatever size
+/// the packets are.
+const WRITE_BUFFER_SIZE: usize = 256;
+/// Opcode of control point responses.
+const RESPONSE: u8 = 0x60;
+/// ATT MTU of a connection until a larger one is negotiated.
+const DEFAULT_MTU: u16 = 23;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ObjectType {
+    Command = 0x01,
+    Data = 0x02,
+}
+
+impl ObjectType {
+    fn from_u8(value: u8) -> Result<Self, DfuResult> {
+        match value {
+            0x01 => Ok(Self::Command),
+            0x02 => Ok(Self::Data),
+            _ => Err(DfuResult::UnsupportedType),
+        }
+    }
+}
+
+/// Result codes of control point responses.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DfuResult {
+    Success = 0x01,
+    OpNotSupported = 0x02,
+    InvalidParameter = 0x03,
+    InsufficientResources = 0x04,
+    InvalidObject = 0x05,
+    UnsupportedType = 0x07,
+    OpNotPermitted = 0x08,
+    OpFailed = 0x0A,
+    /// The response carries an `ExtError`.
+    ExtError = 0x0B,
+}
+
+impl DfuResult {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x01 => Self::Success,
+            0x02 => Self::OpNotSupported,
+            0x03 => Self::InvalidParameter,
+            0x04 => Self::InsufficientResources,
+            0x05 => Self::InvalidObject,
+            0x07 => Self::UnsupportedType,
+            0x08 => Self::OpNotPermitted,
+            0x0A => Self::OpFailed,
+            0x0B => Self::ExtError,
+            _ => return None,
+        })
+    }
+}
+
+/// Extended error codes, following the `ExtError` result, which nrfutil prints.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ExtError {
+    /// The command object could not be decoded.
+    WrongCommandFormat = 0x02,
+    /// The init command is valid but not for this device, such as for an unsupported image type.
+    InitCommandInvalid = 0x04,
+    /// The firmware version is older than the running one.
+    FwVersionFailure = 0x05,
+    HwVersionFailure = 0x06,
+    SdVersionFailure = 0x07,
+    /// The init packet is not signed, but the firmware requires signed updates.
+    SignatureMissing = 0x08,
+    /// The hash type is not SHA-256.
+    WrongHashType = 0x09,
+    /// The image does not match the hash of the init packet.
+    HashFailed = 0x0A,
+    WrongSignatureType = 0x0B,
+    /// The signature does not match the key of the firmware.
+    VerificationFailed = 0x0C,
+    /// The images do not fit in the device.
+    InsufficientSpace = 0x0D,
+}
+
+impl ExtError {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x02 => Self::WrongCommandFormat,
+            0x04 => Self::InitCommandInvalid,
+            0x05 => Self::FwVersionFailure,
+            0x06 => Self::HwVersionFailure,
+            0x07 => Self::SdVersionFailure,
+            0x08 => Self::SignatureMissing,
+            0x09 => Self::WrongHashType,
+            0x0A => Self::HashFailed,
+            0x0B => Self::WrongSignatureType,
+            0x0C => Self::VerificationFailed,
+            0x0D => Self::InsufficientSpace,
+            _ => return None,
+        })
+    }
+}
+
+impl From<InitError> for ExtError {
+    fn from(e: InitError) -> Self {
+        match e {
+            InitError::Malformed => Self::WrongCommandFormat,
+            InitError::UnsupportedType => Self::InitCommandInvalid,
+            InitError::HwVersion => Self::HwVersionFailure,
+            InitError::FwVersion => Self::FwVersionFailure,
+            InitError::Size => Self::InsufficientSpace,
+            InitError::Hash => Self::WrongHashType,
+            InitError::SignatureMissing => Self::SignatureMissing,
+            InitError::SignatureType => Self::WrongSignatureType,
+            InitError::Signature => Self::VerificationFailed,
+        }
+    }
+}
+
+/// Why a request failed, answered with a result code or an extended error.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum DfuError {
+    Result(DfuResult),
+    Ext(ExtError),
+}
+
+impl From<DfuResult> for DfuError {
+    fn from(result: DfuResult) -> Self {
+        Self::Result(result)
+    }
+}
+
+impl From<ExtError> for DfuError {
+    fn from(e: ExtError) -> Self {
+        Self::Ext(e)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DfuRequest<'a> {
+    ProtocolVersion,
+    Create {
+        obj_type: ObjectType,
+        obj_size: u32,
+    },
+    SetReceiptNotification {
+        target: u16,
+    },
+    Crc,
+    Execute,
+    Select {
+        obj_type: ObjectType,
+    },
+    MtuGet,
+    Write {
+        data: &'a [u8],
+    },
+    Ping {
+        id: u8,
+    },
+    HwVersion,
+    FwVersion {
+        image_id: u8,
+    },
+    Abort,
+    /// Vendor extension, not part of Nordic's protocol: erase the user data of the device, see
+    /// `DfuTarget::set_allow_factory_reset`.
+    FactoryReset,
+}
+
+impl<'a> DfuRequest<'a> {
+    const CREATE: u8 = 0x01;
+    const CRC: u8 = 0x03;
+    const WRITE: u8 = 0x08;
+    /// First of the opcodes Nordic leaves free, for the vendor extensions.
+    const FACTORY_RESET: u8 = 0xF0;
+
+    /// Decode a control point write, returning the result to answer it with if it is not a known request. Never
+    /// panics, whatever `data` holds. Bytes past the parameters of a request are ignored.
+    pub fn decode(data: &'a [u8]) -> Result<Self, DfuResult> {
+        let Some((&opcode, rest)) = data.split_first() else {
+            return Err(DfuResult::InvalidParameter);
+        };
+        Ok(match (opcode, rest) {
+            (0x00, _) => Self::ProtocolVersion,
+            (Self::CREATE, [t, a, b, c, d, ..]) => Self::Create {
+                obj_type: ObjectType::from_u8(*t)?,
+                obj_size: u32::from_le_bytes([*a, *b, *c, *d]),
+            },
+            (0x02, [a, b, ..]) => Self::SetReceiptNotification {
+                target: u16::from_le_bytes([*a, *b]),
+            },
+            (Self::CRC, _) => Self::Crc,
+            (0x04, _) => Self::Execute,
+            (0x06, [t, ..]) => Self::Select {
+                obj_type: ObjectType::from_u8(*t)?,
+            },
+            (0x07, _) => Self::MtuGet,
+            (Self::WRITE, data) => Self::Write { data },
+            (0x09, [id, ..]) => Self::Ping { id: *id },
+            (0x0A, _) => Self::HwVersion,
+            (0x0B, [id, ..]) => Self::FwVersion { image_id: *id },
+            (0x0C, _) => Self::Abort,
+            (Self::FACTORY_RESET, _) => Self::FactoryReset,
+            // A known request missing its parameters.
+            (Self::CREATE | 0x02 | 0x06 | 0x09 | 0x0B, _) => return Err(DfuResult::InvalidParameter),
+            _ => return Err(DfuResult::OpNotSupported),
+        })
+    }
+
+    /// Encode the request as a control point write, if it fits in `N` bytes. Over BLE the data of a Write is sent
+    /// on the packet characteristic instead, without the opcode.
+    pub fn encode<const N: usize>(&self) -> Option<Vec<u8, N>> {
+        let mut buf = Vec::new();
+        buf.push(self.opcode()).ok()?;
+        match *self {
+            Self::Create { obj_type, obj_size } => {
+                buf.push(obj_type as u8).ok()?;
+                buf.extend_from_slice(&obj_size.to_le_bytes()).ok()?;
+            }
+            Self::SetReceiptNotification { target } => buf.extend_from_slice(&target.to_le_bytes()).ok()?,
+            Self::Select { obj_type } => buf.push(obj_type as u8).ok()?,
+            Self::Write { data } => buf.extend_from_slice(data).ok()?,
+            Self::Ping { id } => buf.push(id).ok()?,
+            Self::FwVersion { image_id } => buf.push(image_id).ok()?,
+            Self::ProtocolVersion
+            | Self::Crc
+            | Self::Execute
+            | Self::MtuGet
+            | Self::HwVersion
+            | Self::Abort
+            | Self::FactoryReset => {}
+        }
+        Some(buf)
+    }
+
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Self::ProtocolVersion => 0x00,
+            Self::Create { .. } => Self::CREATE,
+            Self::SetReceiptNotification { .. } => 0x02,
+            Self::Crc => Self::CRC,
+            Self::Execute => 0x04,
+            Self::Select { .. } => 0x06,
+            Self::MtuGet => 0x07,
+            Self::Write { .. } => Self::WRITE,
+            Self::Ping { .. } => 0x09,
+            Self::HwVersion => 0x0A,
+            Self::FwVersion { .. } => 0x0B,
+            Self::Abort => 0x0C,
+            Self::FactoryReset => Self::FACTORY_RESET,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HardwareInfo {
+    pub part: u32,
+    pub variant: u32,
+    pub rom_size: u32,
+    pub ram_size: u32,
+    pub rom_page_size: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FirmwareType {
+    Softdevice = 0x00,
+    Application = 0x01,
+    Bootloader = 0x02,
+    /// Answered for image ids past the bootloader.
+    Unknown = 0xFF,
+}
+
+impl FirmwareType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x00 => Self::Softdevice,
+            0x01 => Self::Application,
+            0x02 => Self::Bootloader,
+            0xFF => Self::Unknown,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareInfo {
+    pub ftype: FirmwareType,
+    pub version: u32,
+    pub addr: u32,
+    pub len: u32,
+}
+
+/// Data of a successful response.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResponseBody {
+    None,
+    ProtocolVersion(u8),
+    Crc {
+        offset: u32,
+        crc: u32,
+    },
+    Select {
+        max_size: u32,
+        offset: u32,
+        crc: u32,
+    },
+    Mtu(u16),
+    Ping(u8),
+    HwVersion(HardwareInfo),
+    FwVersion(FirmwareInfo),
+    /// Follows the `ExtError` result of a failed request.
+    ExtError(ExtError),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DfuResponse {
+    opcode: u8,
+    result: DfuResult,
+    body: ResponseBody,
+}
+
+impl DfuResponse {
+    pub fn new(opcode: u8, result: DfuResult) -> Self {
+        Self {
+            opcode,
+            result,
+            body: ResponseBody::None,
+        }
+    }
+
+    /// A successful response to `opcode`, carrying `body`.
+    pub fn success(opcode: u8, body: ResponseBody) -> Self {
+        Self {
+            opcode,
+            result: DfuResult::Success,
+            body,
+        }
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    pub fn result(&self) -> DfuResult {
+        self.result
+    }
+
+    pub fn body(&self) -> ResponseBody {
+        self.body
+    }
+
+    /// A response to `opcode` failing with the extended error `e`.
+    pub fn ext_error(opcode: u8, e: ExtError) -> Self {
+        Self {
+            opcode,
+            result: DfuResult::ExtError,
+            body: ResponseBody::ExtError(e),
+        }
+    }
+
+    fn error(opcode: u8, error: DfuError) -> Self {
+        match error {
+            DfuError::Result(result) => Self::new(opcode, result),
+            DfuError::Ext(e) => Self::ext_error(opcode, e),
+        }
+    }
+
+    /// Encode the response as a control point notification. Every response fits, the largest, to HwVersion, is 23
+    /// bytes.
+    pub fn encode(&self) -> Vec<u8, 32> {
+        let mut buf = Vec::new();
+        let _ = buf.extend_from_slice(&[RESPONSE, self.opcode, self.result as u8]);
+        let words = |buf: &mut Vec<u8, 32>, words: &[u32]| {
+            for word in words {
+                let _ = buf.extend_from_slice(&word.to_le_bytes());
+            }
+        };
+        match self.body {
+            ResponseBody::None => {}
+            ResponseBody::ProtocolVersion(version) => {
+                let _ = buf.push(version);
+            }
+            ResponseBody::Crc { offset, crc } => words(&mut buf, &[offset, crc]),
+            ResponseBody::Select { max_size, offset, crc } => words(&mut buf, &[max_size, offset, crc]),
+            ResponseBody::Mtu(mtu) => {
+                let _ = buf.extend_from_slice(&mtu.to_le_bytes());
+            }
+            ResponseBody::Ping(id) => {
+                let _ = buf.push(id);
+            }
+            ResponseBody::HwVersion(hw) => words(
+                &mut buf,
+                &[hw.part, hw.variant, hw.rom_size, hw.ram_size, hw.rom_page_size],
+            ),
+            ResponseBody::FwVersion(fw) => {
+                let _ = buf.push(fw.ftype as u8);
+                words(&mut buf, &[fw.version, fw.addr, fw.len]);
+            }
+            ResponseBody::ExtError(e) => {
+                let _ = buf.push(e as u8);
+            }
+        }
+        buf
+    }
+
+    /// Decode a control point notification, as a host receives it. The body is read according to the opcode, and
+    /// bytes past it are ignored. Never panics, whatever `data` holds.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let (&[RESPONSE, opcode, result], rest) = (data.get(..3)?, data.get(3..)?) else {
+            return None;
+        };
+        let result = DfuResult::from_u8(result)?;
+        let body = match (result, opcode) {
+            (DfuResult::ExtError, _) => ResponseBody::ExtError(ExtError::from_u8(*rest.first()?)?),
+            (DfuResult::Success, 0x00) => ResponseBody::ProtocolVersion(*rest.first()?),
+            (DfuResult::Success, DfuRequest::CRC) => {
+                let [offset, crc] = words(rest)?;
+                ResponseBody::Crc { offset, crc }
+            }
+            (DfuResult::Success, 0x06) => {
+                let [max_size, offset, crc] = words(rest)?;
+                ResponseBody::Select { max_size, offset, crc }
+            }
+            (DfuResult::Success, 0x07) => ResponseBody::Mtu(u16::from_le_bytes([*rest.first()?, *rest.get(1)?])),
+            (DfuResult::Success, 0x09) => ResponseBody::Ping(*rest.first()?),
+            (DfuResult::Success, 0x0A) => {
+                let [part, variant, rom_size, ram_size, rom_page_size] = words(rest)?;
+                ResponseBody::HwVersion(HardwareInfo {
+                    part,
+                    variant,
+                    rom_size,
+                    ram_size,
+                    rom_page_size,
+                })
+            }
+            (DfuResult::Success, 0x0B) => {
+                let (&ftype, rest) = rest.split_first()?;
+                let [version, addr, len] = words(rest)?;
+                ResponseBody::FwVersion(FirmwareInfo {
+                    ftype: FirmwareType::from_u8(ftype)?,
+                    version,
+                    addr,
+                    len,
+                })
+            }
+            _ => ResponseBody::None,
+        };
+        Some(Self { opcode, result, body })
+    }
+}
+
+/// The first `N` little-endian words of `data`, if it is long enough.
+fn words<const N: usize>(data: &[u8]) -> Option<[u32; N]> {
+    let mut words = [0; N];
+    for (i, word) in words.iter_mut().enumerate() {
+        let bytes = data.get(4 * i..4 * i + 4)?;
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    Some(words)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DfuStatus {
+    Idle,
+    /// The application was received and checked, and can be swapped in.
+    DoneReset,
+    /// A SoftDevice and bootloader of these sizes, either possibly empty, were received to the staging region in that
+    /// order and checked, and can be installed.
+    DoneStaged {
+        softdevice: u32,
+        bootloader: u32,
+    },
+    /// Assets of this size were received to the asset partition and checked.
+    DoneAssets {
+        size: u32,
+    },
+    /// A factory reset was requested, the user data is to be erased.
+    FactoryReset,
+    /// The update under way was aborted and what it wrote erased, its progress is no longer to be resumed.
+    Aborted,
+}
+
+impl DfuStatus {
+    pub fn is_done(&self) -> bool {
+        !matches!(self, Self::Idle | Self::Aborted)
+    }
+}
+
+/// Stage of an update, for the host to show rather than work out from the responses.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DfuPhase {
+    Idle = 0,
+    /// The init packet is being received.
+    Init = 1,
+    /// The init packet was accepted and the image is being received.
+    Image = 2,
+    /// The whole image was received and checked.
+    Done = 3,
+}
+
+/// Progress of an update up to its last executed data object, kept so that it can resume after a disconnect or a
+/// reset.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DfuProgress {
+    /// CRC of the command object, to only resume the same update.
+    pub command_crc: u32,
+    pub offset: u32,
+    pub crc: u32,
+}
+
+impl DfuProgress {
+    pub const SIZE: usize = 12;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut data = [0; Self::SIZE];
+        data[0..4].copy_from_slice(&self.command_crc.to_le_bytes());
+        data[4..8].copy_from_slice(&self.offset.to_le_bytes());
+        data[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data: &[u8; Self::SIZE] = data.try_into().ok()?;
+        let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Some(Self {
+            command_crc: word(0),
+            offset: word(4),
+            crc: word(8),
+        })
+    }
+}
+
+/// The objects of an update, written to the DFU partition, the staging region or the asset partition passed to
+/// `process`, as `staged` and `assets` tell.
+pub struct DfuTarget {
+    capacity: Capacity,
+    /// Public key updates must be signed with, if any.
+    key: Option<[u8; 64]>,
+    /// Whether updates older than the running application are accepted.
+    allow_downgrade: bool,
+    /// Whether FactoryReset is carried out.
+    allow_factory_reset: bool,
+    /// MTU of the transport, answered to MtuGet.
+    mtu: u16,
+    /// The SoftDevice, application and bootloader, by image id, which is their `FirmwareType`.
+    images: [FirmwareInfo; 3],
+    hw_info: HardwareInfo,
+    /// Object type of the last Create or Select, which writes go to.
+    current: ObjectType,
+    /// The command object received so far, and the size it was created with.
+    command: Vec<u8, { initpacket::MAX_SIZE }>,
+    command_size: u32,
+    /// The init packet of the update, once its command object was executed.
+    init: Option<InitPacket>,
+    /// Whether the image of the last init packet was received whole.
+    done: bool,
+    /// Image received so far and its CRC, and up to the last executed data object.
+    offset: u32,
+    crc: u32,
+    executed: u32,
+    executed_crc: u32,
+    /// End of the data object being written.
+    object_end: u32,
+    /// Data received and not yet written, up to `offset`.
+    buffer: Vec<u8, WRITE_BUFFER_SIZE>,
+    /// Progress of an earlier connection, taken up if the same command object is executed.
+    resume: Option<DfuProgress>,
+    /// Packet receipt notification interval, and writes since the last one.
+    prn: u16,
+    writes: u16,
+}
+
+impl DfuTarget {
+    /// A target taking images of up to `capacity`, reporting the running `images` and the device's `hw_info`. Any
+    /// update with a valid init packet is accepted until a key is set with `set_key`.
+    pub fn new(capacity: Capacity, images: [FirmwareInfo; 3], hw_info: HardwareInfo) -> Self {
+        Self {
+            capacity,
+            key: None,
+            allow_downgrade: false,
+            allow_factory_reset: false,
+            mtu: DEFAULT_MTU,
+            images,
+            hw_info,
+            current: ObjectType::Command,
+            command: Vec::new(),
+            command_size: 0,
+            init: None,
+            done: false,
+            offset: 0,
+            crc: 0,
+            executed: 0,
+            executed_crc: 0,
+            object_end: 0,
+            buffer: Vec::new(),
+            resume: None,
+            prn: 0,
+            writes: 0,
+        }
+    }
+
+    /// Only accept updates signed with `key`, the X and Y of an ECDSA P-256 public key, see `InitPacket::verify`.
+    pub fn set_key(&mut self, key: [u8; 64]) {
+        self.key = Some(key);
+    }
+
+    /// Accept updates older than the running application, which are otherwise refused unless they are debug packages.
+    pub fn set_allow_downgrade(&mut self, allow: bool) {
+        self.allow_downgrade = allow;
+    }
+
+    /// Carry out FactoryReset requests, which are otherwise refused. Erasing the device is only safe to allow from a
+    /// trusted host, such as over an encrypted link, and the caller does the erasing, see `DfuStatus::FactoryReset`.
+    pub fn set_allow_factory_reset(&mut self, allow: bool) {
+        self.allow_factory_reset = allow;
+    }
+
+    /// Set the MTU of the transport, see `DfuTransport::mtu`.
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.mtu = mtu;
+    }
+
+    /// Take up an update of an earlier connection once its command object is sent again.
+    pub fn resume(&mut self, progress: DfuProgress) {
+        self.resume = Some(progress);
+    }
+
+    /// Take up the command object of an earlier connection, so that the host can select it rather than send it
+    /// again, and execute it to resume the update, see `resume`.
+    pub fn restore_command(&mut self, command: &[u8]) {
+        if let Ok(command) = Vec::from_slice(command) {
+            self.command = command;
+            self.command_size = self.command.len() as u32;
+        }
+    }
+
+    /// The executed command object of the update under way, to be kept for `restore_command`.
+    pub fn command(&self) -> Option<&[u8]> {
+        self.init.as_ref().map(|_| &self.command[..])
+    }
+
+    /// Progress of the update up to the last executed data object, if one is under way.
+    pub fn progress(&self) -> Option<DfuProgress> {
+        self.init.as_ref().map(|_| DfuProgress {
+            command_crc: crc32(&self.command),
+            offset: self.executed,
+            crc: self.executed_crc,
+        })
+    }
+
+    /// Bytes of the image executed so far and the size of the image, once the init packet is executed.
+    pub fn transferred(&self) -> Option<(u32, u32)> {
+        self.init.as_ref().map(|init| (self.executed, init.image_size()))
+    }
+
+    /// Stage of the update under way, or of the last one if it is done.
+    pub fn phase(&self) -> DfuPhase {
+        if self.done {
+            DfuPhase::Done
+        } else if self.init.is_some() {
+            DfuPhase::Image
+        } else if self.command_size > 0 {
+            DfuPhase::Init
+        } else {
+            DfuPhase::Idle
+        }
+    }
+
+    /// Whether the update under way goes to the staging region rather than the DFU partition.
+    pub fn staged(&self) -> bool {
+        self.init.as_ref().is_some_and(|init| {
+            !matches!(
+                init.fw_type,
+                initpacket::FirmwareType::Application | initpacket::FirmwareType::ExternalApplication
+            )
+        })
+    }
+
+    /// Whether the update under way is assets, which go to the asset partition rather than the DFU partition.
+    pub fn assets(&self) -> bool {
+        self.init
+            .as_ref()
+            .is_some_and(|init| init.fw_type == initpacket::FirmwareType::ExternalApplication)
+    }
+
+    /// Carry out a request, returning the response to send, if any, and whether the update is done.
+    pub fn process<DFU: NorFlash>(
+        &mut self,
+        request: DfuRequest<'_>,
+        dfu: &mut DFU,
+    ) -> (Option<DfuResponse>, DfuStatus) {
+        let opcode = request.opcode();
+        let mut status = DfuStatus::Idle;
+        let result = match request {
+            DfuRequest::ProtocolVersion => Ok(ResponseBody::ProtocolVersion(PROTOCOL_VERSION)),
+            DfuRequest::Create { obj_type, obj_size } => self.create(obj_type, obj_size, dfu).map_err(DfuError::from),
+            DfuRequest::SetReceiptNotification { target } => {
+                self.prn = target;
+                self.writes = 0;
+                Ok(ResponseBody::None)
+            }
+            DfuRequest::Crc => Ok(self.crc_body()),
+            DfuRequest::Execute => self.execute(dfu).map(|done| {
+                status = done;
+                ResponseBody::None
+            }),
+            DfuRequest::Select { obj_type } => {
+                self.current = obj_type;
+                let (offset, crc) = self.position();
+                let max_size = match obj_type {
+                    ObjectType::Command => initpacket::MAX_SIZE as u32,
+                    ObjectType::Data => DATA_OBJECT_SIZE,
+                };
+                Ok(ResponseBody::Select { max_size, offset, crc })
+            }
+            DfuRequest::MtuGet => Ok(ResponseBody::Mtu(self.mtu)),
+            DfuRequest::Write { data } => match self.write(data, dfu) {
+                Ok(true) => Ok(self.crc_body()),
+                // Writes are only answered with receipt notifications and errors.
+                Ok(false) => return (None, status),
+                Err(result) => Err(result.into()),
+            },
+            DfuRequest::Ping { id } => Ok(ResponseBody::Ping(id)),
+            DfuRequest::HwVersion => Ok(ResponseBody::HwVersion(self.hw_info)),
+            DfuRequest::FwVersion { image_id } => Ok(ResponseBody::FwVersion(
+                self.images.get(image_id as usize).copied().unwrap_or(FirmwareInfo {
+                    ftype: FirmwareType::Unknown,
+                    version: 0,
+                    addr: 0,
+                    len: 0,
+                }),
+            )),
+            DfuRequest::Abort => {
+                info!("Firmware update aborted");
+                let erased = self.erase_written(dfu);
+                self.clear();
+                self.resume = None;
+                status = DfuStatus::Aborted;
+                erased.map(|_| ResponseBody::None).map_err(DfuError::from)
+            }
+            DfuRequest::FactoryReset if self.allow_factory_reset => {
+                info!("Factory reset requested");
+                self.clear();
+                status = DfuStatus::FactoryReset;
+                Ok(ResponseBody::None)
+            }
+            DfuRequest::FactoryReset => {
+                warn!("Factory reset refused");
+                Err(DfuResult::OpNotPermitted.into())
+            }
+        };
+        let response = match result {
+            // Receipt notifications answer as a Crc request.
+            Ok(body @ ResponseBody::Crc { .. }) => DfuResponse::success(DfuRequest::CRC, body),
+            Ok(body) => DfuResponse::success(opcode, body),
+            Err(error) => DfuResponse::error(opcode, error),
+        };
+        (Some(response), status)
+    }
+
+    /// Drop the update under way.
+    fn clear(&mut self) {
+        self.init = None;
+        self.done = false;
+        self.command.clear();
+        self.command_size = 0;
+        self.offset = 0;
+        self.crc = 0;
+        self.executed = 0;
+        self.executed_crc = 0;
+        self.buffer.clear();
+    }
+
+    /// Erase the sectors of the partition the update under way wrote to, so that no part of the image is left to be
+    /// taken up by a later update.
+    fn erase_written<DFU: NorFlash>(&self, dfu: &mut DFU) -> Result<(), DfuResult> {
+        let erase = DFU::ERASE_SIZE as u32;
+        let end = (self.offset.div_ceil(erase) * erase).min(dfu.capacity() as u32);
+        if end > 0 && dfu.erase(0, end).is_err() {
+            warn!("Error erasing the aborted update");
+            return Err(DfuResult::OpFailed);
+        }
+        Ok(())
+    }
+
+    fn create<DFU: NorFlash>(
+        &mut self,
+        obj_type: ObjectType,
+        size: u32,
+        dfu: &mut DFU,
+    ) -> Result<ResponseBody, DfuResult> {
+        match obj_type {
+            ObjectType::Command => {
+                if size > initpacket::MAX_SIZE as u32 {
+                    warn!("Init packet of {} bytes too large", size);
+                    return Err(DfuResult::InsufficientResources);
+                }
+                self.command.clear();
+                self.command_size = size;
+                self.init = None;
+                self.done = false;
+            }
+            ObjectType::Data => {
+                let Some(init) = &self.init else {
+                    warn!("Data object before an init packet");
+                    return Err(DfuResult::OpNotPermitted);
+                };
+                // Objects past the image of the init packet would write over whatever follows the partition.
+                let image_size = init.image_size();
+                if size > DATA_OBJECT_SIZE || self.executed + size > image_size {
+                    warn!("Data object of {} bytes at {} does not fit", size, self.executed);
+                    return Err(DfuResult::InsufficientResources);
+                }
+                // The padding of the last write of an object can not be written over by the next one.
+                if self.executed + size < image_size && size as usize % DFU::WRITE_SIZE != 0 {
+                    warn!("Data object of {} bytes is not aligned", size);
+                    return Err(DfuResult::InvalidParameter);
+                }
+                // A sector the object starts within was erased with the previous object, unless an object that
+                // failed its CRC check or was cut off wrote to it since. Erasing it again would take the executed
+                // bytes before with it, so the image goes back to the start of the sector, and the host, told the
+                // object failed, selects and sends it from there.
+                let erase = DFU::ERASE_SIZE as u32;
+                let from = self.executed.div_ceil(erase) * erase;
+                if self.executed % erase != 0 {
+                    let start = self.executed - self.executed % erase;
+                    match is_erased(dfu, self.executed, from.min(dfu.capacity() as u32)) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            warn!(
+                                "Data object at {} starts in a written sector, going back to {}",
+                                self.executed, start
+                            );
+                            let Ok(crc) = read_crc(dfu, 0, start, 0) else {
+                                warn!("Error reading the image back");
+                                return Err(DfuResult::OpFailed);
+                            };
+                            (self.executed, self.executed_crc) = (start, crc);
+                            (self.offset, self.crc) = (start, crc);
+                            self.object_end = start;
+                            self.buffer.clear();
+                            return Err(DfuResult::OpFailed);
+                        }
+                        Err(_) => {
+                            warn!("Error reading the DFU partition");
+                            return Err(DfuResult::OpFailed);
+                        }
+                    }
+                }
+                // A data object created again after a failed CRC check replaces the one not executed.
+                self.offset = self.executed;
+                self.crc = self.executed_crc;
+                self.buffer.clear();
+                self.object_end = self.executed + size;
+                let to = self.object_end.div_ceil(erase) * erase;
+                if from < to && dfu.erase(from, to).is_err() {
+                    warn!("Error erasing the DFU partition");
+                    return Err(DfuResult::OpFailed);
+                }
+            }
+        }
+        self.current = obj_type;
+        self.writes = 0;
+        Ok(ResponseBody::None)
+    }
+
+    /// Write to the current object, returning whether a receipt notification is due.
+    fn write<DFU: NorFlash>(&mut self, data: &[u8], dfu: &mut DFU) -> Result<bool, DfuResult> {
+        match self.current {
+            ObjectType::Command => {
+                // Create refuses command objects larger than the buffer.
+                if self.command.len() + data.len() > self.command_size as usize
+                    || self.command.extend_from_slice(data).is_err()
+                {
+                    warn!("Write past the end of the command object");
+                    return Err(DfuResult::InvalidObject);
+                }
+            }
+            ObjectType::Data => {
+                if self.offset + data.len() as u32 > self.object_end {
+                    warn!("Write past the end of the data object");
+                    return Err(DfuResult::InvalidObject);
+                }
+                self.crc = crc32_update(self.crc, data);
+                let mut data = data;
+                while !data.is_empty() {
+                    let n = (WRITE_BUFFER_SIZE - self.buffer.len()).min(data.len());
+                    let _ = self.buffer.extend_from_slice(&data[..n]);
+                    self.offset += n as u32;
+                    data = &data[n..];
+                    if self.buffer.is_full() {
+                        self.flush(dfu)?;
+                    }
+                }
+            }
+        }
+        self.writes += 1;
+        if self.prn != 0 && self.writes >= self.prn {
+            self.writes = 0;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Execute the current object, returning whether the whole image was received and checked.
+    fn execute<DFU: NorFlash>(&mut self, dfu: &mut DFU) -> Result<DfuStatus, DfuError> {
+        match self.current {
+            ObjectType::Command => {
+                if self.command.len() as u32 != self.command_size {
+                    return Err(DfuResult::OpNotPermitted.into());
+                }
+                let init = InitPacket::decode(&self.command)
+                    .and_then(|init| init.validate(&self.capacity).map(|_| init))
+                    .and_then(|init| {
+                        if self.allow_downgrade {
+                            return Ok(init);
+                        }
+                        let running = self.images[FirmwareType::Application as usize].version;
+                        init.check_version(running).map(|_| init)
+                    })
+                    .and_then(|init| match &self.key {
+                        Some(key) => InitPacket::verify(&self.command, key).map(|_| init),
+                        None => Ok(init),
+                    });
+                let init = match init {
+                    Ok(init) => init,
+                    Err(e) => {
+                        warn!("Invalid init packet: {:?}", e);
+                        return Err(ExtError::from(e).into());
+                    }
+                };
+                info!(
+                    "Receiving {:?} version {} of {} bytes",
+                    init.fw_type,
+                    init.fw_version,
+                    init.image_size()
+                );
+                (self.executed, self.executed_crc) = (0, 0);
+                if let Some(progress) = self.resume.take() {
+                    if progress.command_crc == crc32(&self.command) && progress.offset <= init.image_size() {
+                        info!("Resuming firmware update at {}", progress.offset);
+                        (self.executed, self.executed_crc) = (progress.offset, progress.crc);
+                    }
+                }
+                (self.offset, self.crc) = (self.executed, self.executed_crc);
+                self.object_end = self.executed;
+                self.buffer.clear();
+                self.init = Some(init);
+                Ok(DfuStatus::Idle)
+            }
+            ObjectType::Data => {
+                let Some(image_size) = self.init.as_ref().map(|init| init.image_size()) else {
+                    return Err(DfuResult::OpNotPermitted.into());
+                };
+                if self.offset != self.object_end {
+                    return Err(DfuResult::OpNotPermitted.into());
+                }
+                self.flush(dfu)?;
+                // The CRC the host checked is of the data as it arrived, check it is what landed in flash.
+                match read_crc(dfu, self.executed, self.offset, self.executed_crc) {
+                    Ok(crc) if crc == self.crc => {}
+                    Ok(_) => {
+                        warn!("Data object at {} does not read back as written", self.executed);
+                        (self.offset, self.crc) = (self.executed, self.executed_crc);
+                        return Err(DfuResult::OpFailed.into());
+                    }
+                    Err(_) => {
+                        warn!("Error reading the data object back");
+                        return Err(DfuResult::OpFailed.into());
+                    }
+                }
+                if self.offset < image_size {
+                    (self.executed, self.executed_crc) = (self.offset, self.crc);
+                    return Ok(DfuStatus::Idle);
+                }
+                let Ok(digest) = image_hash(dfu, image_size) else {
+                    warn!("Error reading the image back");
+                    return Err(DfuResult::OpFailed.into());
+                };
+                if !self.init.as_ref().is_some_and(|init| init.matches(&digest)) {
+                    warn!("Image hash does not match the init packet");
+                    self.init = None;
+                    return Err(ExtError::HashFailed.into());
+                }
+                (self.executed, self.executed_crc) = (self.offset, self.crc);
+                // Done, nothing is left to resume.
+                let Some(init) = self.init.take() else {
+                    return Err(DfuResult::OpNotPermitted.into());
+                };
+                self.done = true;
+                Ok(match init.fw_type {
+                    initpacket::FirmwareType::Application => DfuStatus::DoneReset,
+                    initpacket::FirmwareType::Softdevice => DfuStatus::DoneStaged {
+                        softdevice: init.sd_size,
+                        bootloader: 0,
+                    },
+                    initpacket::FirmwareType::Bootloader => DfuStatus::DoneStaged {
+                        softdevice: 0,
+                        bootloader: init.bl_size,
+                    },
+                    initpacket::FirmwareType::ExternalApplication => DfuStatus::DoneAssets { size: init.app_size },
+                    initpacket::FirmwareType::SoftdeviceBootloader => DfuStatus::DoneStaged {
+                        softdevice: init.sd_size,
+                        bootloader: init.bl_size,
+                    },
+                })
+            }
+        }
+    }
+
+    /// Write the buffered data, padded with erased bytes to the write size of the partition.
+    fn flush<DFU: NorFlash>(&mut self, dfu: &mut DFU) -> Result<(), DfuResult> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let start = self.offset - self.buffer.len() as u32;
+        let padded = self.buffer.len().next_multiple_of(DFU::WRITE_SIZE);
+        let result = match self.buffer.resize(padded, 0xFF) {
+            Ok(()) => dfu.write(start, &self.buffer).map_err(|_| ()),
+            Err(()) => Err(()),
+        };
+        self.buffer.clear();
+        result.map_err(|_| {
+            warn!("Error writing the DFU partition");
+            DfuResult::OpFailed
+        })
+    }
+
+    fn crc_body(&self) -> ResponseBody {
+        let (offset, crc) = self.position();
+        ResponseBody::Crc { offset, crc }
+    }
+
+    /// Offset and CRC of the current object.
+    fn position(&self) -> (u32, u32) {
+        match self.current {
+            ObjectType::Command => (self.command.len() as u32, crc32(&self.command)),
+            ObjectType::Data => (self.offset, self.crc),
+        }
+    }
+}
+
+/// CRC-32 of the partition from `from` to `to`, continuing `crc` of the bytes before.
+fn read_crc<DFU: NorFlash>(dfu: &mut DFU, from: u32, to: u32, crc: u32) -> Result<u32, DFU::Error> {
+    let mut crc = crc;
+    let mut buf = [0; 256];
+    for offset in (from..to).step_by(buf.len()) {
+        let chunk = &mut buf[..(to - offset).min(256) as usize];
+        dfu.read(offset, chunk)?;
+        crc = crc32_update(crc, chunk);
+    }
+    Ok(crc)
+}
+
+/// Whether the partition from `from` to `to` is erased.
+fn is_erased<DFU: NorFlash>(dfu: &mut DFU, from: u32, to: u32) -> Result<bool, DFU::Error> {
+    let mut buf = [0; 256];
+    for offset in (from..to).step_by(buf.len()) {
+        let chunk = &mut buf[..(to - offset).min(256) as usize];
+        dfu.read(offset, chunk)?;
+        if chunk.iter().any(|&b| b != 0xFF) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// SHA-256 of the first `size` bytes of the partition.
+fn image_hash<DFU: NorFlash>(dfu: &mut DFU, size: u32) -> Result<[u8; 32], DFU::Error> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 256];
+    for offset in (0..size).step_by(buf.len()) {
+        let chunk = &mut buf[..(size - offset).min(256) as usize];
+        dfu.read(offset, chunk)?;
+        hasher.update(chunk);
+    }
+    Ok(hasher.finish())
+}
@@ -0,0 +1,340 @@
+//! In-memory flash and update packages for testing the DFU protocol on the host.
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+use nrf_dfu::initpacket::Capacity;
+use nrf_dfu::sha256::sha256;
+use nrf_dfu::{
+    ClientError, DfuClient, DfuRequest, DfuStatus, DfuTarget, DfuTransport, FirmwareInfo, FirmwareType, HardwareInfo,
+};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MemFlashError {
+    OutOfBounds,
+    NotAligned,
+    /// A write to bytes that were not erased, which NOR flash can not do.
+    NotErased,
+    /// An error set up by the test.
+    Injected,
+}
+
+impl NorFlashError for MemFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::NotErased | Self::Injected => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Flash written `WRITE` bytes and erased `ERASE` bytes at a time, which fails writes that are not aligned or are to
+/// bytes not erased, as the hardware would misbehave on them.
+pub struct MemFlash<const WRITE: usize, const ERASE: usize> {
+    pub data: Vec<u8>,
+    /// Fail every erase, write or read.
+    pub fail_erase: bool,
+    pub fail_write: bool,
+    pub fail_read: bool,
+    /// Write this byte wrong, as a worn out cell might.
+    pub corrupt: Option<u32>,
+}
+
+impl<const WRITE: usize, const ERASE: usize> MemFlash<WRITE, ERASE> {
+    /// An erased flash of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0xFF; size],
+            fail_erase: false,
+            fail_write: false,
+            fail_read: false,
+            corrupt: None,
+        }
+    }
+
+    fn check(&self, offset: u32, len: usize) -> Result<(), MemFlashError> {
+        match (offset as usize).checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(()),
+            _ => Err(MemFlashError::OutOfBounds),
+        }
+    }
+}
+
+impl<const WRITE: usize, const ERASE: usize> ErrorType for MemFlash<WRITE, ERASE> {
+    type Error = MemFlashError;
+}
+
+impl<const WRITE: usize, const ERASE: usize> ReadNorFlash for MemFlash<WRITE, ERASE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), MemFlashError> {
+        if self.fail_read {
+            return Err(MemFlashError::Injected);
+        }
+        self.check(offset, bytes.len())?;
+        bytes.copy_from_slice(&self.data[offset as usize..][..bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl<const WRITE: usize, const ERASE: usize> NorFlash for MemFlash<WRITE, ERASE> {
+    const WRITE_SIZE: usize = WRITE;
+    const ERASE_SIZE: usize = ERASE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), MemFlashError> {
+        if self.fail_erase {
+            return Err(MemFlashError::Injected);
+        }
+        if from as usize % ERASE != 0 || to as usize % ERASE != 0 || from > to {
+            return Err(MemFlashError::NotAligned);
+        }
+        self.check(from, (to - from) as usize)?;
+        self.data[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), MemFlashError> {
+        if self.fail_write {
+            return Err(MemFlashError::Injected);
+        }
+        if offset as usize % WRITE != 0 || bytes.len() % WRITE != 0 {
+            return Err(MemFlashError::NotAligned);
+        }
+        self.check(offset, bytes.len())?;
+        let data = &mut self.data[offset as usize..][..bytes.len()];
+        if data.iter().any(|&b| b != 0xFF) {
+            return Err(MemFlashError::NotErased);
+        }
+        data.copy_from_slice(bytes);
+        if let Some(corrupt) = self.corrupt {
+            if let Some(b) = corrupt.checked_sub(offset).and_then(|i| data.get_mut(i as usize)) {
+                *b ^= 0x01;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Internal flash of an nRF52.
+pub type Nvmc = MemFlash<4, 4096>;
+
+/// Largest application the targets take.
+pub const CAPACITY: u32 = 64 * 1024;
+
+/// A target running application `version`, taking applications of up to `CAPACITY`.
+pub fn target(version: u32) -> DfuTarget {
+    let hw_info = HardwareInfo {
+        part: 0x52832,
+        variant: 0x41414530,
+        rom_size: 512 * 1024,
+        ram_size: 64 * 1024,
+        rom_page_size: 4096,
+    };
+    let image = |ftype, version, addr, len| FirmwareInfo {
+        ftype,
+        version,
+        addr,
+        len,
+    };
+    let images = [
+        image(FirmwareType::Softdevice, 7_002_000, 0x1000, 0x2_5000),
+        image(FirmwareType::Application, version, 0x2_6000, 0x4_7000),
+        image(FirmwareType::Bootloader, 0, 0x7_8000, 0x6000),
+    ];
+    let capacity = Capacity {
+        application: CAPACITY,
+        softdevice: 0x2_5000,
+        bootloader: 0x6000,
+        assets: CAPACITY,
+    };
+    DfuTarget::new(capacity, images, hw_info)
+}
+
+/// An image of `size` bytes, different for each `seed`.
+pub fn image(size: usize, seed: u8) -> Vec<u8> {
+    (0..size)
+        .map(|i| (i as u8).wrapping_mul(seed) ^ (i >> 8) as u8)
+        .collect()
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn field_varint(buf: &mut Vec<u8>, field: u8, value: u64) {
+    buf.push(field << 3);
+    varint(buf, value);
+}
+
+fn field_bytes(buf: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    buf.push(field << 3 | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// The fields of an init command, as `nrfutil pkg generate` sets them.
+#[derive(Clone)]
+pub struct Init {
+    pub fw_version: u32,
+    pub hw_version: u32,
+    /// `FwType` of `dfu-cc.proto`: 0 application, 1 SoftDevice, 2 bootloader, 3 both, 4 external application.
+    pub fw_type: u64,
+    pub sd_size: u32,
+    pub bl_size: u32,
+    pub app_size: u32,
+    /// `HashType`, 3 for SHA-256.
+    pub hash_type: u64,
+    pub hash: Vec<u8>,
+    pub is_debug: bool,
+}
+
+impl Init {
+    /// The init command of an application `image` of version `fw_version`.
+    pub fn application(image: &[u8], fw_version: u32) -> Self {
+        Self {
+            fw_version,
+            hw_version: 52,
+            fw_type: 0,
+            sd_size: 0,
+            bl_size: 0,
+            app_size: image.len() as u32,
+            hash_type: 3,
+            hash: hash(image),
+            is_debug: false,
+        }
+    }
+
+    /// The init command of a SoftDevice `image`.
+    pub fn softdevice(image: &[u8]) -> Self {
+        Self {
+            fw_type: 1,
+            sd_size: image.len() as u32,
+            app_size: 0,
+            ..Self::application(image, 0)
+        }
+    }
+
+    /// The init command of assets packaged as an external application.
+    pub fn assets(image: &[u8]) -> Self {
+        Self {
+            fw_type: 4,
+            ..Self::application(image, 0)
+        }
+    }
+
+    /// The `Command` message holding the init command.
+    fn command(&self) -> Vec<u8> {
+        let mut init = Vec::new();
+        field_varint(&mut init, 1, self.fw_version as u64);
+        field_varint(&mut init, 2, self.hw_version as u64);
+        field_varint(&mut init, 4, self.fw_type);
+        for (field, size) in [(5, self.sd_size), (6, self.bl_size), (7, self.app_size)] {
+            if size != 0 {
+                field_varint(&mut init, field, size as u64);
+            }
+        }
+        let mut hash = Vec::new();
+        field_varint(&mut hash, 1, self.hash_type);
+        field_bytes(&mut hash, 2, &self.hash);
+        field_bytes(&mut init, 8, &hash);
+        if self.is_debug {
+            field_varint(&mut init, 9, 1);
+        }
+        let mut command = Vec::new();
+        field_varint(&mut command, 1, 1);
+        field_bytes(&mut command, 2, &init);
+        command
+    }
+
+    /// The unsigned init packet.
+    pub fn packet(&self) -> Vec<u8> {
+        let mut packet = Vec::new();
+        field_bytes(&mut packet, 2, &self.command());
+        packet
+    }
+
+    /// The init packet signed with `key`, with r and s little-endian as nrfutil sends them.
+    pub fn signed(&self, key: &SigningKey) -> Vec<u8> {
+        let command = self.command();
+        let signature: Signature = key.sign(&command);
+        let mut le = signature.to_bytes().to_vec();
+        le[..32].reverse();
+        le[32..].reverse();
+        let mut signed = Vec::new();
+        field_bytes(&mut signed, 1, &command);
+        field_varint(&mut signed, 2, 0);
+        field_bytes(&mut signed, 3, &le);
+        let mut packet = Vec::new();
+        field_bytes(&mut packet, 1, &signed);
+        packet
+    }
+}
+
+/// SHA-256 of `image`, byte-reversed as nrfutil stores it.
+pub fn hash(image: &[u8]) -> Vec<u8> {
+    let mut hash = sha256(image).to_vec();
+    hash.reverse();
+    hash
+}
+
+/// A signing key and the X and Y of its public key, as the firmware is built with.
+pub fn key(seed: u8) -> (SigningKey, [u8; 64]) {
+    let key = SigningKey::from_slice(&[seed; 32]).unwrap();
+    let point = key.verifying_key().to_encoded_point(false);
+    (key, point.as_bytes()[1..].try_into().unwrap())
+}
+
+/// Carry out `request`, returning the encoded response, empty if there is none.
+pub fn request<F: NorFlash>(target: &mut DfuTarget, flash: &mut F, request: DfuRequest<'_>) -> Vec<u8> {
+    let (response, _) = target.process(request, flash);
+    response.map_or(Vec::new(), |response| response.encode().to_vec())
+}
+
+/// Send `init` and `image` with a `DfuClient` in Writes of `packet_size`, returning the status after the last request.
+pub fn send<F: NorFlash>(
+    target: &mut DfuTarget,
+    flash: &mut F,
+    init: &[u8],
+    image: &[u8],
+    packet_size: usize,
+) -> Result<DfuStatus, ClientError> {
+    let mut client = DfuClient::new(init, image, packet_size);
+    let mut status = DfuStatus::Idle;
+    while let Some(request) = client.next_request() {
+        let (response, done) = target.process(request, flash);
+        status = done;
+        if let Some(response) = response {
+            client.handle_response(&response.encode())?;
+        }
+    }
+    Ok(status)
+}
+
+/// A transport that keeps the notifications sent, with the ATT MTU of a BLE connection that never raised it.
+#[derive(Default)]
+pub struct Notifications(pub RefCell<Vec<Vec<u8>>>);
+
+impl DfuTransport for Notifications {
+    type Error = ();
+
+    fn notify(&self, data: &[u8]) -> Result<(), ()> {
+        self.0.borrow_mut().push(data.to_vec());
+        Ok(())
+    }
+
+    fn mtu(&self) -> u16 {
+        23
+    }
+}
@@ -0,0 +1,14 @@
+//! The CRC-32 shared by the DFU protocol, the bootloader and the storage of the firmware.
+use nrf_dfu::crc::{crc32, crc32_update};
+
+#[test]
+fn check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+}
+
+#[test]
+fn empty() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32_update(0x1234_5678, b""), 0x1234_5678);
+}
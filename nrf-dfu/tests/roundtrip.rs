@@ -0,0 +1,172 @@
+//! Requests and responses survive encoding and decoding, and decoding takes any bytes without panicking.
+use nrf_dfu::initpacket::InitPacket;
+use nrf_dfu::slip::{slip_encode, SlipDecoder};
+use nrf_dfu::{
+    DfuRequest, DfuResponse, DfuResult, ExtError, FirmwareInfo, FirmwareType, HardwareInfo, ObjectType, ResponseBody,
+};
+use proptest::prelude::*;
+
+/// Largest request encoded, a Write of a 244-byte packet with its opcode.
+const MAX_REQUEST: usize = 245;
+
+fn object_type() -> impl Strategy<Value = ObjectType> {
+    prop_oneof![Just(ObjectType::Command), Just(ObjectType::Data)]
+}
+
+fn request() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 0..MAX_REQUEST)
+}
+
+/// Checks `request` survives encoding and decoding.
+fn request_roundtrip(request: DfuRequest<'_>) -> Result<(), TestCaseError> {
+    let encoded = request.encode::<MAX_REQUEST>().unwrap();
+    prop_assert_eq!(DfuRequest::decode(&encoded), Ok(request));
+    Ok(())
+}
+
+fn firmware_type() -> impl Strategy<Value = FirmwareType> {
+    prop_oneof![
+        Just(FirmwareType::Softdevice),
+        Just(FirmwareType::Application),
+        Just(FirmwareType::Bootloader),
+        Just(FirmwareType::Unknown),
+    ]
+}
+
+fn ext_error() -> impl Strategy<Value = ExtError> {
+    prop_oneof![
+        Just(ExtError::WrongCommandFormat),
+        Just(ExtError::InitCommandInvalid),
+        Just(ExtError::FwVersionFailure),
+        Just(ExtError::HwVersionFailure),
+        Just(ExtError::SdVersionFailure),
+        Just(ExtError::SignatureMissing),
+        Just(ExtError::WrongHashType),
+        Just(ExtError::HashFailed),
+        Just(ExtError::WrongSignatureType),
+        Just(ExtError::VerificationFailed),
+        Just(ExtError::InsufficientSpace),
+    ]
+}
+
+/// Results of failed requests other than `ExtError`, which carries a body.
+fn failure() -> impl Strategy<Value = DfuResult> {
+    prop_oneof![
+        Just(DfuResult::OpNotSupported),
+        Just(DfuResult::InvalidParameter),
+        Just(DfuResult::InsufficientResources),
+        Just(DfuResult::InvalidObject),
+        Just(DfuResult::UnsupportedType),
+        Just(DfuResult::OpNotPermitted),
+        Just(DfuResult::OpFailed),
+    ]
+}
+
+/// Responses a target sends, with the body that goes with each opcode.
+fn response() -> impl Strategy<Value = DfuResponse> {
+    let words = || any::<[u32; 5]>();
+    prop_oneof![
+        any::<u8>().prop_map(|v| DfuResponse::success(0x00, ResponseBody::ProtocolVersion(v))),
+        prop::sample::select(vec![0x01, 0x02, 0x04, 0x0C, 0xF0])
+            .prop_map(|op| DfuResponse::success(op, ResponseBody::None)),
+        any::<(u32, u32)>().prop_map(|(offset, crc)| DfuResponse::success(0x03, ResponseBody::Crc { offset, crc })),
+        any::<(u32, u32, u32)>().prop_map(|(max_size, offset, crc)| {
+            DfuResponse::success(0x06, ResponseBody::Select { max_size, offset, crc })
+        }),
+        any::<u16>().prop_map(|mtu| DfuResponse::success(0x07, ResponseBody::Mtu(mtu))),
+        any::<u8>().prop_map(|id| DfuResponse::success(0x09, ResponseBody::Ping(id))),
+        words().prop_map(|[part, variant, rom_size, ram_size, rom_page_size]| {
+            let hw = HardwareInfo {
+                part,
+                variant,
+                rom_size,
+                ram_size,
+                rom_page_size,
+            };
+            DfuResponse::success(0x0A, ResponseBody::HwVersion(hw))
+        }),
+        (firmware_type(), words()).prop_map(|(ftype, [version, addr, len, ..])| {
+            let fw = FirmwareInfo {
+                ftype,
+                version,
+                addr,
+                len,
+            };
+            DfuResponse::success(0x0B, ResponseBody::FwVersion(fw))
+        }),
+        (any::<u8>(), failure()).prop_map(|(op, result)| DfuResponse::new(op, result)),
+        (any::<u8>(), ext_error()).prop_map(|(op, e)| DfuResponse::ext_error(op, e)),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn requests_roundtrip(
+        obj_type in object_type(),
+        obj_size: u32,
+        target: u16,
+        id: u8,
+        data in prop::collection::vec(any::<u8>(), 0..MAX_REQUEST - 1),
+    ) {
+        request_roundtrip(DfuRequest::ProtocolVersion)?;
+        request_roundtrip(DfuRequest::Create { obj_type, obj_size })?;
+        request_roundtrip(DfuRequest::SetReceiptNotification { target })?;
+        request_roundtrip(DfuRequest::Crc)?;
+        request_roundtrip(DfuRequest::Execute)?;
+        request_roundtrip(DfuRequest::Select { obj_type })?;
+        request_roundtrip(DfuRequest::MtuGet)?;
+        request_roundtrip(DfuRequest::Write { data: &data })?;
+        request_roundtrip(DfuRequest::Ping { id })?;
+        request_roundtrip(DfuRequest::HwVersion)?;
+        request_roundtrip(DfuRequest::FwVersion { image_id: id })?;
+        request_roundtrip(DfuRequest::Abort)?;
+        request_roundtrip(DfuRequest::FactoryReset)?;
+    }
+
+    /// Whatever decodes encodes back to the bytes it was decoded from, less any bytes past its parameters.
+    #[test]
+    fn decoded_requests_reencode(data in request()) {
+        if let Ok(request) = DfuRequest::decode(&data) {
+            let encoded = request.encode::<MAX_REQUEST>().unwrap();
+            prop_assert!(data.starts_with(&encoded));
+        }
+    }
+
+    #[test]
+    fn responses_roundtrip(response in response()) {
+        prop_assert_eq!(DfuResponse::decode(&response.encode()), Some(response));
+    }
+
+    #[test]
+    fn decoded_responses_reencode(data in prop::collection::vec(any::<u8>(), 0..32)) {
+        if let Some(response) = DfuResponse::decode(&data) {
+            prop_assert!(data.starts_with(&response.encode()));
+        }
+    }
+
+    /// Responses are decoded from notifications that look like one, with any opcode, result and body.
+    #[test]
+    fn response_like_bytes_decode(opcode: u8, result: u8, body in prop::collection::vec(any::<u8>(), 0..32)) {
+        let data = [&[0x60, opcode, result][..], &body].concat();
+        if let Some(response) = DfuResponse::decode(&data) {
+            prop_assert_eq!(response.opcode(), opcode);
+            prop_assert_eq!(response.result() as u8, result);
+        }
+    }
+
+    #[test]
+    fn init_packets_decode(data in prop::collection::vec(any::<u8>(), 0..300)) {
+        let _ = InitPacket::decode(&data);
+    }
+
+    #[test]
+    fn slip_frames_roundtrip(data in prop::collection::vec(any::<u8>(), 1..64)) {
+        let frame = slip_encode::<129>(&data).unwrap();
+        let mut decoder = SlipDecoder::<64>::default();
+        let (last, bytes) = frame.split_last().unwrap();
+        for &byte in bytes {
+            prop_assert_eq!(decoder.push(byte), None);
+        }
+        prop_assert_eq!(decoder.push(*last), Some(&data[..]));
+    }
+}
@@ -0,0 +1,113 @@
+//! `DfuSession` fed the control and packet writes of a BLE connection, as the DFU service passes them on.
+mod common;
+
+use common::*;
+use nrf_dfu::crc::crc32;
+use nrf_dfu::{DfuEvent, DfuSession, DfuStatus, DfuTarget};
+
+/// A session with control point notifications enabled, as phones do before the first request.
+fn session(target: &mut DfuTarget, flash: &mut Nvmc, transport: &Notifications) -> DfuSession {
+    let mut session = DfuSession::default();
+    let event = DfuEvent::ControlNotifications(true);
+    assert_eq!(session.handle(target, flash, transport, event), None);
+    session
+}
+
+/// Create an object of `obj_type` for `data`, sent in packets of `packet` bytes, and execute it.
+fn send(
+    session: &mut DfuSession,
+    target: &mut DfuTarget,
+    flash: &mut Nvmc,
+    transport: &Notifications,
+    obj_type: u8,
+    data: &[u8],
+    packet: usize,
+) -> Option<DfuStatus> {
+    let mut create = vec![0x01, obj_type];
+    create.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    session.handle(target, flash, transport, DfuEvent::ControlWrite(&create));
+    for chunk in data.chunks(packet) {
+        session.handle(target, flash, transport, DfuEvent::PacketWrite(chunk));
+    }
+    session.handle(target, flash, transport, DfuEvent::ControlWrite(&[0x03]));
+    session.handle(target, flash, transport, DfuEvent::ControlWrite(&[0x04]))
+}
+
+#[test]
+fn update_through_session() {
+    let image = image(1000, 5);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let transport = Notifications::default();
+    let mut session = session(&mut target, &mut flash, &transport);
+
+    let status = send(&mut session, &mut target, &mut flash, &transport, 0x01, &init, 20);
+    assert_eq!(status, Some(DfuStatus::Idle));
+    let status = send(&mut session, &mut target, &mut flash, &transport, 0x02, &image, 244);
+    assert_eq!(status, Some(DfuStatus::DoneReset));
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+
+    // Packets are not answered, Create, Crc and Execute are, for each object.
+    let mut crc = vec![0x60, 0x03, 0x01];
+    crc.extend_from_slice(&(image.len() as u32).to_le_bytes());
+    crc.extend_from_slice(&crc32(&image).to_le_bytes());
+    let notifications = transport.0.borrow();
+    assert_eq!(notifications.len(), 6);
+    assert_eq!(notifications[3], [0x60, 0x01, 0x01]);
+    assert_eq!(notifications[4], crc);
+    assert_eq!(notifications[5], [0x60, 0x04, 0x01]);
+}
+
+#[test]
+fn mtu_of_the_transport() {
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let transport = Notifications::default();
+    let mut session = session(&mut target, &mut flash, &transport);
+    session.handle(&mut target, &mut flash, &transport, DfuEvent::ControlWrite(&[0x07]));
+    assert_eq!(*transport.0.borrow(), [vec![0x60, 0x07, 0x01, 23, 0]]);
+}
+
+#[test]
+fn silent_without_notifications() {
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let transport = Notifications::default();
+    let mut session = DfuSession::default();
+    let status = session.handle(&mut target, &mut flash, &transport, DfuEvent::ControlWrite(&[0x07]));
+    assert_eq!(status, Some(DfuStatus::Idle));
+    assert!(transport.0.borrow().is_empty());
+}
+
+#[test]
+fn refused_requests_do_not_reach_the_target() {
+    let image = image(1000, 5);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let transport = Notifications::default();
+    let mut session = DfuSession::default();
+    session.refuse(&transport, DfuEvent::ControlNotifications(true));
+
+    let mut create = vec![0x01, 0x01];
+    create.extend_from_slice(&(init.len() as u32).to_le_bytes());
+    session.refuse(&transport, DfuEvent::ControlWrite(&create));
+    session.refuse(&transport, DfuEvent::PacketWrite(&init));
+    session.refuse(&transport, DfuEvent::ControlWrite(&[]));
+    assert_eq!(*transport.0.borrow(), [vec![0x60, 0x01, 0x08]]);
+
+    // Once allowed, the same session selects a command object of which nothing was received: offset and CRC are 0.
+    let status = session.handle(
+        &mut target,
+        &mut flash,
+        &transport,
+        DfuEvent::ControlWrite(&[0x06, 0x01]),
+    );
+    assert_eq!(status, Some(DfuStatus::Idle));
+    let select = &transport.0.borrow()[1];
+    assert_eq!(select[..3], [0x60, 0x06, 0x01]);
+    assert_eq!(select[7..], [0; 8]);
+    assert_eq!(target.progress(), None);
+    assert!(flash.data.iter().all(|&b| b == 0xFF));
+}
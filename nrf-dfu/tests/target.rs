@@ -0,0 +1,659 @@
+//! `DfuTarget` driven through whole updates and their failures, against an in-memory flash.
+mod common;
+
+use common::*;
+use embedded_storage::nor_flash::NorFlash;
+use nrf_dfu::crc::crc32;
+use nrf_dfu::{
+    ClientError, DfuEvent, DfuPhase, DfuRequest, DfuResult, DfuSession, DfuStatus, DfuTarget, ExtError, ObjectType,
+    DATA_OBJECT_SIZE,
+};
+
+/// External flash, written a byte at a time.
+type ExternalFlash = MemFlash<1, 4096>;
+
+/// Send the command object `init` and execute it, returning the response to Execute.
+fn send_init<F: NorFlash>(target: &mut DfuTarget, flash: &mut F, init: &[u8]) -> Vec<u8> {
+    let create = DfuRequest::Create {
+        obj_type: ObjectType::Command,
+        obj_size: init.len() as u32,
+    };
+    assert_eq!(request(target, flash, create), [0x60, 0x01, 0x01]);
+    assert_eq!(request(target, flash, DfuRequest::Write { data: init }), []);
+    request(target, flash, DfuRequest::Execute)
+}
+
+/// Create a data object for `data` and write it, returning the response to Crc.
+fn send_object<F: NorFlash>(target: &mut DfuTarget, flash: &mut F, data: &[u8]) -> Vec<u8> {
+    let create = DfuRequest::Create {
+        obj_type: ObjectType::Data,
+        obj_size: data.len() as u32,
+    };
+    assert_eq!(request(target, flash, create), [0x60, 0x01, 0x01]);
+    for chunk in data.chunks(20) {
+        request(target, flash, DfuRequest::Write { data: chunk });
+    }
+    request(target, flash, DfuRequest::Crc)
+}
+
+/// The response to `opcode` failing with the extended error `e`.
+fn ext_error(opcode: u8, e: ExtError) -> Vec<u8> {
+    vec![0x60, opcode, DfuResult::ExtError as u8, e as u8]
+}
+
+#[test]
+fn update_written_whole() {
+    let image = image(10_001, 7);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    assert_eq!(
+        send(&mut target, &mut flash, &init, &image, 20),
+        Ok(DfuStatus::DoneReset)
+    );
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+    // Nothing is left to resume.
+    assert_eq!(target.progress(), None);
+}
+
+#[test]
+fn packets_of_any_size() {
+    let image = image(9_999, 5);
+    let init = Init::application(&image, 1).packet();
+    for packet_size in [1, 17, 20, 244, 4096] {
+        let mut target = target(1);
+        let mut flash = Nvmc::new(CAPACITY as usize);
+        assert_eq!(
+            send(&mut target, &mut flash, &init, &image, packet_size),
+            Ok(DfuStatus::DoneReset)
+        );
+        assert_eq!(&flash.data[..image.len()], &image[..]);
+    }
+}
+
+#[test]
+fn erase_sizes() {
+    let image = image(3 * DATA_OBJECT_SIZE as usize + 100, 3);
+    let init = Init::application(&image, 1).packet();
+
+    // Sectors smaller than data objects are erased a few at a time.
+    let mut flash = MemFlash::<4, 1024>::new(CAPACITY as usize);
+    assert_eq!(
+        send(&mut target(1), &mut flash, &init, &image, 64),
+        Ok(DfuStatus::DoneReset)
+    );
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+
+    // Sectors larger than data objects are erased by the object that starts them.
+    let mut flash = MemFlash::<4, 8192>::new(CAPACITY as usize);
+    assert_eq!(
+        send(&mut target(1), &mut flash, &init, &image, 64),
+        Ok(DfuStatus::DoneReset)
+    );
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+
+    let mut flash = ExternalFlash::new(CAPACITY as usize);
+    assert_eq!(
+        send(&mut target(1), &mut flash, &init, &image, 64),
+        Ok(DfuStatus::DoneReset)
+    );
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+}
+
+#[test]
+fn object_within_a_written_sector() {
+    let image = image(3 * DATA_OBJECT_SIZE as usize, 4);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = MemFlash::<4, 8192>::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &init);
+    send_object(&mut target, &mut flash, &image[..4096]);
+    request(&mut target, &mut flash, DfuRequest::Execute);
+
+    // The second object starts within the sector of the first, and fails its CRC check.
+    let mut garbled = image[4096..8192].to_vec();
+    garbled[100] ^= 0x01;
+    let crc = send_object(&mut target, &mut flash, &garbled);
+    assert_ne!(&crc[7..11], &crc32(&image[..8192]).to_le_bytes());
+
+    // Created again, it would be written over the bytes of the failed one, which can not be erased without the
+    // first object, so the image goes back to the start of the sector.
+    let create = DfuRequest::Create {
+        obj_type: ObjectType::Data,
+        obj_size: 4096,
+    };
+    assert_eq!(request(&mut target, &mut flash, create), [0x60, 0x01, 0x0A]);
+    assert_eq!(target.progress().unwrap().offset, 0);
+    assert_eq!(
+        send(&mut target, &mut flash, &init, &image, 20),
+        Ok(DfuStatus::DoneReset)
+    );
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+}
+
+#[test]
+fn receipt_notifications() {
+    let image = image(300, 9);
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &Init::application(&image, 1).packet());
+    request(
+        &mut target,
+        &mut flash,
+        DfuRequest::SetReceiptNotification { target: 2 },
+    );
+    let create = DfuRequest::Create {
+        obj_type: ObjectType::Data,
+        obj_size: 300,
+    };
+    request(&mut target, &mut flash, create);
+
+    // Every second write is answered with the offset and CRC, as a Crc response.
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Write { data: &image[..100] }),
+        []
+    );
+    let response = request(&mut target, &mut flash, DfuRequest::Write { data: &image[100..200] });
+    assert_eq!(&response[..3], &[0x60, 0x03, 0x01]);
+    assert_eq!(&response[3..7], &200u32.to_le_bytes());
+    assert_eq!(&response[7..11], &crc32(&image[..200]).to_le_bytes());
+}
+
+#[test]
+fn resumes_after_disconnect() {
+    let image = image(10_000, 3);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &init);
+    send_object(&mut target, &mut flash, &image[..4096]);
+    request(&mut target, &mut flash, DfuRequest::Execute);
+    // The second object is cut off.
+    send_object(&mut target, &mut flash, &image[4096..5000]);
+    let progress = target.progress().unwrap();
+    assert_eq!(progress.offset, 4096);
+    let command = target.command().unwrap().to_vec();
+    assert_eq!(command, init);
+
+    // The next connection finds the init packet and the first object, and only sends the rest.
+    let mut target = common::target(1);
+    target.restore_command(&command);
+    target.resume(progress);
+    let select = request(
+        &mut target,
+        &mut flash,
+        DfuRequest::Select {
+            obj_type: ObjectType::Command,
+        },
+    );
+    assert_eq!(&select[7..11], &(init.len() as u32).to_le_bytes());
+    assert_eq!(&select[11..15], &crc32(&init).to_le_bytes());
+    assert_eq!(
+        send(&mut target, &mut flash, &init, &image, 20),
+        Ok(DfuStatus::DoneReset)
+    );
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+}
+
+#[test]
+fn progress_of_another_update_is_ignored() {
+    let image = image(10_000, 3);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &init);
+    send_object(&mut target, &mut flash, &image[..4096]);
+    request(&mut target, &mut flash, DfuRequest::Execute);
+    let progress = target.progress().unwrap();
+
+    // Another package starts over.
+    let other = common::image(10_000, 11);
+    let mut target = common::target(1);
+    target.resume(progress);
+    let init = Init::application(&other, 1).packet();
+    assert_eq!(send_init(&mut target, &mut flash, &init), [0x60, 0x04, 0x01]);
+    assert_eq!(target.transferred(), Some((0, 10_000)));
+}
+
+#[test]
+fn softdevice_staged() {
+    let image = image(9_000, 5);
+    let init = Init::softdevice(&image).packet();
+    let mut target = target(1);
+    let mut flash = ExternalFlash::new(0x2_5000);
+    assert_eq!(send_init(&mut target, &mut flash, &init), [0x60, 0x04, 0x01]);
+    assert!(target.staged());
+    assert_eq!(target.transferred(), Some((0, 9_000)));
+    assert_eq!(
+        send(&mut target, &mut flash, &init, &image, 20),
+        Ok(DfuStatus::DoneStaged {
+            softdevice: 9_000,
+            bootloader: 0,
+        })
+    );
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+}
+
+#[test]
+fn assets_to_asset_partition() {
+    let image = image(9_000, 6);
+    let init = Init::assets(&image).packet();
+    // Assets are not versioned like the application, whatever runs.
+    let mut target = target(5);
+    let mut flash = ExternalFlash::new(CAPACITY as usize);
+    assert_eq!(send_init(&mut target, &mut flash, &init), [0x60, 0x04, 0x01]);
+    assert!(target.assets());
+    assert!(!target.staged());
+    assert_eq!(
+        send(&mut target, &mut flash, &init, &image, 20),
+        Ok(DfuStatus::DoneAssets { size: 9_000 })
+    );
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+}
+
+#[test]
+fn image_hash_checked() {
+    let image = image(300, 1);
+    let init = Init::application(&image[..299], 1);
+    let init = Init { app_size: 300, ..init };
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let result = send(&mut target, &mut flash, &init.packet(), &image, 20);
+    assert_eq!(
+        result.map_err(|e| match e {
+            ClientError::Refused(response) => response.encode().to_vec(),
+            _ => Vec::new(),
+        }),
+        Err(ext_error(0x04, ExtError::HashFailed))
+    );
+    // The image can not be executed again without a new init packet.
+    assert_eq!(target.transferred(), None);
+}
+
+#[test]
+fn init_packet_checked() {
+    let image = image(300, 1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let refused = |init: Init, e| {
+        let mut flash = Nvmc::new(CAPACITY as usize);
+        assert_eq!(
+            send_init(&mut target(2), &mut flash, &init.packet()),
+            ext_error(0x04, e)
+        );
+    };
+    let init = Init::application(&image, 2);
+
+    refused(
+        Init {
+            hw_version: 51,
+            ..init.clone()
+        },
+        ExtError::HwVersionFailure,
+    );
+    refused(
+        Init {
+            fw_version: 1,
+            ..init.clone()
+        },
+        ExtError::FwVersionFailure,
+    );
+    refused(
+        Init {
+            hash_type: 2,
+            ..init.clone()
+        },
+        ExtError::WrongHashType,
+    );
+    refused(
+        Init {
+            app_size: CAPACITY + 1,
+            ..init.clone()
+        },
+        ExtError::InsufficientSpace,
+    );
+    refused(
+        Init {
+            app_size: 0,
+            ..init.clone()
+        },
+        ExtError::InsufficientSpace,
+    );
+    refused(
+        Init {
+            app_size: CAPACITY + 1,
+            ..Init::assets(&image)
+        },
+        ExtError::InsufficientSpace,
+    );
+    refused(
+        Init {
+            fw_type: 5,
+            ..init.clone()
+        },
+        ExtError::InitCommandInvalid,
+    );
+    assert_eq!(
+        send_init(&mut target(2), &mut flash, &[0x12, 0x05, 0x08]),
+        ext_error(0x04, ExtError::WrongCommandFormat)
+    );
+
+    // Debug packages may be older, as may any with downgrades allowed.
+    let older = Init {
+        fw_version: 1,
+        ..init.clone()
+    };
+    let debug = Init {
+        is_debug: true,
+        ..older.clone()
+    };
+    assert_eq!(
+        send_init(&mut target(2), &mut flash, &debug.packet()),
+        [0x60, 0x04, 0x01]
+    );
+    let mut downgrade = target(2);
+    downgrade.set_allow_downgrade(true);
+    assert_eq!(
+        send_init(&mut downgrade, &mut flash, &older.packet()),
+        [0x60, 0x04, 0x01]
+    );
+}
+
+#[test]
+fn signature_checked() {
+    let image = image(300, 1);
+    let init = Init::application(&image, 1);
+    let (key, public) = key(1);
+    let (other, _) = common::key(2);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let signing = || {
+        let mut target = target(1);
+        target.set_key(public);
+        target
+    };
+
+    assert_eq!(
+        send_init(&mut signing(), &mut flash, &init.signed(&key)),
+        [0x60, 0x04, 0x01]
+    );
+    assert_eq!(
+        send_init(&mut signing(), &mut flash, &init.packet()),
+        ext_error(0x04, ExtError::SignatureMissing)
+    );
+    assert_eq!(
+        send_init(&mut signing(), &mut flash, &init.signed(&other)),
+        ext_error(0x04, ExtError::VerificationFailed)
+    );
+    // Without a key, signed packages are accepted whatever the key.
+    assert_eq!(
+        send_init(&mut target(1), &mut flash, &init.signed(&other)),
+        [0x60, 0x04, 0x01]
+    );
+}
+
+#[test]
+fn objects_checked() {
+    let image = image(5_000, 1);
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let create = |obj_type, obj_size| DfuRequest::Create { obj_type, obj_size };
+
+    // The init packet must fit the command object, and be written whole before it is executed.
+    assert_eq!(
+        request(&mut target, &mut flash, create(ObjectType::Command, 257)),
+        [0x60, 0x01, 0x04]
+    );
+    let init = Init::application(&image, 1).packet();
+    request(&mut target, &mut flash, create(ObjectType::Command, init.len() as u32));
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Write { data: &init[..10] }),
+        []
+    );
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Execute),
+        [0x60, 0x04, 0x08]
+    );
+    let too_long = [&init[10..], &[0][..]].concat();
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Write { data: &too_long }),
+        [0x60, 0x08, 0x05]
+    );
+
+    // Data objects only follow an init packet.
+    assert_eq!(
+        request(&mut target, &mut flash, create(ObjectType::Data, 4096)),
+        [0x60, 0x01, 0x08]
+    );
+    assert_eq!(send_init(&mut target, &mut flash, &init), [0x60, 0x04, 0x01]);
+
+    // Objects must fit in DATA_OBJECT_SIZE and the image, and all but the last be aligned.
+    assert_eq!(
+        request(&mut target, &mut flash, create(ObjectType::Data, 4097)),
+        [0x60, 0x01, 0x04]
+    );
+    assert_eq!(
+        request(&mut target, &mut flash, create(ObjectType::Data, 257)),
+        [0x60, 0x01, 0x03]
+    );
+    let crc = send_object(&mut target, &mut flash, &image[..4096]);
+    assert_eq!(&crc[3..7], &4096u32.to_le_bytes());
+    assert_eq!(&crc[7..11], &crc32(&image[..4096]).to_le_bytes());
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Write { data: &[0; 4] }),
+        [0x60, 0x08, 0x05]
+    );
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Execute),
+        [0x60, 0x04, 0x01]
+    );
+    assert_eq!(
+        request(&mut target, &mut flash, create(ObjectType::Data, 4096)),
+        [0x60, 0x01, 0x04]
+    );
+}
+
+#[test]
+fn flash_errors() {
+    let image = image(5_000, 1);
+    let init = Init::application(&image, 1).packet();
+    let create = DfuRequest::Create {
+        obj_type: ObjectType::Data,
+        obj_size: 4096,
+    };
+
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &init);
+    flash.fail_erase = true;
+    assert_eq!(request(&mut target, &mut flash, create), [0x60, 0x01, 0x0A]);
+
+    let mut target = common::target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &init);
+    flash.fail_write = true;
+    request(&mut target, &mut flash, create);
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Write { data: &image[..256] }),
+        [0x60, 0x08, 0x0A]
+    );
+
+    let mut target = common::target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &init);
+    send_object(&mut target, &mut flash, &image[..4096]);
+    flash.fail_read = true;
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Execute),
+        [0x60, 0x04, 0x0A]
+    );
+}
+
+#[test]
+fn written_data_read_back() {
+    let image = image(5_000, 1);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    flash.corrupt = Some(1000);
+    send_init(&mut target, &mut flash, &init);
+
+    // The CRC of the data received matches, but not what landed in flash.
+    let crc = send_object(&mut target, &mut flash, &image[..4096]);
+    assert_eq!(&crc[7..11], &crc32(&image[..4096]).to_le_bytes());
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Execute),
+        [0x60, 0x04, 0x0A]
+    );
+    // The object is sent again from its start.
+    let crc = request(&mut target, &mut flash, DfuRequest::Crc);
+    assert_eq!(&crc[3..7], &0u32.to_le_bytes());
+    flash.corrupt = None;
+    send_object(&mut target, &mut flash, &image[..4096]);
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Execute),
+        [0x60, 0x04, 0x01]
+    );
+}
+
+#[test]
+fn abort_starts_over() {
+    let image = image(5_000, 1);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &init);
+    send_object(&mut target, &mut flash, &image[..4096]);
+    let (response, status) = target.process(DfuRequest::Abort, &mut flash);
+    assert_eq!(response.unwrap().encode()[..], [0x60, 0x0C, 0x01]);
+    assert_eq!(status, DfuStatus::Aborted);
+    assert_eq!(target.progress(), None);
+    // Nothing of the aborted image is left to be swapped in.
+    assert!(flash.data.iter().all(|&b| b == 0xFF));
+    let create = DfuRequest::Create {
+        obj_type: ObjectType::Data,
+        obj_size: 4096,
+    };
+    assert_eq!(request(&mut target, &mut flash, create), [0x60, 0x01, 0x08]);
+
+    // The update is dropped even if the flash fails, the host is told the image may be left.
+    send_init(&mut target, &mut flash, &init);
+    send_object(&mut target, &mut flash, &image[..4096]);
+    flash.fail_erase = true;
+    assert_eq!(request(&mut target, &mut flash, DfuRequest::Abort), [0x60, 0x0C, 0x0A]);
+    assert_eq!(target.progress(), None);
+}
+
+#[test]
+fn factory_reset_only_if_allowed() {
+    let image = image(5_000, 1);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    send_init(&mut target, &mut flash, &init);
+    assert_eq!(target.process(DfuRequest::FactoryReset, &mut flash).1, DfuStatus::Idle);
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::FactoryReset),
+        [0x60, 0xF0, 0x08]
+    );
+    assert!(target.progress().is_some());
+
+    target.set_allow_factory_reset(true);
+    let (response, status) = target.process(DfuRequest::FactoryReset, &mut flash);
+    assert_eq!(response.unwrap().encode()[..], [0x60, 0xF0, 0x01]);
+    assert_eq!(status, DfuStatus::FactoryReset);
+    // The update under way is dropped along with the rest.
+    assert_eq!(target.progress(), None);
+}
+
+#[test]
+fn phases() {
+    let image = image(5_000, 1);
+    let init = Init::application(&image, 1).packet();
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    assert_eq!(target.phase(), DfuPhase::Idle);
+    let create = DfuRequest::Create {
+        obj_type: ObjectType::Command,
+        obj_size: init.len() as u32,
+    };
+    request(&mut target, &mut flash, create);
+    assert_eq!(target.phase(), DfuPhase::Init);
+    request(&mut target, &mut flash, DfuRequest::Write { data: &init });
+    request(&mut target, &mut flash, DfuRequest::Execute);
+    assert_eq!(target.phase(), DfuPhase::Image);
+    send_object(&mut target, &mut flash, &image[..4096]);
+    assert_eq!(target.phase(), DfuPhase::Image);
+    assert_eq!(
+        send(&mut target, &mut flash, &init, &image, 20),
+        Ok(DfuStatus::DoneReset)
+    );
+    assert_eq!(target.phase(), DfuPhase::Done);
+    request(&mut target, &mut flash, DfuRequest::Abort);
+    assert_eq!(target.phase(), DfuPhase::Idle);
+}
+
+#[test]
+fn device_info() {
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    target.set_mtu(247);
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::ProtocolVersion),
+        [0x60, 0x00, 0x01, 0x01]
+    );
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::MtuGet),
+        [0x60, 0x07, 0x01, 247, 0]
+    );
+    assert_eq!(
+        request(&mut target, &mut flash, DfuRequest::Ping { id: 0x42 }),
+        [0x60, 0x09, 0x01, 0x42]
+    );
+    let hw = request(&mut target, &mut flash, DfuRequest::HwVersion);
+    assert_eq!(&hw[..7], &[0x60, 0x0A, 0x01, 0x32, 0x28, 0x05, 0x00]);
+    assert_eq!(hw.len(), 23);
+    let fw = request(&mut target, &mut flash, DfuRequest::FwVersion { image_id: 1 });
+    assert_eq!(&fw[..8], &[0x60, 0x0B, 0x01, 0x01, 1, 0, 0, 0]);
+    let fw = request(&mut target, &mut flash, DfuRequest::FwVersion { image_id: 3 });
+    assert_eq!(&fw[..4], &[0x60, 0x0B, 0x01, 0xFF]);
+}
+
+#[test]
+fn malformed_requests() {
+    assert_eq!(DfuRequest::decode(&[]), Err(DfuResult::InvalidParameter));
+    assert_eq!(DfuRequest::decode(&[0x01, 0x01]), Err(DfuResult::InvalidParameter));
+    assert_eq!(DfuRequest::decode(&[0x06, 0x03]), Err(DfuResult::UnsupportedType));
+    assert_eq!(DfuRequest::decode(&[0x05]), Err(DfuResult::OpNotSupported));
+    assert_eq!(DfuRequest::decode(&[0x07]), Ok(DfuRequest::MtuGet));
+}
+
+#[test]
+fn malformed_requests_answered() {
+    let mut target = target(1);
+    let mut flash = Nvmc::new(CAPACITY as usize);
+    let mut session = DfuSession::default();
+    let transport = Notifications::default();
+    session.handle(
+        &mut target,
+        &mut flash,
+        &transport,
+        DfuEvent::ControlNotifications(true),
+    );
+    let mut write = |data: &[u8]| {
+        let event = DfuEvent::ControlWrite(data);
+        session.handle(&mut target, &mut flash, &transport, event)
+    };
+    assert_eq!(write(&[]), None);
+    assert_eq!(write(&[0x05]), None);
+    write(&[0x01, 0x01]);
+    write(&[0x06, 0x03]);
+    assert_eq!(write(&[0x09, 0x42]), Some(DfuStatus::Idle));
+    assert_eq!(
+        *transport.0.borrow(),
+        [
+            vec![0x60, 0x05, 0x02],
+            vec![0x60, 0x01, 0x03],
+            vec![0x60, 0x06, 0x07],
+            vec![0x60, 0x09, 0x01, 0x42],
+        ]
+    );
+}
@@ -7,6 +7,8 @@ use embedded_storage::nor_flash::{
 const PAGE_SIZE: usize = 256;
 const ERASE_SIZE: usize = 4096;
 const FLASH_SIZE: usize = 4 * 1024 * 1024;
+// Spins covering the time the flash needs to leave deep power-down (tRES1) at the highest core clock.
+const WAKEUP_SPINS: u32 = 2_000;
 
 #[repr(u8)]
 #[allow(unused)]
@@ -40,6 +42,9 @@ bitflags! {
 
 pub struct XtFlash<SPI: SpiDevice> {
     spi: SPI,
+    asleep: bool,
+    /// Whether the flash answered with the expected id, accesses fail with `Error::Missing` until it has.
+    present: bool,
 }
 
 #[derive(Debug)]
@@ -48,6 +53,8 @@ pub enum Error<SPI> {
     Flash(NorFlashErrorKind),
     InvalidManufacturerId,
     InvalidMemoryType,
+    /// The flash did not answer when probed.
+    Missing,
     NotInRam,
     Unaligned,
 }
@@ -59,12 +66,29 @@ impl<SPI> From<SPI> for Error<SPI> {
 }
 
 impl<SPI: SpiDevice> XtFlash<SPI> {
-    pub fn new(mut spi: SPI) -> Result<Self, Error<SPI::Error>> {
+    pub fn new(spi: SPI) -> Result<Self, Error<SPI::Error>> {
+        let mut flash = Self::unprobed(spi);
+        flash.probe()?;
+        Ok(flash)
+    }
+
+    /// Take the flash on `spi` without talking to it, for a caller that carries on if `probe` fails. Every access
+    /// fails with `Error::Missing` until `probe` succeeds.
+    pub fn unprobed(spi: SPI) -> Self {
+        Self {
+            spi,
+            asleep: false,
+            present: false,
+        }
+    }
+
+    /// Wake the flash up, check its id and unlock it for writes.
+    pub fn probe(&mut self) -> Result<(), Error<SPI::Error>> {
         let mut value: [u8; 4] = [0xAB, 0x01, 0x02, 0x03];
-        spi.transfer_in_place(&mut value[..])?;
+        self.spi.transfer_in_place(&mut value[..])?;
 
         let mut value: [u8; 4] = [OpCode::ReadId as u8, 0, 0, 0];
-        spi.transfer_in_place(&mut value[..])?;
+        self.spi.transfer_in_place(&mut value[..])?;
 
         if value[1] != 0x0B {
             return Err(Error::InvalidManufacturerId);
@@ -74,15 +98,46 @@ impl<SPI: SpiDevice> XtFlash<SPI> {
             return Err(Error::InvalidMemoryType);
         }
 
-        spi.write(&[0x98])?;
+        self.spi.write(&[0x98])?;
+
+        self.spi.write(&[0x50])?;
+
+        self.asleep = false;
+        self.present = true;
+        Ok(())
+    }
+
+    /// Enter deep power-down. Any later access wakes the flash up again first.
+    pub fn power_down(&mut self) -> Result<(), Error<SPI::Error>> {
+        if !self.asleep {
+            self.spi.write(&[OpCode::PowerDown as u8])?;
+            self.asleep = true;
+        }
+        Ok(())
+    }
 
-        spi.write(&[0x50])?;
+    /// Leave deep power-down.
+    pub fn wake_up(&mut self) -> Result<(), Error<SPI::Error>> {
+        if !self.present {
+            return Err(Error::Missing);
+        }
+        if self.asleep {
+            self.spi.write(&[OpCode::Wakeup as u8])?;
+            for _ in 0..WAKEUP_SPINS {
+                core::hint::spin_loop();
+            }
+            self.asleep = false;
+        }
+        Ok(())
+    }
 
-        Ok(Self { spi })
+    pub fn is_asleep(&self) -> bool {
+        self.asleep
     }
 
     pub fn erase(&mut self, from: u32, to: u32) -> Result<(), Error<SPI::Error>> {
         check_erase(self, from, to).map_err(Error::Flash)?;
+        self.wake_up()?;
 
         // info!("Erase 0x{:x} - 0x{:x}", from, to);
         for page in (from..to).step_by(ERASE_SIZE) {
@@ -132,6 +187,7 @@ impl<SPI: SpiDevice> XtFlash<SPI> {
 
     pub fn write(&mut self, mut write_offset: u32, data: &[u8]) -> Result<(), Error<SPI::Error>> {
         check_write(self, write_offset, data.len()).map_err(Error::Flash)?;
+        self.wake_up()?;
         for chunk in data.chunks(PAGE_SIZE / 2) {
             self.write_enable()?;
 
@@ -149,6 +205,7 @@ impl<SPI: SpiDevice> XtFlash<SPI> {
     }
 
     pub fn read(&mut self, mut offset: u32, data: &mut [u8]) -> Result<(), Error<SPI::Error>> {
+        self.wake_up()?;
         for chunk in data.chunks_mut(PAGE_SIZE / 2) {
             let off = offset.to_be_bytes();
             let cmd = [OpCode::Read as u8, off[1], off[2], off[3]];
@@ -168,6 +225,7 @@ impl<SPI: core::fmt::Debug> NorFlashError for Error<SPI> {
             Self::Flash(kind) => *kind,
             Self::InvalidManufacturerId => NorFlashErrorKind::Other,
             Self::InvalidMemoryType => NorFlashErrorKind::Other,
+            Self::Missing => NorFlashErrorKind::Other,
             Self::NotInRam => NorFlashErrorKind::Other,
             Self::Unaligned => NorFlashErrorKind::NotAligned,
             Self::Spi(_) => NorFlashErrorKind::Other,
@@ -34,6 +34,9 @@ const BOOTLOADER_STATE: u32 = 0x0007F000;
 const APPLICATION_DEST: u32 = 0x00000000; // External flash
 const SOFTDEVICE_DEST: u32 = 0x00000000;
 const UICR_ADDRESS: u32 = 0x10001014;
+// Where the MBR keeps a bootloader copy under way, for the firmware to take bootloader updates.
+const MBR_PARAMS: u32 = 0x0007E000;
+const UICR_MBR_PARAMS_ADDRESS: u32 = 0x10001018;
 
 #[interrupt]
 unsafe fn SWI0_EGU0() {
@@ -104,6 +107,8 @@ async fn main(s: Spawner) {
         nvmc.config.write(|w| w.wen().wen());
         while nvmc.ready.read().ready().is_busy() {}
         core::ptr::write_volatile(uicr, BOOTLOADER_DEST);
+        while nvmc.ready.read().ready().is_busy() {}
+        core::ptr::write_volatile(UICR_MBR_PARAMS_ADDRESS as *mut u32, MBR_PARAMS);
         nvmc.config.write(|w| w.wen().ren());
         while nvmc.ready.read().ready().is_busy() {}
 
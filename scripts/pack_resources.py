@@ -0,0 +1,51 @@
+#!/usr/bin/env python3
+# Build a resource pack for the watch from a directory of assets.
+#
+# Usage: pack_resources.py <asset dir> <output file>
+#
+# Files are classified by extension: .font for fonts, .icon for icons and anything else as an image. The name of
+# a resource is the file name without extension, at most 16 bytes. Upload the result to /resources.pack using any
+# InfiniTime compatible file transfer app.
+import os
+import struct
+import sys
+import zlib
+
+MAGIC = b"WRES"
+VERSION = 1
+HEADER_SIZE = 12
+ENTRY_SIZE = 32
+MAX_ENTRIES = 32
+
+KINDS = {".font": 1, ".icon": 2}
+IMAGE = 3
+
+
+def main():
+    src, out = sys.argv[1], sys.argv[2]
+    files = sorted(f for f in os.listdir(src) if os.path.isfile(os.path.join(src, f)))
+    if len(files) > MAX_ENTRIES:
+        sys.exit(f"at most {MAX_ENTRIES} resources are supported")
+
+    offset = HEADER_SIZE + ENTRY_SIZE * len(files)
+    toc = b""
+    data = b""
+    for f in files:
+        name, ext = os.path.splitext(f)
+        name = name.encode()
+        if len(name) > 16:
+            sys.exit(f"resource name too long: {f}")
+        with open(os.path.join(src, f), "rb") as fd:
+            content = fd.read()
+        toc += struct.pack(
+            "<16sB3xIII", name, KINDS.get(ext, IMAGE), offset + len(data), len(content), zlib.crc32(content)
+        )
+        data += content
+
+    header = MAGIC + struct.pack("<HHI", VERSION, len(files), zlib.crc32(toc))
+    with open(out, "wb") as fd:
+        fd.write(header + toc + data)
+
+
+if __name__ == "__main__":
+    main()
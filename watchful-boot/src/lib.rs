@@ -0,0 +1,116 @@
+//! What the application and the bootloader agree on beyond the embassy-boot state page: how a SoftDevice update is
+//! handed over in the staging region of the external flash, and how the bootloader installs it.
+//!
+//! Applications are swapped in by embassy-boot from the DFU partition, and swapped back if they do not mark
+//! themselves booted. A SoftDevice can not be swapped, the application runs on top of it, so the application
+//! receives it to the staging region and writes a `StagingHeader` before resetting. On the next boot,
+//! `install_softdevice` copies it over the running one, before anything starts on top of it, and erases the header.
+#![cfg_attr(not(test), no_std)]
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use nrf_dfu::crc::crc32_update;
+
+/// Start of the staging header when a SoftDevice is staged.
+pub const SOFTDEVICE_MAGIC: u32 = 0x5344_5550;
+
+/// Header telling the bootloader a SoftDevice of `size` bytes is at the start of the staging region, with the CRC-32
+/// of those bytes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StagingHeader {
+    pub size: u32,
+    pub crc: u32,
+}
+
+impl StagingHeader {
+    pub const SIZE: usize = 12;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut data = [0; Self::SIZE];
+        data[0..4].copy_from_slice(&SOFTDEVICE_MAGIC.to_le_bytes());
+        data[4..8].copy_from_slice(&self.size.to_le_bytes());
+        data[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        data
+    }
+
+    /// The header in `data`, `None` if no SoftDevice is staged, as after the header was erased.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data: &[u8; Self::SIZE] = data.try_into().ok()?;
+        let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        if word(0) != SOFTDEVICE_MAGIC {
+            return None;
+        }
+        Some(Self {
+            size: word(4),
+            crc: word(8),
+        })
+    }
+}
+
+/// What `install_softdevice` did.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Install {
+    /// No SoftDevice is staged.
+    Nothing,
+    /// The staged SoftDevice of this size was installed.
+    Installed(u32),
+    /// The staged SoftDevice does not match its header or does not fit, and was dropped.
+    Corrupt,
+    /// The flash failed while the SoftDevice was installed. The header is kept, so the install starts over on the
+    /// next boot.
+    Failed,
+}
+
+/// Install the SoftDevice staged at the start of `staging`, as `header` tells, over `softdevice`. The header is erased
+/// once the SoftDevice is installed, or if the staged image is corrupt, so an install that is interrupted starts over
+/// on the next boot.
+pub fn install_softdevice<SD: NorFlash, H: NorFlash, S: ReadNorFlash>(
+    softdevice: &mut SD,
+    header: &mut H,
+    staging: &mut S,
+) -> Install {
+    let mut data = [0; StagingHeader::SIZE];
+    if header.read(0, &mut data).is_err() {
+        return Install::Failed;
+    }
+    let Some(StagingHeader { size, crc }) = StagingHeader::decode(&data) else {
+        return Install::Nothing;
+    };
+    let install = if size as usize <= softdevice.capacity() && image_crc(staging, size).ok() == Some(crc) {
+        if copy(staging, softdevice, size).is_none() {
+            return Install::Failed;
+        }
+        Install::Installed(size)
+    } else {
+        Install::Corrupt
+    };
+    let _ = header.erase(0, header.capacity() as u32);
+    install
+}
+
+/// Copy the first `size` bytes of `from` over `to`, padded with erased bytes to the write size of `to`.
+fn copy<F: ReadNorFlash, T: NorFlash>(from: &mut F, to: &mut T, size: u32) -> Option<()> {
+    to.erase(0, to.capacity() as u32).ok()?;
+    let mut buf = [0xFF; 256];
+    for offset in (0..size).step_by(buf.len()) {
+        let len = (size - offset).min(buf.len() as u32) as usize;
+        buf.fill(0xFF);
+        from.read(offset, &mut buf[..len]).ok()?;
+        let len = len.next_multiple_of(T::WRITE_SIZE);
+        to.write(offset, &buf[..len]).ok()?;
+    }
+    Some(())
+}
+
+/// CRC-32 (IEEE 802.3) of the first `size` bytes of `flash`, the same as the application's.
+pub fn image_crc<F: ReadNorFlash>(flash: &mut F, size: u32) -> Result<u32, F::Error> {
+    let mut crc = 0;
+    let mut buf = [0; 256];
+    for offset in (0..size).step_by(buf.len()) {
+        let chunk = &mut buf[..(size - offset).min(256) as usize];
+        flash.read(offset, chunk)?;
+        crc = crc32_update(crc, chunk);
+    }
+    Ok(crc)
+}
@@ -0,0 +1,159 @@
+//! SoftDevices staged the way the application stages them, installed against an in-memory flash.
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+use watchful_boot::{image_crc, install_softdevice, Install, StagingHeader};
+
+#[derive(Debug)]
+struct MemFlashError;
+
+impl NorFlashError for MemFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+/// Flash written a word and erased 4 kB at a time, failing writes once `fail_after` bytes were written.
+struct MemFlash {
+    data: Vec<u8>,
+    fail_after: Option<usize>,
+}
+
+impl MemFlash {
+    fn new(size: usize) -> Self {
+        Self {
+            data: vec![0xFF; size],
+            fail_after: None,
+        }
+    }
+
+    fn with(data: &[u8], size: usize) -> Self {
+        let mut flash = Self::new(size);
+        flash.data[..data.len()].copy_from_slice(data);
+        flash
+    }
+}
+
+impl ErrorType for MemFlash {
+    type Error = MemFlashError;
+}
+
+impl ReadNorFlash for MemFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), MemFlashError> {
+        let data = self
+            .data
+            .get(offset as usize..offset as usize + bytes.len())
+            .ok_or(MemFlashError)?;
+        bytes.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for MemFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), MemFlashError> {
+        self.data
+            .get_mut(from as usize..to as usize)
+            .ok_or(MemFlashError)?
+            .fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), MemFlashError> {
+        if let Some(left) = self.fail_after {
+            self.fail_after = Some(left.checked_sub(bytes.len()).ok_or(MemFlashError)?);
+        }
+        let data = self
+            .data
+            .get_mut(offset as usize..offset as usize + bytes.len())
+            .ok_or(MemFlashError)?;
+        assert!(data.iter().all(|&b| b == 0xFF), "write to bytes not erased");
+        data.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+const SOFTDEVICE_SIZE: usize = 16 * 1024;
+
+/// A SoftDevice image of `size` bytes, staged with its header as the application stages it.
+fn staged(size: usize) -> (Vec<u8>, MemFlash, MemFlash) {
+    let image: Vec<u8> = (0..size).map(|i| (i * 7 + (i >> 8)) as u8).collect();
+    let mut staging = MemFlash::with(&image, SOFTDEVICE_SIZE * 2);
+    let header = StagingHeader {
+        size: size as u32,
+        crc: image_crc(&mut staging, size as u32).unwrap(),
+    };
+    let header = MemFlash::with(&header.encode(), 4096);
+    (image, header, staging)
+}
+
+#[test]
+fn staged_softdevice_installed() {
+    let (image, mut header, mut staging) = staged(10_001);
+    let mut softdevice = MemFlash::with(&[0x42; SOFTDEVICE_SIZE], SOFTDEVICE_SIZE);
+    assert_eq!(
+        install_softdevice(&mut softdevice, &mut header, &mut staging),
+        Install::Installed(10_001)
+    );
+    assert_eq!(&softdevice.data[..image.len()], &image[..]);
+    assert!(softdevice.data[image.len()..].iter().all(|&b| b == 0xFF));
+    // Installed once only.
+    assert_eq!(
+        install_softdevice(&mut softdevice, &mut header, &mut staging),
+        Install::Nothing
+    );
+}
+
+#[test]
+fn corrupt_softdevice_dropped() {
+    let (_, mut header, mut staging) = staged(10_000);
+    staging.data[5_000] ^= 0x01;
+    let mut softdevice = MemFlash::with(&[0x42; SOFTDEVICE_SIZE], SOFTDEVICE_SIZE);
+    assert_eq!(
+        install_softdevice(&mut softdevice, &mut header, &mut staging),
+        Install::Corrupt
+    );
+    assert!(softdevice.data.iter().all(|&b| b == 0x42));
+    assert_eq!(StagingHeader::decode(&header.data[..StagingHeader::SIZE]), None);
+
+    // Larger than the SoftDevice region.
+    let (_, mut header, mut staging) = staged(SOFTDEVICE_SIZE + 4);
+    assert_eq!(
+        install_softdevice(&mut softdevice, &mut header, &mut staging),
+        Install::Corrupt
+    );
+}
+
+#[test]
+fn interrupted_install_starts_over() {
+    let (image, mut header, mut staging) = staged(10_000);
+    let mut softdevice = MemFlash::new(SOFTDEVICE_SIZE);
+    softdevice.fail_after = Some(4_096);
+    assert_eq!(
+        install_softdevice(&mut softdevice, &mut header, &mut staging),
+        Install::Failed
+    );
+    softdevice.fail_after = None;
+    assert_eq!(
+        install_softdevice(&mut softdevice, &mut header, &mut staging),
+        Install::Installed(10_000)
+    );
+    assert_eq!(&softdevice.data[..image.len()], &image[..]);
+}
+
+#[test]
+fn header_roundtrip() {
+    let header = StagingHeader {
+        size: 0x2_5000,
+        crc: 0x1234_5678,
+    };
+    assert_eq!(StagingHeader::decode(&header.encode()), Some(header));
+    assert_eq!(StagingHeader::decode(&[0xFF; StagingHeader::SIZE]), None);
+    assert_eq!(StagingHeader::decode(&header.encode()[..8]), None);
+}
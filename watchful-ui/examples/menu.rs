@@ -10,7 +10,7 @@ use watchful_ui::*;
 fn main() -> Result<(), core::convert::Infallible> {
     let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
 
-    let view = MenuView::main();
+    let view = MenuView::main(&[("Workout", MenuAction::Workout), ("Find Phone", MenuAction::FindPhone)]);
     view.draw(&mut display)?;
     let output_settings = OutputSettingsBuilder::new().scale(1).build();
 
@@ -22,6 +22,14 @@ fn main() -> Result<(), core::convert::Infallible> {
 
     Window::new("Settings", &output_settings).show_static(&display);
 
+    let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
+    MenuView::confirm_factory_reset().draw(&mut display)?;
+    Window::new("Factory reset", &output_settings).show_static(&display);
+
+    let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
+    MenuView::power().draw(&mut display)?;
+    Window::new("Power", &output_settings).show_static(&display);
+
     let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
 
     let view = MenuView::firmware_settings(FirmwareDetails::new(
@@ -29,6 +37,7 @@ fn main() -> Result<(), core::convert::Infallible> {
         "0.1.0",
         "abcdefg",
         "2021-02-19T21:32:22.932833758+00:00",
+        "release",
         33,
         false,
         false,
@@ -38,8 +47,39 @@ fn main() -> Result<(), core::convert::Infallible> {
 
     let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
     let t = time::OffsetDateTime::now_utc();
-    let view = TimeView::new(time::PrimitiveDateTime::new(t.date(), t.time()), 5, false);
+    let view = TimeView::new(time::PrimitiveDateTime::new(t.date(), t.time()), 5, false, false);
     view.draw(&mut display)?;
     Window::new("Time", &output_settings).show_static(&display);
+
+    let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
+    let view = TextView::new(
+        "Crashes",
+        "1 recorded, hold to clear\n#0 HardFault pc=00031a2c\nxpsr=0x61000000 r0=0x00000000",
+    );
+    view.draw(&mut display)?;
+    Window::new("Text", &output_settings).show_static(&display);
+
+    let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
+    let values: [u16; 144] = core::array::from_fn(|i| if i % 3 == 0 { 60 + (i % 40) as u16 } else { 0 });
+    let view = ChartView::new("Heart rate", &values);
+    view.draw(&mut display)?;
+    Window::new("Chart", &output_settings).show_static(&display);
+
+    let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
+    let history: [u16; 144] = core::array::from_fn(|i| 100 - (i / 3) as u16);
+    let view = BatteryView::new(52, false, Some(40), &history);
+    view.draw(&mut display)?;
+    Window::new("Battery", &output_settings).show_static(&display);
+
+    let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
+    let mut view = GoalView::new(10_250);
+    while view.next_frame() {}
+    view.draw(&mut display)?;
+    Window::new("Step goal", &output_settings).show_static(&display);
+
+    let mut display = SimulatorDisplay::<Rgb>::new(Size::new(240, 240));
+    let view = UpdateView::new(150_000, 412_000, Some(9_800));
+    view.draw(&mut display)?;
+    Window::new("Update", &output_settings).show_static(&display);
     Ok(())
 }
@@ -2,11 +2,11 @@
 
 use core::fmt::Write as _;
 
-use embedded_graphics::image::Image;
+use embedded_graphics::image::{Image, ImageRawLE};
 use embedded_graphics::pixelcolor::Rgb565 as Rgb;
 use embedded_graphics::prelude::{DrawTarget, *};
-use embedded_graphics::primitives::{PrimitiveStyleBuilder, Rectangle};
-use embedded_graphics::text::{Text, TextStyleBuilder};
+use embedded_graphics::primitives::{Circle, Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, Triangle};
+use embedded_graphics::text::{Text, TextStyle, TextStyleBuilder};
 use embedded_iconoir::prelude::*;
 use embedded_layout::layout::linear::{spacing, LinearLayout};
 use embedded_layout::prelude::*;
@@ -17,6 +17,11 @@ use u8g2_fonts::{fonts, U8g2TextStyle};
 const WIDTH: u32 = 240;
 const HEIGHT: u32 = 240;
 const GRID_ITEMS: u32 = 3;
+/// Number of apps that fit in the main menu, above the settings.
+pub const MAIN_MENU_APPS: usize = GRID_ITEMS as usize - 1;
+/// Width and height of the icons of the watch face, and the size of one as raw little endian RGB565 pixels.
+pub const ICON_SIZE: u32 = 24;
+pub const ICON_BYTES: usize = (ICON_SIZE * ICON_SIZE * 2) as usize;
 
 fn watch_text_style(color: Rgb) -> U8g2TextStyle<Rgb> {
     //U8g2TextStyle::new(fonts::u8g2_font_unifont_t_symbols, Rgb::YELLOW)
@@ -37,6 +42,7 @@ fn text_text_style(color: Rgb) -> U8g2TextStyle<Rgb> {
     U8g2TextStyle::new(fonts::u8g2_font_unifont_t_symbols, color)
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ButtonEvent {
     ShortPress,
     LongPress,
@@ -57,21 +63,90 @@ pub enum TouchGesture {
     SwipeRight(Point),
 }
 
+/// How often a view must be redrawn to keep what it shows current.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Refresh {
+    /// The view shows seconds or is animated.
+    Second,
+    Minute,
+}
+
 #[derive(PartialEq)]
 pub struct TimeView {
     pub time: time::PrimitiveDateTime,
     pub battery_level: u32,
     pub battery_charging: bool,
+    /// Show that the watch face assets failed their integrity check and should be uploaded again.
+    pub resources_corrupt: bool,
+    /// Short message shown at the bottom, e.g. about features turned off to save power.
+    pub notice: Option<&'static str>,
+    /// Progress towards the daily step goal in percent, shown as a bar along the top.
+    pub step_progress: Option<u8>,
+    /// Current temperature in degrees Celsius, shown in the top left corner.
+    pub temperature: Option<i16>,
+    /// Battery icon from the resource pack, drawn instead of the built-in one.
+    pub battery_icon: Option<[u8; ICON_BYTES]>,
 }
 
 impl TimeView {
-    pub fn new(time: time::PrimitiveDateTime, battery_level: u32, battery_charging: bool) -> Self {
+    pub fn new(
+        time: time::PrimitiveDateTime,
+        battery_level: u32,
+        battery_charging: bool,
+        resources_corrupt: bool,
+    ) -> Self {
         Self {
             time,
             battery_level,
             battery_charging,
+            resources_corrupt,
+            notice: None,
+            step_progress: None,
+            temperature: None,
+            battery_icon: None,
+        }
+    }
+
+    pub fn with_notice(mut self, notice: &'static str) -> Self {
+        self.notice = Some(notice);
+        self
+    }
+
+    pub fn with_step_progress(mut self, percent: u8) -> Self {
+        self.step_progress = Some(percent);
+        self
+    }
+
+    pub fn with_temperature(mut self, celsius: i16) -> Self {
+        self.temperature = Some(celsius);
+        self
+    }
+
+    /// Name in the resource pack of the icon for the battery level shown.
+    pub fn battery_icon_name(&self) -> &'static str {
+        match self.battery_level {
+            _ if self.battery_charging => "battery-charging",
+            86.. => "battery-full",
+            66..=85 => "battery-75",
+            36..=65 => "battery-50",
+            11..=35 => "battery-25",
+            _ => "battery-empty",
+        }
+    }
+
+    /// Draw `icon`, `ICON_SIZE` pixels square, as the battery icon. Icons of another size are ignored.
+    pub fn with_battery_icon(mut self, icon: &[u8]) -> Self {
+        if let Ok(icon) = icon.try_into() {
+            self.battery_icon = Some(icon);
         }
+        self
+    }
+
+    /// The face shows hours and minutes only.
+    pub fn refresh(&self) -> Refresh {
+        Refresh::Minute
     }
+
     pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
         display.clear(Rgb::BLACK)?;
 
@@ -109,11 +184,24 @@ impl TimeView {
             .align_to(&display_area, horizontal::Center, vertical::Center)
             .draw(display)?;
 
+        if let Some(percent) = self.step_progress {
+            let color = if percent >= 100 {
+                Rgb::CSS_GOLD
+            } else {
+                Rgb::CSS_DARK_CYAN
+            };
+            Rectangle::new(Point::zero(), Size::new(WIDTH * percent.min(100) as u32 / 100, 3))
+                .into_styled(PrimitiveStyle::with_fill(color))
+                .draw(display)?;
+        }
+
         let display_area = display_area.offset(-5);
         let top_right_y = display_area.top_left.y;
         let top_right_x = display_area.top_left.x + display_area.size.width as i32 - 30;
         let pos = Point::new(top_right_x, top_right_y);
-        if self.battery_charging {
+        if let Some(icon) = &self.battery_icon {
+            Image::new(&ImageRawLE::<Rgb>::new(icon, ICON_SIZE), pos).draw(display)?
+        } else if self.battery_charging {
             Image::new(&icons::size24px::system::BatteryCharging::new(Rgb::CSS_DARK_CYAN), pos).draw(display)?
         } else {
             if self.battery_level > 85 {
@@ -129,6 +217,38 @@ impl TimeView {
             }
         };
 
+        if let Some(celsius) = self.temperature {
+            let mut buf: heapless::String<8> = heapless::String::new();
+            write!(buf, "{}°", celsius).unwrap();
+            Text::with_text_style(
+                &buf,
+                display_area.top_left,
+                date_text_style(Rgb::CSS_DARK_CYAN),
+                TextStyleBuilder::new()
+                    .baseline(embedded_graphics::text::Baseline::Top)
+                    .build(),
+            )
+            .draw(display)?;
+        }
+
+        let notice = if self.resources_corrupt {
+            Some("Re-upload resources")
+        } else {
+            self.notice
+        };
+        if let Some(notice) = notice {
+            Text::with_text_style(
+                notice,
+                Point::new(display_area.center().x, display_area.bottom_right().unwrap().y),
+                text_text_style(Rgb::CSS_ORANGE),
+                TextStyleBuilder::new()
+                    .alignment(embedded_graphics::text::Alignment::Center)
+                    .baseline(embedded_graphics::text::Baseline::Bottom)
+                    .build(),
+            )
+            .draw(display)?;
+        }
+
         Ok(())
     }
 }
@@ -136,12 +256,25 @@ impl TimeView {
 pub struct WorkoutView {
     hr: u32,
     duration: time::Duration,
+    /// Distance in meters and pace in seconds per kilometer, if known.
+    distance: Option<(u32, Option<u32>)>,
 }
 
 impl WorkoutView {
     pub fn new(hr: u32, duration: time::Duration) -> Self {
-        Self { hr, duration }
+        Self {
+            hr,
+            duration,
+            distance: None,
+        }
+    }
+
+    /// Show the distance covered in meters and the current pace in seconds per kilometer, if moving.
+    pub fn with_distance(mut self, meters: u32, pace: Option<u32>) -> Self {
+        self.distance = Some((meters, pace));
+        self
     }
+
     pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
         display.clear(Rgb::BLACK)?;
 
@@ -175,8 +308,26 @@ impl WorkoutView {
                 .build(),
         );
 
+        let mut buf: heapless::String<24> = heapless::String::new();
+        if let Some((meters, pace)) = self.distance {
+            write!(buf, "{}.{:02} km", meters / 1000, meters % 1000 / 10).unwrap();
+            match pace {
+                Some(pace) if pace < 6000 => write!(buf, " {}'{:02}\"", pace / 60, pace % 60).unwrap(),
+                _ => write!(buf, " -'--\"").unwrap(),
+            }
+        }
+        let distance = Text::with_text_style(
+            &buf,
+            display.bounding_box().center(),
+            date_text_style(Rgb::WHITE),
+            TextStyleBuilder::new()
+                .alignment(embedded_graphics::text::Alignment::Center)
+                .baseline(embedded_graphics::text::Baseline::Alphabetic)
+                .build(),
+        );
+
         let display_area = display.bounding_box();
-        LinearLayout::vertical(Chain::new(hr).append(secs))
+        LinearLayout::vertical(Chain::new(hr).append(secs).append(distance))
             .with_spacing(spacing::FixedMargin(10))
             .with_alignment(horizontal::Center)
             .arrange()
@@ -187,50 +338,852 @@ impl WorkoutView {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Frames of the step goal celebration.
+pub const GOAL_FRAMES: u8 = 12;
+
+/// Celebration of the daily step goal, animated by drawing each frame in turn.
+#[derive(PartialEq)]
+pub struct GoalView {
+    steps: u32,
+    frame: u8,
+}
+
+impl GoalView {
+    pub fn new(steps: u32) -> Self {
+        Self { steps, frame: 0 }
+    }
+
+    /// Move to the next frame, returning false once the animation is complete.
+    pub fn next_frame(&mut self) -> bool {
+        if self.frame + 1 < GOAL_FRAMES {
+            self.frame += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        // Rings grow out from the center, one more each frame.
+        let center = display.bounding_box().center();
+        for ring in 0..=self.frame as u32 {
+            let color = if ring % 2 == 0 {
+                Rgb::CSS_GOLD
+            } else {
+                Rgb::CSS_DARK_CYAN
+            };
+            Circle::with_center(center, (ring + 1) * WIDTH / GOAL_FRAMES as u32)
+                .into_styled(PrimitiveStyle::with_stroke(color, 4))
+                .draw(display)?;
+        }
+
+        let style = TextStyleBuilder::new()
+            .alignment(embedded_graphics::text::Alignment::Center)
+            .build();
+        Text::with_text_style("Goal reached!", center, date_text_style(Rgb::WHITE), style).draw(display)?;
+        let mut buf: heapless::String<16> = heapless::String::new();
+        write!(buf, "{} steps", self.steps).unwrap();
+        Text::with_text_style(&buf, center + Point::new(0, 30), date_text_style(Rgb::WHITE), style).draw(display)?;
+
+        Ok(())
+    }
+}
+
+/// Progress of a firmware update, with the transfer rate and a warning not to turn the watch off.
+#[derive(PartialEq)]
+pub struct UpdateView {
+    received: u32,
+    total: u32,
+    /// Bytes per second, if known.
+    rate: Option<u32>,
+}
+
+impl UpdateView {
+    pub fn new(received: u32, total: u32, rate: Option<u32>) -> Self {
+        Self { received, total, rate }
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        let style = TextStyleBuilder::new()
+            .alignment(embedded_graphics::text::Alignment::Center)
+            .build();
+        let center = WIDTH as i32 / 2;
+        Text::with_text_style(
+            "Updating",
+            Point::new(center, 30),
+            date_text_style(Rgb::CSS_DARK_CYAN),
+            style,
+        )
+        .draw(display)?;
+
+        let bar = Rectangle::new(Point::new(20, 90), Size::new(WIDTH - 40, 24));
+        bar.into_styled(PrimitiveStyle::with_stroke(Rgb::WHITE, 2))
+            .draw(display)?;
+        let filled = (self.received as u64 * (bar.size.width - 8) as u64)
+            .checked_div(self.total as u64)
+            .unwrap_or(0)
+            .min((bar.size.width - 8) as u64) as u32;
+        if filled > 0 {
+            Rectangle::new(bar.top_left + Point::new(4, 4), Size::new(filled, bar.size.height - 8))
+                .into_styled(PrimitiveStyle::with_fill(Rgb::CSS_DARK_CYAN))
+                .draw(display)?;
+        }
+
+        let mut buf: heapless::String<32> = heapless::String::new();
+        write!(buf, "{} / {} KB", self.received / 1024, self.total.div_ceil(1024)).unwrap();
+        Text::with_text_style(&buf, Point::new(center, 145), date_text_style(Rgb::WHITE), style).draw(display)?;
+        if let Some(rate) = self.rate {
+            buf.clear();
+            write!(buf, "{}.{} KB/s", rate / 1024, rate % 1024 * 10 / 1024).unwrap();
+            Text::with_text_style(&buf, Point::new(center, 175), date_text_style(Rgb::WHITE), style).draw(display)?;
+        }
+
+        let notice = if self.total > 0 && self.received >= self.total {
+            "Restarting"
+        } else {
+            "Do not power off"
+        };
+        Text::with_text_style(
+            notice,
+            Point::new(center, 215),
+            date_text_style(Rgb::CSS_LIGHT_CORAL),
+            style,
+        )
+        .draw(display)?;
+        Ok(())
+    }
+}
+
+/// Maximum length of the text shown by a `TextView`.
+pub const TEXT_SIZE: usize = 256;
+
+/// Page of text with a title, used for diagnostics and other informational screens.
+#[derive(PartialEq)]
+pub struct TextView {
+    title: &'static str,
+    text: heapless::String<TEXT_SIZE>,
+}
+
+impl TextView {
+    pub fn new(title: &'static str, text: &str) -> Self {
+        let mut view = Self {
+            title,
+            text: heapless::String::new(),
+        };
+        for c in text.chars() {
+            if view.text.push(c).is_err() {
+                break;
+            }
+        }
+        view
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        Text::with_text_style(
+            self.title,
+            Point::new(WIDTH as i32 / 2, 30),
+            date_text_style(Rgb::CSS_DARK_CYAN),
+            TextStyleBuilder::new()
+                .alignment(embedded_graphics::text::Alignment::Center)
+                .build(),
+        )
+        .draw(display)?;
+
+        let bounds = Rectangle::new(Point::new(5, 45), Size::new(WIDTH - 10, HEIGHT - 50));
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(embedded_text::alignment::HorizontalAlignment::Left)
+            .build();
+        TextBox::with_textbox_style(&self.text, bounds, text_text_style(Rgb::CSS_LIGHT_CORAL), textbox_style)
+            .draw(display)?;
+        Ok(())
+    }
+}
+
+/// Maneuver shown by a `NavigationView`, drawn as an arrow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Turn {
+    Straight,
+    SlightLeft,
+    Left,
+    SharpLeft,
+    SlightRight,
+    Right,
+    SharpRight,
+    UTurn,
+    Roundabout,
+    Arrive,
+}
+
+pub const INSTRUCTION_SIZE: usize = 64;
+pub const DISTANCE_SIZE: usize = 16;
+
+/// The upcoming turn of a route the phone navigates, with the distance to it and the progress along the route.
+#[derive(PartialEq)]
+pub struct NavigationView {
+    turn: Turn,
+    instruction: heapless::String<INSTRUCTION_SIZE>,
+    distance: heapless::String<DISTANCE_SIZE>,
+    /// Percent of the route done.
+    progress: u8,
+}
+
+impl NavigationView {
+    pub fn new(turn: Turn, instruction: &str, distance: &str, progress: u8) -> Self {
+        let mut view = Self {
+            turn,
+            instruction: heapless::String::new(),
+            distance: heapless::String::new(),
+            progress: progress.min(100),
+        };
+        for c in instruction.chars() {
+            if view.instruction.push(c).is_err() {
+                break;
+            }
+        }
+        for c in distance.chars() {
+            if view.distance.push(c).is_err() {
+                break;
+            }
+        }
+        view
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        draw_turn(display, self.turn, Point::new(WIDTH as i32 / 2, 60))?;
+
+        Text::with_text_style(
+            &self.distance,
+            Point::new(WIDTH as i32 / 2, 140),
+            date_text_style(Rgb::WHITE),
+            TextStyleBuilder::new()
+                .alignment(embedded_graphics::text::Alignment::Center)
+                .build(),
+        )
+        .draw(display)?;
+
+        let bounds = Rectangle::new(Point::new(5, 150), Size::new(WIDTH - 10, 70));
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(embedded_text::alignment::HorizontalAlignment::Center)
+            .build();
+        TextBox::with_textbox_style(
+            &self.instruction,
+            bounds,
+            text_text_style(Rgb::CSS_LIGHT_CORAL),
+            textbox_style,
+        )
+        .draw(display)?;
+
+        let bar = Rectangle::new(Point::new(20, 226), Size::new(WIDTH - 40, 6));
+        bar.into_styled(PrimitiveStyle::with_fill(Rgb::CSS_DARK_SLATE_GRAY))
+            .draw(display)?;
+        let done = bar.size.width * self.progress as u32 / 100;
+        if done > 0 {
+            Rectangle::new(bar.top_left, Size::new(done, bar.size.height))
+                .into_styled(PrimitiveStyle::with_fill(Rgb::CSS_DARK_CYAN))
+                .draw(display)?;
+        }
+        Ok(())
+    }
+}
+
+/// Arrows of the turns around their center, from the stem below to the tip. Turns to the right are drawn mirrored.
+const ARROW_STRAIGHT: &[Point] = &[Point::new(0, 40), Point::new(0, -34)];
+const ARROW_SLIGHT: &[Point] = &[Point::new(0, 40), Point::new(0, 0), Point::new(-24, -24)];
+const ARROW_TURN: &[Point] = &[Point::new(0, 40), Point::new(0, 0), Point::new(-34, 0)];
+const ARROW_SHARP: &[Point] = &[Point::new(0, 40), Point::new(0, 0), Point::new(-24, 24)];
+const ARROW_U_TURN: &[Point] = &[
+    Point::new(18, 40),
+    Point::new(18, -16),
+    Point::new(-18, -16),
+    Point::new(-18, 24),
+];
+
+fn draw_turn<D: DrawTarget<Color = Rgb>>(display: &mut D, turn: Turn, center: Point) -> Result<(), D::Error> {
+    let stroke = PrimitiveStyle::with_stroke(Rgb::CSS_DARK_CYAN, 8);
+    let fill = PrimitiveStyle::with_fill(Rgb::CSS_DARK_CYAN);
+    let (arrow, mirror) = match turn {
+        Turn::Arrive => {
+            Circle::with_center(center, 56).into_styled(stroke).draw(display)?;
+            return Circle::with_center(center, 24).into_styled(fill).draw(display);
+        }
+        Turn::Straight => (ARROW_STRAIGHT, false),
+        Turn::Roundabout => {
+            Circle::with_center(center, 32).into_styled(stroke).draw(display)?;
+            (ARROW_STRAIGHT, false)
+        }
+        Turn::SlightLeft => (ARROW_SLIGHT, false),
+        Turn::Left => (ARROW_TURN, false),
+        Turn::SharpLeft => (ARROW_SHARP, false),
+        Turn::SlightRight => (ARROW_SLIGHT, true),
+        Turn::Right => (ARROW_TURN, true),
+        Turn::SharpRight => (ARROW_SHARP, true),
+        Turn::UTurn => (ARROW_U_TURN, false),
+    };
+    let point = |p: Point| center + if mirror { Point::new(-p.x, p.y) } else { p };
+    for leg in arrow.windows(2) {
+        Line::new(point(leg[0]), point(leg[1]))
+            .into_styled(stroke)
+            .draw(display)?;
+    }
+
+    // The head is a triangle at the tip, pointing along the last leg.
+    let tip = point(arrow[arrow.len() - 1]);
+    let last = tip - point(arrow[arrow.len() - 2]);
+    let direction = Point::new(last.x.signum(), last.y.signum());
+    let along = if direction.x != 0 && direction.y != 0 {
+        direction * 10
+    } else {
+        direction * 14
+    };
+    let across = Point::new(-along.y, along.x);
+    Triangle::new(tip + along, tip + across, tip - across)
+        .into_styled(fill)
+        .draw(display)
+}
+
+/// Weather conditions, as the phone sends them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Condition {
+    Clear,
+    FewClouds,
+    Clouds,
+    HeavyClouds,
+    Rain,
+    Showers,
+    Thunderstorm,
+    Snow,
+    Mist,
+    Unknown,
+}
+
+impl Condition {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Clear => "Clear",
+            Self::FewClouds => "Few clouds",
+            Self::Clouds => "Cloudy",
+            Self::HeavyClouds => "Overcast",
+            Self::Rain => "Rain",
+            Self::Showers => "Showers",
+            Self::Thunderstorm => "Storm",
+            Self::Snow => "Snow",
+            Self::Mist => "Mist",
+            Self::Unknown => "",
+        }
+    }
+}
+
+/// Days of forecast a `WeatherView` shows.
+pub const FORECAST_DAYS: usize = 5;
+pub const LOCATION_SIZE: usize = 32;
+
+/// Temperatures in degrees Celsius, low then high, and the conditions of a day.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DayWeather {
+    pub min: i16,
+    pub max: i16,
+    pub condition: Condition,
+}
+
+/// Current weather at the location of the phone, and the forecast for the coming days.
+#[derive(PartialEq)]
+pub struct WeatherView {
+    location: heapless::String<LOCATION_SIZE>,
+    /// Current temperature in degrees Celsius, and the range and conditions of today.
+    current: Option<(i16, DayWeather)>,
+    forecast: heapless::Vec<(time::Weekday, DayWeather), FORECAST_DAYS>,
+}
+
+impl WeatherView {
+    pub fn new(location: &str) -> Self {
+        let mut view = Self {
+            location: heapless::String::new(),
+            current: None,
+            forecast: heapless::Vec::new(),
+        };
+        for c in location.chars() {
+            if view.location.push(c).is_err() {
+                break;
+            }
+        }
+        view
+    }
+
+    pub fn with_current(mut self, temperature: i16, today: DayWeather) -> Self {
+        self.current = Some((temperature, today));
+        self
+    }
+
+    /// Add a day to the forecast, days beyond `FORECAST_DAYS` are left out.
+    pub fn with_day(mut self, weekday: time::Weekday, weather: DayWeather) -> Self {
+        let _ = self.forecast.push((weekday, weather));
+        self
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        let center = TextStyleBuilder::new()
+            .alignment(embedded_graphics::text::Alignment::Center)
+            .build();
+        let title = if self.location.is_empty() {
+            "Weather"
+        } else {
+            self.location.as_str()
+        };
+        Text::with_text_style(
+            title,
+            Point::new(WIDTH as i32 / 2, 30),
+            date_text_style(Rgb::CSS_DARK_CYAN),
+            center,
+        )
+        .draw(display)?;
+
+        if self.current.is_none() && self.forecast.is_empty() {
+            Text::with_text_style(
+                "No weather data",
+                Point::new(WIDTH as i32 / 2, 120),
+                text_text_style(Rgb::CSS_LIGHT_CORAL),
+                center,
+            )
+            .draw(display)?;
+            return Ok(());
+        }
+
+        let mut buf: heapless::String<32> = heapless::String::new();
+        if let Some((temperature, today)) = self.current {
+            write!(buf, "{}°", temperature).unwrap();
+            Text::with_text_style(
+                &buf,
+                Point::new(20, 80),
+                menu_text_style(Rgb::WHITE),
+                TextStyle::default(),
+            )
+            .draw(display)?;
+            buf.clear();
+            write!(buf, "{}° / {}°", today.min, today.max).unwrap();
+            Text::with_text_style(
+                &buf,
+                Point::new(110, 60),
+                text_text_style(Rgb::WHITE),
+                TextStyle::default(),
+            )
+            .draw(display)?;
+            Text::with_text_style(
+                today.condition.name(),
+                Point::new(110, 80),
+                text_text_style(Rgb::CSS_LIGHT_CORAL),
+                TextStyle::default(),
+            )
+            .draw(display)?;
+        }
+
+        for (row, (weekday, day)) in self.forecast.iter().enumerate() {
+            let y = 120 + row as i32 * 22;
+            buf.clear();
+            write!(buf, "{}", weekday).unwrap();
+            buf.truncate(3);
+            Text::with_text_style(
+                &buf,
+                Point::new(10, y),
+                text_text_style(Rgb::CSS_DARK_CYAN),
+                TextStyle::default(),
+            )
+            .draw(display)?;
+            Text::with_text_style(
+                day.condition.name(),
+                Point::new(50, y),
+                text_text_style(Rgb::CSS_LIGHT_CORAL),
+                TextStyle::default(),
+            )
+            .draw(display)?;
+            buf.clear();
+            write!(buf, "{}°/{}°", day.min, day.max).unwrap();
+            Text::with_text_style(
+                &buf,
+                Point::new(WIDTH as i32 - 10, y),
+                text_text_style(Rgb::WHITE),
+                TextStyleBuilder::new()
+                    .alignment(embedded_graphics::text::Alignment::Right)
+                    .build(),
+            )
+            .draw(display)?;
+        }
+        Ok(())
+    }
+}
+
+pub const TRACK_SIZE: usize = 64;
+
+/// What the phone plays, with the controls: play or pause in the middle, the previous and next track at the sides.
+#[derive(PartialEq)]
+pub struct MusicView {
+    artist: heapless::String<TRACK_SIZE>,
+    track: heapless::String<TRACK_SIZE>,
+    playing: bool,
+}
+
+impl MusicView {
+    pub fn new(artist: &str, track: &str, playing: bool) -> Self {
+        let mut view = Self {
+            artist: heapless::String::new(),
+            track: heapless::String::new(),
+            playing,
+        };
+        for c in artist.chars() {
+            if view.artist.push(c).is_err() {
+                break;
+            }
+        }
+        for c in track.chars() {
+            if view.track.push(c).is_err() {
+                break;
+            }
+        }
+        view
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(embedded_text::alignment::HorizontalAlignment::Center)
+            .build();
+        let track = if self.track.is_empty() {
+            "Not playing"
+        } else {
+            self.track.as_str()
+        };
+        TextBox::with_textbox_style(
+            track,
+            Rectangle::new(Point::new(5, 20), Size::new(WIDTH - 10, 60)),
+            date_text_style(Rgb::WHITE),
+            textbox_style,
+        )
+        .draw(display)?;
+        TextBox::with_textbox_style(
+            &self.artist,
+            Rectangle::new(Point::new(5, 85), Size::new(WIDTH - 10, 40)),
+            text_text_style(Rgb::CSS_LIGHT_CORAL),
+            textbox_style,
+        )
+        .draw(display)?;
+
+        let fill = PrimitiveStyle::with_fill(Rgb::CSS_DARK_CYAN);
+        let center = Point::new(WIDTH as i32 / 2, 180);
+        if self.playing {
+            for x in [-14, 6] {
+                Rectangle::new(center + Point::new(x, -20), Size::new(8, 40))
+                    .into_styled(fill)
+                    .draw(display)?;
+            }
+        } else {
+            Triangle::new(
+                center + Point::new(-14, -20),
+                center + Point::new(-14, 20),
+                center + Point::new(20, 0),
+            )
+            .into_styled(fill)
+            .draw(display)?;
+        }
+        // Previous and next, two triangles each pointing to their side of the screen.
+        for (x, direction) in [(40, -1), (WIDTH as i32 - 40, 1)] {
+            for offset in [-10, 6] {
+                let tip = Point::new(x + direction * (offset + 14), center.y);
+                let base = x + direction * offset;
+                Triangle::new(Point::new(base, center.y - 12), Point::new(base, center.y + 12), tip)
+                    .into_styled(fill)
+                    .draw(display)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The passkey to type on the phone to pair with the watch.
+#[derive(PartialEq)]
+pub struct PasskeyView {
+    passkey: [u8; 6],
+}
+
+impl PasskeyView {
+    /// `passkey` holds the six ASCII digits, as the SoftDevice hands them out.
+    pub fn new(passkey: [u8; 6]) -> Self {
+        Self { passkey }
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        let center = TextStyleBuilder::new()
+            .alignment(embedded_graphics::text::Alignment::Center)
+            .build();
+        Text::with_text_style(
+            "Pairing",
+            Point::new(WIDTH as i32 / 2, 30),
+            date_text_style(Rgb::CSS_DARK_CYAN),
+            center,
+        )
+        .draw(display)?;
+        Text::with_text_style(
+            core::str::from_utf8(&self.passkey).unwrap_or("------"),
+            Point::new(WIDTH as i32 / 2, 140),
+            watch_text_style(Rgb::WHITE),
+            center,
+        )
+        .draw(display)?;
+        Text::with_text_style(
+            "Enter this code on the phone",
+            Point::new(WIDTH as i32 / 2, 200),
+            text_text_style(Rgb::CSS_LIGHT_CORAL),
+            center,
+        )
+        .draw(display)?;
+        Ok(())
+    }
+}
+
+/// Number of values a chart can display, enough for a day of 10 minute samples.
+pub const CHART_VALUES: usize = 144;
+
+/// Bar chart of a series of values, such as heart rate samples over a day.
+#[derive(PartialEq)]
+pub struct ChartView {
+    title: &'static str,
+    values: heapless::Vec<u16, CHART_VALUES>,
+}
+
+impl ChartView {
+    pub fn new(title: &'static str, values: &[u16]) -> Self {
+        let values = &values[..values.len().min(CHART_VALUES)];
+        Self {
+            title,
+            values: heapless::Vec::from_slice(values).unwrap(),
+        }
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        let max = self.values.iter().copied().max().unwrap_or(0);
+        let mut buf: heapless::String<32> = heapless::String::new();
+        write!(buf, "{} (max {})", self.title, max).unwrap();
+        Text::with_text_style(
+            &buf,
+            Point::new(WIDTH as i32 / 2, 30),
+            date_text_style(Rgb::CSS_DARK_CYAN),
+            TextStyleBuilder::new()
+                .alignment(embedded_graphics::text::Alignment::Center)
+                .build(),
+        )
+        .draw(display)?;
+
+        draw_bars(display, &self.values, max)
+    }
+}
+
+/// Draw `values` as bars scaled to `max` below the title of a chart.
+fn draw_bars<D: DrawTarget<Color = Rgb>>(display: &mut D, values: &[u16], max: u16) -> Result<(), D::Error> {
+    if values.is_empty() || max == 0 {
+        return Ok(());
+    }
+
+    let area = Rectangle::with_corners(Point::new(10, 50), Point::new(WIDTH as i32 - 10, HEIGHT as i32 - 20));
+    let bar_width = (area.size.width / values.len() as u32).max(1);
+    let bar_style = PrimitiveStyleBuilder::new().fill_color(Rgb::CSS_DARK_CYAN).build();
+    for (i, value) in values.iter().enumerate() {
+        let height = *value as u32 * area.size.height / max as u32;
+        if height == 0 {
+            continue;
+        }
+        let x = area.top_left.x + (i as u32 * area.size.width / values.len() as u32) as i32;
+        let y = area.top_left.y + (area.size.height - height) as i32;
+        Rectangle::new(Point::new(x, y), Size::new(bar_width, height))
+            .into_styled(bar_style)
+            .draw(display)?;
+    }
+    Ok(())
+}
+
+/// Battery level over the last day with the estimated time remaining.
+#[derive(PartialEq)]
+pub struct BatteryView {
+    level: u32,
+    charging: bool,
+    /// Estimated hours of runtime left, if known.
+    remaining_hours: Option<u32>,
+    history: heapless::Vec<u16, CHART_VALUES>,
+}
+
+impl BatteryView {
+    pub fn new(level: u32, charging: bool, remaining_hours: Option<u32>, history: &[u16]) -> Self {
+        let history = &history[history.len().saturating_sub(CHART_VALUES)..];
+        Self {
+            level,
+            charging,
+            remaining_hours,
+            history: heapless::Vec::from_slice(history).unwrap(),
+        }
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb>>(&self, display: &mut D) -> Result<(), D::Error> {
+        display.clear(Rgb::BLACK)?;
+
+        let mut buf: heapless::String<32> = heapless::String::new();
+        if self.charging {
+            write!(buf, "{}% charging", self.level).unwrap();
+        } else if let Some(hours) = self.remaining_hours {
+            write!(buf, "{}% ~{}d {}h left", self.level, hours / 24, hours % 24).unwrap();
+        } else {
+            write!(buf, "{}%", self.level).unwrap();
+        }
+        Text::with_text_style(
+            &buf,
+            Point::new(WIDTH as i32 / 2, 30),
+            date_text_style(Rgb::CSS_DARK_CYAN),
+            TextStyleBuilder::new()
+                .alignment(embedded_graphics::text::Alignment::Center)
+                .build(),
+        )
+        .draw(display)?;
+
+        draw_bars(display, &self.history, 100)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MenuAction {
     Workout,
+    WalkWorkout,
+    RunWorkout,
+    WorkoutHistory,
     FindPhone,
     Settings,
     FirmwareSettings,
     ValidateFirmware,
+    Diagnostics,
+    ResetOptions,
     Reset,
+    /// Let new phones find the watch and pair with it.
+    PairingMode,
+    FactoryReset,
+    ConfirmFactoryReset,
+    ConfirmPowerOff,
+    PowerReserve,
+    /// Leave the menu without doing anything.
+    Cancel,
 }
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum MenuView {
     Main {
-        workout: MenuItem,
-        find_phone: MenuItem,
+        apps: [Option<(MenuItem, MenuAction)>; MAIN_MENU_APPS],
         settings: MenuItem,
     },
+    Workout {
+        walk: MenuItem,
+        run: MenuItem,
+        history: MenuItem,
+    },
     Settings {
         firmware: MenuItem,
+        diagnostics: MenuItem,
         reset: MenuItem,
     },
     Firmware {
         details: FirmwareDetails,
         item: MenuItem,
     },
+    Reset {
+        restart: MenuItem,
+        pairing: MenuItem,
+        factory_reset: MenuItem,
+    },
+    ConfirmFactoryReset {
+        cancel: MenuItem,
+        confirm: MenuItem,
+    },
+    Power {
+        reserve: MenuItem,
+        power_off: MenuItem,
+        cancel: MenuItem,
+    },
 }
 
 impl MenuView {
-    pub fn main() -> Self {
+    /// The main menu with the given apps, by name and the action that opens them, followed by the settings. Apps
+    /// beyond `MAIN_MENU_APPS` are left out.
+    pub fn main(apps: &[(&'static str, MenuAction)]) -> Self {
+        let mut items = [None; MAIN_MENU_APPS];
+        for (idx, (item, &(name, action))) in items.iter_mut().zip(apps).enumerate() {
+            *item = Some((MenuItem::new(name, idx as u32), action));
+        }
         Self::Main {
-            workout: MenuItem::new("Workout", 0),
-            find_phone: MenuItem::new("Find Phone", 1),
-            settings: MenuItem::new("Settings", 2),
+            apps: items,
+            settings: MenuItem::new("Settings", apps.len().min(MAIN_MENU_APPS) as u32),
+        }
+    }
+
+    pub fn workout() -> Self {
+        Self::Workout {
+            walk: MenuItem::new("Walk", 0),
+            run: MenuItem::new("Run", 1),
+            history: MenuItem::new("History", 2),
         }
     }
 
     pub fn settings() -> Self {
         Self::Settings {
             firmware: MenuItem::new("Firmware", 0),
+            diagnostics: MenuItem::new("Diagnostics", 1),
             reset: MenuItem::new("Reset", 2),
         }
     }
 
+    pub fn reset() -> Self {
+        Self::Reset {
+            restart: MenuItem::new("Restart", 0),
+            pairing: MenuItem::new("Pair phone", 1),
+            factory_reset: MenuItem::new("Factory reset", 2),
+        }
+    }
+
+    pub fn confirm_factory_reset() -> Self {
+        Self::ConfirmFactoryReset {
+            cancel: MenuItem::new("Cancel", 1),
+            confirm: MenuItem::new("Erase all", 2),
+        }
+    }
+
+    /// Shown when the button is held, so choosing an item also confirms the long press was intended.
+    pub fn power() -> Self {
+        Self::Power {
+            reserve: MenuItem::new("Power reserve", 0),
+            power_off: MenuItem::new("Power off", 1),
+            cancel: MenuItem::new("Cancel", 2),
+        }
+    }
+
     pub fn firmware_settings(details: FirmwareDetails) -> Self {
         let valid = details.validated;
         Self::Firmware {
@@ -243,18 +1196,26 @@ impl MenuView {
         display.clear(Rgb::BLACK)?;
 
         match self {
-            Self::Main {
-                workout,
-                find_phone,
-                settings,
-            } => {
-                workout.draw(display)?;
-                find_phone.draw(display)?;
+            Self::Main { apps, settings } => {
+                for (item, _) in apps.iter().flatten() {
+                    item.draw(display)?;
+                }
                 settings.draw(display)?;
             }
 
-            Self::Settings { firmware, reset } => {
+            Self::Workout { walk, run, history } => {
+                walk.draw(display)?;
+                run.draw(display)?;
+                history.draw(display)?;
+            }
+
+            Self::Settings {
+                firmware,
+                diagnostics,
+                reset,
+            } => {
                 firmware.draw(display)?;
+                diagnostics.draw(display)?;
                 reset.draw(display)?;
             }
 
@@ -262,6 +1223,40 @@ impl MenuView {
                 details.draw(display)?;
                 item.draw(display)?;
             }
+
+            Self::Reset {
+                restart,
+                pairing,
+                factory_reset,
+            } => {
+                restart.draw(display)?;
+                pairing.draw(display)?;
+                factory_reset.draw(display)?;
+            }
+
+            Self::ConfirmFactoryReset { cancel, confirm } => {
+                Text::with_text_style(
+                    "Erase all data?",
+                    Point::new(WIDTH as i32 / 2, 47),
+                    menu_text_style(Rgb::CSS_LIGHT_CORAL),
+                    TextStyleBuilder::new()
+                        .alignment(embedded_graphics::text::Alignment::Center)
+                        .build(),
+                )
+                .draw(display)?;
+                cancel.draw(display)?;
+                confirm.draw(display)?;
+            }
+
+            Self::Power {
+                reserve,
+                power_off,
+                cancel,
+            } => {
+                reserve.draw(display)?;
+                power_off.draw(display)?;
+                cancel.draw(display)?;
+            }
         }
 
         Ok(())
@@ -269,26 +1264,37 @@ impl MenuView {
 
     pub fn on_event(&self, input: InputEvent) -> Option<MenuAction> {
         match self {
-            Self::Main {
-                workout,
-                find_phone,
-                settings,
-            } => {
-                if workout.is_clicked(input) {
-                    Some(MenuAction::Workout)
-                } else if find_phone.is_clicked(input) {
-                    Some(MenuAction::FindPhone)
+            Self::Main { apps, settings } => {
+                if let Some((_, action)) = apps.iter().flatten().find(|(item, _)| item.is_clicked(input)) {
+                    Some(*action)
                 } else if settings.is_clicked(input) {
                     Some(MenuAction::Settings)
                 } else {
                     None
                 }
             }
-            Self::Settings { firmware, reset } => {
+            Self::Workout { walk, run, history } => {
+                if walk.is_clicked(input) {
+                    Some(MenuAction::WalkWorkout)
+                } else if run.is_clicked(input) {
+                    Some(MenuAction::RunWorkout)
+                } else if history.is_clicked(input) {
+                    Some(MenuAction::WorkoutHistory)
+                } else {
+                    None
+                }
+            }
+            Self::Settings {
+                firmware,
+                diagnostics,
+                reset,
+            } => {
                 if firmware.is_clicked(input) {
                     Some(MenuAction::FirmwareSettings)
+                } else if diagnostics.is_clicked(input) {
+                    Some(MenuAction::Diagnostics)
                 } else if reset.is_clicked(input) {
-                    Some(MenuAction::Reset)
+                    Some(MenuAction::ResetOptions)
                 } else {
                     None
                 }
@@ -300,6 +1306,45 @@ impl MenuView {
                     None
                 }
             }
+            Self::Reset {
+                restart,
+                pairing,
+                factory_reset,
+            } => {
+                if restart.is_clicked(input) {
+                    Some(MenuAction::Reset)
+                } else if pairing.is_clicked(input) {
+                    Some(MenuAction::PairingMode)
+                } else if factory_reset.is_clicked(input) {
+                    Some(MenuAction::FactoryReset)
+                } else {
+                    None
+                }
+            }
+            Self::ConfirmFactoryReset { cancel, confirm } => {
+                if cancel.is_clicked(input) {
+                    Some(MenuAction::Settings)
+                } else if confirm.is_clicked(input) {
+                    Some(MenuAction::ConfirmFactoryReset)
+                } else {
+                    None
+                }
+            }
+            Self::Power {
+                reserve,
+                power_off,
+                cancel,
+            } => {
+                if reserve.is_clicked(input) {
+                    Some(MenuAction::PowerReserve)
+                } else if power_off.is_clicked(input) {
+                    Some(MenuAction::ConfirmPowerOff)
+                } else if cancel.is_clicked(input) {
+                    Some(MenuAction::Cancel)
+                } else {
+                    None
+                }
+            }
         }
     }
 }
@@ -367,6 +1412,7 @@ pub struct FirmwareDetails {
     version: &'static str,
     commit: &'static str,
     build_timestamp: &'static str,
+    profile: &'static str,
     battery_level: u32,
     battery_charging: bool,
     validated: bool,
@@ -378,6 +1424,7 @@ impl FirmwareDetails {
         version: &'static str,
         commit: &'static str,
         build_timestamp: &'static str,
+        profile: &'static str,
         battery_level: u32,
         battery_charging: bool,
         validated: bool,
@@ -387,6 +1434,7 @@ impl FirmwareDetails {
             version,
             commit,
             build_timestamp,
+            profile,
             battery_level,
             battery_charging,
             validated,
@@ -410,11 +1458,12 @@ impl FirmwareDetails {
         let mut info: heapless::String<512> = heapless::String::new();
         write!(
             info,
-            "Name: {}\nVersion: {}\nCommit: {}\nBuild: {}\nBattery: {}{}",
+            "Name: {}\nVersion: {}\nCommit: {}\nBuild: {} {}\nBattery: {}{}",
             self.name,
             self.version,
             self.commit,
             self.build_timestamp,
+            self.profile,
             self.battery_level,
             if self.battery_charging { "(Charging)" } else { "" }
         )